@@ -5,13 +5,13 @@ use std::time::Duration;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LoginResponse {
-    pub accessToken: String,
+    #[serde(rename = "accessToken")]
+    pub access_token: String,
     pub licensed: bool,
 }
 
 #[derive(Debug)]
 pub enum AuthError {
-    Http(String),
     Status(u16, String),
     Deserialize(String),
     Network(String),
@@ -0,0 +1,677 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Parser, Subcommand};
+use iced_x86::{Formatter, IntelFormatter};
+
+use crate::pipeline::{self, PipelineMessage, PipelineOptions};
+use crate::pipeline::artifacts::ArtifactReader;
+use crate::pipeline::cancel::CancellationToken;
+use crate::pipeline::error::StepError;
+use crate::pipeline::packer::PackedInputPolicy;
+use crate::pipeline::parse;
+use crate::pipeline::code::{CodeMap, Function};
+use crate::pipeline::hash::HashAlgorithm;
+use crate::pipeline::image::Image;
+use crate::pipeline::profile::{AntiDebugCheck, DebuggerResponse, DecryptTiming, FunctionSelector, ProtectionProfile, ResourceDecoding, ResourceSelector, StartupHook, StripItem, TamperResponse, WatermarkCarrier};
+use crate::pipeline::profile::parse_sip_key;
+use crate::pipeline::minidump::{self, Minidump};
+use crate::pipeline::symbol_map::{self, SymbolMap};
+use crate::pipeline::symbolicate::{OriginalSymbols, Symbolicator};
+use crate::pipeline::watermark;
+
+/// Headless entry point; used when the executable is started with arguments.
+#[derive(Parser)]
+#[command(name = "obscura", version, about = "Obscura Defender command line")]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the protection pipeline on a binary
+    Protect(Box<ProtectArgs>),
+    /// Analyze a binary and export the report
+    Analyze(AnalyzeArgs),
+    /// Find the watermarks a protected binary carries
+    ExtractWatermark(ExtractWatermarkArgs),
+    /// Translate addresses and obfuscated names of a protected build back to the original functions
+    Deobfuscate(DeobfuscateArgs),
+    /// Print the symbolicated stacks of a minidump of a protected build
+    Symbolicate(SymbolicateArgs),
+    /// Check the signature of a side artifact (symbol map, import hashes, .enc) and decrypt it
+    OpenArtifact(OpenArtifactArgs),
+}
+
+#[derive(Args)]
+struct ProtectArgs {
+    /// Binary to protect
+    input: String,
+    /// Only report what would change; no file is written
+    #[arg(long)]
+    dry_run: bool,
+    /// Skip the string encryption step
+    #[arg(long)]
+    no_encrypt_strings: bool,
+    /// Skip the function obfuscation step
+    #[arg(long)]
+    no_obfuscate_functions: bool,
+    /// What to do when the input looks already packed
+    #[arg(long, value_enum, default_value_t = PackedInputPolicy::Warn)]
+    on_packed: PackedInputPolicy,
+    /// Also save the dry-run plan as JSON
+    #[arg(long, value_name = "PATH", requires = "dry_run")]
+    plan_json: Option<PathBuf>,
+    /// Protection profile (JSON) with the settings of the rewriting steps
+    #[arg(long, value_name = "PATH")]
+    profile: Option<PathBuf>,
+    /// Remove imports from the import table and resolve them at startup
+    #[arg(long)]
+    hide_imports: bool,
+    /// Import that stays static, as DLL or DLL:FUNCTION (repeatable)
+    #[arg(long, value_name = "DLL[:FUNCTION]")]
+    keep_import: Vec<String>,
+    /// Hash stored in place of hidden import names (overrides the profile)
+    #[arg(long, value_enum, value_name = "ALGORITHM")]
+    import_hash: Option<HashAlgorithm>,
+    /// SipHash key as 32 hex digits (random per build when unset)
+    #[arg(long, value_name = "HEX")]
+    sip_key: Option<String>,
+    /// Move every movable function into a new code section, unchanged
+    #[arg(long)]
+    move_functions: bool,
+    /// Flatten the control flow of a function: name, RVA (0x...), @exported, @entry or @all (repeatable)
+    #[arg(long, value_name = "FUNCTION")]
+    flatten: Vec<String>,
+    /// Insert opaque predicates and junk code into a function, selected as for --flatten (repeatable)
+    #[arg(long, value_name = "FUNCTION")]
+    opaque: Vec<String>,
+    /// Chance (0 to 1) for each basic block to get an opaque predicate and a junk sequence
+    #[arg(long, value_name = "F")]
+    opaque_density: Option<f64>,
+    /// Rewrite arithmetic, logic and constant loads of a function into longer equivalent sequences (repeatable)
+    #[arg(long, value_name = "FUNCTION")]
+    substitute: Vec<String>,
+    /// Translate a function into bytecode run by an embedded interpreter (repeatable)
+    #[arg(long, value_name = "FUNCTION")]
+    virtualize: Vec<String>,
+    /// Encrypt the body of a function in the output and decrypt it at runtime (repeatable)
+    #[arg(long, value_name = "FUNCTION")]
+    encrypt_code: Vec<String>,
+    /// When the bodies encrypted by --encrypt-code are decrypted
+    #[arg(long, value_enum, value_name = "WHEN")]
+    decrypt_at: Option<DecryptTiming>,
+    /// Encrypt a body again each time its outermost call returns (first-call decryption)
+    #[arg(long)]
+    reencrypt: bool,
+    /// Inject startup checks that react to an attached debugger
+    #[arg(long)]
+    anti_debug: bool,
+    /// Anti-debug check to inject, replacing the default set (repeatable; implies --anti-debug)
+    #[arg(long, value_enum, value_name = "CHECK")]
+    anti_debug_check: Vec<AntiDebugCheck>,
+    /// What the anti-debug checks do when they find a debugger
+    #[arg(long, value_enum, value_name = "RESPONSE")]
+    debugger_response: Option<DebuggerResponse>,
+    /// Check at startup that the code and read-only data were not patched
+    #[arg(long)]
+    integrity: bool,
+    /// Section to hash for --integrity, instead of every read-only section (repeatable)
+    #[arg(long, value_name = "NAME")]
+    integrity_section: Vec<String>,
+    /// Repeat the integrity check every N seconds from a background thread
+    #[arg(long, value_name = "N")]
+    integrity_interval: Option<u32>,
+    /// What the integrity check does when the code was patched
+    #[arg(long, value_enum, value_name = "RESPONSE")]
+    tamper_response: Option<TamperResponse>,
+    /// Encrypt resources, as TYPE or TYPE/NAME (RT_RCDATA, #10 or a custom type; repeatable)
+    #[arg(long, value_name = "TYPE[/NAME]")]
+    protect_resource: Vec<String>,
+    /// Compress the resources selected by --protect-resource before encrypting them
+    #[arg(long)]
+    compress_resources: bool,
+    /// When the protected resources are decoded: on LoadResource, or all at startup
+    #[arg(long, value_enum, value_name = "WHEN")]
+    decode_resources: Option<ResourceDecoding>,
+    /// Remove the debug directory, Rich header and COFF symbols (PE) or the debug sections and symbol table (ELF)
+    #[arg(long)]
+    strip: bool,
+    /// Item to strip, replacing the default set (repeatable; implies --strip)
+    #[arg(long, value_enum, value_name = "ITEM")]
+    strip_item: Vec<StripItem>,
+    /// Embed a customer or build identifier (up to 32 bytes) in the output
+    #[arg(long, value_name = "ID")]
+    watermark: Option<String>,
+    /// Key of the watermark, as 32 hex digits; without it the built-in key is used
+    #[arg(long, value_name = "HEX")]
+    watermark_key: Option<String>,
+    /// Where to hide the watermark, replacing the default set (repeatable)
+    #[arg(long, value_enum, value_name = "CARRIER")]
+    watermark_carrier: Vec<WatermarkCarrier>,
+    /// Compress the sections into one payload that a stub unpacks at startup
+    #[arg(long)]
+    pack: bool,
+    /// What calls the injected startup routines: the entry point, or a TLS callback run before the others (PE)
+    #[arg(long, value_enum, value_name = "HOOK")]
+    startup_hook: Option<StartupHook>,
+    /// Seed of the randomized passes; the same seed, input and options give the same output (the seed of each run is logged)
+    #[arg(long, value_name = "N")]
+    seed: Option<u64>,
+    /// Directory of the side artifacts (symbol map, import hashes, .enc); a per-user data directory by default
+    #[arg(long, value_name = "DIR")]
+    artifacts_dir: Option<PathBuf>,
+    /// Team key the side artifacts are encrypted with, as 64 hex digits
+    #[arg(long, value_name = "HEX")]
+    artifact_key: Option<String>,
+    /// Ed25519 secret key the side artifacts are signed with, as 64 hex digits
+    #[arg(long, value_name = "HEX")]
+    signing_key: Option<String>,
+}
+
+#[derive(Args)]
+struct AnalyzeArgs {
+    /// Binary to analyze
+    input: String,
+    /// Write the report as JSON
+    #[arg(long, value_name = "PATH")]
+    json: Option<PathBuf>,
+    /// Write the report as a standalone HTML page
+    #[arg(long, value_name = "PATH")]
+    html: Option<PathBuf>,
+    /// List the functions found by the disassembler
+    #[arg(long)]
+    functions: bool,
+    /// Print the basic blocks of a function, given by name or RVA (0x...)
+    #[arg(long, value_name = "FUNCTION")]
+    disasm: Option<String>,
+}
+
+#[derive(Args)]
+struct ExtractWatermarkArgs {
+    /// Protected binary
+    input: String,
+    /// Key the watermark was embedded with, as 32 hex digits
+    #[arg(long, value_name = "HEX")]
+    key: Option<String>,
+}
+
+#[derive(Args)]
+struct DeobfuscateArgs {
+    /// Addresses (0x... or MODULE+0x...) or names to translate; a stack trace is read from standard input when none are given
+    items: Vec<String>,
+    /// Symbol map written with the protected build (<input>.<hash>.obf-map in the artifacts directory)
+    #[arg(long, value_name = "PATH")]
+    map: PathBuf,
+    #[command(flatten)]
+    keys: ArtifactKeyArgs,
+    /// Address the module was loaded at, for traces of a rebased process; the image base by default
+    #[arg(long, value_name = "ADDRESS")]
+    base: Option<String>,
+    /// Check that the map was written for this protected binary
+    #[arg(long, value_name = "PATH")]
+    binary: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct SymbolicateArgs {
+    /// Minidump of the crashed process
+    dump: PathBuf,
+    /// Symbol map written with the protected build (<input>.<hash>.obf-map in the artifacts directory)
+    #[arg(long, value_name = "PATH")]
+    map: PathBuf,
+    #[command(flatten)]
+    keys: ArtifactKeyArgs,
+    /// Symbols of the unprotected input: its PDB, or the binary itself (ELF symbol table or exports)
+    #[arg(long, value_name = "PATH")]
+    symbols: Option<PathBuf>,
+    /// The protected binary: checks the map and the call sites of the stack, and names the added sections
+    #[arg(long, value_name = "PATH")]
+    binary: Option<PathBuf>,
+    /// File name of the protected module in the dump; the main executable by default
+    #[arg(long, value_name = "NAME")]
+    module: Option<String>,
+    /// Print every thread, not only the one that raised the exception
+    #[arg(long)]
+    all_threads: bool,
+}
+
+#[derive(Args)]
+struct OpenArtifactArgs {
+    /// Side artifact written by `protect`
+    input: PathBuf,
+    #[command(flatten)]
+    keys: ArtifactKeyArgs,
+    /// Write the contents here instead of to standard output
+    #[arg(long, value_name = "PATH")]
+    output: Option<PathBuf>,
+}
+
+/// Keys to read side artifacts with.
+#[derive(Args)]
+struct ArtifactKeyArgs {
+    /// Team key the artifact was encrypted with, as 64 hex digits
+    #[arg(long, value_name = "HEX")]
+    artifact_key: Option<String>,
+    /// Ed25519 public key, as 64 hex digits: the artifact must carry a valid signature of it (<artifact>.sig)
+    #[arg(long, value_name = "HEX")]
+    public_key: Option<String>,
+}
+
+impl ArtifactKeyArgs {
+    fn reader(&self) -> Result<ArtifactReader, StepError> {
+        ArtifactReader::new(self.artifact_key.as_deref(), self.public_key.as_deref())
+    }
+}
+
+/// Runs the parsed command and returns the process exit code.
+pub fn run(cli: Cli) -> i32 {
+    let result = match cli.command {
+        Command::Protect(args) => protect(*args),
+        Command::Analyze(args) => analyze(args),
+        Command::ExtractWatermark(args) => extract_watermark(args),
+        Command::Deobfuscate(args) => deobfuscate(args),
+        Command::Symbolicate(args) => symbolicate(args),
+        Command::OpenArtifact(args) => open_artifact(args),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(StepError::Cancelled) => {
+            eprintln!("Pipeline cancelled");
+            130
+        }
+        Err(e) => {
+            eprintln!("{}: {}", e.category(), e.message());
+            1
+        }
+    }
+}
+
+/// Adds the functions selected on the command line to a code pass, which is
+/// enabled when there are any.
+fn select_functions(
+    texts: &[String],
+    functions: &mut Vec<FunctionSelector>,
+    enabled: &mut bool,
+) -> Result<(), StepError> {
+    for text in texts {
+        functions.push(FunctionSelector::parse(text)?);
+    }
+    *enabled |= !texts.is_empty();
+    Ok(())
+}
+
+fn protect(args: ProtectArgs) -> Result<(), StepError> {
+    let mut profile = match &args.profile {
+        Some(path) => ProtectionProfile::load(path)?,
+        None => ProtectionProfile::default(),
+    };
+    profile.imports.enabled |= args.hide_imports;
+    profile.move_functions |= args.move_functions;
+    if let Some(hash) = args.import_hash {
+        profile.imports.hash = hash;
+    }
+    if args.sip_key.is_some() {
+        profile.imports.sip_key = args.sip_key.clone();
+    }
+    for keep in &args.keep_import {
+        let (dll, function) = keep.split_once(':').unwrap_or((keep, "*"));
+        profile
+            .imports
+            .keep
+            .entry(dll.to_string())
+            .or_default()
+            .push(function.to_string());
+    }
+
+    select_functions(&args.flatten, &mut profile.flatten.functions, &mut profile.flatten.enabled)?;
+    select_functions(&args.opaque, &mut profile.opaque.functions, &mut profile.opaque.enabled)?;
+    select_functions(&args.substitute, &mut profile.substitute.functions, &mut profile.substitute.enabled)?;
+    select_functions(&args.virtualize, &mut profile.virtualize.functions, &mut profile.virtualize.enabled)?;
+    select_functions(&args.encrypt_code, &mut profile.encrypt_code.functions, &mut profile.encrypt_code.enabled)?;
+    if let Some(timing) = args.decrypt_at {
+        profile.encrypt_code.decrypt = timing;
+    }
+    profile.encrypt_code.reencrypt |= args.reencrypt;
+    if !args.anti_debug_check.is_empty() {
+        profile.anti_debug.checks = args.anti_debug_check.clone();
+        profile.anti_debug.enabled = true;
+    }
+    profile.anti_debug.enabled |= args.anti_debug;
+    if let Some(response) = args.debugger_response {
+        profile.anti_debug.response = response;
+    }
+    if !args.integrity_section.is_empty() {
+        profile.integrity.sections = args.integrity_section.clone();
+    }
+    profile.integrity.enabled |= args.integrity || !args.integrity_section.is_empty();
+    if args.integrity_interval.is_some() {
+        profile.integrity.interval_secs = args.integrity_interval;
+    }
+    if let Some(response) = args.tamper_response {
+        profile.integrity.response = response;
+    }
+    for text in &args.protect_resource {
+        profile.resources.protect.push(ResourceSelector::parse(text)?);
+        profile.resources.enabled = true;
+    }
+    profile.resources.compress |= args.compress_resources;
+    if let Some(decode) = args.decode_resources {
+        profile.resources.decode = decode;
+    }
+    if !args.strip_item.is_empty() {
+        profile.strip.items = args.strip_item.clone();
+    }
+    profile.strip.enabled |= args.strip || !args.strip_item.is_empty();
+    if let Some(id) = &args.watermark {
+        profile.watermark.id = id.clone();
+        profile.watermark.enabled = true;
+    }
+    if args.watermark_key.is_some() {
+        profile.watermark.key = args.watermark_key.clone();
+    }
+    if !args.watermark_carrier.is_empty() {
+        profile.watermark.carriers = args.watermark_carrier.clone();
+    }
+    profile.pack |= args.pack;
+    if let Some(hook) = args.startup_hook {
+        profile.startup_hook = hook;
+    }
+    if let Some(density) = args.opaque_density {
+        profile.opaque.density = density;
+    }
+    if args.seed.is_some() {
+        profile.seed = args.seed;
+    }
+    if args.artifacts_dir.is_some() {
+        profile.artifacts.dir = args.artifacts_dir.clone();
+    }
+    if args.artifact_key.is_some() {
+        profile.artifacts.key = args.artifact_key.clone();
+    }
+    if args.signing_key.is_some() {
+        profile.artifacts.signing_key = args.signing_key.clone();
+    }
+
+    let options = PipelineOptions {
+        encrypt_strings: !args.no_encrypt_strings,
+        obfuscate_functions: !args.no_obfuscate_functions,
+        dry_run: args.dry_run,
+        packed_input: args.on_packed,
+        profile,
+    };
+    let rx = pipeline::spawn_pipeline(args.input, options, CancellationToken::new());
+
+    // the runner always ends with exactly one terminal message
+    for msg in rx {
+        match msg {
+            PipelineMessage::Log(s) => eprintln!("{}", s),
+            PipelineMessage::Progress(_) | PipelineMessage::Analysis(_) => {}
+            PipelineMessage::Done(output) => {
+                println!("Protected output: {}", output);
+                return Ok(());
+            }
+            PipelineMessage::Planned(plan) => {
+                println!("Dry run for {}: {}", plan.input, plan.summary());
+                for change in &plan.changes {
+                    println!("  {}", change.describe());
+                }
+                if let Some(path) = &args.plan_json {
+                    let json = serde_json::to_string_pretty(&plan)
+                        .map_err(|e| StepError::Internal(format!("Failed to serialize the plan: {}", e)))?;
+                    fs::write(path, json).map_err(|e| StepError::io("Failed to write plan to", path, e))?;
+                    println!("Plan saved to {}", path.display());
+                }
+                return Ok(());
+            }
+            PipelineMessage::Error(e) => return Err(e),
+            PipelineMessage::Cancelled => return Err(StepError::Cancelled),
+        }
+    }
+
+    Err(StepError::Internal("Pipeline thread exited without a result".into()))
+}
+
+fn analyze(args: AnalyzeArgs) -> Result<(), StepError> {
+    let bytes = fs::read(&args.input).map_err(|e| StepError::io("Failed to read file", &args.input, e))?;
+    let report = parse::analyze(&args.input, &bytes)?;
+
+    println!("{}: {} {}", report.file, report.format, report.architecture);
+    for s in &report.sections {
+        println!(
+            "  {:<8} va=0x{:08x} raw={:<8} entropy={:.3} {}",
+            s.name,
+            s.virtual_address,
+            s.raw_size,
+            s.entropy,
+            s.flags.join("|")
+        );
+    }
+    println!(
+        "  {} import DLLs, {} exports, {} TLS callbacks, {} resources",
+        report.imports.len(),
+        report.exports.len(),
+        report.tls_callbacks.len(),
+        report.resources.len()
+    );
+    if let Some(o) = &report.overlay {
+        println!("  overlay: {} bytes at 0x{:x}, entropy {:.3}", o.size, o.offset, o.entropy);
+    }
+    if report.packer.looks_packed {
+        println!(
+            "  looks packed: {}",
+            report.packer.packer.as_deref().unwrap_or("unknown packer")
+        );
+    }
+    for reason in &report.packer.reasons {
+        println!("    - {}", reason);
+    }
+
+    if args.functions || args.disasm.is_some() {
+        let mut image = Image::parse(bytes)?;
+        let code = image.code_map();
+        if args.functions {
+            print_functions(code);
+        }
+        if let Some(wanted) = &args.disasm {
+            let rva = wanted
+                .strip_prefix("0x")
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok());
+            let function = code
+                .functions
+                .iter()
+                .find(|f| Some(f.info.rva) == rva || f.name() == *wanted)
+                .ok_or_else(|| StepError::InvalidInput(format!("No disassembled function '{}'", wanted)))?;
+            print_disassembly(function);
+        }
+    }
+
+    for (path, contents) in [
+        (&args.json, report.to_json()),
+        (&args.html, report.to_html()),
+    ] {
+        let Some(path) = path else { continue };
+        fs::write(path, contents).map_err(|e| StepError::io("Failed to write", path, e))?;
+        println!("Report saved to {}", path.display());
+    }
+    Ok(())
+}
+
+fn print_functions(code: &CodeMap) {
+    println!("  {} functions, {} not disassembled", code.functions.len(), code.skipped.len());
+    for (index, f) in code.functions.iter().enumerate() {
+        let movable = match code.check_movable(index) {
+            Ok(()) => "movable".to_string(),
+            Err(reason) => format!("fixed: {}", reason),
+        };
+        println!(
+            "    0x{:08x} {:>6} {:<32} {:>4} blocks {:>5} instrs {:>4} xrefs  {}",
+            f.info.rva,
+            f.info.size,
+            f.name(),
+            f.blocks.len(),
+            f.instructions.len(),
+            f.xrefs.len(),
+            movable
+        );
+    }
+    for (info, reason) in &code.skipped {
+        println!("    0x{:08x} {:<39} skipped: {}", info.rva, info.display_name(), reason);
+    }
+}
+
+fn print_disassembly(function: &Function) {
+    let mut formatter = IntelFormatter::new();
+    let mut text = String::new();
+    println!("  {} @0x{:08x} ({} bytes)", function.name(), function.info.rva, function.info.size);
+    for (index, block) in function.blocks.iter().enumerate() {
+        let successors: Vec<String> = block.successors.iter().map(|s| s.to_string()).collect();
+        println!("  block {} -> [{}]", index, successors.join(", "));
+        for instruction in &function.instructions[block.start..block.end] {
+            text.clear();
+            formatter.format(instruction, &mut text);
+            println!("    {:016x}  {}", instruction.ip(), text);
+        }
+    }
+}
+
+fn extract_watermark(args: ExtractWatermarkArgs) -> Result<(), StepError> {
+    let key = args.key.as_deref().map(parse_sip_key).transpose()?.unwrap_or(watermark::DEFAULT_KEY);
+    if args.key.is_none() {
+        eprintln!("No --key given: looking for watermarks of the built-in key, which anyone with this tool can forge");
+    }
+    let bytes = fs::read(&args.input).map_err(|e| StepError::io("Failed to read file", &args.input, e))?;
+    let findings = watermark::extract(bytes, key)?;
+    if findings.is_empty() {
+        return Err(StepError::InvalidInput(format!("{}: no watermark found", args.input)));
+    }
+    for finding in &findings {
+        println!("{:<12} {}  ({})", finding.carrier.to_string(), finding.id, finding.evidence);
+    }
+    Ok(())
+}
+
+fn deobfuscate(args: DeobfuscateArgs) -> Result<(), StepError> {
+    let map = SymbolMap::load(&args.map, &args.keys.reader()?)?;
+    if let Some(binary) = &args.binary {
+        read_build(&map, binary)?;
+    }
+    let base = match &args.base {
+        Some(text) => u64::from_str_radix(text.trim_start_matches("0x"), 16)
+            .map_err(|_| StepError::InvalidInput(format!("Invalid load address '{}'", text)))?,
+        None => map.image_base,
+    };
+
+    if !args.items.is_empty() {
+        for item in &args.items {
+            println!("{}", map.deobfuscate_line(item, base));
+        }
+        return Ok(());
+    }
+    for line in std::io::stdin().lines() {
+        let line = line.map_err(|e| StepError::io("Failed to read", "standard input", e))?;
+        println!("{}", map.deobfuscate_line(&line, base));
+    }
+    Ok(())
+}
+
+/// Reads the protected binary at `path`, checking that `map` was written for it.
+fn read_build(map: &SymbolMap, path: &Path) -> Result<Vec<u8>, StepError> {
+    let bytes = fs::read(path).map_err(|e| StepError::io("Failed to read file", path, e))?;
+    let id = symbol_map::build_id(&bytes);
+    if id != map.build_id {
+        return Err(StepError::InvalidInput(format!(
+            "{} is build {}, but the map was written for build {}",
+            path.display(),
+            id,
+            map.build_id
+        )));
+    }
+    Ok(bytes)
+}
+
+fn symbolicate(args: SymbolicateArgs) -> Result<(), StepError> {
+    let map = SymbolMap::load(&args.map, &args.keys.reader()?)?;
+    let bytes = fs::read(&args.dump).map_err(|e| StepError::io("Failed to read minidump", &args.dump, e))?;
+    let dump = Minidump::parse(bytes)?;
+    let binary = match &args.binary {
+        Some(path) => Some(Image::parse(read_build(&map, path)?)?),
+        None => None,
+    };
+    let symbols = match &args.symbols {
+        Some(path) => {
+            let bytes = fs::read(path).map_err(|e| StepError::io("Failed to read symbols", path, e))?;
+            Some(OriginalSymbols::load(bytes)?)
+        }
+        None => None,
+    };
+    let module = match &args.module {
+        Some(name) => dump
+            .modules
+            .iter()
+            .find(|m| m.name.rsplit(['\\', '/']).next().is_some_and(|file| file.eq_ignore_ascii_case(name)))
+            .ok_or_else(|| StepError::InvalidInput(format!("No module named {} in the dump", name)))?,
+        None => dump.modules.first().ok_or_else(|| StepError::InvalidInput("The dump lists no modules".into()))?,
+    };
+
+    println!(
+        "{}: {:?}, {} modules, {} threads",
+        args.dump.display(),
+        dump.architecture,
+        dump.modules.len(),
+        dump.threads.len()
+    );
+    println!("Protected module: {} at 0x{:x} (map of build {})", module.name, module.base, map.build_id);
+    if let Some(symbols) = &symbols {
+        println!("Original symbols: {}", symbols.description);
+        if let (Some(pdb), Some(codeview)) = (symbols.pdb, &module.codeview) {
+            if pdb != (codeview.guid, codeview.age) {
+                println!("  warning: the PDB does not match the module's CodeView record ({})", codeview.pdb);
+            }
+        }
+    }
+    let symbolicator = Symbolicator::new(&dump, module, &map, symbols.as_ref(), binary.as_ref());
+    if let Some(exception) = &dump.exception {
+        println!(
+            "Exception {} (0x{:08x}) at 0x{:x} {} in thread {}",
+            minidump::exception_name(exception.code).unwrap_or("code"),
+            exception.code,
+            exception.address,
+            symbolicator.describe(exception.address),
+            exception.thread_id
+        );
+    }
+
+    let crashed = dump.exception.as_ref().map(|e| e.thread_id);
+    let mut threads: Vec<_> = dump.threads.iter().filter(|t| args.all_threads || crashed.is_none_or(|id| t.id == id)).collect();
+    // the crashed thread first
+    threads.sort_by_key(|t| Some(t.id) != crashed);
+    for thread in threads {
+        println!();
+        println!("Thread {}{}", thread.id, if Some(thread.id) == crashed { " (crashed)" } else { "" });
+        let frames = symbolicator.walk(thread);
+        if frames.is_empty() {
+            println!("  no register context");
+        }
+        for (i, frame) in frames.iter().enumerate() {
+            println!(
+                "  #{:<2} 0x{:016x}  {}  ({})",
+                i,
+                frame.address,
+                symbolicator.describe(frame.address),
+                frame.trust
+            );
+        }
+    }
+    Ok(())
+}
+
+fn open_artifact(args: OpenArtifactArgs) -> Result<(), StepError> {
+    let contents = args.keys.reader()?.read(&args.input)?;
+    match &args.output {
+        Some(path) => fs::write(path, &contents).map_err(|e| StepError::io("Failed to write", path, e)),
+        None => std::io::Write::write_all(&mut std::io::stdout(), &contents)
+            .map_err(|e| StepError::io("Failed to write", "standard output", e)),
+    }
+}
@@ -2,13 +2,19 @@ mod app;
 mod state;
 mod pipeline;
 mod ui;
-mod auth;
 mod auth_client;
+mod cli;
 
 use app::Obscura;
+use clap::Parser;
 use eframe::egui;
 
 fn main() -> eframe::Result<()> {
+    // any argument switches to the command line interface
+    if std::env::args_os().len() > 1 {
+        std::process::exit(cli::run(cli::Cli::parse()));
+    }
+
     let native_options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([1280.0, 720.0])
@@ -0,0 +1,672 @@
+//! Debugger checks of `AntiDebugStep`, run as the first startup routine.
+//!
+//! The routine runs each configured check in turn and stops at the first
+//! that finds a debugger. On PE the Windows APIs it needs are looked up in
+//! kernel32 through the PEB (see `stub`); a check whose API is missing is
+//! skipped. On ELF everything goes through system calls and `/proc`.
+//! Debugger names for the parent process check are compared by hash.
+
+use std::sync::mpsc::Sender;
+
+use iced_x86::code_asm::*;
+use iced_x86::IcedError;
+use rand::Rng;
+
+use crate::pipeline::{PipelineContext, PipelineMessage};
+use crate::pipeline::error::StepError;
+use crate::pipeline::hash::{HashAlgorithm, NameHasher};
+use crate::pipeline::image::{Image, ImageFormat, StartupPhase, IMAGE_SCN_MEM_EXECUTE, IMAGE_SCN_MEM_WRITE, STUB_SECTION};
+use crate::pipeline::plan::PlannedChange;
+use crate::pipeline::profile::{step_rng, AntiDebugCheck, AntiDebugOptions, DebuggerResponse};
+use crate::pipeline::step::PipelineStep;
+use crate::pipeline::stub::Runtime;
+
+pub const ANTI_DEBUG_SECTION: &str = ".obsa";
+/// Exit status of the `exit` response.
+const EXIT_DEBUGGER: u32 = 1;
+/// Iterations of the timed loop, and the cycles it may take. Thousands of
+/// times what it needs, but far less than single-stepping through it.
+const TIMING_LOOPS: u32 = 1000;
+const TIMING_THRESHOLD: i32 = 0x1000_0000;
+/// Qwords of initialized data scrambled by the `corrupt` response.
+const CORRUPT_TARGETS: usize = 16;
+/// Scratch space of the routine: `CONTEXT` or `PROCESSENTRY32W` on PE (kept
+/// under a page, the stack is not probed), `/proc` file contents on ELF.
+const PE_BUFFER: i32 = 0x4d0;
+const ELF_BUFFER: i32 = 0x1000;
+
+const CONTEXT_DEBUG_REGISTERS: u32 = 0x0010_0010;
+const TH32CS_SNAPPROCESS: u32 = 2;
+const PROCESSENTRY32W_SIZE: u32 = 568;
+
+const SYS_READ: u32 = 0;
+const SYS_OPEN: u32 = 2;
+const SYS_CLOSE: u32 = 3;
+const SYS_PTRACE: u32 = 101;
+const SYS_GETPPID: u32 = 110;
+const SYS_EXIT_GROUP: u32 = 231;
+
+/// Parent processes the `parent_process` check treats as debuggers.
+const PE_DEBUGGERS: [&str; 12] = [
+    "x64dbg.exe",
+    "x32dbg.exe",
+    "x96dbg.exe",
+    "ollydbg.exe",
+    "windbg.exe",
+    "dbgx.shell.exe",
+    "ida.exe",
+    "ida64.exe",
+    "idaq.exe",
+    "idaq64.exe",
+    "immunitydebugger.exe",
+    "dnspy.exe",
+];
+const ELF_DEBUGGERS: [&str; 8] = ["gdb", "lldb", "lldb-server", "strace", "ltrace", "radare2", "r2", "edb"];
+
+/// Injects a startup routine that looks for a debugger and exits, crashes or
+/// silently corrupts data when it finds one.
+///
+/// Works on x64 PE images and ELF executables. The checks are heuristics a
+/// determined analyst can patch out; they raise the cost of casual debugging.
+pub struct AntiDebugStep {
+    options: AntiDebugOptions,
+}
+
+impl AntiDebugStep {
+    pub fn new(options: AntiDebugOptions) -> Self {
+        Self { options }
+    }
+}
+
+impl PipelineStep for AntiDebugStep {
+    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> Result<(), StepError> {
+        ctx.cancel.check()?;
+        tx.send(PipelineMessage::Log("Injecting anti-debug checks...".into())).ok();
+
+        let Some(image) = ctx.image.as_mut() else {
+            return Err(StepError::Internal("no image loaded before anti-debug injection".into()));
+        };
+        if !image.supports_startup_routines() {
+            tx.send(PipelineMessage::Log(
+                "Anti-debug checks run at startup, which needs an x64 PE image or an ELF executable; skipped".into(),
+            ))
+            .ok();
+            return Ok(());
+        }
+
+        let mut checks = Vec::new();
+        for &check in &self.options.checks {
+            if !check.supports(image.format) {
+                tx.send(PipelineMessage::Log(format!(
+                    "The {} check does not apply to {} images; skipped",
+                    check,
+                    if image.format == ImageFormat::Pe { "PE" } else { "ELF" }
+                )))
+                .ok();
+            } else if !checks.contains(&check) {
+                checks.push(check);
+            }
+        }
+        if checks.is_empty() {
+            tx.send(PipelineMessage::Log("No anti-debug check to inject".into())).ok();
+            return Ok(());
+        }
+
+        let mut rng = step_rng(ctx.seed, "anti_debug");
+        let mut response = self.options.response;
+        let mut targets = Vec::new();
+        if response == DebuggerResponse::Corrupt {
+            targets = corruption_targets(image, &mut rng);
+            if targets.is_empty() {
+                tx.send(PipelineMessage::Log(
+                    "No writable data to corrupt; the anti-debug checks crash instead".into(),
+                ))
+                .ok();
+                response = DebuggerResponse::Crash;
+            }
+        }
+        let names: Vec<String> = checks.iter().map(|c| c.to_string()).collect();
+        if ctx.dry_run {
+            ctx.plan.push(PlannedChange::AddSection {
+                name: ANTI_DEBUG_SECTION.into(),
+                description: format!("anti-debug checks ({})", names.join(", ")),
+            });
+            return Ok(());
+        }
+
+        let section_rva = image.next_section_rva();
+        let hasher = NameHasher::new(HashAlgorithm::default(), [0; 2]);
+        let code = emit_checks(image, section_rva, &checks, response, &targets, hasher)?;
+        let rva = image.add_section(ANTI_DEBUG_SECTION, &code, STUB_SECTION)?;
+        if rva != section_rva {
+            return Err(StepError::Internal(format!(
+                "Anti-debug section placed at 0x{:x} instead of 0x{:x}",
+                rva, section_rva
+            )));
+        }
+        image.add_startup_routine(StartupPhase::AntiDebug, "anti-debug checks", rva);
+        tx.send(PipelineMessage::Log(format!(
+            "Anti-debug checks ({}) in section {}, response: {:?}",
+            names.join(", "),
+            ANTI_DEBUG_SECTION,
+            response
+        )))
+        .ok();
+        Ok(())
+    }
+}
+
+/// Random qword RVAs in writable, non-executable sections, outside of what
+/// the ELF loader makes read-only after relocating.
+fn corruption_targets(image: &Image, rng: &mut impl Rng) -> Vec<u32> {
+    let relro = image.relro().map(|(rva, size)| rva..rva + size);
+    let data: Vec<(u32, u32)> = image
+        .sections
+        .iter()
+        .filter(|s| s.characteristics & IMAGE_SCN_MEM_WRITE != 0 && s.characteristics & IMAGE_SCN_MEM_EXECUTE == 0)
+        .map(|s| (s.virtual_address, s.virtual_size.max(s.raw_size) / 8))
+        .filter(|&(_, qwords)| qwords > 0)
+        .collect();
+    let mut targets = Vec::new();
+    if data.is_empty() {
+        return targets;
+    }
+    for _ in 0..CORRUPT_TARGETS * 4 {
+        let (start, qwords) = data[rng.gen_range(0..data.len())];
+        let rva = start + rng.gen_range(0..qwords) * 8;
+        if relro.as_ref().is_some_and(|r| r.contains(&rva)) || targets.contains(&rva) {
+            continue;
+        }
+        targets.push(rva);
+        if targets.len() == CORRUPT_TARGETS {
+            break;
+        }
+    }
+    targets
+}
+
+/// Kernel32 functions the PE checks call; 0 when the lookup failed.
+struct Apis {
+    exit_process: CodeLabel,
+    get_thread_context: CodeLabel,
+    snapshot: CodeLabel,
+    first: CodeLabel,
+    next: CodeLabel,
+    close_handle: CodeLabel,
+}
+
+/// The routine, placed at `section_rva`. While the checks run, `rbx` holds
+/// the image base and `rsp + 0x40` points to the scratch buffer.
+fn emit_checks(
+    image: &Image,
+    section_rva: u32,
+    checks: &[AntiDebugCheck],
+    response: DebuggerResponse,
+    targets: &[u32],
+    hasher: NameHasher,
+) -> Result<Vec<u8>, StepError> {
+    let format = image.format;
+    let mut a = CodeAssembler::new(64)?;
+    let mut entry = a.create_label();
+    let mut detected = a.create_label();
+    let mut done = a.create_label();
+    let mut crash = a.create_label();
+    let mut status_path = a.create_label();
+    let mut apis = Apis {
+        exit_process: a.create_label(),
+        get_thread_context: a.create_label(),
+        snapshot: a.create_label(),
+        first: a.create_label(),
+        next: a.create_label(),
+        close_handle: a.create_label(),
+    };
+    let runtime = (format == ImageFormat::Pe).then(|| Runtime::new(&mut a, hasher));
+    let mut hash_str = match &runtime {
+        Some(runtime) => runtime.hash_str,
+        None => a.create_label(),
+    };
+
+    a.set_label(&mut entry)?;
+    a.push(rbp)?;
+    a.mov(rbp, rsp)?;
+    for register in [rbx, rsi, rdi, r12, r13, r14, r15] {
+        a.push(register)?;
+    }
+    a.and(rsp, -16)?;
+    a.sub(rsp, 0x40 + if format == ImageFormat::Pe { PE_BUFFER } else { ELF_BUFFER })?;
+    // rbx = runtime image base
+    a.lea(rbx, ptr(entry))?;
+    a.sub(rbx, section_rva as i32)?;
+
+    if let Some(runtime) = &runtime {
+        let mut resolved = a.create_label();
+        a.mov(ecx, hasher.hash("kernel32.dll"))?;
+        a.call(runtime.find_module)?;
+        a.test(rax, rax)?;
+        a.jz(resolved)?;
+        a.mov(r15, rax)?;
+        let mut wanted = vec![("GetProcAddress", runtime.get_proc_address), ("ExitProcess", apis.exit_process)];
+        if checks.contains(&AntiDebugCheck::DebugRegisters) {
+            wanted.push(("GetThreadContext", apis.get_thread_context));
+        }
+        if checks.contains(&AntiDebugCheck::ParentProcess) {
+            wanted.push(("CreateToolhelp32Snapshot", apis.snapshot));
+            wanted.push(("Process32FirstW", apis.first));
+            wanted.push(("Process32NextW", apis.next));
+            wanted.push(("CloseHandle", apis.close_handle));
+        }
+        for (name, slot) in wanted {
+            a.mov(rcx, r15)?;
+            a.mov(edx, hasher.hash(name))?;
+            a.call(runtime.find_export)?;
+            a.mov(qword_ptr(slot), rax)?;
+        }
+        a.set_label(&mut resolved)?;
+    }
+
+    for &check in checks {
+        let mut skip = a.create_label();
+        match check {
+            AntiDebugCheck::Peb => {
+                a.mov(rax, qword_ptr(0x60u64).gs())?;
+                a.cmp(byte_ptr(rax + 2), 0)?;
+                a.jne(detected)?;
+                // FLG_HEAP_ENABLE_TAIL_CHECK | FLG_HEAP_ENABLE_FREE_CHECK | FLG_HEAP_VALIDATE_PARAMETERS
+                a.test(dword_ptr(rax + 0xbc), 0x70)?;
+                a.jnz(detected)?;
+            }
+            AntiDebugCheck::DebugRegisters => {
+                a.mov(rax, qword_ptr(apis.get_thread_context))?;
+                a.test(rax, rax)?;
+                a.jz(skip)?;
+                a.lea(rdx, ptr(rsp + 0x40))?;
+                a.mov(dword_ptr(rdx + 0x30), CONTEXT_DEBUG_REGISTERS)?;
+                // GetCurrentThread()
+                a.mov(rcx, -2i64)?;
+                a.call(rax)?;
+                a.test(eax, eax)?;
+                a.jz(skip)?;
+                a.mov(rax, qword_ptr(rsp + 0x40 + 0x48))?;
+                for dr in [0x50, 0x58, 0x60] {
+                    a.or(rax, qword_ptr(rsp + 0x40 + dr))?;
+                }
+                a.jnz(detected)?;
+            }
+            AntiDebugCheck::Timing => {
+                let mut spin = a.create_label();
+                a.rdtsc()?;
+                a.shl(rdx, 32)?;
+                a.or(rax, rdx)?;
+                a.mov(r12, rax)?;
+                a.mov(ecx, TIMING_LOOPS)?;
+                a.set_label(&mut spin)?;
+                a.dec(ecx)?;
+                a.jnz(spin)?;
+                a.rdtsc()?;
+                a.shl(rdx, 32)?;
+                a.or(rax, rdx)?;
+                a.sub(rax, r12)?;
+                a.cmp(rax, TIMING_THRESHOLD)?;
+                a.ja(detected)?;
+            }
+            AntiDebugCheck::ParentProcess if format == ImageFormat::Pe => {
+                emit_parent_pe(&mut a, &apis, hash_str, hasher, detected, skip)?;
+            }
+            AntiDebugCheck::ParentProcess => {
+                emit_parent_elf(&mut a, hash_str, hasher, detected, skip)?;
+            }
+            AntiDebugCheck::TracerPid => {
+                emit_tracer_pid(&mut a, status_path, detected, skip)?;
+            }
+            AntiDebugCheck::Ptrace => {
+                // ptrace(PTRACE_TRACEME, 0, 0, 0) fails with EPERM under a tracer
+                a.xor(edi, edi)?;
+                a.xor(esi, esi)?;
+                a.xor(edx, edx)?;
+                a.xor(r10d, r10d)?;
+                a.mov(eax, SYS_PTRACE)?;
+                a.syscall()?;
+                a.test(rax, rax)?;
+                a.js(detected)?;
+            }
+        }
+        a.set_label(&mut skip)?;
+    }
+    a.jmp(done)?;
+
+    a.set_label(&mut detected)?;
+    match response {
+        DebuggerResponse::Exit if format == ImageFormat::Pe => {
+            a.mov(rax, qword_ptr(apis.exit_process))?;
+            a.test(rax, rax)?;
+            a.jz(crash)?;
+            a.mov(ecx, EXIT_DEBUGGER)?;
+            a.call(rax)?;
+        }
+        DebuggerResponse::Exit => {
+            a.mov(edi, EXIT_DEBUGGER)?;
+            a.mov(eax, SYS_EXIT_GROUP)?;
+            a.syscall()?;
+        }
+        DebuggerResponse::Crash => {}
+        DebuggerResponse::Corrupt => {
+            a.rdtsc()?;
+            a.shl(rdx, 32)?;
+            a.or(rax, rdx)?;
+            for &rva in targets {
+                a.xor(qword_ptr(rbx + rva as i32), rax)?;
+                a.rol(rax, 17)?;
+            }
+            a.jmp(done)?;
+        }
+    }
+    if response != DebuggerResponse::Crash {
+        a.set_label(&mut crash)?;
+    }
+    a.ud2()?;
+
+    a.set_label(&mut done)?;
+    a.lea(rsp, ptr(rbp - 7 * 8))?;
+    for register in [r15, r14, r13, r12, rdi, rsi, rbx] {
+        a.pop(register)?;
+    }
+    a.pop(rbp)?;
+    a.ret()?;
+
+    match runtime {
+        Some(runtime) => runtime.emit(&mut a)?,
+        None if checks.contains(&AntiDebugCheck::ParentProcess) => hasher.emit(&mut a, &mut hash_str)?,
+        None => {}
+    }
+    a.set_label(&mut status_path)?;
+    a.db(b"/proc/self/status\0")?;
+    for slot in [
+        &mut apis.exit_process,
+        &mut apis.get_thread_context,
+        &mut apis.snapshot,
+        &mut apis.first,
+        &mut apis.next,
+        &mut apis.close_handle,
+    ] {
+        a.set_label(slot)?;
+        a.dq(&[0])?;
+    }
+
+    Ok(a.assemble(image.image_base + section_rva as u64)?)
+}
+
+/// Finds the parent process id in a process snapshot (first pass), then the
+/// parent's entry and compares the hash of its executable name.
+fn emit_parent_pe(
+    a: &mut CodeAssembler,
+    apis: &Apis,
+    hash_str: CodeLabel,
+    hasher: NameHasher,
+    detected: CodeLabel,
+    skip: CodeLabel,
+) -> Result<(), IcedError> {
+    let mut close = a.create_label();
+    let mut flag = a.create_label();
+    for slot in [apis.snapshot, apis.first, apis.next, apis.close_handle] {
+        a.cmp(qword_ptr(slot), 0)?;
+        a.je(skip)?;
+    }
+    a.mov(ecx, TH32CS_SNAPPROCESS)?;
+    a.xor(edx, edx)?;
+    a.call(qword_ptr(apis.snapshot))?;
+    a.cmp(rax, -1)?;
+    a.je(skip)?;
+    a.mov(r14, rax)?;
+    a.xor(esi, esi)?;
+    // TEB ClientId.UniqueProcess
+    a.mov(r13d, dword_ptr(0x40u64).gs())?;
+
+    // pass 0 looks for our own entry, pass 1 for the parent's (pid in r12d)
+    for pass in 0..2 {
+        let mut next = a.create_label();
+        let mut found = a.create_label();
+        a.mov(dword_ptr(rsp + 0x40), PROCESSENTRY32W_SIZE)?;
+        a.mov(rcx, r14)?;
+        a.lea(rdx, ptr(rsp + 0x40))?;
+        a.call(qword_ptr(apis.first))?;
+        a.set_label(&mut next)?;
+        a.test(eax, eax)?;
+        a.jz(close)?;
+        // th32ProcessID
+        a.cmp(dword_ptr(rsp + 0x40 + 8), if pass == 0 { r13d } else { r12d })?;
+        a.je(found)?;
+        a.mov(rcx, r14)?;
+        a.lea(rdx, ptr(rsp + 0x40))?;
+        a.call(qword_ptr(apis.next))?;
+        a.jmp(next)?;
+        a.set_label(&mut found)?;
+        if pass == 0 {
+            // th32ParentProcessID
+            a.mov(r12d, dword_ptr(rsp + 0x40 + 0x20))?;
+        }
+    }
+    // szExeFile
+    a.lea(rcx, ptr(rsp + 0x40 + 0x2c))?;
+    a.mov(edx, 2)?;
+    a.mov(r8d, 260)?;
+    a.call(hash_str)?;
+    for name in PE_DEBUGGERS {
+        a.cmp(eax, hasher.hash(name))?;
+        a.je(flag)?;
+    }
+    a.jmp(close)?;
+    a.set_label(&mut flag)?;
+    a.mov(esi, 1)?;
+    a.set_label(&mut close)?;
+    a.mov(rcx, r14)?;
+    a.call(qword_ptr(apis.close_handle))?;
+    a.test(esi, esi)?;
+    a.jnz(detected)
+}
+
+/// Reads `/proc/<ppid>/comm` and compares the hash of the parent's name.
+fn emit_parent_elf(
+    a: &mut CodeAssembler,
+    hash_str: CodeLabel,
+    hasher: NameHasher,
+    detected: CodeLabel,
+    skip: CodeLabel,
+) -> Result<(), IcedError> {
+    let mut digit = a.create_label();
+    let mut copy = a.create_label();
+    a.mov(eax, SYS_GETPPID)?;
+    a.syscall()?;
+
+    // path at rsp+0x40, pid digits written backwards below rsp+0x80
+    a.lea(rdi, ptr(rsp + 0x40))?;
+    a.mov(dword_ptr(rdi), u32::from_le_bytes(*b"/pro"))?;
+    a.mov(word_ptr(rdi + 4), u16::from_le_bytes(*b"c/") as u32)?;
+    a.add(rdi, 6)?;
+    a.lea(rsi, ptr(rsp + 0x80))?;
+    a.mov(r8, rsi)?;
+    a.mov(ecx, 10)?;
+    a.set_label(&mut digit)?;
+    a.xor(edx, edx)?;
+    a.div(ecx)?;
+    a.add(dl, b'0' as u32)?;
+    a.dec(rsi)?;
+    a.mov(byte_ptr(rsi), dl)?;
+    a.test(eax, eax)?;
+    a.jnz(digit)?;
+    a.set_label(&mut copy)?;
+    a.mov(al, byte_ptr(rsi))?;
+    a.mov(byte_ptr(rdi), al)?;
+    a.inc(rsi)?;
+    a.inc(rdi)?;
+    a.cmp(rsi, r8)?;
+    a.jne(copy)?;
+    a.mov(dword_ptr(rdi), u32::from_le_bytes(*b"/com"))?;
+    a.mov(word_ptr(rdi + 4), u16::from_le_bytes(*b"m\0") as u32)?;
+
+    a.lea(rdi, ptr(rsp + 0x40))?;
+    a.xor(esi, esi)?;
+    a.mov(eax, SYS_OPEN)?;
+    a.syscall()?;
+    a.test(rax, rax)?;
+    a.js(skip)?;
+    a.mov(r12, rax)?;
+    a.mov(rdi, rax)?;
+    a.lea(rsi, ptr(rsp + 0x80))?;
+    a.mov(edx, 0x40)?;
+    a.mov(eax, SYS_READ)?;
+    a.syscall()?;
+    a.mov(r13, rax)?;
+    a.mov(rdi, r12)?;
+    a.mov(eax, SYS_CLOSE)?;
+    a.syscall()?;
+    a.test(r13, r13)?;
+    a.jle(skip)?;
+    // the name ends with a newline
+    a.mov(byte_ptr(rsp + r13 + 0x80 - 1), 0)?;
+
+    a.lea(rcx, ptr(rsp + 0x80))?;
+    a.mov(edx, 1)?;
+    a.mov(r8d, 0x40)?;
+    a.call(hash_str)?;
+    for name in ELF_DEBUGGERS {
+        a.cmp(eax, hasher.hash(name))?;
+        a.je(detected)?;
+    }
+    Ok(())
+}
+
+/// Reads `/proc/self/status` and looks for a non-zero `TracerPid:`.
+fn emit_tracer_pid(a: &mut CodeAssembler, path: CodeLabel, detected: CodeLabel, skip: CodeLabel) -> Result<(), IcedError> {
+    let mut scan = a.create_label();
+    let mut next = a.create_label();
+    let mut blank = a.create_label();
+    a.lea(rdi, ptr(path))?;
+    a.xor(esi, esi)?;
+    a.mov(eax, SYS_OPEN)?;
+    a.syscall()?;
+    a.test(rax, rax)?;
+    a.js(skip)?;
+    a.mov(r12, rax)?;
+    a.mov(rdi, rax)?;
+    a.lea(rsi, ptr(rsp + 0x40))?;
+    a.mov(edx, ELF_BUFFER as u32)?;
+    a.mov(eax, SYS_READ)?;
+    a.syscall()?;
+    a.mov(r13, rax)?;
+    a.mov(rdi, r12)?;
+    a.mov(eax, SYS_CLOSE)?;
+    a.syscall()?;
+    a.test(r13, r13)?;
+    a.jle(skip)?;
+
+    // rsi = cursor, rdi = end of the data
+    a.lea(rsi, ptr(rsp + 0x40))?;
+    a.lea(rdi, ptr(rsi + r13))?;
+    a.mov(r8, u64::from_le_bytes(*b"TracerPi"))?;
+    a.set_label(&mut scan)?;
+    a.lea(rax, ptr(rsi + 10))?;
+    a.cmp(rax, rdi)?;
+    a.ja(skip)?;
+    a.cmp(qword_ptr(rsi), r8)?;
+    a.jne(next)?;
+    a.cmp(word_ptr(rsi + 8), u16::from_le_bytes(*b"d:") as u32)?;
+    a.jne(next)?;
+    a.add(rsi, 10)?;
+    a.set_label(&mut blank)?;
+    a.cmp(rsi, rdi)?;
+    a.jae(skip)?;
+    a.mov(al, byte_ptr(rsi))?;
+    a.inc(rsi)?;
+    a.cmp(al, b'\t' as u32)?;
+    a.je(blank)?;
+    a.cmp(al, b' ' as u32)?;
+    a.je(blank)?;
+    // any pid but 0
+    a.cmp(al, b'1' as u32)?;
+    a.jb(skip)?;
+    a.cmp(al, b'9' as u32)?;
+    a.jbe(detected)?;
+    a.jmp(skip)?;
+    a.set_label(&mut next)?;
+    a.inc(rsi)?;
+    a.jmp(scan)
+}
+
+#[cfg(all(test, target_os = "linux", target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::process::CommandExt;
+    use std::path::Path;
+    use std::process::Command;
+    use std::sync::mpsc;
+
+    use crate::pipeline::cancel::CancellationToken;
+
+    /// `/bin/true` with the `tracer_pid` check and the `exit` response.
+    fn protected_true(dir: &Path) -> std::path::PathBuf {
+        let mut ctx = PipelineContext::new("/bin/true".into(), CancellationToken::new());
+        ctx.image = Some(Image::parse(std::fs::read("/bin/true").unwrap()).unwrap());
+        let options = AntiDebugOptions {
+            enabled: true,
+            checks: vec![AntiDebugCheck::TracerPid],
+            response: DebuggerResponse::Exit,
+        };
+        let (tx, rx) = mpsc::channel();
+        AntiDebugStep::new(options).run(&mut ctx, &tx).unwrap();
+        drop(tx);
+        assert!(rx
+            .iter()
+            .any(|message| matches!(message, PipelineMessage::Log(line) if line.contains(ANTI_DEBUG_SECTION))));
+        let mut image = ctx.image.take().unwrap();
+        image.finalize().unwrap();
+
+        let target = dir.join("true");
+        std::fs::write(&target, image.into_bytes()).unwrap();
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o755)).unwrap();
+        target
+    }
+
+    /// Runs `path` as a tracee of this process, passing on every signal but
+    /// the `SIGTRAP` of the `execve`, and returns its exit status.
+    fn traced_exit_status(path: &Path) -> i32 {
+        let mut command = Command::new(path);
+        // SAFETY: ptrace is async-signal-safe and touches no state of the parent.
+        unsafe {
+            command.pre_exec(|| {
+                if libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let pid = command.spawn().unwrap().id() as libc::pid_t;
+        loop {
+            let mut status = 0;
+            assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+            if libc::WIFEXITED(status) {
+                return libc::WEXITSTATUS(status);
+            }
+            assert!(libc::WIFSTOPPED(status), "tracee ended by a signal (status 0x{:x})", status);
+            let signal = match libc::WSTOPSIG(status) {
+                libc::SIGTRAP => 0,
+                signal => signal,
+            };
+            assert_eq!(unsafe { libc::ptrace(libc::PTRACE_CONT, pid, 0, signal) }, 0);
+        }
+    }
+
+    #[test]
+    fn exits_under_a_tracer_only() {
+        let dir = std::env::temp_dir().join(format!("obscura-antidebug-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let target = protected_true(&dir);
+
+        assert_eq!(Command::new(&target).status().unwrap().code(), Some(0));
+        assert_eq!(traced_exit_status(&target), EXIT_DEBUGGER as i32);
+        // the unprotected binary succeeds under the same tracer
+        assert_eq!(traced_exit_status(Path::new("/bin/true")), 0);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,332 @@
+//! Side artifacts of a run: the symbol map, the import hash report and the
+//! encrypted strings copy.
+//!
+//! They are written to `ArtifactOptions::dir` or, by default, to a per-user
+//! data directory (`%LOCALAPPDATA%\obscura\artifacts`,
+//! `~/Library/Application Support/obscura/artifacts` or
+//! `$XDG_DATA_HOME/obscura/artifacts`), never next to the protected output:
+//! a copy of the release folder must not carry the original function names.
+//! Without any of those variables there is no such directory and writing an
+//! artifact fails rather than falling back to a shared one. Artifacts are
+//! named `<input file name>.<hash>.<extension>`, the hash being of the
+//! absolute input path, so inputs of the same name do not overwrite each
+//! other's.
+//!
+//! With a team key an artifact is sealed with ChaCha20-Poly1305:
+//!
+//! ```text
+//! "OBSCSEAL" | version (1) | nonce (12) | ciphertext | tag (16)
+//! ```
+//!
+//! where the first 21 bytes are the associated data. With a signing key an
+//! Ed25519 signature of the file as written (sealed or not) goes to
+//! `<artifact>.sig`:
+//!
+//! ```json
+//! { "format": "obscura-signature", "version": 1, "public_key": "<64 hex>", "signature": "<128 hex>" }
+//! ```
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::pipeline::error::StepError;
+use crate::pipeline::hash::siphash24;
+use crate::pipeline::profile::ArtifactOptions;
+
+const MAGIC: &[u8; 8] = b"OBSCSEAL";
+const SEAL_VERSION: u8 = 1;
+const NONCE_SIZE: usize = 12;
+const HEADER_SIZE: usize = MAGIC.len() + 1 + NONCE_SIZE;
+const SIGNATURE_FORMAT: &str = "obscura-signature";
+const SIGNATURE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct SignatureFile {
+    format: String,
+    version: u32,
+    public_key: String,
+    signature: String,
+}
+
+/// Parses a 32-byte key given as 64 hex digits; `what` names it in the error.
+pub fn parse_key(hex: &str, what: &str) -> Result<[u8; 32], StepError> {
+    let invalid = || StepError::InvalidInput(format!("{} must be 64 hex digits", what));
+    parse_hex(hex).and_then(|bytes| bytes.try_into().ok()).ok_or_else(invalid)
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Where a signature of `path` is written.
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".sig");
+    PathBuf::from(name)
+}
+
+/// The directory artifacts go to when none is configured.
+pub fn default_dir() -> Result<PathBuf, StepError> {
+    let env = |name: &str| std::env::var_os(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+    let data = if cfg!(windows) {
+        env("LOCALAPPDATA")
+    } else if cfg!(target_os = "macos") {
+        env("HOME").map(|home| home.join("Library/Application Support"))
+    } else {
+        env("XDG_DATA_HOME").or_else(|| env("HOME").map(|home| home.join(".local/share")))
+    };
+    // the temporary directory is shared with every user: better no artifacts than leaked ones
+    let data = data.ok_or_else(|| {
+        StepError::InvalidInput(
+            "No per-user data directory (LOCALAPPDATA, XDG_DATA_HOME or HOME) to write the artifacts to; \
+             set the artifacts directory"
+                .into(),
+        )
+    })?;
+    Ok(data.join("obscura").join("artifacts"))
+}
+
+/// How the artifacts of a run are written, resolved from `ArtifactOptions`.
+#[derive(Default)]
+pub struct Artifacts {
+    dir: Option<PathBuf>,
+    key: Option<[u8; 32]>,
+    signing_key: Option<SigningKey>,
+}
+
+impl Artifacts {
+    pub fn new(options: &ArtifactOptions) -> Result<Self, StepError> {
+        Ok(Self {
+            dir: options.dir.clone(),
+            key: options.key.as_deref().map(|hex| parse_key(hex, "Artifact key")).transpose()?,
+            signing_key: options
+                .signing_key
+                .as_deref()
+                .map(|hex| parse_key(hex, "Signing key").map(|seed| SigningKey::from_bytes(&seed)))
+                .transpose()?,
+        })
+    }
+
+    /// Path of the artifact `<input file name>.<hash of the input path>.<extension>`.
+    pub fn path(&self, input_path: &str, extension: &str) -> Result<PathBuf, StepError> {
+        let input = Path::new(input_path);
+        let name = input.file_name().unwrap_or_default().to_string_lossy();
+        let absolute = std::path::absolute(input).unwrap_or_else(|_| input.to_path_buf());
+        let hash = siphash24([0, 0], absolute.as_os_str().as_encoded_bytes()) as u32;
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => default_dir()?,
+        };
+        Ok(dir.join(format!("{}.{:08x}.{}", name, hash, extension)))
+    }
+
+    /// How the artifacts are protected, for the log.
+    pub fn describe(&self) -> String {
+        match (&self.key, &self.signing_key) {
+            (None, None) => "plain".into(),
+            (Some(_), None) => "encrypted".into(),
+            (None, Some(key)) => format!("signed with {}", to_hex(key.verifying_key().as_bytes())),
+            (Some(_), Some(key)) => format!("encrypted, signed with {}", to_hex(key.verifying_key().as_bytes())),
+        }
+    }
+
+    /// Writes `contents` to `path`, sealed and signed as configured, and
+    /// returns the files written.
+    pub fn write(&self, path: &Path, contents: &[u8]) -> Result<Vec<PathBuf>, StepError> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(|e| StepError::io("Failed to create artifact directory", dir, e))?;
+        }
+        let bytes = match &self.key {
+            Some(key) => seal(key, contents)?,
+            None => contents.to_vec(),
+        };
+        fs::write(path, &bytes).map_err(|e| StepError::io("Failed to write artifact", path, e))?;
+        let mut written = vec![path.to_path_buf()];
+        if let Some(signing_key) = &self.signing_key {
+            let signature = SignatureFile {
+                format: SIGNATURE_FORMAT.into(),
+                version: SIGNATURE_VERSION,
+                public_key: to_hex(signing_key.verifying_key().as_bytes()),
+                signature: to_hex(&signing_key.sign(&bytes).to_bytes()),
+            };
+            let sig_path = signature_path(path);
+            let json = serde_json::to_string_pretty(&signature).unwrap_or_default();
+            fs::write(&sig_path, json).map_err(|e| StepError::io("Failed to write signature", &sig_path, e))?;
+            written.push(sig_path);
+        }
+        Ok(written)
+    }
+}
+
+fn seal(key: &[u8; 32], contents: &[u8]) -> Result<Vec<u8>, StepError> {
+    let nonce: [u8; NONCE_SIZE] = rand::random();
+    let mut header = MAGIC.to_vec();
+    header.push(SEAL_VERSION);
+    header.extend_from_slice(&nonce);
+    let ciphertext = ChaCha20Poly1305::new(key.into())
+        .encrypt(&nonce.into(), Payload { msg: contents, aad: &header })
+        .map_err(|_| StepError::Internal("artifact encryption failed".into()))?;
+    header.extend_from_slice(&ciphertext);
+    Ok(header)
+}
+
+/// Reads artifacts back: checks the signature when a public key is given
+/// and opens sealed ones with the team key.
+pub struct ArtifactReader {
+    key: Option<[u8; 32]>,
+    public_key: Option<VerifyingKey>,
+}
+
+impl ArtifactReader {
+    /// From the hex keys given on the command line.
+    pub fn new(key: Option<&str>, public_key: Option<&str>) -> Result<Self, StepError> {
+        let public_key = match public_key {
+            Some(hex) => Some(
+                VerifyingKey::from_bytes(&parse_key(hex, "Public key")?)
+                    .map_err(|_| StepError::InvalidInput("Public key is not a valid Ed25519 key".into()))?,
+            ),
+            None => None,
+        };
+        Ok(Self { key: key.map(|hex| parse_key(hex, "Artifact key")).transpose()?, public_key })
+    }
+
+    pub fn read(&self, path: &Path) -> Result<Vec<u8>, StepError> {
+        let bytes = fs::read(path).map_err(|e| StepError::io("Failed to read artifact", path, e))?;
+        if let Some(public_key) = &self.public_key {
+            verify(path, &bytes, public_key)?;
+        }
+        if !bytes.starts_with(MAGIC) {
+            return Ok(bytes);
+        }
+        let invalid = |what: &str| StepError::InvalidInput(format!("Sealed artifact '{}' {}", path.display(), what));
+        let key = self.key.as_ref().ok_or_else(|| invalid("is encrypted; the team key is needed to read it"))?;
+        if bytes.len() < HEADER_SIZE || bytes[MAGIC.len()] != SEAL_VERSION {
+            return Err(invalid("has an unknown version or is truncated"));
+        }
+        let (header, ciphertext) = bytes.split_at(HEADER_SIZE);
+        let nonce: [u8; NONCE_SIZE] = header[MAGIC.len() + 1..].try_into().unwrap();
+        ChaCha20Poly1305::new(key.into())
+            .decrypt(&nonce.into(), Payload { msg: ciphertext, aad: header })
+            .map_err(|_| invalid("does not open with this key or was modified"))
+    }
+}
+
+fn verify(path: &Path, bytes: &[u8], public_key: &VerifyingKey) -> Result<(), StepError> {
+    let sig_path = signature_path(path);
+    let invalid = |what: String| StepError::InvalidInput(format!("Signature of '{}': {}", path.display(), what));
+    let text = fs::read_to_string(&sig_path).map_err(|e| StepError::io("Failed to read signature", &sig_path, e))?;
+    let file: SignatureFile = serde_json::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+    if file.format != SIGNATURE_FORMAT || file.version > SIGNATURE_VERSION {
+        return Err(invalid(format!("unknown format '{}' version {}", file.format, file.version)));
+    }
+    if parse_hex(&file.public_key).as_deref() != Some(public_key.as_bytes().as_slice()) {
+        return Err(invalid(format!("signed with {}, not the expected key", file.public_key)));
+    }
+    let signature = parse_hex(&file.signature)
+        .and_then(|bytes| <[u8; 64]>::try_from(bytes).ok())
+        .map(|bytes| Signature::from_bytes(&bytes))
+        .ok_or_else(|| invalid("malformed signature".into()))?;
+    public_key
+        .verify(bytes, &signature)
+        .map_err(|_| invalid("does not match the file; it was modified after signing".into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const OTHER_KEY: &str = "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+    const SIGNING_KEY: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("obscura-artifacts-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn artifacts(dir: &Path, key: Option<&str>, signing_key: Option<&str>) -> Artifacts {
+        Artifacts::new(&ArtifactOptions {
+            dir: Some(dir.to_path_buf()),
+            key: key.map(String::from),
+            signing_key: signing_key.map(String::from),
+        })
+        .unwrap()
+    }
+
+    fn public_key(signing_key: &str) -> String {
+        to_hex(SigningKey::from_bytes(&parse_key(signing_key, "key").unwrap()).verifying_key().as_bytes())
+    }
+
+    #[test]
+    fn inputs_of_the_same_name_get_their_own_artifacts() {
+        let artifacts = artifacts(Path::new("/artifacts"), None, None);
+        let first = artifacts.path("/builds/a/app.exe", "obf-map").unwrap();
+        let second = artifacts.path("/builds/b/app.exe", "obf-map").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(first, artifacts.path("/builds/a/app.exe", "obf-map").unwrap());
+        let name = first.file_name().unwrap().to_string_lossy().into_owned();
+        assert!(name.starts_with("app.exe.") && name.ends_with(".obf-map"), "{}", name);
+        assert_eq!(first.parent(), Some(Path::new("/artifacts")));
+    }
+
+    #[test]
+    fn sealed_artifact_opens_with_the_team_key_only() {
+        let dir = scratch_dir("seal");
+        let path = dir.join("map");
+        artifacts(&dir, Some(KEY), None).write(&path, b"original names").unwrap();
+        let sealed = fs::read(&path).unwrap();
+        assert!(sealed.starts_with(MAGIC));
+        assert!(!sealed.windows(8).any(|w| w == b"original"));
+
+        assert_eq!(ArtifactReader::new(Some(KEY), None).unwrap().read(&path).unwrap(), b"original names");
+        let error = ArtifactReader::new(Some(OTHER_KEY), None).unwrap().read(&path).unwrap_err();
+        assert!(error.message().contains("does not open with this key"), "{}", error.message());
+        assert!(ArtifactReader::new(None, None).unwrap().read(&path).is_err());
+
+        for at in [MAGIC.len(), HEADER_SIZE - 1, HEADER_SIZE, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[at] ^= 1;
+            fs::write(&path, &tampered).unwrap();
+            assert!(ArtifactReader::new(Some(KEY), None).unwrap().read(&path).is_err(), "byte {} changed", at);
+        }
+        fs::write(&path, &sealed[..HEADER_SIZE - 1]).unwrap();
+        assert!(ArtifactReader::new(Some(KEY), None).unwrap().read(&path).is_err());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn signed_artifact_verifies_with_the_public_key_only() {
+        let dir = scratch_dir("sign");
+        let path = dir.join("map");
+        let written = artifacts(&dir, Some(KEY), Some(SIGNING_KEY)).write(&path, b"original names").unwrap();
+        assert_eq!(written, [path.clone(), signature_path(&path)]);
+
+        let public = public_key(SIGNING_KEY);
+        let reader = ArtifactReader::new(Some(KEY), Some(&public)).unwrap();
+        assert_eq!(reader.read(&path).unwrap(), b"original names");
+        let other = public_key(OTHER_KEY);
+        let error = ArtifactReader::new(Some(KEY), Some(&other)).unwrap().read(&path).unwrap_err();
+        assert!(error.message().contains("not the expected key"), "{}", error.message());
+
+        let mut tampered = fs::read(&path).unwrap();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        fs::write(&path, &tampered).unwrap();
+        let error = reader.read(&path).unwrap_err();
+        assert!(error.message().contains("modified after signing"), "{}", error.message());
+        fs::remove_dir_all(&dir).ok();
+    }
+}
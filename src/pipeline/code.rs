@@ -0,0 +1,581 @@
+//! Disassembly layer: function discovery and an instruction-level IR.
+//!
+//! `CodeMap::build` finds the functions of an image (exception directory,
+//! ELF symbols, exports, entry point and the direct calls and tail jumps
+//! between them) and lifts each one into a list of `iced_x86::Instruction`s
+//! with its basic blocks and cross-references. Transformation passes edit
+//! `Function::instructions` and set `modified`; `reassemble` moves those
+//! functions into a new code section when the image is written.
+//!
+//! Instructions keep their original address in `ip`, which is what branches
+//! inside the function target. Instructions added by a pass use ip 0, or an
+//! address from `CodeMap::new_label` when something branches to them.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use iced_x86::{Code, Decoder, DecoderOptions, FlowControl, Instruction, Mnemonic, OpKind, Register};
+use serde::Serialize;
+
+use crate::pipeline::image::{Image, DIR_EXCEPTION};
+use crate::pipeline::profile::{FunctionAttribute, FunctionSelector};
+
+const UNW_FLAG_EHANDLER: u8 = 0x1;
+const UNW_FLAG_UHANDLER: u8 = 0x2;
+const UNW_FLAG_CHAININFO: u8 = 0x4;
+
+/// Upper bound for the extent of a function found without exception data.
+const MAX_FUNCTION_SIZE: u32 = 0x10_0000;
+/// Upper bound for the number of functions lifted from one image.
+const MAX_FUNCTIONS: usize = 50_000;
+
+/// A `jmp rel32` patched over the original start of a moved function.
+pub const MIN_MOVABLE_SIZE: u32 = 5;
+
+/// Where a function is and how it unwinds.
+#[derive(Debug, Clone, Default)]
+pub struct FunctionInfo {
+    pub name: Option<String>,
+    pub rva: u32,
+    /// Size in bytes; 0 until lifting found the extent of a function without exception data.
+    pub size: u32,
+    pub unwind: Option<UnwindInfo>,
+    pub exported: bool,
+}
+
+/// The `RUNTIME_FUNCTION` of an x64 function and the header of its `UNWIND_INFO`.
+#[derive(Debug, Clone, Copy)]
+pub struct UnwindInfo {
+    pub unwind_rva: u32,
+    pub flags: u8,
+    pub prolog_size: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum XrefKind {
+    Call,
+    Jump,
+    /// RIP-relative or absolute memory operand.
+    Data,
+}
+
+/// A reference from an instruction of a function to an address of the image (both RVAs).
+#[derive(Debug, Clone, Copy)]
+pub struct Xref {
+    pub from: u32,
+    pub to: u32,
+    pub kind: XrefKind,
+}
+
+/// Instructions `start..end` (indices into `Function::instructions`).
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+    /// Indices of the blocks control can reach next.
+    pub successors: Vec<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub info: FunctionInfo,
+    pub instructions: Vec<Instruction>,
+    pub blocks: Vec<BasicBlock>,
+    pub xrefs: Vec<Xref>,
+    /// Set by a pass that changed `instructions`; the function is then moved when writing.
+    pub modified: bool,
+    /// Set by a pass that rewrote the body in the image itself (virtualization, encryption);
+    /// `instructions` no longer describe what is there.
+    pub replaced: bool,
+}
+
+impl Function {
+    pub fn name(&self) -> String {
+        self.info.display_name()
+    }
+
+    /// Number of leading instructions that form the prologue described by the
+    /// unwind info. Passes must keep them unchanged and first.
+    pub fn prologue_len(&self) -> usize {
+        let (Some(unwind), Some(first)) = (self.info.unwind, self.instructions.first()) else {
+            return 0;
+        };
+        let (start, end) = (first.ip(), first.ip() + unwind.prolog_size as u64);
+        self.instructions
+            .iter()
+            .take_while(|i| i.ip() >= start && i.ip() < end)
+            .count()
+    }
+
+    /// Index of the first instruction of the epilogue of `block` (stack
+    /// adjustment and register pops before a return or tail jump), or its
+    /// end if the block does not leave the function. Like the prologue, the
+    /// unwinder recognizes it by its exact form.
+    pub fn epilogue_start(&self, block: &BasicBlock) -> usize {
+        let last = &self.instructions[block.end - 1];
+        if !matches!(
+            last.flow_control(),
+            FlowControl::Return | FlowControl::UnconditionalBranch | FlowControl::IndirectBranch
+        ) {
+            return block.end;
+        }
+        let mut first = block.end - 1;
+        while first > block.start {
+            let i = &self.instructions[first - 1];
+            let adjusts_rsp = i.op0_register() == Register::RSP && matches!(i.mnemonic(), Mnemonic::Add | Mnemonic::Lea);
+            if !(adjusts_rsp || i.mnemonic() == Mnemonic::Pop) {
+                break;
+            }
+            first -= 1;
+        }
+        first
+    }
+
+    /// Recomputes `blocks` after `instructions` changed.
+    pub fn rebuild_blocks(&mut self) {
+        self.blocks = basic_blocks(&self.instructions);
+    }
+}
+
+impl FunctionInfo {
+    pub fn display_name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| format!("sub_{:x}", self.rva))
+    }
+}
+
+/// The functions of an image.
+pub struct CodeMap {
+    pub bitness: u32,
+    pub image_base: u64,
+    pub functions: Vec<Function>,
+    /// Functions that were found but could not be lifted, with the reason.
+    pub skipped: Vec<(FunctionInfo, String)>,
+    /// RVA of the entry point.
+    pub entry: u32,
+    /// RVAs covered by base relocations.
+    relocations: Vec<u32>,
+    /// Whether moved functions can take their relocations along (`Image::can_move_relocations`).
+    movable_relocations: bool,
+    next_label: u64,
+}
+
+impl CodeMap {
+    pub fn build(image: &Image) -> Self {
+        let bitness = if image.is_64 { 64 } else { 32 };
+        let mut map = CodeMap {
+            bitness,
+            image_base: image.image_base,
+            functions: Vec::new(),
+            skipped: Vec::new(),
+            entry: image.entry,
+            relocations: image.base_relocations(),
+            movable_relocations: image.can_move_relocations(),
+            next_label: if image.is_64 { 0xF000_0000_0000_0000 } else { 0xF000_0000 },
+        };
+
+        let mut candidates: BTreeMap<u32, FunctionInfo> = exception_functions(image)
+            .into_iter()
+            .map(|f| (f.rva, f))
+            .collect();
+        for (rva, size, name) in image.symbols() {
+            let info = candidates.entry(rva).or_insert(FunctionInfo { rva, size, ..Default::default() });
+            info.name.get_or_insert(name);
+        }
+        for (rva, name) in image.exports() {
+            let info = candidates.entry(rva).or_insert(FunctionInfo { rva, ..Default::default() });
+            info.name.get_or_insert(name);
+            info.exported = true;
+        }
+        if image.entry != 0 {
+            let info = candidates.entry(image.entry).or_insert(FunctionInfo {
+                rva: image.entry,
+                ..Default::default()
+            });
+            info.name.get_or_insert_with(|| "entry".into());
+        }
+
+        // lift every candidate; targets of direct calls and tail jumps become candidates in turn
+        let mut pending: Vec<u32> = candidates.keys().rev().copied().collect();
+        let mut seen: HashSet<u32> = pending.iter().copied().collect();
+        while let Some(rva) = pending.pop() {
+            if map.functions.len() + map.skipped.len() >= MAX_FUNCTIONS {
+                break;
+            }
+            let info = candidates[&rva].clone();
+            if !image.is_executable(rva) {
+                map.skipped.push((info, "not in an executable section".into()));
+                continue;
+            }
+            match lift(image, bitness, info.clone(), &candidates) {
+                Ok(function) => {
+                    let own = function.info.rva..function.info.rva + function.info.size;
+                    for xref in &function.xrefs {
+                        let external_branch = match xref.kind {
+                            XrefKind::Call => true,
+                            XrefKind::Jump => !own.contains(&xref.to),
+                            XrefKind::Data => false,
+                        };
+                        if external_branch
+                            && image.is_executable(xref.to)
+                            && !inside_known(&candidates, xref.to)
+                            && seen.insert(xref.to)
+                        {
+                            candidates.insert(xref.to, FunctionInfo { rva: xref.to, ..Default::default() });
+                            pending.push(xref.to);
+                        }
+                    }
+                    candidates.insert(rva, function.info.clone());
+                    map.functions.push(function);
+                }
+                Err(reason) => map.skipped.push((info, reason)),
+            }
+        }
+        map.functions.sort_by_key(|f| f.info.rva);
+        map.skipped.sort_by_key(|(f, _)| f.rva);
+        map
+    }
+
+    /// A fresh address for an instruction added by a pass that something branches to.
+    pub fn new_label(&mut self) -> u64 {
+        self.next_label += 1;
+        self.next_label
+    }
+
+    /// Indices of the functions matched by any of `selectors`, plus the
+    /// selectors that matched nothing.
+    pub fn select<'a>(&self, selectors: &'a [FunctionSelector]) -> (Vec<usize>, Vec<&'a FunctionSelector>) {
+        let matches = |selector: &FunctionSelector, f: &Function| match selector {
+            FunctionSelector::Name(name) => f.info.name.as_deref() == Some(name) || f.name() == *name,
+            FunctionSelector::Rva(rva) => f.info.rva == *rva,
+            FunctionSelector::Attribute(FunctionAttribute::Exported) => f.info.exported,
+            FunctionSelector::Attribute(FunctionAttribute::Entry) => f.info.rva == self.entry,
+            FunctionSelector::Attribute(FunctionAttribute::All) => true,
+        };
+        let selected = (0..self.functions.len())
+            .filter(|&i| selectors.iter().any(|s| matches(s, &self.functions[i])))
+            .collect();
+        let unmatched = selectors
+            .iter()
+            .filter(|s| !self.functions.iter().any(|f| matches(s, f)))
+            .collect();
+        (selected, unmatched)
+    }
+
+    /// Why the function at `index` cannot be moved to another address, if it can't.
+    pub fn check_movable(&self, index: usize) -> Result<(), String> {
+        let function = &self.functions[index];
+        let (start, end) = (function.info.rva, function.info.rva + function.info.size);
+        if function.info.size < MIN_MOVABLE_SIZE {
+            return Err(format!("too small ({} bytes)", function.info.size));
+        }
+        if let Some(unwind) = function.info.unwind {
+            if unwind.flags & UNW_FLAG_CHAININFO != 0 {
+                return Err("chained unwind info".into());
+            }
+            if unwind.flags & (UNW_FLAG_EHANDLER | UNW_FLAG_UHANDLER) != 0 {
+                return Err("has an exception handler".into());
+            }
+        }
+        if let Some(i) = function
+            .instructions
+            .iter()
+            .find(|i| i.flow_control() == FlowControl::IndirectBranch && !i.is_ip_rel_memory_operand())
+        {
+            return Err(format!("indirect jump at 0x{:x} (jump table?)", self.rva_of(i.ip())));
+        }
+        if !self.movable_relocations {
+            self.check_relocations(index)?;
+        }
+        for other in &self.functions {
+            let own = other.info.rva == start;
+            for xref in &other.xrefs {
+                // the start stays valid through the jump patched over it;
+                // branches inside the function move along with it
+                let into_body = xref.to > start && xref.to < end;
+                if into_body && (!own || xref.kind == XrefKind::Data) {
+                    return Err(format!(
+                        "0x{:x} is referenced from {} at 0x{:x}",
+                        xref.to,
+                        other.name(),
+                        xref.from
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Why the body of the function at `index` cannot be encrypted in place,
+    /// if it can't. With `lazy` decryption the body is still encrypted until
+    /// the function is first called, so nothing else may branch into it.
+    pub fn check_encryptable(&self, index: usize, lazy: bool) -> Result<(), String> {
+        let function = &self.functions[index];
+        let (start, end) = (function.info.rva, function.info.rva + function.info.size);
+        if function.info.size < MIN_MOVABLE_SIZE {
+            return Err(format!("too small ({} bytes)", function.info.size));
+        }
+        self.check_relocations(index)?;
+        if !lazy {
+            return Ok(());
+        }
+        for other in self.functions.iter().filter(|f| f.info.rva != start) {
+            if let Some(xref) = other.xrefs.iter().find(|x| x.to > start && x.to < end) {
+                return Err(format!(
+                    "0x{:x} is referenced from {} at 0x{:x}",
+                    xref.to,
+                    other.name(),
+                    xref.from
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the loader would patch absolute addresses in the function at
+    /// `index`, for passes that copy its instructions somewhere the loader
+    /// does not look.
+    pub fn check_relocations(&self, index: usize) -> Result<(), String> {
+        let function = &self.functions[index];
+        let (start, end) = (function.info.rva, function.info.rva + function.info.size);
+        let first_reloc = self.relocations.partition_point(|&r| r < start);
+        if self.relocations.get(first_reloc).is_some_and(|&r| r < end) {
+            return Err("contains absolute addresses (base relocations)".into());
+        }
+        Ok(())
+    }
+
+    fn rva_of(&self, va: u64) -> u32 {
+        va.wrapping_sub(self.image_base) as u32
+    }
+}
+
+/// Whether `rva` lies inside (not at the start of) a function of known size;
+/// a size running past 4 GiB covers the rest of the address space.
+fn inside_known(known: &BTreeMap<u32, FunctionInfo>, rva: u32) -> bool {
+    known
+        .range(..rva)
+        .next_back()
+        .is_some_and(|(_, f)| f.rva.checked_add(f.size).is_none_or(|end| rva < end))
+}
+
+/// Functions listed in the x64 exception directory (`.pdata`).
+fn exception_functions(image: &Image) -> Vec<FunctionInfo> {
+    let (table, size) = image.data_directory(DIR_EXCEPTION);
+    if !image.is_64 || table == 0 {
+        return Vec::new();
+    }
+    (0..size / 12)
+        .filter_map(|i| {
+            let at = table + i * 12;
+            let (begin, end, unwind_rva) =
+                (image.read_u32(at)?, image.read_u32(at + 4)?, image.read_u32(at + 8)?);
+            if end <= begin || unwind_rva & 1 != 0 {
+                return None;
+            }
+            let header = image.read(unwind_rva, 2)?;
+            Some(FunctionInfo {
+                rva: begin,
+                size: end - begin,
+                unwind: Some(UnwindInfo {
+                    unwind_rva,
+                    flags: header[0] >> 3,
+                    prolog_size: header[1],
+                }),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+/// Decodes the function described by `info`. Functions without a known size
+/// are followed from their start to find their extent.
+fn lift(
+    image: &Image,
+    bitness: u32,
+    mut info: FunctionInfo,
+    known: &BTreeMap<u32, FunctionInfo>,
+) -> Result<Function, String> {
+    if info.size == 0 {
+        info.size = extent(image, bitness, info.rva, known)?;
+        if let Some((next, other)) = known.range(info.rva + 1..).next() {
+            if *next < info.rva + info.size {
+                return Err(format!("overlaps {}", other.display_name()));
+            }
+        }
+    }
+    let bytes = image
+        .read(info.rva, info.size as usize)
+        .ok_or("not backed by file data")?;
+    let instructions = decode_all(bitness, bytes, image.image_base, info.rva)?;
+
+    let starts: HashSet<u64> = instructions.iter().map(|i| i.ip()).collect();
+    let (lo, hi) = (image.image_base + info.rva as u64, image.image_base + (info.rva + info.size) as u64);
+    for i in &instructions {
+        if let Some(target) = branch_target(i) {
+            if target >= lo && target < hi && !starts.contains(&target) {
+                return Err(format!("branch into the middle of an instruction at 0x{:x}", target - image.image_base));
+            }
+        }
+    }
+
+    let xrefs = instructions
+        .iter()
+        .filter_map(|i| {
+            let (to, kind) = match (branch_target(i), i.flow_control()) {
+                (Some(target), FlowControl::Call) => (target, XrefKind::Call),
+                (Some(target), _) => (target, XrefKind::Jump),
+                (None, _) => (data_reference(i)?, XrefKind::Data),
+            };
+            let to = to.checked_sub(image.image_base)?;
+            (to < u32::MAX as u64).then(|| Xref {
+                from: (i.ip() - image.image_base) as u32,
+                to: to as u32,
+                kind,
+            })
+        })
+        .collect();
+
+    Ok(Function {
+        blocks: basic_blocks(&instructions),
+        info,
+        instructions,
+        xrefs,
+        modified: false,
+        replaced: false,
+    })
+}
+
+fn decode_all(bitness: u32, bytes: &[u8], image_base: u64, rva: u32) -> Result<Vec<Instruction>, String> {
+    let mut decoder = Decoder::with_ip(bitness, bytes, image_base + rva as u64, DecoderOptions::NONE);
+    let mut instructions = Vec::new();
+    while decoder.can_decode() {
+        let i = decoder.decode();
+        if i.is_invalid() {
+            return Err(format!("undecodable bytes at 0x{:x}", i.ip() - image_base));
+        }
+        instructions.push(i);
+    }
+    Ok(instructions)
+}
+
+/// Follows the control flow from `rva` and returns the size of the code it
+/// reaches before other functions. Jumps to other functions are tail calls.
+fn extent(image: &Image, bitness: u32, rva: u32, known: &BTreeMap<u32, FunctionInfo>) -> Result<u32, String> {
+    let section = image.section_at(rva).ok_or("outside of the sections")?;
+    let size = match section.virtual_size {
+        0 => section.raw_size,
+        virtual_size => virtual_size.min(section.raw_size),
+    };
+    let limit = section.virtual_address.saturating_add(size).min(rva.saturating_add(MAX_FUNCTION_SIZE));
+    let base = image.image_base;
+    let mut pending = vec![rva];
+    let mut visited: HashSet<u32> = HashSet::new();
+    let mut end = rva;
+    while let Some(at) = pending.pop() {
+        if !visited.insert(at) {
+            continue;
+        }
+        let len = limit.checked_sub(at).ok_or("outside section")?;
+        let bytes = image.read(at, len as usize).ok_or("not backed by file data")?;
+        let mut decoder = Decoder::with_ip(bitness, bytes, base + at as u64, DecoderOptions::NONE);
+        while decoder.can_decode() {
+            let i = decoder.decode();
+            if i.is_invalid() {
+                return Err(format!("undecodable bytes at 0x{:x}", i.ip() - base));
+            }
+            end = end.max((i.next_ip() - base) as u32);
+            let internal = |target: u64| {
+                let t = target.wrapping_sub(base);
+                (t > rva as u64 && t < limit as u64 && !known.contains_key(&(t as u32))).then_some(t as u32)
+            };
+            match i.flow_control() {
+                FlowControl::ConditionalBranch => pending.extend(branch_target(&i).and_then(internal)),
+                FlowControl::UnconditionalBranch => {
+                    pending.extend(branch_target(&i).and_then(internal));
+                    break;
+                }
+                FlowControl::Return | FlowControl::IndirectBranch | FlowControl::Exception => break,
+                // padding after a call that does not return
+                FlowControl::Interrupt if i.code() == Code::Int3 => break,
+                _ => {}
+            }
+            let next = (i.next_ip() - base) as u32;
+            if next != rva && known.contains_key(&next) {
+                break;
+            }
+        }
+    }
+    Ok(end - rva)
+}
+
+/// Target of a direct near branch or call.
+pub fn branch_target(i: &Instruction) -> Option<u64> {
+    matches!(i.op0_kind(), OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64)
+        .then(|| i.near_branch_target())
+}
+
+/// Address read or written by a RIP-relative or absolute memory operand.
+fn data_reference(i: &Instruction) -> Option<u64> {
+    if i.is_ip_rel_memory_operand() {
+        return Some(i.ip_rel_memory_address());
+    }
+    let absolute = (0..i.op_count()).any(|n| i.op_kind(n) == OpKind::Memory)
+        && i.memory_base() == Register::None
+        && i.memory_index() == Register::None;
+    absolute.then(|| i.memory_displacement64())
+}
+
+/// Splits `instructions` into basic blocks. Branch targets are matched against
+/// the `ip` of the instructions; a block ends after any branch or return.
+pub fn basic_blocks(instructions: &[Instruction]) -> Vec<BasicBlock> {
+    let index: HashMap<u64, usize> = instructions
+        .iter()
+        .enumerate()
+        .filter(|(_, i)| i.ip() != 0)
+        .map(|(n, i)| (i.ip(), n))
+        .collect();
+    let target_index = |i: &Instruction| branch_target(i).and_then(|t| index.get(&t).copied());
+    let ends_block = |i: &Instruction| {
+        !matches!(
+            i.flow_control(),
+            FlowControl::Next | FlowControl::Call | FlowControl::IndirectCall | FlowControl::Interrupt
+        )
+    };
+
+    let mut leaders: Vec<usize> = vec![0];
+    for (n, i) in instructions.iter().enumerate() {
+        if ends_block(i) {
+            leaders.push(n + 1);
+            leaders.extend(target_index(i));
+        }
+    }
+    leaders.retain(|&n| n < instructions.len());
+    leaders.sort_unstable();
+    leaders.dedup();
+
+    let block_of = |n: usize| leaders.partition_point(|&l| l <= n) - 1;
+    leaders
+        .iter()
+        .enumerate()
+        .map(|(b, &start)| {
+            let end = leaders.get(b + 1).copied().unwrap_or(instructions.len());
+            let last = &instructions[end - 1];
+            let falls_through = end < instructions.len();
+            let mut successors = Vec::new();
+            match last.flow_control() {
+                FlowControl::ConditionalBranch => {
+                    successors.extend(target_index(last).map(block_of));
+                    if falls_through {
+                        successors.push(b + 1);
+                    }
+                }
+                FlowControl::UnconditionalBranch => successors.extend(target_index(last).map(block_of)),
+                FlowControl::Return | FlowControl::IndirectBranch | FlowControl::Exception => {}
+                _ if falls_through => successors.push(b + 1),
+                _ => {}
+            }
+            successors.dedup();
+            BasicBlock { start, end, successors }
+        })
+        .collect()
+}
@@ -0,0 +1,251 @@
+//! ELF x86-64 support of `Image`: the loadable segments play the part of PE
+//! sections (RVAs are relative to the lowest segment), function symbols
+//! name the code, and new code goes into a segment described by a program
+//! header that previously held a `PT_NOTE`.
+
+use goblin::elf::header::{EM_386, EM_X86_64, ET_DYN};
+use goblin::elf::program_header::{PF_R, PF_W, PF_X, PT_GNU_RELRO, PT_INTERP, PT_LOAD, PT_NOTE};
+use goblin::elf::section_header::{SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, SHT_NOBITS};
+use goblin::elf::sym::{STB_GLOBAL, STB_WEAK, STT_FUNC};
+use goblin::elf::Elf;
+use goblin::pe::section_table;
+
+use crate::pipeline::error::StepError;
+use crate::pipeline::image::{Section, IMAGE_SCN_CNT_CODE, IMAGE_SCN_MEM_EXECUTE, IMAGE_SCN_MEM_READ, IMAGE_SCN_MEM_WRITE};
+
+pub const PAGE_SIZE: u32 = 0x1000;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const R_X86_64_64: u32 = 1;
+const R_X86_64_RELATIVE: u32 = 8;
+const R_X86_64_IRELATIVE: u32 = 37;
+
+/// Human readable architecture for an ELF machine value.
+pub fn machine_name(machine: u16) -> String {
+    match machine {
+        EM_386 => "x86 (32-bit)".to_string(),
+        EM_X86_64 => "x86_64 (64-bit)".to_string(),
+        m => goblin::elf::header::machine_to_str(m).to_string(),
+    }
+}
+
+/// PE section characteristics equivalent to ELF section flags, so reports
+/// and the rest of the pipeline can treat both formats alike.
+pub fn section_characteristics(sh_type: u32, sh_flags: u64) -> u32 {
+    let flags = sh_flags as u32;
+    let mut characteristics = 0;
+    if flags & SHF_EXECINSTR != 0 {
+        characteristics |= IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE;
+    } else if sh_type == SHT_NOBITS {
+        characteristics |= section_table::IMAGE_SCN_CNT_UNINITIALIZED_DATA;
+    } else {
+        characteristics |= section_table::IMAGE_SCN_CNT_INITIALIZED_DATA;
+    }
+    if flags & SHF_ALLOC != 0 {
+        characteristics |= IMAGE_SCN_MEM_READ;
+    }
+    if flags & SHF_WRITE != 0 {
+        characteristics |= IMAGE_SCN_MEM_WRITE;
+    }
+    characteristics
+}
+
+fn segment_characteristics(p_flags: u32) -> u32 {
+    let mut characteristics = 0;
+    if p_flags & PF_X != 0 {
+        characteristics |= IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE;
+    }
+    if p_flags & PF_R != 0 {
+        characteristics |= IMAGE_SCN_MEM_READ;
+    }
+    if p_flags & PF_W != 0 {
+        characteristics |= IMAGE_SCN_MEM_WRITE;
+    }
+    characteristics
+}
+
+fn segment_flags(characteristics: u32) -> u32 {
+    let mut flags = 0;
+    if characteristics & IMAGE_SCN_MEM_EXECUTE != 0 {
+        flags |= PF_X;
+    }
+    if characteristics & IMAGE_SCN_MEM_READ != 0 {
+        flags |= PF_R;
+    }
+    if characteristics & IMAGE_SCN_MEM_WRITE != 0 {
+        flags |= PF_W;
+    }
+    flags
+}
+
+/// What `Image` needs to know about an ELF file.
+pub struct ElfLayout {
+    /// Virtual address of the lowest loadable segment (0 for position-independent files).
+    pub base: u64,
+    pub entry: u32,
+    pub is_shared_library: bool,
+    /// The `PT_LOAD` segments.
+    pub sections: Vec<Section>,
+    /// Defined functions of the symbol tables as `(rva, size, name)`.
+    pub symbols: Vec<(u32, u32, String)>,
+    /// Functions of the dynamic symbol table visible to other modules.
+    pub exports: Vec<(u32, String)>,
+    /// RVAs of the words the dynamic loader relocates, sorted.
+    pub relocations: Vec<u32>,
+    /// RVA and size of the range made read-only after relocation (`PT_GNU_RELRO`).
+    pub relro: Option<(u32, u32)>,
+    /// RVA and size of the file and program headers, when a segment maps them.
+    pub headers: Option<(u32, u32)>,
+    /// Allocated sections with file data, by name.
+    pub named_sections: Vec<(String, Section)>,
+    /// Whether a relocation calls an IFUNC resolver of this file at load time.
+    pub ifunc_relocations: bool,
+    program_headers: usize,
+    program_header_count: usize,
+}
+
+impl ElfLayout {
+    pub fn parse(bytes: &[u8]) -> Result<Self, StepError> {
+        let elf = Elf::parse(bytes).map_err(|e| StepError::InvalidInput(format!("Malformed ELF file: {}", e)))?;
+        if !elf.is_64 || elf.header.e_machine != EM_X86_64 {
+            return Err(StepError::UnsupportedFormat(format!(
+                "Only x86-64 ELF files can be protected (this one is {})",
+                machine_name(elf.header.e_machine)
+            )));
+        }
+        let loads: Vec<_> = elf.program_headers.iter().filter(|p| p.p_type == PT_LOAD).collect();
+        let base = loads
+            .iter()
+            .map(|p| p.p_vaddr & !(PAGE_SIZE as u64 - 1))
+            .min()
+            .ok_or_else(|| StepError::InvalidInput("ELF file without loadable segments".into()))?;
+        let rva = |va: u64| va.wrapping_sub(base) as u32;
+
+        let sections = loads
+            .iter()
+            .map(|p| Section {
+                virtual_address: rva(p.p_vaddr),
+                virtual_size: p.p_memsz as u32,
+                raw_offset: p.p_offset as u32,
+                raw_size: p.p_filesz as u32,
+                characteristics: segment_characteristics(p.p_flags),
+            })
+            .collect();
+
+        let mut symbols: Vec<(u32, u32, String)> = Vec::new();
+        let mut exports = Vec::new();
+        let tables = [(&elf.syms, &elf.strtab, false), (&elf.dynsyms, &elf.dynstrtab, true)];
+        for (table, strings, dynamic) in tables {
+            for sym in table.iter() {
+                if sym.st_type() != STT_FUNC || sym.st_value == 0 || sym.st_shndx == 0 {
+                    continue;
+                }
+                let Some(name) = strings.get_at(sym.st_name).filter(|n| !n.is_empty()) else {
+                    continue;
+                };
+                let at = rva(sym.st_value);
+                if !symbols.iter().any(|s| s.0 == at) {
+                    symbols.push((at, sym.st_size as u32, name.to_string()));
+                }
+                if dynamic && matches!(sym.st_bind(), STB_GLOBAL | STB_WEAK) {
+                    exports.push((at, name.to_string()));
+                }
+            }
+        }
+        symbols.sort_by_key(|s| s.0);
+
+        let mut relocations: Vec<u32> = elf
+            .dynrelas
+            .iter()
+            .chain(elf.dynrels.iter())
+            .filter(|r| matches!(r.r_type, R_X86_64_RELATIVE | R_X86_64_64))
+            .map(|r| rva(r.r_offset))
+            .collect();
+        relocations.sort_unstable();
+
+        let headers_end = elf.header.e_phoff + elf.header.e_phnum as u64 * PROGRAM_HEADER_SIZE as u64;
+        let headers = loads
+            .iter()
+            .find(|p| p.p_offset == 0 && p.p_filesz >= headers_end)
+            .map(|p| (rva(p.p_vaddr), headers_end as u32));
+        let named_sections = elf
+            .section_headers
+            .iter()
+            .filter(|s| s.sh_flags & SHF_ALLOC as u64 != 0 && s.sh_type != SHT_NOBITS && s.sh_size > 0)
+            .filter_map(|s| {
+                let name = elf.shdr_strtab.get_at(s.sh_name)?;
+                Some((
+                    name.to_string(),
+                    Section {
+                        virtual_address: rva(s.sh_addr),
+                        virtual_size: s.sh_size as u32,
+                        raw_offset: s.sh_offset as u32,
+                        raw_size: s.sh_size as u32,
+                        characteristics: section_characteristics(s.sh_type, s.sh_flags),
+                    },
+                ))
+            })
+            .collect();
+
+        Ok(Self {
+            base,
+            entry: rva(elf.entry),
+            is_shared_library: elf.header.e_type == ET_DYN
+                && !elf.program_headers.iter().any(|p| p.p_type == PT_INTERP),
+            sections,
+            symbols,
+            exports,
+            relocations,
+            relro: elf
+                .program_headers
+                .iter()
+                .find(|p| p.p_type == PT_GNU_RELRO)
+                .map(|p| (rva(p.p_vaddr), p.p_memsz as u32)),
+            headers,
+            named_sections,
+            ifunc_relocations: elf.dynrelas.iter().chain(elf.pltrelocs.iter()).any(|r| r.r_type == R_X86_64_IRELATIVE),
+            program_headers: elf.header.e_phoff as usize,
+            program_header_count: elf.header.e_phnum as usize,
+        })
+    }
+
+    /// Describes `size` bytes at file `offset` as a loadable segment at `rva`,
+    /// reusing the program header of a `PT_NOTE` (notes are not needed to run).
+    pub fn add_segment(
+        &mut self,
+        bytes: &mut [u8],
+        offset: u32,
+        rva: u32,
+        size: u32,
+        characteristics: u32,
+    ) -> Result<(), StepError> {
+        let header_at = |i: usize| self.program_headers + i * PROGRAM_HEADER_SIZE;
+        let p_type = |bytes: &[u8], i: usize| u32::from_le_bytes(bytes[header_at(i)..header_at(i) + 4].try_into().unwrap());
+        let note = (0..self.program_header_count)
+            .find(|&i| p_type(bytes, i) == PT_NOTE)
+            .ok_or_else(|| StepError::UnsupportedFormat("No program header left for a new segment".into()))?;
+
+        let va = self.base + rva as u64;
+        let mut header = [0u8; PROGRAM_HEADER_SIZE];
+        header[0..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        header[4..8].copy_from_slice(&segment_flags(characteristics).to_le_bytes());
+        header[8..16].copy_from_slice(&(offset as u64).to_le_bytes());
+        header[16..24].copy_from_slice(&va.to_le_bytes());
+        header[24..32].copy_from_slice(&va.to_le_bytes());
+        header[32..40].copy_from_slice(&(size as u64).to_le_bytes());
+        header[40..48].copy_from_slice(&(size as u64).to_le_bytes());
+        header[48..56].copy_from_slice(&(PAGE_SIZE as u64).to_le_bytes());
+        bytes[header_at(note)..header_at(note) + PROGRAM_HEADER_SIZE].copy_from_slice(&header);
+
+        // loadable segments must stay sorted by address
+        let slots: Vec<usize> = (0..self.program_header_count).filter(|&i| p_type(bytes, i) == PT_LOAD).collect();
+        let mut loads: Vec<[u8; PROGRAM_HEADER_SIZE]> = slots
+            .iter()
+            .map(|&i| bytes[header_at(i)..header_at(i) + PROGRAM_HEADER_SIZE].try_into().unwrap())
+            .collect();
+        loads.sort_by_key(|h| u64::from_le_bytes(h[16..24].try_into().unwrap()));
+        for (&i, load) in slots.iter().zip(&loads) {
+            bytes[header_at(i)..header_at(i) + PROGRAM_HEADER_SIZE].copy_from_slice(load);
+        }
+        Ok(())
+    }
+}
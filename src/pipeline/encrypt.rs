@@ -1,10 +1,11 @@
 use std::fs;
 use std::sync::mpsc::Sender;
-use std::sync::atomic::Ordering;
 use std::time::Duration;
 
 use crate::pipeline::{PipelineContext, PipelineMessage};
 use crate::pipeline::step::PipelineStep;
+use crate::pipeline::error::StepError;
+use crate::pipeline::plan::PlannedChange;
 
 use goblin::Object;
 
@@ -23,19 +24,18 @@ impl EncryptStringsStep {
 
 /// Helper: returns true if byte is printable ASCII (space..~)
 fn is_printable_ascii(b: u8) -> bool {
-    b >= 0x20 && b <= 0x7E
+    (0x20..=0x7E).contains(&b)
 }
 
 impl PipelineStep for EncryptStringsStep {
-    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> anyhow::Result<()> {
+    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> Result<(), StepError> {
         tx.send(PipelineMessage::Log("Encrypting strings step (PoC) started".into())).ok();
         tx.send(PipelineMessage::Progress(0.15)).ok();
         std::thread::sleep(Duration::from_millis(120));
 
         // Read file bytes
-        let bytes = fs::read(&ctx.input_path).map_err(|e| {
-            anyhow::anyhow!("Encrypt step: failed to read '{}': {}", ctx.input_path, e)
-        })?;
+        let bytes = fs::read(&ctx.input_path)
+            .map_err(|e| StepError::io("Encrypt step: failed to read", &ctx.input_path, e))?;
 
         // Parse as PE to get sections; fall back to scanning whole file if not PE
         let mut candidate_ranges: Vec<(usize, usize)> = Vec::new(); // (start, length)
@@ -76,10 +76,7 @@ impl PipelineStep for EncryptStringsStep {
         let mut total_checked = 0usize;
         for (ri, (start, len)) in candidate_ranges.iter().cloned().enumerate() {
             // verificar cancelamento
-            if ctx.cancel_flag.load(Ordering::Relaxed) {
-                let _ = tx.send(PipelineMessage::Cancelled);
-                return Ok(());
-            }
+            ctx.cancel.check()?;
             let slice = &bytes[start..start + len];
             let mut i = 0usize;
             while i < slice.len() {
@@ -103,6 +100,21 @@ impl PipelineStep for EncryptStringsStep {
             std::thread::sleep(Duration::from_millis(80));
         }
 
+        // Runs overlapping words the loader relocates are pointers, not text
+        if let Some(image) = ctx.image.as_ref() {
+            let before = found_strings.len();
+            found_strings.retain(|&(off, len)| {
+                !image.offset_to_rva(off).is_some_and(|rva| image.is_relocated(rva, len as u32))
+            });
+            if found_strings.len() < before {
+                tx.send(PipelineMessage::Log(format!(
+                    "Encrypt step: skipped {} candidates overlapping base relocations",
+                    before - found_strings.len()
+                )))
+                .ok();
+            }
+        }
+
         // Summary log
         let count = found_strings.len();
         tx.send(PipelineMessage::Log(format!(
@@ -111,15 +123,31 @@ impl PipelineStep for EncryptStringsStep {
         )))
         .ok();
 
+        // Dry run: record what would be encrypted and stop here
+        if ctx.dry_run {
+            for (off, len) in &found_strings {
+                ctx.plan.push(PlannedChange::encrypt_string(*off, &bytes[*off..*off + *len]));
+            }
+            if count > 0 {
+                ctx.plan.push(PlannedChange::WriteFile {
+                    path: ctx.artifacts.path(&ctx.input_path, "enc")?.display().to_string(),
+                    description: format!("PoC encrypted copy, {} strings", count),
+                });
+            }
+            tx.send(PipelineMessage::Log(format!(
+                "Dry run: {} strings planned for encryption, nothing written",
+                count
+            )))
+            .ok();
+            return Ok(());
+        }
+
         // Simulate encryption
         if count > 0 {
             let mut out_bytes = bytes.clone();
             for (idx, (off, len)) in found_strings.iter().enumerate() {
                 // verificar cancelamento
-                if ctx.cancel_flag.load(Ordering::Relaxed) {
-                    let _ = tx.send(PipelineMessage::Cancelled);
-                    return Ok(());
-                }
+                ctx.cancel.check()?;
                 let end = (*off).saturating_add(*len).min(out_bytes.len());
                 for b in out_bytes[*off..end].iter_mut() {
                     *b ^= XOR_KEY;
@@ -128,12 +156,13 @@ impl PipelineStep for EncryptStringsStep {
                 tx.send(PipelineMessage::Progress(p.min(0.85))).ok();
             }
 
-            let out_path = format!("{}.enc", ctx.input_path);
-            match fs::write(&out_path, &out_bytes) {
-                Ok(_) => {
+            let out_path = ctx.artifacts.path(&ctx.input_path, "enc")?;
+            match ctx.write_artifact(&out_path, &out_bytes) {
+                Ok(()) => {
                     tx.send(PipelineMessage::Log(format!(
-                        "Wrote PoC encrypted file: {}",
-                        out_path
+                        "Wrote PoC encrypted file: {} ({})",
+                        out_path.display(),
+                        ctx.artifacts.describe()
                     )))
                     .ok();
                 }
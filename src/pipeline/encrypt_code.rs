@@ -0,0 +1,591 @@
+//! Function body encryption of `EncryptCodeStep`.
+//!
+//! Every selected body is XORed in the file with a keystream drawn from a
+//! per-function key, and the `.obsd` section gets the routines that undo it:
+//!
+//! ```text
+//! decrypt, encrypt   toggle one body under a spin lock (encrypt only with re-encryption)
+//! startup routine    decrypts every body (startup decryption)
+//! stubs              one per function (first-call decryption)
+//! lock, VirtualProtect slot (PE)
+//! entries            40 bytes each: rva, size, key, state (u32 each), the first
+//!                    5 encrypted bytes (padded to 8), stub rva and padding
+//!                    (u32 each), saved return address (u64)
+//! ```
+//!
+//! With first-call decryption the start of each body is patched with a
+//! `jmp` to its stub, which saves the argument registers, has the body
+//! decrypted (restoring its first bytes) and jumps to it; later calls run
+//! the plain body directly. With re-encryption the stub calls the body
+//! instead and encrypts it again, start patch included, when it returns.
+//!
+//! The pages are made writable around each change with `VirtualProtect`
+//! (found through the PEB, see `stub`) on PE and `mprotect` on ELF.
+
+use std::sync::mpsc::Sender;
+
+use iced_x86::code_asm::*;
+use iced_x86::{BlockEncoderOptions, IcedError};
+use rand::Rng;
+
+use crate::pipeline::{PipelineContext, PipelineMessage};
+use crate::pipeline::code::MIN_MOVABLE_SIZE;
+use crate::pipeline::elf::PAGE_SIZE;
+use crate::pipeline::error::StepError;
+use crate::pipeline::hash::{HashAlgorithm, NameHasher};
+use crate::pipeline::image::{ImageFormat, StartupPhase, STUB_SECTION};
+use crate::pipeline::plan::PlannedChange;
+use crate::pipeline::profile::{step_rng, DecryptTiming, EncryptCodeOptions};
+use crate::pipeline::step::PipelineStep;
+use crate::pipeline::stub::Runtime;
+
+pub const DECRYPT_SECTION: &str = ".obsd";
+const ENTRY_SIZE: i32 = 40;
+/// Bytes of the start patch (`jmp rel32`) restored from the entry on decryption.
+const HEAD_SIZE: usize = MIN_MOVABLE_SIZE as usize;
+pub const LCG_MUL: u32 = 1_664_525;
+pub const LCG_INC: u32 = 1_013_904_223;
+const PAGE_EXECUTE_READWRITE: u32 = 0x40;
+const SYS_MPROTECT: u32 = 10;
+const PROT_READ_WRITE_EXEC: u32 = 7;
+const PROT_READ_EXEC: u32 = 5;
+
+/// Encrypts the bodies of the functions selected in the profile and adds the
+/// stubs decrypting them, at their first call or at startup.
+///
+/// Works on x64 PE and ELF images; startup decryption needs an entry point
+/// that runs, so not ELF shared libraries. Bodies moved or virtualized by an
+/// earlier pass, containing base relocations or (for first-call decryption)
+/// entered other than through their start are left alone. Re-encryption
+/// keeps one return address per function: it is meant for functions that
+/// are not running on two threads at once, and an exception unwinding
+/// through the stub (which has no unwind information) is fatal.
+pub struct EncryptCodeStep {
+    options: EncryptCodeOptions,
+}
+
+impl EncryptCodeStep {
+    pub fn new(options: EncryptCodeOptions) -> Self {
+        Self { options }
+    }
+}
+
+/// A body encrypted in the file.
+struct Body {
+    rva: u32,
+    size: u32,
+    key: u32,
+    /// The first `HEAD_SIZE` encrypted bytes, overwritten by the start patch.
+    head: [u8; 8],
+}
+
+impl PipelineStep for EncryptCodeStep {
+    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> Result<(), StepError> {
+        ctx.cancel.check()?;
+        tx.send(PipelineMessage::Log("Encrypting function bodies...".into())).ok();
+
+        let Some(image) = ctx.image.as_mut() else {
+            return Err(StepError::Internal("no image loaded before code encryption".into()));
+        };
+        if !image.is_64 {
+            tx.send(PipelineMessage::Log("Code encryption supports x64 images only; skipped".into())).ok();
+            return Ok(());
+        }
+        let lazy = self.options.decrypt == DecryptTiming::FirstCall;
+        if !lazy && !image.supports_startup_routines() {
+            tx.send(PipelineMessage::Log(
+                "Startup decryption needs an entry point, which ELF shared libraries do not run; skipped".into(),
+            ))
+            .ok();
+            return Ok(());
+        }
+        if self.options.reencrypt && !lazy {
+            tx.send(PipelineMessage::Log(
+                "Re-encryption only applies to first-call decryption; ignored".into(),
+            ))
+            .ok();
+        }
+        let reencrypt = self.options.reencrypt && lazy;
+        let (format, image_base, entry) = (image.format, image.image_base, image.entry);
+        let code = image.code_map();
+        let (selected, unmatched) = code.select(&self.options.functions);
+        for selector in unmatched {
+            tx.send(PipelineMessage::Log(format!("Encrypt code: no function matches '{}'", selector))).ok();
+        }
+
+        let mut rng = step_rng(ctx.seed, "encrypt_code");
+        let mut encrypted = 0;
+        let mut bodies = Vec::new();
+        for index in selected {
+            ctx.cancel.check()?;
+            let function = &code.functions[index];
+            let name = function.name();
+            let eligible = if function.replaced {
+                Err("its body was replaced by another pass".into())
+            } else if function.modified {
+                Err("it is moved by another pass".into())
+            } else if reencrypt && format == ImageFormat::Elf && function.info.rva == entry {
+                // the stub calls the body, which would find its return address where argc should be
+                Err("the ELF entry point reads its arguments from the stack and never returns".into())
+            } else {
+                code.check_encryptable(index, lazy)
+            };
+            if let Err(reason) = eligible {
+                tx.send(PipelineMessage::Log(format!("Not encrypting {}: {}", name, reason))).ok();
+                continue;
+            }
+            if ctx.dry_run {
+                ctx.plan.push(PlannedChange::TransformFunction {
+                    function: name.clone(),
+                    rva: function.info.rva,
+                    transform: "encrypt".into(),
+                });
+            } else {
+                code.functions[index].replaced = true;
+                bodies.push(Body {
+                    rva: code.functions[index].info.rva,
+                    size: code.functions[index].info.size,
+                    key: rng.gen(),
+                    head: [0; 8],
+                });
+            }
+            tx.send(PipelineMessage::Log(format!("Encrypted {}", name))).ok();
+            encrypted += 1;
+        }
+        if ctx.dry_run && encrypted > 0 {
+            ctx.plan.push_once(PlannedChange::AddSection {
+                name: DECRYPT_SECTION.into(),
+                description: "code decryption stubs".into(),
+            });
+        }
+        if bodies.is_empty() {
+            tx.send(PipelineMessage::Log(format!("Code encryption: {} functions", encrypted))).ok();
+            return Ok(());
+        }
+
+        let mut contents = Vec::with_capacity(bodies.len());
+        for body in &mut bodies {
+            let mut bytes = image
+                .read(body.rva, body.size as usize)
+                .ok_or_else(|| StepError::Internal(format!("body at 0x{:x} is not backed by file data", body.rva)))?
+                .to_vec();
+            crypt(body.key, &mut bytes);
+            body.head[..HEAD_SIZE].copy_from_slice(&bytes[..HEAD_SIZE]);
+            contents.push(bytes);
+        }
+
+        let section_rva = image.next_section_rva();
+        let hasher = NameHasher::new(HashAlgorithm::default(), [0; 2]);
+        let (data, stubs, startup) = assemble(format, image_base, section_rva, &bodies, lazy, reencrypt, hasher)?;
+        let rva = image.add_section(DECRYPT_SECTION, &data, STUB_SECTION)?;
+        if rva != section_rva {
+            return Err(StepError::Internal(format!(
+                "Decryption section placed at 0x{:x} instead of 0x{:x}",
+                rva, section_rva
+            )));
+        }
+        for (i, (body, mut bytes)) in bodies.iter().zip(contents).enumerate() {
+            if let Some(stub) = stubs.get(i) {
+                bytes[0] = 0xe9;
+                bytes[1..5].copy_from_slice(&stub.wrapping_sub(body.rva + 5).to_le_bytes());
+            }
+            image.write(body.rva, &bytes)?;
+            image.mark_runtime_written(body.rva, body.size);
+        }
+        if !lazy {
+            image.add_startup_routine(StartupPhase::DecryptCode, "code decryption", startup);
+        }
+        tx.send(PipelineMessage::Log(format!(
+            "Code encryption: {} functions decrypted {}{}, {} bytes in {}",
+            bodies.len(),
+            if lazy { "at first call" } else { "at startup" },
+            if reencrypt { " and encrypted again on return" } else { "" },
+            data.len(),
+            DECRYPT_SECTION
+        )))
+        .ok();
+        Ok(())
+    }
+}
+
+/// XORs `bytes` with the keystream of `key`: the high byte of each step of
+/// a 32-bit LCG. The toggle routines compute the same stream.
+pub fn crypt(key: u32, bytes: &mut [u8]) {
+    let mut state = key;
+    for b in bytes {
+        state = state.wrapping_mul(LCG_MUL).wrapping_add(LCG_INC);
+        *b ^= (state >> 24) as u8;
+    }
+}
+
+/// Labels the toggle routines share.
+struct Shared<'a> {
+    /// Start of the section, at `section_rva`.
+    start: CodeLabel,
+    section_rva: u32,
+    lock: CodeLabel,
+    virtual_protect: CodeLabel,
+    fail: CodeLabel,
+    /// PEB-walking lookup of `VirtualProtect`; `None` for ELF images.
+    runtime: Option<&'a Runtime>,
+    hasher: NameHasher,
+}
+
+/// Lays out the decryption section at `section_rva` and returns its bytes,
+/// the RVAs of the stubs of the bodies (first-call decryption only) and of
+/// the startup routine (startup decryption only).
+fn assemble(
+    format: ImageFormat,
+    image_base: u64,
+    section_rva: u32,
+    bodies: &[Body],
+    lazy: bool,
+    reencrypt: bool,
+    hasher: NameHasher,
+) -> Result<(Vec<u8>, Vec<u32>, u32), StepError> {
+    let section_va = image_base + section_rva as u64;
+    let mut a = CodeAssembler::new(64)?;
+    let mut decrypt = a.create_label();
+    let mut encrypt = a.create_label();
+    let mut startup = a.create_label();
+    let mut lock = a.create_label();
+    let mut virtual_protect = a.create_label();
+    let mut fail = a.create_label();
+    let mut entries: Vec<CodeLabel> = bodies.iter().map(|_| a.create_label()).collect();
+    let mut returns: Vec<CodeLabel> = bodies.iter().map(|_| a.create_label()).collect();
+    let mut stubs: Vec<CodeLabel> = bodies.iter().map(|_| a.create_label()).collect();
+    let runtime = (format == ImageFormat::Pe).then(|| Runtime::new(&mut a, hasher));
+
+    let shared = Shared {
+        start: decrypt,
+        section_rva,
+        lock,
+        virtual_protect,
+        fail,
+        runtime: runtime.as_ref(),
+        hasher,
+    };
+    // first, so that its address is the section start
+    emit_toggle(&mut a, &mut decrypt, false, &shared)?;
+    if reencrypt {
+        emit_toggle(&mut a, &mut encrypt, true, &shared)?;
+    }
+
+    if lazy {
+        for (i, body) in bodies.iter().enumerate() {
+            let function = image_base + body.rva as u64;
+            a.set_label(&mut stubs[i])?;
+            if !reencrypt {
+                save_volatile(&mut a)?;
+                a.lea(rcx, ptr(entries[i]))?;
+                a.call(decrypt)?;
+                restore_volatile(&mut a)?;
+                a.jmp(function)?;
+                continue;
+            }
+            // the stub's return address takes the place of the caller's, so
+            // stack arguments stay where the function expects them
+            a.pop(qword_ptr(returns[i]))?;
+            a.sub(rsp, 8)?;
+            save_volatile(&mut a)?;
+            a.lea(rcx, ptr(entries[i]))?;
+            a.call(decrypt)?;
+            restore_volatile(&mut a)?;
+            a.add(rsp, 8)?;
+            a.call(function)?;
+            a.sub(rsp, 8)?;
+            save_volatile(&mut a)?;
+            a.lea(rcx, ptr(entries[i]))?;
+            a.call(encrypt)?;
+            restore_volatile(&mut a)?;
+            a.add(rsp, 8)?;
+            a.jmp(qword_ptr(returns[i]))?;
+        }
+    } else {
+        let mut next = a.create_label();
+        a.set_label(&mut startup)?;
+        a.push(rbx)?;
+        a.push(r12)?;
+        a.sub(rsp, 0x28)?;
+        a.lea(rbx, ptr(entries[0]))?;
+        a.mov(r12d, bodies.len() as u32)?;
+        a.set_label(&mut next)?;
+        a.mov(rcx, rbx)?;
+        a.call(decrypt)?;
+        a.add(rbx, ENTRY_SIZE)?;
+        a.dec(r12d)?;
+        a.jnz(next)?;
+        a.add(rsp, 0x28)?;
+        a.pop(r12)?;
+        a.pop(rbx)?;
+        a.ret()?;
+    }
+
+    a.set_label(&mut fail)?;
+    a.ud2()?;
+    if let Some(runtime) = runtime {
+        runtime.emit(&mut a)?;
+    }
+    a.set_label(&mut lock)?;
+    a.dd(&[0])?;
+    a.set_label(&mut virtual_protect)?;
+    a.dq(&[0])?;
+    for (i, body) in bodies.iter().enumerate() {
+        a.set_label(&mut entries[i])?;
+        a.dd(&[body.rva, body.size, body.key, 0])?;
+        a.db(&body.head)?;
+        // stub rva, filled in below
+        a.dd(&[0, 0])?;
+        a.set_label(&mut returns[i])?;
+        a.dq(&[0])?;
+    }
+
+    let result = a.assemble_options(section_va, BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS)?;
+    let rva_of = |label: &CodeLabel| -> Result<u32, StepError> { Ok((result.label_ip(label)? - image_base) as u32) };
+    let mut data = result.inner.code_buffer.clone();
+    let mut stub_rvas = Vec::new();
+    if lazy {
+        for (entry, stub) in entries.iter().zip(&stubs) {
+            let stub_rva = rva_of(stub)?;
+            let at = (rva_of(entry)? - section_rva) as usize + 24;
+            data[at..at + 4].copy_from_slice(&stub_rva.to_le_bytes());
+            stub_rvas.push(stub_rva);
+        }
+    }
+    let startup_rva = if lazy { 0 } else { rva_of(&startup)? };
+    Ok((data, stub_rvas, startup_rva))
+}
+
+/// Emits the routine at `label` that decrypts the body of the entry in
+/// `rcx` (or, with `encrypt`, encrypts it again and patches its start with
+/// the `jmp` to its stub) unless it is already in that state. Returns the
+/// body address in `rax`.
+fn emit_toggle(a: &mut CodeAssembler, label: &mut CodeLabel, encrypt: bool, shared: &Shared) -> Result<(), IcedError> {
+    let mut spin = a.create_label();
+    let mut locked = a.create_label();
+    let mut next = a.create_label();
+    let mut crypted = a.create_label();
+    let mut done = a.create_label();
+
+    a.set_label(label)?;
+    a.push(rbx)?;
+    a.push(rsi)?;
+    a.push(rdi)?;
+    a.push(r12)?;
+    a.push(r13)?;
+    a.push(r14)?;
+    a.push(r15)?;
+    a.sub(rsp, 0x30)?;
+    a.mov(r12, rcx)?;
+    // rbx = runtime image base
+    a.lea(rbx, ptr(shared.start))?;
+    a.sub(rbx, shared.section_rva as i32)?;
+
+    a.set_label(&mut spin)?;
+    a.mov(eax, 1)?;
+    a.xchg(dword_ptr(shared.lock), eax)?;
+    a.test(eax, eax)?;
+    a.jz(locked)?;
+    a.pause()?;
+    a.jmp(spin)?;
+    a.set_label(&mut locked)?;
+
+    a.mov(eax, dword_ptr(r12))?;
+    a.lea(r13, ptr(rbx + rax))?;
+    a.mov(r14d, dword_ptr(r12 + 4))?;
+    a.cmp(dword_ptr(r12 + 12), if encrypt { 1 } else { 0 })?;
+    a.jne(done)?;
+    set_protection(a, shared, true)?;
+    if !encrypt {
+        a.mov(eax, dword_ptr(r12 + 16))?;
+        a.mov(dword_ptr(r13), eax)?;
+        a.mov(al, byte_ptr(r12 + 20))?;
+        a.mov(byte_ptr(r13 + 4), al)?;
+    }
+
+    a.mov(rdi, r13)?;
+    a.mov(esi, r14d)?;
+    a.mov(eax, dword_ptr(r12 + 8))?;
+    a.set_label(&mut next)?;
+    a.test(esi, esi)?;
+    a.jz(crypted)?;
+    a.imul_3(eax, eax, LCG_MUL as i32)?;
+    a.add(eax, LCG_INC as i32)?;
+    a.mov(edx, eax)?;
+    a.shr(edx, 24)?;
+    a.xor(byte_ptr(rdi), dl)?;
+    a.inc(rdi)?;
+    a.dec(esi)?;
+    a.jmp(next)?;
+    a.set_label(&mut crypted)?;
+
+    if encrypt {
+        a.mov(byte_ptr(r13), 0xe9)?;
+        a.mov(eax, dword_ptr(r12 + 24))?;
+        a.sub(eax, dword_ptr(r12))?;
+        a.sub(eax, 5)?;
+        a.mov(dword_ptr(r13 + 1), eax)?;
+    }
+    set_protection(a, shared, false)?;
+    a.mov(dword_ptr(r12 + 12), if encrypt { 0 } else { 1 })?;
+
+    a.set_label(&mut done)?;
+    a.mov(dword_ptr(shared.lock), 0)?;
+    a.mov(rax, r13)?;
+    a.add(rsp, 0x30)?;
+    a.pop(r15)?;
+    a.pop(r14)?;
+    a.pop(r13)?;
+    a.pop(r12)?;
+    a.pop(rdi)?;
+    a.pop(rsi)?;
+    a.pop(rbx)?;
+    a.ret()
+}
+
+/// Makes the pages of the body at `r13` (`r14d` bytes) writable, or gives
+/// them back their protection. The old PE protection is kept at `[rsp+0x20]`.
+fn set_protection(a: &mut CodeAssembler, shared: &Shared, writable: bool) -> Result<(), IcedError> {
+    let Some(runtime) = shared.runtime else {
+        a.mov(rdi, r13)?;
+        a.and(rdi, -(PAGE_SIZE as i32))?;
+        a.lea(rsi, ptr(r13 + r14))?;
+        a.sub(rsi, rdi)?;
+        a.mov(edx, if writable { PROT_READ_WRITE_EXEC } else { PROT_READ_EXEC })?;
+        a.mov(eax, SYS_MPROTECT)?;
+        a.syscall()?;
+        a.test(rax, rax)?;
+        return a.jnz(shared.fail);
+    };
+    if writable {
+        let mut resolved = a.create_label();
+        a.mov(rax, qword_ptr(shared.virtual_protect))?;
+        a.test(rax, rax)?;
+        a.jnz(resolved)?;
+        a.mov(ecx, shared.hasher.hash("kernel32.dll"))?;
+        a.call(runtime.find_module)?;
+        a.test(rax, rax)?;
+        a.jz(shared.fail)?;
+        a.mov(rcx, rax)?;
+        a.mov(edx, shared.hasher.hash("VirtualProtect"))?;
+        a.call(runtime.find_export)?;
+        a.test(rax, rax)?;
+        a.jz(shared.fail)?;
+        a.mov(qword_ptr(shared.virtual_protect), rax)?;
+        a.set_label(&mut resolved)?;
+        a.mov(r8d, PAGE_EXECUTE_READWRITE)?;
+        a.lea(r9, ptr(rsp + 0x20))?;
+    } else {
+        a.mov(r8d, dword_ptr(rsp + 0x20))?;
+        a.lea(r9, ptr(rsp + 0x28))?;
+    }
+    a.mov(rcx, r13)?;
+    a.mov(edx, r14d)?;
+    a.call(qword_ptr(shared.virtual_protect))?;
+    a.test(eax, eax)?;
+    a.jz(shared.fail)
+}
+
+const VOLATILE: [AsmRegister64; 9] = [rax, rcx, rdx, rsi, rdi, r8, r9, r10, r11];
+const VECTOR_ARGS: [AsmRegisterXmm; 8] = [xmm0, xmm1, xmm2, xmm3, xmm4, xmm5, xmm6, xmm7];
+
+/// Saves the registers that can hold arguments or return values under either
+/// calling convention. Expects `rsp` 8 bytes below a 16-byte boundary, as at
+/// a function entry, and leaves it aligned.
+fn save_volatile(a: &mut CodeAssembler) -> Result<(), IcedError> {
+    for register in VOLATILE {
+        a.push(register)?;
+    }
+    a.sub(rsp, 0x80)?;
+    for (i, register) in VECTOR_ARGS.into_iter().enumerate() {
+        a.movdqu(xmmword_ptr(rsp + i as i32 * 16), register)?;
+    }
+    Ok(())
+}
+
+fn restore_volatile(a: &mut CodeAssembler) -> Result<(), IcedError> {
+    for (i, register) in VECTOR_ARGS.into_iter().enumerate() {
+        a.movdqu(register, xmmword_ptr(rsp + i as i32 * 16))?;
+    }
+    a.add(rsp, 0x80)?;
+    for register in VOLATILE.into_iter().rev() {
+        a.pop(register)?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux", target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::process::Command;
+    use std::sync::mpsc;
+
+    use crate::pipeline::cancel::CancellationToken;
+    use crate::pipeline::image::Image;
+    use crate::pipeline::profile::{FunctionAttribute, FunctionSelector};
+
+    /// Encrypts every function of the ELF at `path` that can be; returns
+    /// whether any was and the written file.
+    fn encrypt_file(path: &Path, decrypt: DecryptTiming, reencrypt: bool) -> Option<(bool, Vec<u8>)> {
+        let image = Image::parse(std::fs::read(path).ok()?).ok()?;
+        if image.format != ImageFormat::Elf || image.is_dll {
+            return None;
+        }
+        let mut ctx = PipelineContext::new(path.to_string_lossy().into_owned(), CancellationToken::new());
+        ctx.seed = 1;
+        ctx.image = Some(image);
+        let options = EncryptCodeOptions {
+            enabled: true,
+            functions: vec![FunctionSelector::Attribute(FunctionAttribute::All)],
+            decrypt,
+            reencrypt,
+        };
+        let (tx, rx) = mpsc::channel();
+        EncryptCodeStep::new(options).run(&mut ctx, &tx).unwrap();
+        drop(tx);
+        let encrypted = rx
+            .iter()
+            .any(|message| matches!(message, PipelineMessage::Log(line) if line.starts_with("Encrypted ")));
+        let mut image = ctx.image.take().unwrap();
+        image.finalize().unwrap();
+        Some((encrypted, image.into_bytes()))
+    }
+
+    #[test]
+    fn encrypted_elf_binaries_run() {
+        let dir = std::env::temp_dir().join(format!("obscura-encrypt-code-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let runs: [(&str, &[&str]); 3] = [
+            ("/bin/true", &[]),
+            ("/bin/ls", &["-la", "--color=never", "/etc"]),
+            ("/bin/cat", &["-n", "/etc/passwd"]),
+        ];
+        let timings = [
+            (DecryptTiming::FirstCall, false),
+            (DecryptTiming::FirstCall, true),
+            (DecryptTiming::Startup, false),
+        ];
+        for (decrypt, reencrypt) in timings {
+            let mut encrypted_any = false;
+            for (path, args) in runs {
+                let Some((encrypted, bytes)) = encrypt_file(Path::new(path), decrypt, reencrypt) else {
+                    continue;
+                };
+                encrypted_any |= encrypted;
+                let target = dir.join(Path::new(path).file_name().unwrap());
+                std::fs::write(&target, bytes).unwrap();
+                std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o755)).unwrap();
+                let expected = Command::new(path).args(args).output().unwrap();
+                let actual = Command::new(&target).args(args).output().unwrap();
+                let run = format!("{} {:?} ({:?}, re-encryption {})", path, args, decrypt, reencrypt);
+                assert_eq!(actual.status.code(), expected.status.code(), "{}", run);
+                assert_eq!(actual.stdout, expected.stdout, "{}", run);
+            }
+            assert!(encrypted_any, "no function was encrypted ({:?}, re-encryption {})", decrypt, reencrypt);
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
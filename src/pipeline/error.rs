@@ -0,0 +1,71 @@
+use std::fmt;
+use std::io;
+
+use crate::pipeline::cancel::Cancelled;
+
+/// Error returned by a pipeline step.
+///
+/// Any error aborts the run; the runner forwards it to the UI as
+/// `PipelineMessage::Error` (or `Cancelled`), so the category is preserved
+/// instead of being flattened into a string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepError {
+    /// The input file exists but is not something we can protect (corrupt, truncated...).
+    InvalidInput(String),
+    /// The input is a valid binary of a format or architecture we do not support.
+    UnsupportedFormat(String),
+    /// Reading the input or writing an artifact failed.
+    Io(String),
+    /// A bug or unexpected state inside a step.
+    Internal(String),
+    /// The run was cancelled through its `CancellationToken`.
+    Cancelled,
+}
+
+impl StepError {
+    /// Builds an `Io` error that names the file involved.
+    pub fn io(what: &str, path: impl AsRef<std::path::Path>, e: io::Error) -> Self {
+        StepError::Io(format!("{} '{}': {}", what, path.as_ref().display(), e))
+    }
+
+    /// Short human readable category, used as a label in the UI.
+    pub fn category(&self) -> &'static str {
+        match self {
+            StepError::InvalidInput(_) => "Invalid input",
+            StepError::UnsupportedFormat(_) => "Unsupported format",
+            StepError::Io(_) => "I/O error",
+            StepError::Internal(_) => "Internal error",
+            StepError::Cancelled => "Cancelled",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            StepError::InvalidInput(m)
+            | StepError::UnsupportedFormat(m)
+            | StepError::Io(m)
+            | StepError::Internal(m) => m,
+            StepError::Cancelled => "pipeline cancelled",
+        }
+    }
+}
+
+impl fmt::Display for StepError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.category(), self.message())
+    }
+}
+
+impl std::error::Error for StepError {}
+
+impl From<Cancelled> for StepError {
+    fn from(_: Cancelled) -> Self {
+        StepError::Cancelled
+    }
+}
+
+impl From<iced_x86::IcedError> for StepError {
+    fn from(e: iced_x86::IcedError) -> Self {
+        StepError::Internal(format!("Code generation failed: {}", e))
+    }
+}
@@ -0,0 +1,283 @@
+//! Minimal x64 PE executables built in memory for the unit tests.
+//!
+//! The image has a `.text` section holding the given code (which is also the
+//! entry point) and a `.rdata` section with an import directory. It carries
+//! no relocations, exception data or certificate, so every byte the tests
+//! look at is one they put there; `add_rcdata` adds a resource. `native`
+//! runs such an image's code on the host, for the passes that only apply to
+//! PE.
+
+use iced_x86::code_asm::*;
+
+use crate::pipeline::image::{Image, DIR_RESOURCE};
+
+pub const IMAGE_BASE: u64 = 0x1_4000_0000;
+pub const TEXT_RVA: u32 = 0x1000;
+pub const RDATA_RVA: u32 = 0x2000;
+
+const SECTION_ALIGNMENT: u32 = 0x1000;
+const FILE_ALIGNMENT: u32 = 0x200;
+const HEADERS_SIZE: u32 = 0x400;
+const PE_OFFSET: usize = 0x80;
+
+/// An x64 executable running `code` at `TEXT_RVA` and importing, by name,
+/// the functions listed for each DLL.
+pub fn pe64(code: &[u8], imports: &[(&str, &[&str])]) -> Vec<u8> {
+    let rdata = import_data(imports);
+    let text_raw = align(code.len() as u32, FILE_ALIGNMENT);
+    let rdata_raw = align(rdata.data.len() as u32, FILE_ALIGNMENT);
+    let size_of_image = RDATA_RVA + align(rdata.data.len() as u32, SECTION_ALIGNMENT);
+
+    let mut bytes = vec![0u8; HEADERS_SIZE as usize];
+    let mut put = |at: usize, value: &[u8]| bytes[at..at + value.len()].copy_from_slice(value);
+    put(0, b"MZ");
+    put(0x3c, &(PE_OFFSET as u32).to_le_bytes());
+    put(PE_OFFSET, b"PE\0\0");
+
+    let coff = PE_OFFSET + 4;
+    put(coff, &0x8664u16.to_le_bytes());
+    put(coff + 2, &2u16.to_le_bytes());
+    put(coff + 16, &240u16.to_le_bytes());
+    // executable, large address aware
+    put(coff + 18, &0x22u16.to_le_bytes());
+
+    let optional = coff + 20;
+    put(optional, &0x20bu16.to_le_bytes());
+    put(optional + 4, &text_raw.to_le_bytes());
+    put(optional + 16, &TEXT_RVA.to_le_bytes());
+    put(optional + 20, &TEXT_RVA.to_le_bytes());
+    put(optional + 24, &IMAGE_BASE.to_le_bytes());
+    put(optional + 32, &SECTION_ALIGNMENT.to_le_bytes());
+    put(optional + 36, &FILE_ALIGNMENT.to_le_bytes());
+    put(optional + 40, &6u16.to_le_bytes());
+    put(optional + 48, &6u16.to_le_bytes());
+    put(optional + 56, &size_of_image.to_le_bytes());
+    put(optional + 60, &HEADERS_SIZE.to_le_bytes());
+    // console subsystem
+    put(optional + 68, &3u16.to_le_bytes());
+    for (at, size) in [(72, 0x10_0000u64), (80, 0x1000), (88, 0x10_0000), (96, 0x1000)] {
+        put(optional + at, &size.to_le_bytes());
+    }
+    put(optional + 108, &16u32.to_le_bytes());
+    let directory = |index: usize| optional + 112 + index * 8;
+    put(directory(1), &RDATA_RVA.to_le_bytes());
+    put(directory(1) + 4, &(((imports.len() + 1) * 20) as u32).to_le_bytes());
+    put(directory(12), &rdata.iat.0.to_le_bytes());
+    put(directory(12) + 4, &rdata.iat.1.to_le_bytes());
+
+    let table = optional + 240;
+    let sections = [
+        (b".text\0\0\0", TEXT_RVA, code.len() as u32, HEADERS_SIZE, text_raw, 0x6000_0020u32),
+        (b".rdata\0\0", RDATA_RVA, rdata.data.len() as u32, HEADERS_SIZE + text_raw, rdata_raw, 0x4000_0040),
+    ];
+    for (i, (name, rva, size, raw_offset, raw_size, characteristics)) in sections.into_iter().enumerate() {
+        let at = table + i * 40;
+        put(at, name);
+        put(at + 8, &size.to_le_bytes());
+        put(at + 12, &rva.to_le_bytes());
+        put(at + 16, &raw_size.to_le_bytes());
+        put(at + 20, &raw_offset.to_le_bytes());
+        put(at + 36, &characteristics.to_le_bytes());
+    }
+
+    bytes.extend_from_slice(code);
+    bytes.resize((HEADERS_SIZE + text_raw) as usize, 0xcc);
+    bytes.extend_from_slice(&rdata.data);
+    bytes.resize((HEADERS_SIZE + text_raw + rdata_raw) as usize, 0);
+    bytes
+}
+
+/// A loop with forward and backward branches, for `TEXT_RVA`: the sum of
+/// `ecx`..1, minus 7 every time it passes 100.
+pub fn loop_function() -> Vec<u8> {
+    let mut a = CodeAssembler::new(64).unwrap();
+    let mut head = a.create_label();
+    let mut big = a.create_label();
+    let mut done = a.create_label();
+    a.push(rbx).unwrap();
+    a.sub(rsp, 0x20).unwrap();
+    a.mov(ebx, ecx).unwrap();
+    a.xor(eax, eax).unwrap();
+    a.set_label(&mut head).unwrap();
+    a.test(ebx, ebx).unwrap();
+    a.jz(done).unwrap();
+    a.add(eax, ebx).unwrap();
+    a.dec(ebx).unwrap();
+    a.cmp(eax, 100).unwrap();
+    a.ja(big).unwrap();
+    a.jmp(head).unwrap();
+    a.set_label(&mut big).unwrap();
+    a.sub(eax, 7).unwrap();
+    a.jmp(head).unwrap();
+    a.set_label(&mut done).unwrap();
+    a.add(rsp, 0x20).unwrap();
+    a.pop(rbx).unwrap();
+    a.ret().unwrap();
+    a.assemble(IMAGE_BASE + TEXT_RVA as u64).unwrap()
+}
+
+/// Adds a `.rsrc` section holding `RT_RCDATA/1` with `data`; returns the
+/// RVA of the data.
+pub fn add_rcdata(image: &mut Image, data: &[u8]) -> u32 {
+    const RT_RCDATA: u32 = 10;
+    let rva = image.next_section_rva();
+    let mut section = vec![0u8; 0x60];
+    // root, type and name directories, one ID entry each, then the data entry
+    for (dir, id, target) in [(0x00, RT_RCDATA, 0x8000_0018u32), (0x18, 1, 0x8000_0030), (0x30, 0, 0x48)] {
+        section[dir + 14..dir + 16].copy_from_slice(&1u16.to_le_bytes());
+        section[dir + 16..dir + 20].copy_from_slice(&id.to_le_bytes());
+        section[dir + 20..dir + 24].copy_from_slice(&target.to_le_bytes());
+    }
+    section[0x48..0x4c].copy_from_slice(&(rva + 0x60).to_le_bytes());
+    section[0x4c..0x50].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    section.extend_from_slice(data);
+    assert_eq!(image.add_section(".rsrc", &section, 0x4000_0040).unwrap(), rva);
+    image.set_data_directory(DIR_RESOURCE, rva, section.len() as u32);
+    rva + 0x60
+}
+
+struct ImportData {
+    data: Vec<u8>,
+    /// RVA and size of all the IATs, for the IAT data directory.
+    iat: (u32, u32),
+}
+
+/// `.rdata` contents: descriptors, then the lookup tables, the IATs, the
+/// hint/name entries and the DLL names.
+fn import_data(imports: &[(&str, &[&str])]) -> ImportData {
+    let table_size = |functions: &[&str]| (functions.len() as u32 + 1) * 8;
+    let descriptors_size = (imports.len() as u32 + 1) * 20;
+    let tables_size: u32 = imports.iter().map(|(_, functions)| table_size(functions)).sum();
+    let lookup_start = RDATA_RVA + descriptors_size;
+    let iat_start = lookup_start + tables_size;
+    let mut strings_at = iat_start + tables_size;
+
+    let mut descriptors = Vec::new();
+    let mut lookup = Vec::new();
+    let mut strings = Vec::new();
+    for (dll, functions) in imports {
+        let lookup_rva = lookup_start + lookup.len() as u32;
+        let iat_rva = iat_start + lookup.len() as u32;
+        for function in functions.iter() {
+            lookup.extend_from_slice(&(strings_at as u64).to_le_bytes());
+            let mut entry = vec![0, 0];
+            entry.extend_from_slice(function.as_bytes());
+            entry.push(0);
+            entry.resize(entry.len().next_multiple_of(2), 0);
+            strings_at += entry.len() as u32;
+            strings.extend(entry);
+        }
+        lookup.extend_from_slice(&[0; 8]);
+        let name_rva = strings_at;
+        strings.extend_from_slice(dll.as_bytes());
+        strings.extend_from_slice(&[0, 0]);
+        strings_at += dll.len() as u32 + 2;
+        for field in [lookup_rva, 0, 0, name_rva, iat_rva] {
+            descriptors.extend_from_slice(&field.to_le_bytes());
+        }
+    }
+    descriptors.extend_from_slice(&[0; 20]);
+
+    let mut data = descriptors;
+    data.extend_from_slice(&lookup);
+    // the IATs start out as copies of the lookup tables, like a linker writes them
+    data.extend_from_slice(&lookup);
+    data.extend_from_slice(&strings);
+    ImportData { data, iat: (iat_start, tables_size) }
+}
+
+fn align(value: u32, alignment: u32) -> u32 {
+    value.next_multiple_of(alignment)
+}
+
+/// Runs the code of an image on the host CPU.
+#[cfg(all(unix, target_arch = "x86_64"))]
+pub mod native {
+    use std::sync::{Mutex, MutexGuard};
+
+    use crate::pipeline::image::Image;
+
+    type Entry = extern "win64" fn(u64, u64, u64, u64) -> u64;
+
+    /// Serializes the mappings, which all sit at `IMAGE_BASE`.
+    static MAPPED: Mutex<()> = Mutex::new(());
+
+    /// A PE image mapped read-write-execute at its preferred base, so that
+    /// neither relocations nor absolute addresses need fixing. Imports are
+    /// not resolved: only code that calls no API can run.
+    pub struct Mapped {
+        memory: *mut libc::c_void,
+        len: usize,
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    impl Mapped {
+        pub fn new(bytes: &[u8]) -> Self {
+            let lock = MAPPED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let image = Image::parse(bytes.to_vec()).unwrap();
+            let end = image
+                .sections
+                .iter()
+                .map(|s| s.virtual_address + s.virtual_size.max(s.raw_size))
+                .max()
+                .unwrap_or(0);
+            let len = (end as usize).next_multiple_of(0x1000);
+            // SAFETY: a fresh anonymous mapping owned by this value; NOREPLACE
+            // fails rather than map over anything already there
+            let memory = unsafe {
+                libc::mmap(
+                    image.image_base as *mut libc::c_void,
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED_NOREPLACE,
+                    -1,
+                    0,
+                )
+            };
+            assert_eq!(memory as u64, image.image_base, "cannot map the image at its base");
+            for section in &image.sections {
+                let at = (section.raw_offset as usize).min(bytes.len());
+                let data = &bytes[at..(at + section.raw_size as usize).min(bytes.len())];
+                // SAFETY: the section lies inside the mapping, which is `end` bytes long
+                unsafe {
+                    let to = memory.cast::<u8>().add(section.virtual_address as usize);
+                    std::ptr::copy_nonoverlapping(data.as_ptr(), to, data.len());
+                }
+            }
+            Self { memory, len, _lock: lock }
+        }
+
+        /// Calls the code at `rva` with the Win64 convention.
+        pub fn call(&self, rva: u32, args: [u64; 4]) -> u64 {
+            assert!((rva as usize) < self.len);
+            // SAFETY: the caller vouches for the code at `rva` being a function
+            // that follows the Win64 convention and touches only the image
+            let entry: Entry = unsafe { std::mem::transmute(self.memory.cast::<u8>().add(rva as usize)) };
+            entry(args[0], args[1], args[2], args[3])
+        }
+
+        /// `len` bytes of the mapped image at `rva`.
+        pub fn read(&self, rva: u32, len: usize) -> Vec<u8> {
+            assert!(rva as usize + len <= self.len);
+            // SAFETY: the range lies inside the mapping
+            unsafe { std::slice::from_raw_parts(self.memory.cast::<u8>().add(rva as usize), len).to_vec() }
+        }
+
+        /// Overwrites the mapped image at `rva`, as the loader would fill a slot.
+        pub fn write(&self, rva: u32, data: &[u8]) {
+            assert!(rva as usize + data.len() <= self.len);
+            // SAFETY: the range lies inside the mapping, which is writable
+            unsafe {
+                let to = self.memory.cast::<u8>().add(rva as usize);
+                std::ptr::copy_nonoverlapping(data.as_ptr(), to, data.len());
+            }
+        }
+    }
+
+    impl Drop for Mapped {
+        fn drop(&mut self) {
+            // SAFETY: unmaps the mapping created in `new`
+            unsafe { libc::munmap(self.memory, self.len) };
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::Sender;
+
+use iced_x86::{Code, FlowControl, IcedError, Instruction, Register};
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::pipeline::{PipelineContext, PipelineMessage};
+use crate::pipeline::code::{branch_target, CodeMap};
+use crate::pipeline::error::StepError;
+use crate::pipeline::image::ImageFormat;
+use crate::pipeline::plan::PlannedChange;
+use crate::pipeline::profile::{step_rng, FlattenOptions, StateEncoding};
+use crate::pipeline::reassemble::CODE_SECTION;
+use crate::pipeline::step::PipelineStep;
+
+/// Control-flow flattening of the functions selected in the profile.
+///
+/// Every basic block ends by storing the (encoded) number of the next block
+/// in `eax` and jumping to a dispatcher, which decodes it and jumps to that
+/// block; the blocks themselves are laid out in random order. `rax` and the
+/// flags are saved on the stack across the dispatcher, so the original code
+/// sees them unchanged. The first block, which holds the prologue, stays first.
+///
+/// x64 only: the 16 bytes pushed below `rsp` rely on Windows having no red zone.
+/// The transitions have no unwind information: while one runs, `rsp` is 16
+/// bytes below what the function's unwind data describes, so a stack walk
+/// from there (a profiler sample, an exception raised in another thread's
+/// suspension) misreads the frame. Functions with exception handlers are not
+/// flattened, as they are not moved.
+pub struct FlattenStep {
+    options: FlattenOptions,
+}
+
+impl FlattenStep {
+    pub fn new(options: FlattenOptions) -> Self {
+        Self { options }
+    }
+}
+
+impl PipelineStep for FlattenStep {
+    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> Result<(), StepError> {
+        ctx.cancel.check()?;
+        tx.send(PipelineMessage::Log("Flattening control flow...".into())).ok();
+
+        let Some(image) = ctx.image.as_mut() else {
+            return Err(StepError::Internal("no image loaded before flattening".into()));
+        };
+        if !image.is_64 || image.format != ImageFormat::Pe {
+            tx.send(PipelineMessage::Log(
+                "Control-flow flattening supports x64 PE images only; skipped".into(),
+            ))
+            .ok();
+            return Ok(());
+        }
+        let code = image.code_map();
+        let (selected, unmatched) = code.select(&self.options.functions);
+        for selector in unmatched {
+            tx.send(PipelineMessage::Log(format!("Flatten: no function matches '{}'", selector))).ok();
+        }
+
+        let mut rng = step_rng(ctx.seed, "flatten");
+        let mut flattened = 0;
+        for index in selected {
+            ctx.cancel.check()?;
+            let name = code.functions[index].name();
+            if let Err(reason) = self.check(code, index) {
+                tx.send(PipelineMessage::Log(format!("Not flattening {}: {}", name, reason))).ok();
+                continue;
+            }
+            let blocks = code.functions[index].blocks.len();
+            if ctx.dry_run {
+                ctx.plan.push(PlannedChange::TransformFunction {
+                    function: name.clone(),
+                    rva: code.functions[index].info.rva,
+                    transform: "flatten".into(),
+                });
+            } else {
+                let instructions = flatten(code, index, self.options.state_encoding, &mut rng)?;
+                let function = &mut code.functions[index];
+                function.instructions = instructions;
+                function.rebuild_blocks();
+                function.modified = true;
+            }
+            tx.send(PipelineMessage::Log(format!("Flattened {} ({} blocks)", name, blocks))).ok();
+            flattened += 1;
+        }
+        if ctx.dry_run && flattened > 0 {
+            ctx.plan.push_once(PlannedChange::AddSection {
+                name: CODE_SECTION.into(),
+                description: "relocated function bodies".into(),
+            });
+        }
+        tx.send(PipelineMessage::Log(format!("Control-flow flattening: {} functions", flattened))).ok();
+        Ok(())
+    }
+}
+
+impl FlattenStep {
+    /// Why the function at `index` is left alone, if it is.
+    fn check(&self, code: &CodeMap, index: usize) -> Result<(), String> {
+        code.check_movable(index)?;
+        let function = &code.functions[index];
+        if function.info.size > self.options.max_size {
+            return Err(format!(
+                "{} bytes, more than max_size ({})",
+                function.info.size, self.options.max_size
+            ));
+        }
+        if function.blocks.len() < 2 {
+            return Err("only one basic block".into());
+        }
+        if function.prologue_len() > function.blocks[0].end {
+            return Err("the prologue spans several blocks".into());
+        }
+        if function.blocks.iter().any(|b| b.successors.contains(&0)) {
+            return Err("branches back to its first block".into());
+        }
+        Ok(())
+    }
+}
+
+/// How block numbers are stored in `eax` and turned back into them.
+struct StateCodec {
+    encoding: StateEncoding,
+    key: u32,
+    a: u32,
+    b: u32,
+}
+
+impl StateCodec {
+    fn random(encoding: StateEncoding, rng: &mut impl Rng) -> Self {
+        Self {
+            encoding,
+            key: rng.gen(),
+            a: rng.gen::<u32>() | 1,
+            b: rng.gen(),
+        }
+    }
+
+    fn encode(&self, state: u32) -> u32 {
+        match self.encoding {
+            StateEncoding::Plain => state,
+            StateEncoding::Xor => state ^ self.key,
+            StateEncoding::Affine => state.wrapping_sub(self.b).wrapping_mul(inverse(self.a)),
+        }
+    }
+
+    /// Turns the stored value in `eax` back into the block number.
+    fn decoder(&self) -> Result<Vec<Instruction>, IcedError> {
+        Ok(match self.encoding {
+            StateEncoding::Plain => Vec::new(),
+            StateEncoding::Xor => vec![Instruction::with2(Code::Xor_EAX_imm32, Register::EAX, self.key)?],
+            StateEncoding::Affine => vec![
+                Instruction::with3(Code::Imul_r32_rm32_imm32, Register::EAX, Register::EAX, self.a)?,
+                Instruction::with2(Code::Add_EAX_imm32, Register::EAX, self.b)?,
+            ],
+        })
+    }
+}
+
+/// Multiplicative inverse of an odd number modulo 2^32 (Newton's iteration).
+fn inverse(a: u32) -> u32 {
+    let mut x = a;
+    for _ in 0..5 {
+        x = x.wrapping_mul(2u32.wrapping_sub(a.wrapping_mul(x)));
+    }
+    x
+}
+
+/// The flattened instructions of the function at `index`; see `FlattenStep`.
+fn flatten(
+    code: &mut CodeMap,
+    index: usize,
+    encoding: StateEncoding,
+    rng: &mut impl Rng,
+) -> Result<Vec<Instruction>, StepError> {
+    let function = code.functions[index].clone();
+    let count = function.blocks.len();
+    let labels: Vec<u64> = (0..count).map(|_| code.new_label()).collect();
+    let dispatcher = code.new_label();
+    let block_at: HashMap<u64, usize> = function
+        .blocks
+        .iter()
+        .enumerate()
+        .map(|(b, block)| (function.instructions[block.start].ip(), b))
+        .collect();
+
+    let mut used = HashSet::new();
+    let states: Vec<u32> = (0..count)
+        .map(|_| loop {
+            let state = rng.gen();
+            if used.insert(state) {
+                break state;
+            }
+        })
+        .collect();
+    let codec = StateCodec::random(encoding, rng);
+
+    let transition = |to: usize, ip: u64| -> Result<Vec<Instruction>, IcedError> {
+        let mut push = Instruction::with1(Code::Push_r64, Register::RAX)?;
+        push.set_ip(ip);
+        Ok(vec![
+            push,
+            Instruction::with(Code::Pushfq),
+            Instruction::with2(Code::Mov_r32_imm32, Register::EAX, codec.encode(states[to]))?,
+            Instruction::with_branch(Code::Jmp_rel32_64, dispatcher)?,
+        ])
+    };
+
+    let mut order: Vec<usize> = (1..count).collect();
+    order.shuffle(rng);
+    let mut out = Vec::with_capacity(function.instructions.len() + count * 8);
+    for &b in std::iter::once(&0).chain(&order) {
+        let block = &function.blocks[b];
+        if b != 0 {
+            let mut popf = Instruction::with(Code::Popfq);
+            popf.set_ip(labels[b]);
+            out.push(popf);
+            out.push(Instruction::with1(Code::Pop_r64, Register::RAX)?);
+        }
+        let last = function.instructions[block.end - 1];
+        let internal = branch_target(&last).and_then(|t| block_at.get(&t).copied());
+        let next = (block.end < function.instructions.len()).then_some(b + 1);
+        out.extend_from_slice(&function.instructions[block.start..block.end - 1]);
+        match (last.flow_control(), internal) {
+            (FlowControl::ConditionalBranch, Some(taken)) => {
+                let label = code.new_label();
+                let mut branch = last;
+                branch.set_near_branch64(label);
+                out.push(branch);
+                if let Some(next) = next {
+                    out.extend(transition(next, 0)?);
+                }
+                out.extend(transition(taken, label)?);
+            }
+            (FlowControl::UnconditionalBranch, Some(target)) => out.extend(transition(target, 0)?),
+            (FlowControl::Return | FlowControl::IndirectBranch | FlowControl::UnconditionalBranch, _)
+            | (FlowControl::Exception, _) => out.push(last),
+            _ => {
+                out.push(last);
+                if let Some(next) = next {
+                    out.extend(transition(next, 0)?);
+                }
+            }
+        }
+    }
+
+    let mut dispatch = codec.decoder()?;
+    for &b in &order {
+        dispatch.push(Instruction::with2(Code::Cmp_EAX_imm32, Register::EAX, states[b])?);
+        dispatch.push(Instruction::with_branch(Code::Je_rel32_64, labels[b])?);
+    }
+    dispatch.push(Instruction::with(Code::Ud2));
+    dispatch[0].set_ip(dispatcher);
+    out.extend(dispatch);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+
+    use crate::pipeline::cancel::CancellationToken;
+    use crate::pipeline::fixture::{self, TEXT_RVA};
+    use crate::pipeline::image::Image;
+    use crate::pipeline::profile::{FunctionAttribute, FunctionSelector};
+
+    /// Flattens the loop of `fixture::loop_function`; returns the written file.
+    fn flatten_sample(encoding: StateEncoding, seed: u64) -> Vec<u8> {
+        let mut ctx = PipelineContext::new("sample.exe".into(), CancellationToken::new());
+        ctx.seed = seed;
+        ctx.image = Some(Image::parse(fixture::pe64(&fixture::loop_function(), &[])).unwrap());
+        let options = FlattenOptions {
+            enabled: true,
+            functions: vec![FunctionSelector::Attribute(FunctionAttribute::Entry)],
+            state_encoding: encoding,
+            ..Default::default()
+        };
+        let (tx, rx) = mpsc::channel();
+        FlattenStep::new(options).run(&mut ctx, &tx).unwrap();
+        drop(tx);
+        assert!(rx
+            .iter()
+            .any(|message| matches!(message, PipelineMessage::Log(line) if line.starts_with("Flattened "))));
+        let mut image = ctx.image.take().unwrap();
+        image.finalize().unwrap();
+        image.into_bytes()
+    }
+
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    #[test]
+    fn flattened_function_computes_the_same() {
+        let original = fixture::pe64(&fixture::loop_function(), &[]);
+        let inputs = [0u64, 1, 5, 14, 15, 40, 1000];
+        let expected: Vec<u64> = {
+            let mapped = fixture::native::Mapped::new(&original);
+            inputs.iter().map(|&n| mapped.call(TEXT_RVA, [n, 0, 0, 0])).collect()
+        };
+        for (seed, encoding) in [(1, StateEncoding::Plain), (2, StateEncoding::Xor), (3, StateEncoding::Affine)] {
+            let bytes = flatten_sample(encoding, seed);
+            assert!(bytes != original);
+            let mapped = fixture::native::Mapped::new(&bytes);
+            let results: Vec<u64> = inputs.iter().map(|&n| mapped.call(TEXT_RVA, [n, 0, 0, 0])).collect();
+            assert_eq!(results, expected, "{:?}", encoding);
+        }
+    }
+}
@@ -0,0 +1,412 @@
+//! Name hashing schemes for hidden imports.
+//!
+//! Every scheme hashes the ASCII-lowercased name, so `KERNEL32.dll` in the
+//! import table matches `KERNEL32.DLL` in the loader list. The Rust side and
+//! the x64 routine emitted by `NameHasher::emit` must agree bit for bit.
+
+use iced_x86::code_asm::*;
+use iced_x86::IcedError;
+use serde::{Deserialize, Serialize};
+
+const FNV_OFFSET: u32 = 0x811C_9DC5;
+const FNV_PRIME: u32 = 0x0100_0193;
+const DJB2_INIT: u32 = 5381;
+const CRC32_POLY: u32 = 0xEDB8_8320;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum HashAlgorithm {
+    /// CRC-32 (IEEE, reflected).
+    Crc32,
+    /// 32-bit FNV-1a.
+    #[default]
+    Fnv1a,
+    /// djb2 (`h * 33 + c`).
+    Djb2,
+    /// SipHash-2-4 with a 128-bit key, truncated to 32 bits.
+    #[value(name = "siphash")]
+    #[serde(rename = "siphash")]
+    SipHash,
+}
+
+impl std::fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            HashAlgorithm::Crc32 => "crc32",
+            HashAlgorithm::Fnv1a => "fnv1a",
+            HashAlgorithm::Djb2 => "djb2",
+            HashAlgorithm::SipHash => "siphash",
+        })
+    }
+}
+
+/// A hashing scheme plus its key (only used by SipHash).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NameHasher {
+    pub algorithm: HashAlgorithm,
+    pub key: [u64; 2],
+}
+
+impl NameHasher {
+    pub fn new(algorithm: HashAlgorithm, key: [u64; 2]) -> Self {
+        Self { algorithm, key }
+    }
+
+    pub fn hash(&self, name: &str) -> u32 {
+        let bytes = name.bytes().map(|b| b.to_ascii_lowercase());
+        match self.algorithm {
+            HashAlgorithm::Crc32 => !bytes.fold(!0u32, |mut h, b| {
+                h ^= b as u32;
+                for _ in 0..8 {
+                    h = if h & 1 != 0 { (h >> 1) ^ CRC32_POLY } else { h >> 1 };
+                }
+                h
+            }),
+            HashAlgorithm::Fnv1a => bytes.fold(FNV_OFFSET, |h, b| (h ^ b as u32).wrapping_mul(FNV_PRIME)),
+            HashAlgorithm::Djb2 => bytes.fold(DJB2_INIT, |h, b| h.wrapping_mul(33).wrapping_add(b as u32)),
+            HashAlgorithm::SipHash => siphash24(self.key, &bytes.collect::<Vec<u8>>()) as u32,
+        }
+    }
+
+    /// Emits the hash routine at `label`:
+    /// `rcx` = string, `rdx` = char stride (1 or 2), `r8` = max chars → `eax`.
+    /// Only the low byte of each char is hashed; a NUL ends the string.
+    /// Clobbers `rcx`, `rdx`, `r8`-`r11`.
+    pub fn emit(&self, a: &mut CodeAssembler, label: &mut CodeLabel) -> Result<(), IcedError> {
+        if self.algorithm == HashAlgorithm::SipHash {
+            return self.emit_siphash(a, label);
+        }
+        let mut next = a.create_label();
+        let mut lower = a.create_label();
+        let mut done = a.create_label();
+        a.set_label(label)?;
+        a.mov(
+            eax,
+            match self.algorithm {
+                HashAlgorithm::Crc32 => !0,
+                HashAlgorithm::Djb2 => DJB2_INIT,
+                _ => FNV_OFFSET,
+            },
+        )?;
+        a.set_label(&mut next)?;
+        load_lowercase_char(a, rcx, &mut done, &mut lower)?;
+        match self.algorithm {
+            HashAlgorithm::Crc32 => {
+                let mut bit = a.create_label();
+                let mut no_xor = a.create_label();
+                a.xor(eax, r9d)?;
+                a.mov(r10d, 8)?;
+                a.set_label(&mut bit)?;
+                a.shr(eax, 1)?;
+                a.jae(no_xor)?;
+                a.xor(eax, CRC32_POLY)?;
+                a.set_label(&mut no_xor)?;
+                a.dec(r10d)?;
+                a.jnz(bit)?;
+            }
+            HashAlgorithm::Djb2 => {
+                a.imul_3(eax, eax, 33)?;
+                a.add(eax, r9d)?;
+            }
+            _ => {
+                a.xor(eax, r9d)?;
+                a.imul_3(eax, eax, FNV_PRIME as i32)?;
+            }
+        }
+        a.add(rcx, rdx)?;
+        a.dec(r8)?;
+        a.jmp(next)?;
+        a.set_label(&mut done)?;
+        if self.algorithm == HashAlgorithm::Crc32 {
+            a.not(eax)?;
+        }
+        a.ret()
+    }
+
+    /// SipHash-2-4: v0-v3 in r12-r15, pending message bytes in rbx, length in edi.
+    fn emit_siphash(&self, a: &mut CodeAssembler, label: &mut CodeLabel) -> Result<(), IcedError> {
+        let mut next = a.create_label();
+        let mut lower = a.create_label();
+        let mut done = a.create_label();
+        let mut no_block = a.create_label();
+        let v = [r12, r13, r14, r15];
+        let init = siphash_init(self.key);
+
+        a.set_label(label)?;
+        for reg in [rbx, rsi, rdi, r12, r13, r14, r15] {
+            a.push(reg)?;
+        }
+        for (reg, value) in v.iter().zip(init) {
+            a.mov(*reg, value)?;
+        }
+        a.mov(rsi, rcx)?;
+        a.xor(ebx, ebx)?;
+        a.xor(edi, edi)?;
+        a.set_label(&mut next)?;
+        load_lowercase_char(a, rsi, &mut done, &mut lower)?;
+        a.mov(ecx, edi)?;
+        a.and(ecx, 7)?;
+        a.shl(ecx, 3)?;
+        a.shl(r9, cl)?;
+        a.or(rbx, r9)?;
+        a.inc(edi)?;
+        a.test(edi, 7)?;
+        a.jnz(no_block)?;
+        a.xor(r15, rbx)?;
+        emit_sip_rounds(a, 2)?;
+        a.xor(r12, rbx)?;
+        a.xor(ebx, ebx)?;
+        a.set_label(&mut no_block)?;
+        a.add(rsi, rdx)?;
+        a.dec(r8)?;
+        a.jmp(next)?;
+
+        a.set_label(&mut done)?;
+        a.mov(eax, edi)?;
+        a.shl(rax, 56)?;
+        a.or(rbx, rax)?;
+        a.xor(r15, rbx)?;
+        emit_sip_rounds(a, 2)?;
+        a.xor(r12, rbx)?;
+        a.xor(r14, 0xff)?;
+        emit_sip_rounds(a, 4)?;
+        a.mov(rax, r12)?;
+        a.xor(rax, r13)?;
+        a.xor(rax, r14)?;
+        a.xor(rax, r15)?;
+        for reg in [r15, r14, r13, r12, rdi, rsi, rbx] {
+            a.pop(reg)?;
+        }
+        a.ret()
+    }
+}
+
+/// Loads the next char of the string at `src` into `r9d`, lowercased; jumps
+/// to `done` at the end of the string (`r8` exhausted or NUL).
+fn load_lowercase_char(
+    a: &mut CodeAssembler,
+    src: AsmRegister64,
+    done: &mut CodeLabel,
+    lower: &mut CodeLabel,
+) -> Result<(), IcedError> {
+    a.test(r8, r8)?;
+    a.jz(*done)?;
+    a.movzx(r9d, byte_ptr(src))?;
+    a.test(r9d, r9d)?;
+    a.jz(*done)?;
+    a.lea(r10d, ptr(r9 - 0x41))?;
+    a.cmp(r10d, 25)?;
+    a.ja(*lower)?;
+    a.or(r9d, 0x20)?;
+    a.set_label(lower)
+}
+
+fn emit_sip_rounds(a: &mut CodeAssembler, rounds: usize) -> Result<(), IcedError> {
+    let [v0, v1, v2, v3] = [r12, r13, r14, r15];
+    for _ in 0..rounds {
+        a.add(v0, v1)?;
+        a.rol(v1, 13)?;
+        a.xor(v1, v0)?;
+        a.rol(v0, 32)?;
+        a.add(v2, v3)?;
+        a.rol(v3, 16)?;
+        a.xor(v3, v2)?;
+        a.add(v0, v3)?;
+        a.rol(v3, 21)?;
+        a.xor(v3, v0)?;
+        a.add(v2, v1)?;
+        a.rol(v1, 17)?;
+        a.xor(v1, v2)?;
+        a.rol(v2, 32)?;
+    }
+    Ok(())
+}
+
+fn siphash_init(key: [u64; 2]) -> [u64; 4] {
+    [
+        key[0] ^ 0x736f_6d65_7073_6575,
+        key[1] ^ 0x646f_7261_6e64_6f6d,
+        key[0] ^ 0x6c79_6765_6e65_7261,
+        key[1] ^ 0x7465_6462_7974_6573,
+    ]
+}
+
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+pub fn siphash24(key: [u64; 2], data: &[u8]) -> u64 {
+    let mut v = siphash_init(key);
+    let compress = |v: &mut [u64; 4], m: u64, rounds: usize| {
+        v[3] ^= m;
+        for _ in 0..rounds {
+            sip_round(v);
+        }
+        v[0] ^= m;
+    };
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        compress(&mut v, u64::from_le_bytes(chunk.try_into().unwrap()), 2);
+    }
+    let mut last = (data.len() as u64) << 56;
+    for (i, b) in chunks.remainder().iter().enumerate() {
+        last |= (*b as u64) << (8 * i);
+    }
+    compress(&mut v, last, 2);
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALGORITHMS: [HashAlgorithm; 4] =
+        [HashAlgorithm::Crc32, HashAlgorithm::Fnv1a, HashAlgorithm::Djb2, HashAlgorithm::SipHash];
+    /// The key of the SipHash paper's test vectors (bytes 00..0f).
+    const REFERENCE_KEY: [u64; 2] = [0x0706_0504_0302_0100, 0x0f0e_0d0c_0b0a_0908];
+
+    #[test]
+    fn hashes_match_fixed_vectors() {
+        let vectors = [
+            (HashAlgorithm::Crc32, "123456789", 0xcbf4_3926),
+            (HashAlgorithm::Crc32, "KERNEL32.DLL", 0x6ae6_9f02),
+            (HashAlgorithm::Fnv1a, "a", 0xe40c_292c),
+            (HashAlgorithm::Fnv1a, "foobar", 0xbf9c_f968),
+            (HashAlgorithm::Fnv1a, "LoadLibraryA", 0x4dbc_712f),
+            (HashAlgorithm::Djb2, "a", 0x0002_b606),
+            (HashAlgorithm::Djb2, "kernel32.dll", 0x7040_ee75),
+            (HashAlgorithm::SipHash, "", 0xdd0e_0e31),
+        ];
+        for (algorithm, name, expected) in vectors {
+            let hash = NameHasher::new(algorithm, REFERENCE_KEY).hash(name);
+            assert_eq!(hash, expected, "{} of {:?}", algorithm, name);
+        }
+    }
+
+    #[test]
+    fn siphash_matches_reference_vectors() {
+        // SipHash-2-4 paper, appendix A, and the reference implementation's vectors.h
+        let vectors = [
+            (0, 0x726f_db47_dd0e_0e31),
+            (1, 0x74f8_39c5_93dc_67fd),
+            (2, 0x0d6c_8009_d9a9_4f5a),
+            (3, 0x8567_6696_d7fb_7e2d),
+            (7, 0xab02_00f5_8b01_d137),
+            (8, 0x93f5_f579_9a93_2462),
+            (15, 0xa129_ca61_49be_45e5),
+            (63, 0x958a_324c_eb06_4572),
+        ];
+        for (len, expected) in vectors {
+            let message: Vec<u8> = (0..len).collect();
+            assert_eq!(siphash24(REFERENCE_KEY, &message), expected, "message of {} bytes", len);
+        }
+    }
+
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    #[test]
+    fn emitted_routines_agree_with_rust() {
+        let names = [
+            "",
+            "a",
+            "kernel32.dll",
+            "KERNEL32.DLL",
+            "GetProcAddress",
+            "VirtualProtect",
+            // around the A-Z range the routine lowercases
+            "x@[`{Z",
+            "Name_Longer_Than_Two_SipHash_Blocks.dll",
+        ];
+        for algorithm in ALGORITHMS {
+            for key in [REFERENCE_KEY, [0x1234_5678_9abc_def0, 0x0fed_cba9_8765_4321]] {
+                let hasher = NameHasher::new(algorithm, key);
+                let routine = native::Routine::new(&hasher);
+                for name in names {
+                    let narrow: Vec<u8> = name.bytes().chain([0]).collect();
+                    let wide: Vec<u8> = name.bytes().flat_map(|b| [b, 0]).chain([0, 0]).collect();
+                    let expected = hasher.hash(name);
+                    assert_eq!(routine.call(&narrow, 1, u64::MAX), expected, "{} of {:?}", algorithm, name);
+                    assert_eq!(routine.call(&wide, 2, u64::MAX), expected, "{} of wide {:?}", algorithm, name);
+                    // a length limit ends the string like the loader's counted UNICODE_STRING
+                    let prefix = &name[..name.len() / 2];
+                    assert_eq!(routine.call(&wide, 2, prefix.len() as u64), hasher.hash(prefix));
+                }
+            }
+        }
+    }
+
+    /// Runs the routine emitted by `NameHasher::emit` on the host CPU.
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    mod native {
+        use super::*;
+
+        type Entry = extern "sysv64" fn(*const u8, u64, u64) -> u32;
+
+        pub struct Routine {
+            memory: *mut libc::c_void,
+            len: usize,
+        }
+
+        impl Routine {
+            pub fn new(hasher: &NameHasher) -> Self {
+                let len = 0x4000;
+                // SAFETY: a fresh anonymous mapping owned by this value
+                let memory = unsafe {
+                    libc::mmap(
+                        std::ptr::null_mut(),
+                        len,
+                        libc::PROT_READ | libc::PROT_WRITE,
+                        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                        -1,
+                        0,
+                    )
+                };
+                assert_ne!(memory, libc::MAP_FAILED);
+
+                // System V arguments (rdi, rsi, rdx) to the routine's (rcx, rdx, r8)
+                let mut a = CodeAssembler::new(64).unwrap();
+                let mut hash = a.create_label();
+                a.mov(rcx, rdi).unwrap();
+                a.mov(r8, rdx).unwrap();
+                a.mov(rdx, rsi).unwrap();
+                a.call(hash).unwrap();
+                a.ret().unwrap();
+                hasher.emit(&mut a, &mut hash).unwrap();
+                let code = a.assemble(memory as u64).unwrap();
+                assert!(code.len() <= len);
+                // SAFETY: `code` fits in the mapping, which is then made executable
+                unsafe {
+                    std::ptr::copy_nonoverlapping(code.as_ptr(), memory.cast(), code.len());
+                    assert_eq!(libc::mprotect(memory, len, libc::PROT_READ | libc::PROT_EXEC), 0);
+                }
+                Self { memory, len }
+            }
+
+            pub fn call(&self, string: &[u8], stride: u64, max_chars: u64) -> u32 {
+                // SAFETY: the mapping starts with the wrapper above, which follows
+                // the System V convention and only reads the NUL-terminated `string`
+                let entry: Entry = unsafe { std::mem::transmute(self.memory) };
+                entry(string.as_ptr(), stride, max_chars)
+            }
+        }
+
+        impl Drop for Routine {
+            fn drop(&mut self) {
+                // SAFETY: unmaps the mapping created in `new`
+                unsafe { libc::munmap(self.memory, self.len) };
+            }
+        }
+    }
+}
@@ -0,0 +1,1188 @@
+//! Mutable copy of the input PE (or x86-64 ELF) that the protection steps edit in place.
+//!
+//! `ParseStep` loads it into `PipelineContext::image`; `WriteOutputStep`
+//! finalizes it (moved functions, rewritten base relocations, entry thunk for the startup routines,
+//! integrity hashes, packing, checksum) and writes it. For ELF files the loadable segments stand in for
+//! sections and the PE-only parts (data directories, checksum) are absent;
+//! see `elf`.
+
+use goblin::pe::section_table::{SectionTable, IMAGE_SCN_CNT_INITIALIZED_DATA, IMAGE_SCN_MEM_DISCARDABLE};
+use iced_x86::code_asm::*;
+
+use crate::pipeline::code::CodeMap;
+use crate::pipeline::elf::{self, ElfLayout};
+use crate::pipeline::error::StepError;
+use crate::pipeline::integrity::{self, IntegrityCheck, INTEGRITY_SECTION};
+use crate::pipeline::pack;
+use crate::pipeline::profile::StartupHook;
+use crate::pipeline::reassemble;
+use crate::pipeline::reloc::{Relocations, IMAGE_REL_BASED_DIR64};
+use crate::pipeline::pe::{self, read_u16, read_u32, ResourceLeaf};
+
+pub const IMAGE_SCN_CNT_CODE: u32 = 0x0000_0020;
+pub const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+pub const IMAGE_SCN_MEM_READ: u32 = 0x4000_0000;
+pub const IMAGE_SCN_MEM_WRITE: u32 = 0x8000_0000;
+
+/// Characteristics of the sections holding injected stubs (code plus their own mutable data).
+pub const STUB_SECTION: u32 =
+    IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE;
+
+pub const DIR_EXPORT: usize = 0;
+pub const DIR_IMPORT: usize = 1;
+pub const DIR_RESOURCE: usize = 2;
+pub const DIR_EXCEPTION: usize = 3;
+pub const DIR_SECURITY: usize = 4;
+pub const DIR_BASERELOC: usize = 5;
+pub const DIR_DEBUG: usize = 6;
+pub const DIR_TLS: usize = 9;
+pub const DIR_LOAD_CONFIG: usize = 10;
+pub const DIR_BOUND_IMPORT: usize = 11;
+pub const DIR_IAT: usize = 12;
+pub const DIR_COM_DESCRIPTOR: usize = 14;
+
+/// New relocation table, when the rewritten one outgrows the original.
+pub const RELOC_SECTION: &str = ".obsr";
+
+const IMAGE_FILE_DLL: u16 = 0x2000;
+const TLS_DIRECTORY_SIZE: u32 = 40;
+/// Size of an `IMAGE_DEBUG_DIRECTORY` entry.
+pub const DEBUG_ENTRY_SIZE: u32 = 28;
+const SECTION_HEADER_SIZE: usize = 40;
+
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub virtual_address: u32,
+    pub virtual_size: u32,
+    pub raw_offset: u32,
+    pub raw_size: u32,
+    pub characteristics: u32,
+}
+
+impl Section {
+    fn contains_rva(&self, rva: u32) -> bool {
+        pe::range_contains(self.virtual_address, self.virtual_size.max(self.raw_size), rva)
+    }
+}
+
+/// When a startup routine runs relative to the others; routines run in phase order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StartupPhase {
+    /// Looks for a debugger before anything else runs (`AntiDebugStep`).
+    AntiDebug,
+    /// Compares the code with the hashes computed by `finalize` (`IntegrityStep`).
+    Integrity,
+    /// Fills the IAT slots hidden by `ProtectImportsStep`.
+    Imports,
+    /// Decrypts the function bodies encrypted by `EncryptCodeStep`.
+    DecryptCode,
+    /// Decodes the resources of `ProtectResourcesStep`, or hooks `LoadResource` to decode them when loaded.
+    Resources,
+}
+
+/// A function injected by a step that has to run once before the original entry point.
+///
+/// Routines take no arguments, return nothing and preserve the registers
+/// both the Win64 and the System V conventions make non-volatile.
+#[derive(Debug, Clone)]
+pub struct StartupRoutine {
+    pub phase: StartupPhase,
+    pub name: String,
+    pub rva: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Pe,
+    Elf,
+}
+
+pub struct Image {
+    bytes: Vec<u8>,
+    pub format: ImageFormat,
+    pub is_64: bool,
+    pub is_dll: bool,
+    pub image_base: u64,
+    pub entry: u32,
+    pub sections: Vec<Section>,
+    pe_offset: usize,
+    optional_offset: usize,
+    section_alignment: u32,
+    file_alignment: u32,
+    startup: Vec<StartupRoutine>,
+    /// What calls the startup routines.
+    startup_hook: StartupHook,
+    elf: Option<ElfLayout>,
+    /// Words the loader patches when rebasing; rewritten by `finalize` once changed.
+    relocations: Relocations,
+    /// Functions lifted by `code_map`; the modified ones are moved when writing.
+    code: Option<CodeMap>,
+    /// Hashed by `finalize` once everything else is written.
+    integrity: Option<IntegrityCheck>,
+    /// Ranges of non-writable sections the stubs rewrite while the program runs.
+    runtime_writes: Vec<(u32, u32)>,
+    /// Functions `reassemble` moved, as `(original rva, new rva, new size)`.
+    moved: Vec<(u32, u32, u32)>,
+    /// Compress the sections into one payload when finalizing (`PackStep`).
+    pack: bool,
+    /// Side effects the user should know about (dropped signature, cleared bound imports...).
+    pub notes: Vec<String>,
+}
+
+impl Image {
+    /// Reads the headers of a PE or ELF file that goblin already accepted.
+    pub fn parse(bytes: Vec<u8>) -> Result<Self, StepError> {
+        if bytes.starts_with(b"\x7fELF") {
+            return Self::parse_elf(bytes);
+        }
+        let invalid = |what: &str| StepError::InvalidInput(format!("Malformed PE headers: {}", what));
+
+        let pe_offset = read_u32(&bytes, 0x3c).ok_or_else(|| invalid("truncated DOS header"))? as usize;
+        if bytes.get(pe_offset..pe_offset + 4) != Some(b"PE\0\0") {
+            return Err(invalid("missing PE signature"));
+        }
+        let coff = pe_offset + 4;
+        let characteristics = read_u16(&bytes, coff + 18).ok_or_else(|| invalid("truncated COFF header"))?;
+        let optional_offset = coff + 20;
+        let is_64 = match read_u16(&bytes, optional_offset) {
+            Some(0x20b) => true,
+            Some(0x10b) => false,
+            _ => return Err(invalid("unknown optional header magic")),
+        };
+
+        let mut image = Image {
+            format: ImageFormat::Pe,
+            is_64,
+            is_dll: characteristics & IMAGE_FILE_DLL != 0,
+            image_base: 0,
+            entry: read_u32(&bytes, optional_offset + 16).ok_or_else(|| invalid("truncated optional header"))?,
+            sections: Vec::new(),
+            pe_offset,
+            optional_offset,
+            section_alignment: read_u32(&bytes, optional_offset + 32).unwrap_or(0x1000),
+            file_alignment: read_u32(&bytes, optional_offset + 36).unwrap_or(0x200),
+            startup: Vec::new(),
+            startup_hook: StartupHook::default(),
+            elf: None,
+            relocations: Relocations::default(),
+            code: None,
+            integrity: None,
+            runtime_writes: Vec::new(),
+            moved: Vec::new(),
+            pack: false,
+            notes: Vec::new(),
+            bytes,
+        };
+        image.image_base = if is_64 {
+            image.read_u64_at(optional_offset + 24)
+        } else {
+            read_u32(&image.bytes, optional_offset + 28).map(u64::from)
+        }
+        .ok_or_else(|| invalid("truncated optional header"))?;
+        image.sections = image.read_section_table().ok_or_else(|| invalid("truncated section table"))?;
+        let (table, size) = image.data_directory(DIR_BASERELOC);
+        image.relocations = Relocations::parse_blocks(image.read(table, size as usize).unwrap_or_default());
+        Ok(image)
+    }
+
+    fn parse_elf(bytes: Vec<u8>) -> Result<Self, StepError> {
+        let layout = ElfLayout::parse(&bytes)?;
+        Ok(Image {
+            format: ImageFormat::Elf,
+            is_64: true,
+            is_dll: layout.is_shared_library,
+            image_base: layout.base,
+            entry: layout.entry,
+            sections: layout.sections.clone(),
+            pe_offset: 0,
+            optional_offset: 0,
+            section_alignment: elf::PAGE_SIZE,
+            file_alignment: elf::PAGE_SIZE,
+            startup: Vec::new(),
+            startup_hook: StartupHook::default(),
+            relocations: Relocations::from_rvas(&layout.relocations, IMAGE_REL_BASED_DIR64),
+            elf: Some(layout),
+            code: None,
+            integrity: None,
+            runtime_writes: Vec::new(),
+            moved: Vec::new(),
+            pack: false,
+            notes: Vec::new(),
+            bytes,
+        })
+    }
+
+    fn section_count(&self) -> usize {
+        read_u16(&self.bytes, self.pe_offset + 6).unwrap_or(0) as usize
+    }
+
+    fn section_table_offset(&self) -> usize {
+        let optional_size = read_u16(&self.bytes, self.pe_offset + 20).unwrap_or(0) as usize;
+        self.optional_offset + optional_size
+    }
+
+    fn read_section_table(&self) -> Option<Vec<Section>> {
+        let table = self.section_table_offset();
+        (0..self.section_count())
+            .map(|i| {
+                let at = table + i * SECTION_HEADER_SIZE;
+                Some(Section {
+                    virtual_size: read_u32(&self.bytes, at + 8)?,
+                    virtual_address: read_u32(&self.bytes, at + 12)?,
+                    raw_size: read_u32(&self.bytes, at + 16)?,
+                    raw_offset: read_u32(&self.bytes, at + 20)?,
+                    characteristics: read_u32(&self.bytes, at + 36)?,
+                })
+            })
+            .collect()
+    }
+
+    fn read_u64_at(&self, at: usize) -> Option<u64> {
+        self.bytes
+            .get(at..at + 8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn write_u16_at(&mut self, at: usize, value: u16) {
+        self.bytes[at..at + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn write_u32_at(&mut self, at: usize, value: u32) {
+        self.bytes[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Section containing `rva`, if any.
+    pub fn section_at(&self, rva: u32) -> Option<&Section> {
+        self.sections.iter().find(|s| s.contains_rva(rva))
+    }
+
+    /// Whether `rva` lies in an executable section.
+    pub fn is_executable(&self, rva: u32) -> bool {
+        self.section_at(rva)
+            .is_some_and(|s| s.characteristics & IMAGE_SCN_MEM_EXECUTE != 0)
+    }
+
+    /// The file as it stands, headers and data outside the sections included.
+    pub fn file(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Overwrites file bytes at `offset`, for data no section maps (headers,
+    /// symbol tables, debug records).
+    pub fn write_file(&mut self, offset: usize, data: &[u8]) -> Result<(), StepError> {
+        let target = self.bytes.get_mut(offset..offset + data.len()).ok_or_else(|| {
+            StepError::Internal(format!("File offset 0x{:x} (+{} bytes) is past the end", offset, data.len()))
+        })?;
+        target.copy_from_slice(data);
+        Ok(())
+    }
+
+    /// RVA of the byte at file offset `offset`, if a section maps it.
+    pub fn offset_to_rva(&self, offset: usize) -> Option<u32> {
+        self.sections
+            .iter()
+            .find(|s| {
+                let end = s.raw_offset.checked_add(s.raw_size).map_or(0, |end| end as usize);
+                offset >= s.raw_offset as usize && offset < end
+            })
+            .and_then(|s| s.virtual_address.checked_add((offset - s.raw_offset as usize) as u32))
+    }
+
+    pub fn rva_to_offset(&self, rva: u32) -> Option<usize> {
+        let sec = self.section_at(rva)?;
+        let delta = rva - sec.virtual_address;
+        sec.raw_offset.checked_add(delta).filter(|_| delta < sec.raw_size).map(|offset| offset as usize)
+    }
+
+    /// `len` bytes of file data at `rva`; `None` if they are not all backed by the file.
+    pub fn read(&self, rva: u32, len: usize) -> Option<&[u8]> {
+        let at = self.file_range(rva, len)?;
+        Some(&self.bytes[at..at + len])
+    }
+
+    fn file_range(&self, rva: u32, len: usize) -> Option<usize> {
+        let sec = self.section_at(rva)?;
+        let delta = (rva - sec.virtual_address) as usize;
+        let at = sec.raw_offset as usize + delta;
+        (delta + len <= sec.raw_size as usize && at + len <= self.bytes.len()).then_some(at)
+    }
+
+    pub fn read_u32(&self, rva: u32) -> Option<u32> {
+        self.read(rva, 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&self, rva: u32) -> Option<u64> {
+        self.read(rva, 8).map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    /// NUL terminated ASCII string at `rva` (at most 512 bytes).
+    pub fn read_c_string(&self, rva: u32) -> Option<String> {
+        let at = self.rva_to_offset(rva)?;
+        let tail = &self.bytes[at..self.bytes.len().min(at + 512)];
+        let len = tail.iter().position(|&b| b == 0)?;
+        Some(String::from_utf8_lossy(&tail[..len]).into_owned())
+    }
+
+    pub fn write(&mut self, rva: u32, data: &[u8]) -> Result<(), StepError> {
+        let at = self.file_range(rva, data.len()).ok_or_else(|| {
+            StepError::Internal(format!(
+                "RVA 0x{:x} (+{} bytes) is not backed by file data",
+                rva,
+                data.len()
+            ))
+        })?;
+        self.bytes[at..at + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn data_directory_offset(&self, index: usize) -> usize {
+        self.optional_offset + if self.is_64 { 112 } else { 96 } + index * 8
+    }
+
+    /// RVA and size of a data directory entry (`(0, 0)` when absent).
+    pub fn data_directory(&self, index: usize) -> (u32, u32) {
+        if self.elf.is_some() {
+            return (0, 0);
+        }
+        let count = read_u32(&self.bytes, self.optional_offset + if self.is_64 { 108 } else { 92 }).unwrap_or(0);
+        if index >= count as usize {
+            return (0, 0);
+        }
+        let at = self.data_directory_offset(index);
+        (
+            read_u32(&self.bytes, at).unwrap_or(0),
+            read_u32(&self.bytes, at + 4).unwrap_or(0),
+        )
+    }
+
+    pub fn set_data_directory(&mut self, index: usize, rva: u32, size: u32) {
+        if self.elf.is_some() {
+            return;
+        }
+        let at = self.data_directory_offset(index);
+        self.write_u32_at(at, rva);
+        self.write_u32_at(at + 4, size);
+    }
+
+    /// Named exports as `(rva, name)`; forwarders are left out.
+    pub fn exports(&self) -> Vec<(u32, String)> {
+        if let Some(layout) = &self.elf {
+            return layout.exports.clone();
+        }
+        let (dir, dir_size) = self.data_directory(DIR_EXPORT);
+        let field = |at: u32| dir.checked_add(at).and_then(|rva| self.read_u32(rva)).unwrap_or(0);
+        if dir == 0 {
+            return Vec::new();
+        }
+        let (functions, names, ordinals) = (field(0x1c), field(0x20), field(0x24));
+        (0..field(0x18).min(0x10000))
+            .filter_map(|i| {
+                let name = self.read_c_string(self.read_u32(names.checked_add(i * 4)?)?)?;
+                let ordinal = self.read(ordinals.checked_add(i * 2)?, 2).map(|b| u16::from_le_bytes([b[0], b[1]]))?;
+                let rva = self.read_u32(functions.checked_add(ordinal as u32 * 4)?)?;
+                let forwarder = pe::range_contains(dir, dir_size, rva);
+                (!forwarder && rva != 0).then_some((rva, name))
+            })
+            .collect()
+    }
+
+    /// RVAs patched by the loader when the image is rebased (`.reloc`), sorted.
+    pub fn base_relocations(&self) -> Vec<u32> {
+        self.relocations.rvas()
+    }
+
+    /// Whether the loader patches any byte of `rva..rva + len` when it rebases the image.
+    pub fn is_relocated(&self, rva: u32, len: u32) -> bool {
+        self.relocations.overlaps(rva, len)
+    }
+
+    /// Whether relocations can move with the code: `finalize` rewrites the
+    /// PE relocation directory (unless it has `HIGHADJ` entries), the ELF
+    /// dynamic relocations stay as they are.
+    pub fn can_move_relocations(&self) -> bool {
+        self.elf.is_none() && !self.relocations.has_high_adjust()
+    }
+
+    /// Relocations starting in `start..end` as `(rva, IMAGE_REL_BASED_* type)`.
+    pub fn relocations_in(&self, start: u32, end: u32) -> Vec<(u32, u16)> {
+        self.relocations.in_range(start, end)
+    }
+
+    /// Drops the relocations starting in `start..end` and returns them.
+    pub fn remove_relocations(&mut self, start: u32, end: u32) -> Vec<(u32, u16)> {
+        self.relocations.remove(start, end)
+    }
+
+    pub fn add_relocation(&mut self, rva: u32, kind: u16) {
+        self.relocations.insert(rva, kind);
+    }
+
+    /// RVA and size of the data the ELF loader makes read-only once it is
+    /// relocated; PE images have none.
+    pub fn relro(&self) -> Option<(u32, u32)> {
+        self.elf.as_ref().and_then(|layout| layout.relro)
+    }
+
+    /// RVA and size of the headers, when they are mapped.
+    pub fn headers(&self) -> Option<(u32, u32)> {
+        match &self.elf {
+            Some(layout) => layout.headers,
+            None => read_u32(&self.bytes, self.optional_offset + 60).map(|size| (0, size)),
+        }
+    }
+
+    /// Leaves of the resource tree (PE).
+    pub fn resources(&self) -> Vec<ResourceLeaf> {
+        let (rva, size) = self.data_directory(DIR_RESOURCE);
+        if self.format != ImageFormat::Pe || rva == 0 || size == 0 {
+            return Vec::new();
+        }
+        let sections: Vec<SectionTable> = self
+            .sections
+            .iter()
+            .map(|s| SectionTable {
+                virtual_address: s.virtual_address,
+                virtual_size: s.virtual_size,
+                pointer_to_raw_data: s.raw_offset,
+                size_of_raw_data: s.raw_size,
+                ..Default::default()
+            })
+            .collect();
+        pe::resource_leaves(&self.bytes, &sections, rva)
+    }
+
+    /// Sections by name: the section table of a PE image, the section headers
+    /// of an ELF file (whose loadable segments are the `sections`).
+    pub fn named_sections(&self) -> Vec<(String, Section)> {
+        if let Some(layout) = &self.elf {
+            return layout.named_sections.clone();
+        }
+        let table = self.section_table_offset();
+        self.sections
+            .iter()
+            .enumerate()
+            .map(|(i, section)| {
+                let at = table + i * SECTION_HEADER_SIZE;
+                let name = &self.bytes[at..at + 8];
+                let end = name.iter().position(|&b| b == 0).unwrap_or(8);
+                (String::from_utf8_lossy(&name[..end]).into_owned(), section.clone())
+            })
+            .collect()
+    }
+
+    /// Records that a stub rewrites `size` bytes at `rva` while the program
+    /// runs, so the integrity check leaves them out.
+    pub fn mark_runtime_written(&mut self, rva: u32, size: u32) {
+        self.runtime_writes.push((rva, size));
+    }
+
+    pub fn runtime_writes(&self) -> &[(u32, u32)] {
+        &self.runtime_writes
+    }
+
+    /// Records that the function at `rva` now runs from `size` bytes at `new_rva`.
+    pub fn record_move(&mut self, rva: u32, new_rva: u32, size: u32) {
+        self.moved.push((rva, new_rva, size));
+    }
+
+    /// Functions moved when writing, as `(original rva, new rva, new size)`.
+    pub fn moved_functions(&self) -> &[(u32, u32, u32)] {
+        &self.moved
+    }
+
+    /// Has `finalize` hash the image for the checker of `IntegrityStep`.
+    pub fn set_integrity_check(&mut self, check: IntegrityCheck) {
+        self.integrity = Some(check);
+    }
+
+    /// Runs the startup routines from a TLS callback rather than the entry point (PE).
+    pub fn set_startup_hook(&mut self, hook: StartupHook) {
+        self.startup_hook = hook;
+    }
+
+    pub fn startup_hook(&self) -> StartupHook {
+        self.startup_hook
+    }
+
+    /// Has `finalize` pack the image once everything else is in place.
+    pub fn set_packing(&mut self) {
+        self.pack = true;
+    }
+
+    /// Whether an ELF relocation calls an IFUNC resolver while the image is
+    /// loaded, before the entry point runs.
+    pub fn has_ifunc_relocations(&self) -> bool {
+        self.elf.as_ref().is_some_and(|layout| layout.ifunc_relocations)
+    }
+
+    /// Functions named by a symbol table as `(rva, size, name)`; PE images have none.
+    pub fn symbols(&self) -> Vec<(u32, u32, String)> {
+        self.elf.as_ref().map(|layout| layout.symbols.clone()).unwrap_or_default()
+    }
+
+    /// The functions of the image, discovered and lifted on first use.
+    pub fn code_map(&mut self) -> &mut CodeMap {
+        if self.code.is_none() {
+            self.code = Some(CodeMap::build(self));
+        }
+        self.code.as_mut().unwrap()
+    }
+
+    pub fn set_entry(&mut self, rva: u32) {
+        self.entry = rva;
+        match &self.elf {
+            // e_entry
+            Some(layout) => self.bytes[24..32].copy_from_slice(&(layout.base + rva as u64).to_le_bytes()),
+            None => self.write_u32_at(self.optional_offset + 16, rva),
+        }
+    }
+
+    /// Whether `finalize` can run startup routines: x64 PE images, and ELF
+    /// executables (a shared library's entry point is never called).
+    pub fn supports_startup_routines(&self) -> bool {
+        self.is_64 && !(self.format == ImageFormat::Elf && self.is_dll)
+    }
+
+    /// Registers a routine the entry thunk calls before the original entry point.
+    pub fn add_startup_routine(&mut self, phase: StartupPhase, name: impl Into<String>, rva: u32) {
+        self.startup.push(StartupRoutine {
+            phase,
+            name: name.into(),
+            rva,
+        });
+    }
+
+    pub fn startup_routines(&self) -> &[StartupRoutine] {
+        &self.startup
+    }
+
+    /// RVA a section added now would get.
+    pub fn next_section_rva(&self) -> u32 {
+        let end = self
+            .sections
+            .iter()
+            .map(|s| s.virtual_address + s.virtual_size.max(s.raw_size))
+            .max()
+            .unwrap_or(self.section_alignment);
+        align_up(end, self.section_alignment)
+    }
+
+    /// Appends a section with `data` and returns its RVA.
+    ///
+    /// The header goes into the slack after the section table and the data
+    /// right after the last section; an overlay, if any, is moved behind it.
+    /// The Authenticode signature is dropped since it no longer matches.
+    pub fn add_section(&mut self, name: &str, data: &[u8], characteristics: u32) -> Result<u32, StepError> {
+        if self.elf.is_some() {
+            return self.add_segment(name, data, characteristics);
+        }
+        self.drop_certificate();
+
+        let header_at = self.section_table_offset() + self.section_count() * SECTION_HEADER_SIZE;
+        let size_of_headers = read_u32(&self.bytes, self.optional_offset + 60).unwrap_or(0) as usize;
+        let first_raw = self
+            .sections
+            .iter()
+            .filter(|s| s.raw_size > 0)
+            .map(|s| s.raw_offset as usize)
+            .min()
+            .unwrap_or(size_of_headers);
+        if header_at + SECTION_HEADER_SIZE > size_of_headers.min(first_raw) {
+            return Err(StepError::UnsupportedFormat(format!(
+                "No room for another section header (headers end at 0x{:x})",
+                size_of_headers
+            )));
+        }
+        let (bound_rva, bound_size) = self.data_directory(DIR_BOUND_IMPORT);
+        if bound_size > 0 && (bound_rva as usize) < header_at + SECTION_HEADER_SIZE + 8 {
+            self.set_data_directory(DIR_BOUND_IMPORT, 0, 0);
+            self.notes.push("Bound import directory removed to make room for a section header".into());
+        }
+
+        let rva = self.next_section_rva();
+        let raw_offset = align_up(
+            self.sections
+                .iter()
+                .map(|s| s.raw_offset + s.raw_size)
+                .max()
+                .unwrap_or(size_of_headers as u32),
+            self.file_alignment,
+        ) as usize;
+        let raw_size = align_up(data.len() as u32, self.file_alignment) as usize;
+
+        let overlay = self.bytes.split_off(raw_offset.min(self.bytes.len()));
+        self.bytes.resize(raw_offset, 0);
+        self.bytes.extend_from_slice(data);
+        self.bytes.resize(raw_offset + raw_size, 0);
+        self.bytes.extend_from_slice(&overlay);
+
+        let mut header = [0u8; SECTION_HEADER_SIZE];
+        let name_bytes = name.as_bytes();
+        header[..name_bytes.len().min(8)].copy_from_slice(&name_bytes[..name_bytes.len().min(8)]);
+        header[8..12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&rva.to_le_bytes());
+        header[16..20].copy_from_slice(&(raw_size as u32).to_le_bytes());
+        header[20..24].copy_from_slice(&(raw_offset as u32).to_le_bytes());
+        header[36..40].copy_from_slice(&characteristics.to_le_bytes());
+        self.bytes[header_at..header_at + SECTION_HEADER_SIZE].copy_from_slice(&header);
+
+        let count = self.section_count() as u16 + 1;
+        self.write_u16_at(self.pe_offset + 6, count);
+        let size_of_image = align_up(rva + data.len() as u32, self.section_alignment);
+        self.write_u32_at(self.optional_offset + 56, size_of_image);
+
+        self.sections.push(Section {
+            virtual_address: rva,
+            virtual_size: data.len() as u32,
+            raw_offset: raw_offset as u32,
+            raw_size: raw_size as u32,
+            characteristics,
+        });
+        Ok(rva)
+    }
+
+    /// ELF counterpart of `add_section`: the data is appended to the file and
+    /// mapped by a new loadable segment (ELF segments have no names).
+    fn add_segment(&mut self, name: &str, data: &[u8], characteristics: u32) -> Result<u32, StepError> {
+        let rva = self.next_section_rva();
+        let offset = align_up(self.bytes.len() as u32, elf::PAGE_SIZE);
+        let layout = self.elf.as_mut().unwrap();
+        self.bytes.resize(offset as usize, 0);
+        self.bytes.extend_from_slice(data);
+        layout.add_segment(&mut self.bytes, offset, rva, data.len() as u32, characteristics)?;
+        self.notes.push(format!(
+            "{} mapped by a program header that held a PT_NOTE segment",
+            name
+        ));
+        self.sections.push(Section {
+            virtual_address: rva,
+            virtual_size: data.len() as u32,
+            raw_offset: offset,
+            raw_size: data.len() as u32,
+            characteristics,
+        });
+        Ok(rva)
+    }
+
+    /// Drops the file data of section `index`, which the loader then maps as
+    /// zeros. ELF segments keep their file range, zero-filled: kernels before
+    /// 6.7 do not map a segment without file data unless it is the last one.
+    ///
+    /// On PE, the debug directory is removed when it or the data of one of
+    /// its entries was in the section, and so is the COFF symbol table; file
+    /// offsets past the removed data are moved down.
+    pub fn strip_section_data(&mut self, index: usize) {
+        let Section { virtual_address, raw_offset, raw_size, .. } = self.sections[index];
+        // a section whose raw data runs past the end of the file keeps only what is there
+        let offset = (raw_offset as usize).min(self.bytes.len());
+        let size = (raw_size as usize).min(self.bytes.len() - offset);
+        if self.elf.is_some() {
+            self.bytes[offset..offset + size].fill(0);
+            return;
+        }
+        let removed = offset..offset + size;
+        let (debug, debug_size) = self.data_directory(DIR_DEBUG);
+        let debug_entries: Vec<(u32, u32)> = (0..debug_size / DEBUG_ENTRY_SIZE)
+            .filter_map(|i| {
+                let entry = debug.checked_add(i * DEBUG_ENTRY_SIZE)?;
+                Some((entry, self.read_u32(entry.checked_add(24)?)?))
+            })
+            .collect();
+        let debug_removed = debug_size > 0
+            && (pe::range_contains(virtual_address, raw_size, debug)
+                || debug_entries.iter().any(|&(_, data)| removed.contains(&(data as usize))));
+        let coff = self.pe_offset + 4;
+        let symbols = read_u32(&self.bytes, coff + 8).unwrap_or(0) as usize;
+
+        self.drop_certificate();
+        self.bytes.drain(removed.clone());
+        let table = self.section_table_offset();
+        for i in 0..self.sections.len() {
+            let at = table + i * SECTION_HEADER_SIZE;
+            if i == index {
+                if self.sections[i].virtual_size == 0 {
+                    self.sections[i].virtual_size = raw_size;
+                    self.write_u32_at(at + 8, raw_size);
+                }
+                self.sections[i].raw_offset = 0;
+                self.sections[i].raw_size = 0;
+                self.write_u32_at(at + 16, 0);
+                self.write_u32_at(at + 20, 0);
+            } else if self.sections[i].raw_offset as usize >= offset + size {
+                self.sections[i].raw_offset -= size as u32;
+                self.write_u32_at(at + 20, self.sections[i].raw_offset);
+            }
+        }
+
+        if debug_removed {
+            self.set_data_directory(DIR_DEBUG, 0, 0);
+        } else {
+            for (entry, data) in debug_entries.into_iter().filter(|&(_, data)| data as usize >= removed.end) {
+                if let Some(at) = self.rva_to_offset(entry + 24) {
+                    self.write_u32_at(at, data - size as u32);
+                }
+            }
+        }
+        if removed.contains(&symbols) {
+            self.write_u32_at(coff + 8, 0);
+            self.write_u32_at(coff + 12, 0);
+        } else if symbols >= removed.end && symbols > 0 {
+            self.write_u32_at(coff + 8, (symbols - size) as u32);
+        }
+    }
+
+    /// Writes the current relocations over the original table when they fit;
+    /// otherwise returns the new table for the caller to place.
+    fn write_relocations(&mut self) -> Result<Option<Vec<u8>>, StepError> {
+        if self.elf.is_some() {
+            return Err(StepError::Internal("ELF relocations cannot be rewritten".into()));
+        }
+        if self.relocations.has_high_adjust() {
+            return Err(StepError::UnsupportedFormat(
+                "the base relocations have IMAGE_REL_BASED_HIGHADJ entries, which cannot be rewritten".into(),
+            ));
+        }
+        let first_page = self.sections.iter().map(|s| s.virtual_address).min().unwrap_or(0);
+        let table = self.relocations.to_blocks(first_page);
+        let (rva, size) = self.data_directory(DIR_BASERELOC);
+        if table.len() <= size as usize && self.read(rva, size as usize).is_some() {
+            let mut data = table.clone();
+            data.resize(size as usize, 0);
+            self.write(rva, &data)?;
+            self.set_data_directory(DIR_BASERELOC, rva, table.len() as u32);
+            return Ok(None);
+        }
+        Ok(Some(table))
+    }
+
+    fn drop_certificate(&mut self) {
+        let (offset, size) = self.data_directory(DIR_SECURITY);
+        if size == 0 {
+            return;
+        }
+        self.set_data_directory(DIR_SECURITY, 0, 0);
+        if offset.checked_add(size).is_some_and(|end| end as usize == self.bytes.len()) {
+            self.bytes.truncate(offset as usize);
+        }
+        self.notes
+            .push("Authenticode signature removed; sign the protected output again".into());
+    }
+
+    /// Moves the modified functions, emits the startup thunk for the
+    /// registered routines, rewrites the base relocations if they changed,
+    /// hashes the image for the integrity check, packs it and fixes the
+    /// checksum. Call once, before `into_bytes`.
+    pub fn finalize(&mut self) -> Result<(), StepError> {
+        if let Some(code) = self.code.take() {
+            reassemble::reassemble(self, &code)?;
+        }
+        let integrity = self.integrity.take();
+        if integrity.is_some() || !self.startup.is_empty() {
+            self.emit_startup(integrity)?;
+        } else if self.relocations.is_changed() {
+            if let Some(table) = self.write_relocations()? {
+                let rva = self.add_section(
+                    RELOC_SECTION,
+                    &table,
+                    IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_DISCARDABLE | IMAGE_SCN_MEM_READ,
+                )?;
+                self.set_data_directory(DIR_BASERELOC, rva, table.len() as u32);
+                self.notes.push(format!("Relocation table rewritten to section {}", RELOC_SECTION));
+            }
+        }
+        if self.pack {
+            let note = pack::pack(self)?;
+            self.notes.push(note);
+        }
+        if self.elf.is_some() {
+            return Ok(());
+        }
+        let checksum_at = self.optional_offset + 64;
+        self.write_u32_at(checksum_at, 0);
+        let checksum = pe_checksum(&self.bytes, checksum_at);
+        self.write_u32_at(checksum_at, checksum);
+        Ok(())
+    }
+
+    /// Adds the section holding the startup thunk, the integrity checker and,
+    /// when it outgrew the original, the relocation table: ELF files have few
+    /// program headers to spare. The checker hashes the image once the thunk
+    /// is hooked in and the relocations are written.
+    fn emit_startup(&mut self, integrity: Option<IntegrityCheck>) -> Result<(), StepError> {
+        let section_rva = self.next_section_rva();
+        let mut checker_rva = None;
+        if integrity.is_some() {
+            // the thunk's size does not depend on where the routines are
+            self.add_startup_routine(StartupPhase::Integrity, "integrity check", section_rva);
+            let thunk_size = self.startup_thunk(section_rva)?.code.len() as u32;
+            let rva = section_rva + thunk_size.next_multiple_of(16);
+            for routine in self.startup.iter_mut().filter(|r| r.phase == StartupPhase::Integrity) {
+                routine.rva = rva;
+            }
+            checker_rva = Some(rva);
+        }
+        let thunk = self.startup_thunk(section_rva)?;
+        if let Some(tls) = &thunk.tls {
+            self.hook_tls_callback(tls)?;
+        }
+        let relocation_table = match self.relocations.is_changed() {
+            true => self.write_relocations()?,
+            false => None,
+        };
+
+        let mut data = thunk.code;
+        let mut characteristics = thunk.characteristics;
+        let mut name = ".obse";
+        if let (Some(check), Some(rva)) = (&integrity, checker_rva) {
+            let (code, note) = integrity::emit_checker(self, check, rva)?;
+            data.resize((rva - section_rva) as usize, 0xcc);
+            data.extend_from_slice(&code);
+            self.notes.push(note);
+            characteristics = STUB_SECTION;
+            name = INTEGRITY_SECTION;
+        }
+        let mut relocation_dir = None;
+        if let Some(table) = &relocation_table {
+            data.resize(data.len().next_multiple_of(4), 0);
+            relocation_dir = Some((section_rva + data.len() as u32, table.len() as u32));
+            data.extend_from_slice(table);
+        }
+        let rva = self.add_section(name, &data, characteristics)?;
+        if rva != section_rva {
+            return Err(StepError::Internal(format!(
+                "Startup section placed at 0x{:x} instead of 0x{:x}",
+                rva, section_rva
+            )));
+        }
+        if let Some((table, size)) = relocation_dir {
+            self.set_data_directory(DIR_BASERELOC, table, size);
+            self.notes.push(format!("Relocation table rewritten to section {}", name));
+        }
+        if thunk.tls.is_none() {
+            self.set_entry(section_rva);
+        }
+        Ok(())
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// Thunk placed at `rva` that runs each routine once: the new entry
+    /// point, or a TLS callback with `StartupHook::TlsCallback`.
+    fn startup_thunk(&mut self, rva: u32) -> Result<StartupThunk, StepError> {
+        if !self.supports_startup_routines() {
+            return Err(StepError::UnsupportedFormat(
+                "Startup routines are only generated for x64 PE images and ELF executables".into(),
+            ));
+        }
+        self.startup.sort_by_key(|r| r.phase);
+        if self.startup_hook == StartupHook::TlsCallback && self.elf.is_none() {
+            return self.tls_callback_thunk(rva);
+        }
+        let va = self.image_base + rva as u64;
+
+        let mut a = CodeAssembler::new(64)?;
+        if self.elf.is_some() {
+            // _start: rsp is 16-byte aligned and rdx holds the loader's exit handler
+            a.push(rdx)?;
+            a.sub(rsp, 8)?;
+            for routine in &self.startup {
+                a.call(self.image_base + routine.rva as u64)?;
+            }
+            a.add(rsp, 8)?;
+            a.pop(rdx)?;
+            a.jmp(self.image_base + self.entry as u64)?;
+            return Ok(StartupThunk {
+                code: a.assemble(va)?,
+                characteristics: IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE | IMAGE_SCN_MEM_READ,
+                tls: None,
+            });
+        }
+        let mut done = a.create_label();
+        let mut original = a.create_label();
+
+        // saves the entry arguments and runs the routines on DLL_PROCESS_ATTACH for DLLs
+        if self.is_dll {
+            a.cmp(edx, 1)?;
+            a.jne(original)?;
+        }
+        self.emit_run_once(&mut a, &mut done)?;
+        a.set_label(&mut original)?;
+        if self.entry != 0 {
+            a.jmp(self.image_base + self.entry as u64)?;
+        } else {
+            // resource-only DLL: report success to the loader
+            a.mov(eax, 1)?;
+            a.ret()?;
+        }
+        a.set_label(&mut done)?;
+        a.db(&[0])?;
+
+        Ok(StartupThunk {
+            code: a.assemble(va)?,
+            characteristics: STUB_SECTION,
+            tls: None,
+        })
+    }
+
+    /// Calls the routines unless `done` is set, preserving the Win64 argument registers.
+    fn emit_run_once(&self, a: &mut CodeAssembler, done: &mut CodeLabel) -> Result<(), IcedError> {
+        let mut restore = a.create_label();
+        a.push(rcx)?;
+        a.push(rdx)?;
+        a.push(r8)?;
+        a.push(r9)?;
+        a.sub(rsp, 0x28)?;
+        a.cmp(byte_ptr(*done), 0)?;
+        a.jne(restore)?;
+        a.mov(byte_ptr(*done), 1)?;
+        for routine in &self.startup {
+            a.call(self.image_base + routine.rva as u64)?;
+        }
+        a.set_label(&mut restore)?;
+        a.add(rsp, 0x28)?;
+        a.pop(r9)?;
+        a.pop(r8)?;
+        a.pop(rdx)?;
+        a.pop(rcx)
+    }
+
+    /// TLS callback at `rva` running the routines on `DLL_PROCESS_ATTACH`,
+    /// followed by the new callback array (this callback, then the existing
+    /// ones) and, when the image has none, a TLS directory.
+    fn tls_callback_thunk(&self, rva: u32) -> Result<StartupThunk, StepError> {
+        let va = self.image_base + rva as u64;
+        let mut a = CodeAssembler::new(64)?;
+        let mut done = a.create_label();
+        let mut skip = a.create_label();
+        a.cmp(edx, 1)?;
+        a.jne(skip)?;
+        self.emit_run_once(&mut a, &mut done)?;
+        a.set_label(&mut skip)?;
+        a.ret()?;
+        a.set_label(&mut done)?;
+        a.db(&[0])?;
+        let mut code = a.assemble(va)?;
+
+        let (directory, size) = self.data_directory(DIR_TLS);
+        let mut callbacks = vec![va];
+        if size > 0 {
+            let unreadable = || StepError::UnsupportedFormat("The TLS directory is not backed by file data".into());
+            let array = self.read_u64(directory + 24).ok_or_else(unreadable)?;
+            if array != 0 {
+                let array = array.checked_sub(self.image_base).ok_or_else(unreadable)? as u32;
+                for i in 0.. {
+                    match self.read_u64(array + i * 8).ok_or_else(unreadable)? {
+                        0 => break,
+                        callback => callbacks.push(callback),
+                    }
+                }
+            }
+        }
+        code.resize(code.len().next_multiple_of(8), 0xcc);
+        let array_rva = rva + code.len() as u32;
+        let mut relocations = Vec::new();
+        for (i, callback) in callbacks.iter().enumerate() {
+            relocations.push(array_rva + i as u32 * 8);
+            code.extend_from_slice(&callback.to_le_bytes());
+        }
+        code.extend_from_slice(&0u64.to_le_bytes());
+
+        let mut new_directory = None;
+        if size == 0 {
+            // no template data; the loader still stores the TLS index in AddressOfIndex
+            let directory_rva = rva + code.len() as u32;
+            let index_rva = directory_rva + TLS_DIRECTORY_SIZE;
+            for field in [0, 0, self.image_base + index_rva as u64, self.image_base + array_rva as u64, 0] {
+                code.extend_from_slice(&field.to_le_bytes());
+            }
+            relocations.extend([directory_rva + 16, directory_rva + 24]);
+            code.extend_from_slice(&0u32.to_le_bytes());
+            new_directory = Some(directory_rva);
+        }
+        Ok(StartupThunk {
+            code,
+            characteristics: STUB_SECTION,
+            tls: Some(TlsHook {
+                array_rva,
+                relocations,
+                new_directory,
+                existing: callbacks.len() - 1,
+            }),
+        })
+    }
+
+    /// Points the TLS directory at the callback array of `tls`.
+    fn hook_tls_callback(&mut self, tls: &TlsHook) -> Result<(), StepError> {
+        for &rva in &tls.relocations {
+            self.add_relocation(rva, IMAGE_REL_BASED_DIR64);
+        }
+        match tls.new_directory {
+            Some(directory) => self.set_data_directory(DIR_TLS, directory, TLS_DIRECTORY_SIZE),
+            None => {
+                let field = self.data_directory(DIR_TLS).0 + 24;
+                self.write(field, &(self.image_base + tls.array_rva as u64).to_le_bytes())?;
+                self.add_relocation(field, IMAGE_REL_BASED_DIR64);
+            }
+        }
+        self.notes.push(format!(
+            "Startup routines run from a TLS callback, before the {} existing ones",
+            tls.existing
+        ));
+        Ok(())
+    }
+}
+
+/// Code (and data) of the thunk that calls the startup routines.
+struct StartupThunk {
+    code: Vec<u8>,
+    characteristics: u32,
+    tls: Option<TlsHook>,
+}
+
+/// Where the TLS callback thunk hooks into the TLS directory.
+struct TlsHook {
+    /// RVA of the new callback array.
+    array_rva: u32,
+    /// Absolute addresses in the thunk's section.
+    relocations: Vec<u32>,
+    /// RVA of the TLS directory created in the thunk's section, if the image had none.
+    new_directory: Option<u32>,
+    /// Number of callbacks the image already had.
+    existing: usize,
+}
+
+pub fn align_up(value: u32, alignment: u32) -> u32 {
+    if alignment == 0 {
+        return value;
+    }
+    value.div_ceil(alignment) * alignment
+}
+
+/// The optional header `CheckSum` (required for drivers and some system DLLs).
+fn pe_checksum(bytes: &[u8], checksum_at: usize) -> u32 {
+    let mut sum: u64 = 0;
+    for (i, chunk) in bytes.chunks(2).enumerate() {
+        if i * 2 == checksum_at || i * 2 == checksum_at + 2 {
+            continue;
+        }
+        let word = u16::from_le_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]) as u64;
+        sum += word;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum = (sum & 0xffff) + (sum >> 16);
+    (sum as u32).wrapping_add(bytes.len() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::pipeline::fixture::{self, IMAGE_BASE, RDATA_RVA, TEXT_RVA};
+
+    /// `mov rax, imm64; mov eax, [rax]; ret`: returns the dword at `va`.
+    fn load_dword(va: u64) -> Vec<u8> {
+        let mut a = CodeAssembler::new(64).unwrap();
+        a.mov(rax, va).unwrap();
+        a.mov(eax, dword_ptr(rax)).unwrap();
+        a.ret().unwrap();
+        a.assemble(IMAGE_BASE + TEXT_RVA as u64).unwrap()
+    }
+
+    /// Strips `.text` from a fixture with a COFF symbol table and a debug
+    /// record after the sections, each moved into `.text` when asked.
+    fn strip_text(debug_in_text: bool, symbols_in_text: bool) {
+        const DEBUG_DIRECTORY: u32 = RDATA_RVA + 0x100;
+        const TEXT_OFFSET: u32 = 0x400;
+        let mut bytes = fixture::pe64(&[0xc3], &[]);
+        let symbols = b"main\0\0\0\0\x01\0\0\0\x01\0\0\0\x02\0\x04\0\0\0";
+        let record = b"RSDS\x11\x22\x33\x44\x55\x66\x77\x88\x99\xaa\xbb\xcc\xdd\xee\xff\0\x01\0\0\0a.pdb\0";
+        let symbols_at = if symbols_in_text { TEXT_OFFSET + 0x10 } else { bytes.len() as u32 };
+        let record_at = if debug_in_text { TEXT_OFFSET + 0x80 } else { (bytes.len() + symbols.len()) as u32 };
+        bytes.extend_from_slice(symbols);
+        bytes.extend_from_slice(record);
+        bytes[symbols_at as usize..][..symbols.len()].copy_from_slice(symbols);
+        bytes[record_at as usize..][..record.len()].copy_from_slice(record);
+
+        let mut image = Image::parse(bytes).unwrap();
+        let coff = image.pe_offset + 4;
+        image.write_u32_at(coff + 8, symbols_at);
+        image.write_u32_at(coff + 12, 1);
+        let mut entry = [0u8; DEBUG_ENTRY_SIZE as usize];
+        entry[12..16].copy_from_slice(&2u32.to_le_bytes());
+        entry[16..20].copy_from_slice(&(record.len() as u32).to_le_bytes());
+        entry[24..28].copy_from_slice(&record_at.to_le_bytes());
+        image.write(DEBUG_DIRECTORY, &entry).unwrap();
+        image.set_data_directory(DIR_DEBUG, DEBUG_DIRECTORY, DEBUG_ENTRY_SIZE);
+        image.strip_section_data(0);
+
+        let bytes = image.into_bytes();
+        let image = Image::parse(bytes.clone()).unwrap();
+        assert_eq!(image.sections[0].raw_size, 0);
+        assert_eq!(image.sections[1].raw_offset, TEXT_OFFSET);
+        assert_eq!(image.read_u32(RDATA_RVA), Some(0));
+        if debug_in_text {
+            assert_eq!(image.data_directory(DIR_DEBUG), (0, 0));
+        } else {
+            assert_eq!(image.data_directory(DIR_DEBUG), (DEBUG_DIRECTORY, DEBUG_ENTRY_SIZE));
+            let moved = image.read_u32(DEBUG_DIRECTORY + 24).unwrap() as usize;
+            assert_eq!(&bytes[moved..moved + record.len()], record);
+        }
+        let (table, count) = (read_u32(&bytes, coff + 8).unwrap(), read_u32(&bytes, coff + 12).unwrap());
+        if symbols_in_text {
+            assert_eq!((table, count), (0, 0));
+        } else {
+            assert_eq!(count, 1);
+            assert_eq!(&bytes[table as usize..table as usize + symbols.len()], symbols);
+        }
+    }
+
+    #[test]
+    fn stripping_section_data_keeps_the_debug_directory_and_symbols_consistent() {
+        for (debug_in_text, symbols_in_text) in [(false, false), (true, false), (false, true), (true, true)] {
+            strip_text(debug_in_text, symbols_in_text);
+        }
+    }
+
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    #[test]
+    fn tls_callback_runs_the_startup_routines_before_the_entry_point() {
+        let mut image = Image::parse(fixture::pe64(&load_dword(0), &[])).unwrap();
+        // a routine that sets the flag stored after it, which the entry point returns
+        let rva = image.next_section_rva();
+        let flag = IMAGE_BASE + rva as u64 + 32;
+        let mut a = CodeAssembler::new(64).unwrap();
+        a.mov(rax, flag).unwrap();
+        a.mov(dword_ptr(rax), 1).unwrap();
+        a.ret().unwrap();
+        let mut routine = a.assemble(IMAGE_BASE + rva as u64).unwrap();
+        routine.resize(36, 0);
+        assert_eq!(image.add_section(".flag", &routine, STUB_SECTION).unwrap(), rva);
+        image.write(TEXT_RVA, &load_dword(flag)).unwrap();
+        image.add_startup_routine(StartupPhase::AntiDebug, "flag", rva);
+        image.set_startup_hook(StartupHook::TlsCallback);
+        image.finalize().unwrap();
+        let bytes = image.into_bytes();
+
+        let image = Image::parse(bytes.clone()).unwrap();
+        assert_eq!(image.entry, TEXT_RVA);
+        let (directory, size) = image.data_directory(DIR_TLS);
+        assert_eq!(size, TLS_DIRECTORY_SIZE);
+        let array = (image.read_u64(directory + 24).unwrap() - IMAGE_BASE) as u32;
+        let callbacks: Vec<u32> = (0..)
+            .map(|i| image.read_u64(array + i * 8).unwrap())
+            .take_while(|&va| va != 0)
+            .map(|va| (va - IMAGE_BASE) as u32)
+            .collect();
+        assert_eq!(callbacks.len(), 1);
+
+        let mapped = fixture::native::Mapped::new(&bytes);
+        assert_eq!(mapped.call(TEXT_RVA, [IMAGE_BASE, 1, 0, 0]), 0);
+        // DLL_THREAD_ATTACH does not run them
+        mapped.call(callbacks[0], [IMAGE_BASE, 2, 0, 0]);
+        assert_eq!(mapped.call(TEXT_RVA, [IMAGE_BASE, 1, 0, 0]), 0);
+        mapped.call(callbacks[0], [IMAGE_BASE, 1, 0, 0]);
+        assert_eq!(mapped.call(TEXT_RVA, [IMAGE_BASE, 1, 0, 0]), 1);
+    }
+}
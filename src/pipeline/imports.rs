@@ -0,0 +1,848 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::mpsc::Sender;
+
+use iced_x86::code_asm::*;
+use rand::rngs::StdRng;
+use rand::Rng;
+use serde::Serialize;
+
+use crate::pipeline::{PipelineContext, PipelineMessage};
+use crate::pipeline::error::StepError;
+use crate::pipeline::hash::{HashAlgorithm, NameHasher};
+use crate::pipeline::image::{self, Image, ImageFormat, StartupPhase, DIR_BOUND_IMPORT, DIR_IMPORT, DIR_TLS};
+use crate::pipeline::plan::PlannedChange;
+use crate::pipeline::profile::{step_rng, ImportProtection, StartupHook};
+use crate::pipeline::step::PipelineStep;
+use crate::pipeline::stub::Runtime;
+
+/// Name of the section holding the rebuilt import directory and the resolver.
+const SECTION_NAME: &str = ".obsi";
+/// STATUS_ENTRYPOINT_NOT_FOUND, the exit code when a hidden import cannot be resolved.
+const EXIT_UNRESOLVED: u32 = 0xC000_0139;
+const PAGE_READWRITE: u32 = 0x04;
+/// kernel32 functions the resolver looks up for itself.
+const BOOTSTRAP_IMPORTS: [&str; 4] = ["GetProcAddress", "LoadLibraryA", "VirtualProtect", "ExitProcess"];
+/// How many random SipHash keys are tried before a collision is reported.
+const KEY_ATTEMPTS: usize = 8;
+
+/// Removes imports from the import table and resolves them at startup.
+///
+/// Hidden imports leave no DLL or function name in the file: the resolver
+/// finds each DLL in the loader list (or loads it) and each function in the
+/// export table by hash, then fills the original IAT slots, so the code that
+/// calls through the IAT is unchanged. Ordinal imports and the ones in the
+/// profile allowlist stay static.
+pub struct ProtectImportsStep {
+    options: ImportProtection,
+}
+
+impl ProtectImportsStep {
+    pub fn new(options: ImportProtection) -> Self {
+        Self { options }
+    }
+}
+
+struct ImportThunk {
+    /// IAT slot filled by the loader (or the resolver).
+    slot_rva: u32,
+    /// Original lookup table value (hint/name RVA or ordinal).
+    value: u64,
+    /// RVA of the hint/name entry and the name; `None` for ordinal imports.
+    name: Option<(u32, String)>,
+    hidden: bool,
+}
+
+struct ImportDescriptor {
+    dll: String,
+    name_rva: u32,
+    lookup_rva: u32,
+    iat_rva: u32,
+    thunks: Vec<ImportThunk>,
+}
+
+impl PipelineStep for ProtectImportsStep {
+    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> Result<(), StepError> {
+        ctx.cancel.check()?;
+        tx.send(PipelineMessage::Log("Protecting imports...".into())).ok();
+
+        let Some(image) = ctx.image.as_mut() else {
+            return Err(StepError::Internal("no image loaded before import protection".into()));
+        };
+        if !image.is_64 || image.format != ImageFormat::Pe {
+            tx.send(PipelineMessage::Log(
+                "Import protection supports x64 PE images only; skipped".into(),
+            ))
+            .ok();
+            return Ok(());
+        }
+
+        let mut descriptors = read_imports(image)?;
+        for desc in &mut descriptors {
+            for thunk in &mut desc.thunks {
+                thunk.hidden = match &thunk.name {
+                    Some((_, name)) => !self.options.keeps(&desc.dll, name),
+                    None => false,
+                };
+            }
+        }
+
+        let hidden: usize = descriptors.iter().flat_map(|d| &d.thunks).filter(|t| t.hidden).count();
+        if hidden == 0 {
+            tx.send(PipelineMessage::Log("No imports to hide".into())).ok();
+            return Ok(());
+        }
+        // keep one kernel32 import so the loader still has something to bind
+        if descriptors.iter().flat_map(|d| &d.thunks).all(|t| t.hidden) {
+            let kept = descriptors
+                .iter_mut()
+                .find(|d| d.dll.eq_ignore_ascii_case("kernel32.dll"))
+                .and_then(|d| d.thunks.first_mut().map(|t| (d.dll.clone(), t)));
+            if let Some((dll, thunk)) = kept {
+                thunk.hidden = false;
+                let name = thunk.name.as_ref().map(|(_, n)| n.as_str()).unwrap_or_default();
+                tx.send(PipelineMessage::Log(format!("Keeping {}!{} as a static import", dll, name))).ok();
+            }
+        }
+        let hasher = self.hasher(&descriptors, &mut step_rng(ctx.seed, "imports"))?;
+        tx.send(PipelineMessage::Log(match hasher.algorithm {
+            HashAlgorithm::SipHash => format!(
+                "Import names hashed with siphash (key {:016x}{:016x})",
+                hasher.key[0], hasher.key[1]
+            ),
+            algorithm => format!("Import names hashed with {}", algorithm),
+        }))
+        .ok();
+        let report_path = ctx.artifacts.path(&ctx.input_path, "import-hashes.json")?;
+
+        if image.data_directory(DIR_TLS).1 != 0 && image.startup_hook() == StartupHook::Entry {
+            tx.send(PipelineMessage::Log(
+                "WARNING: the input has TLS callbacks; they run before the resolver and must not call hidden imports \
+                 (use the tls_callback startup hook to resolve first)"
+                    .into(),
+            ))
+            .ok();
+        }
+
+        if ctx.dry_run {
+            for desc in &descriptors {
+                for thunk in desc.thunks.iter().filter(|t| t.hidden) {
+                    let function = thunk.name.as_ref().map(|(_, n)| n.clone()).unwrap_or_default();
+                    ctx.plan.push(PlannedChange::HideImport {
+                        dll: desc.dll.clone(),
+                        hash: format!("0x{:08x}", hasher.hash(&function)),
+                        function,
+                    });
+                }
+            }
+            ctx.plan.push(PlannedChange::AddSection {
+                name: SECTION_NAME.into(),
+                description: "rebuilt import directory and import resolver".into(),
+            });
+            ctx.plan.push(PlannedChange::WriteFile {
+                path: report_path.display().to_string(),
+                description: "hashes of the hidden imports".into(),
+            });
+            return Ok(());
+        }
+
+        ctx.cancel.check()?;
+        let (section, code_offset) = build_section(image, &descriptors, &hasher)?;
+        let rva = image.add_section(SECTION_NAME, &section, image::STUB_SECTION)?;
+        strip_hidden_names(image, &descriptors)?;
+        let kept_runs = runs(&descriptors).len() as u32;
+        image.set_data_directory(DIR_IMPORT, rva, (kept_runs + 1) * 20);
+        if image.data_directory(DIR_BOUND_IMPORT).1 != 0 {
+            image.set_data_directory(DIR_BOUND_IMPORT, 0, 0);
+        }
+        image.add_startup_routine(StartupPhase::Imports, "import resolver", rva + code_offset);
+
+        let hidden_dlls = descriptors.iter().filter(|d| d.thunks.iter().all(|t| t.hidden)).count();
+        tx.send(PipelineMessage::Log(format!(
+            "Hidden {} imports ({} DLLs removed from the import table); resolver in section {} ({} bytes)",
+            descriptors.iter().flat_map(|d| &d.thunks).filter(|t| t.hidden).count(),
+            hidden_dlls,
+            SECTION_NAME,
+            section.len()
+        )))
+        .ok();
+
+        ctx.write_artifact(&report_path, hash_report(&descriptors, &hasher).as_bytes())?;
+        tx.send(PipelineMessage::Log(format!(
+            "Import hashes written to {} ({})",
+            report_path.display(),
+            ctx.artifacts.describe()
+        )))
+        .ok();
+        tx.send(PipelineMessage::Progress(0.35)).ok();
+
+        Ok(())
+    }
+}
+
+impl ProtectImportsStep {
+    /// Builds the configured hasher, making sure no two names the resolver
+    /// has to tell apart hash to the same value. With SipHash and no fixed
+    /// key, a few random keys are tried before giving up.
+    fn hasher(&self, descriptors: &[ImportDescriptor], rng: &mut StdRng) -> Result<NameHasher, StepError> {
+        let configured = self.options.sip_key()?;
+        let mut random_key = || [rng.gen::<u64>(), rng.gen::<u64>()];
+        let mut hasher = NameHasher::new(self.options.hash, configured.unwrap_or_else(&mut random_key));
+        for attempt in 1.. {
+            let Some(collision) = find_collision(&hasher, descriptors) else {
+                return Ok(hasher);
+            };
+            if hasher.algorithm == HashAlgorithm::SipHash && configured.is_none() && attempt < KEY_ATTEMPTS {
+                hasher.key = random_key();
+                continue;
+            }
+            return Err(StepError::InvalidInput(format!(
+                "{} under {}; choose another hash algorithm{}",
+                collision,
+                hasher.algorithm,
+                if hasher.algorithm == HashAlgorithm::SipHash { " or SipHash key" } else { "" }
+            )));
+        }
+        Err(StepError::Internal("Import hasher retries ended without a result".into()))
+    }
+}
+
+/// First pair of names with the same hash: among the imported DLLs, and
+/// among the functions imported from each DLL (plus the resolver's own
+/// kernel32 imports). Names are compared case-insensitively, like the hashes.
+fn find_collision(hasher: &NameHasher, descriptors: &[ImportDescriptor]) -> Option<String> {
+    let mut functions: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for desc in descriptors {
+        let names = functions.entry(desc.dll.to_ascii_lowercase()).or_default();
+        names.extend(desc.thunks.iter().filter_map(|t| t.name.as_ref().map(|(_, n)| n.as_str())));
+    }
+    functions
+        .entry("kernel32.dll".into())
+        .or_default()
+        .extend(BOOTSTRAP_IMPORTS);
+
+    let first_clash = |names: &mut dyn Iterator<Item = &str>| {
+        let mut seen: BTreeMap<u32, String> = BTreeMap::new();
+        for name in names {
+            let lower = name.to_ascii_lowercase();
+            match seen.get(&hasher.hash(name)) {
+                Some(other) if *other != lower => return Some((other.clone(), name.to_string())),
+                _ => {
+                    seen.insert(hasher.hash(name), lower);
+                }
+            }
+        }
+        None
+    };
+
+    if let Some((a, b)) = first_clash(&mut functions.keys().map(String::as_str)) {
+        return Some(format!("DLL names {} and {} have the same hash", a, b));
+    }
+    functions.iter().find_map(|(dll, names)| {
+        first_clash(&mut names.iter().copied())
+            .map(|(a, b)| format!("{} and {} from {} have the same hash", a, b, dll))
+    })
+}
+
+#[derive(Serialize)]
+struct HashReport {
+    algorithm: HashAlgorithm,
+    /// SipHash key (hex), needed to recompute the hashes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    dlls: Vec<DllHashes>,
+}
+
+#[derive(Serialize)]
+struct DllHashes {
+    dll: String,
+    hash: String,
+    functions: Vec<FunctionHash>,
+}
+
+#[derive(Serialize)]
+struct FunctionHash {
+    name: String,
+    hash: String,
+    iat_rva: String,
+}
+
+/// JSON listing every hidden import with its hash and IAT slot, so a
+/// resolution failure (exit code 0xC0000139) can be traced to a name.
+fn hash_report(descriptors: &[ImportDescriptor], hasher: &NameHasher) -> String {
+    let report = HashReport {
+        algorithm: hasher.algorithm,
+        key: (hasher.algorithm == HashAlgorithm::SipHash)
+            .then(|| format!("{:016x}{:016x}", hasher.key[0], hasher.key[1])),
+        dlls: descriptors
+            .iter()
+            .filter(|d| d.thunks.iter().any(|t| t.hidden))
+            .map(|d| DllHashes {
+                dll: d.dll.clone(),
+                hash: format!("0x{:08x}", hasher.hash(&d.dll)),
+                functions: d
+                    .thunks
+                    .iter()
+                    .filter(|t| t.hidden)
+                    .filter_map(|t| t.name.as_ref().map(|(_, n)| (n, t.slot_rva)))
+                    .map(|(name, slot)| FunctionHash {
+                        name: name.clone(),
+                        hash: format!("0x{:08x}", hasher.hash(name)),
+                        iat_rva: format!("0x{:x}", slot),
+                    })
+                    .collect(),
+            })
+            .collect(),
+    };
+    serde_json::to_string_pretty(&report).unwrap_or_default()
+}
+
+/// Reads the import descriptors and their lookup tables.
+fn read_imports(image: &Image) -> Result<Vec<ImportDescriptor>, StepError> {
+    let malformed = |what: String| StepError::InvalidInput(format!("Malformed import table: {}", what));
+    let (dir_rva, _) = image.data_directory(DIR_IMPORT);
+    let mut descriptors = Vec::new();
+    if dir_rva == 0 {
+        return Ok(descriptors);
+    }
+
+    for i in 0.. {
+        let at = dir_rva + i * 20;
+        let raw = image
+            .read(at, 20)
+            .ok_or_else(|| malformed(format!("descriptor at 0x{:x} is outside the file", at)))?;
+        if raw.iter().all(|&b| b == 0) {
+            break;
+        }
+        let field = |n: usize| u32::from_le_bytes(raw[n * 4..n * 4 + 4].try_into().unwrap());
+        let (original_first_thunk, name_rva, first_thunk) = (field(0), field(3), field(4));
+        let dll = image
+            .read_c_string(name_rva)
+            .ok_or_else(|| malformed(format!("DLL name at 0x{:x}", name_rva)))?;
+        let lookup_rva = if original_first_thunk != 0 { original_first_thunk } else { first_thunk };
+
+        let mut thunks = Vec::new();
+        for j in 0.. {
+            let value = image
+                .read_u64(lookup_rva + j * 8)
+                .ok_or_else(|| malformed(format!("lookup table of {}", dll)))?;
+            if value == 0 {
+                break;
+            }
+            let name = if value & (1 << 63) == 0 {
+                let hint_name = value as u32;
+                let name = image
+                    .read_c_string(hint_name + 2)
+                    .ok_or_else(|| malformed(format!("import name at 0x{:x}", hint_name)))?;
+                Some((hint_name, name))
+            } else {
+                None
+            };
+            thunks.push(ImportThunk {
+                slot_rva: first_thunk + j * 8,
+                value,
+                name,
+                hidden: false,
+            });
+        }
+        descriptors.push(ImportDescriptor {
+            dll,
+            name_rva,
+            lookup_rva,
+            iat_rva: first_thunk,
+            thunks,
+        });
+    }
+    Ok(descriptors)
+}
+
+/// IAT slots the loader fills with the import `function` (by name, from any
+/// DLL), as `(dll, slot rva)`.
+pub fn import_slots(image: &Image, function: &str) -> Result<Vec<(String, u32)>, StepError> {
+    let mut slots = Vec::new();
+    for desc in read_imports(image)? {
+        for thunk in &desc.thunks {
+            if thunk.name.as_ref().is_some_and(|(_, name)| name == function) {
+                slots.push((desc.dll.clone(), thunk.slot_rva));
+            }
+        }
+    }
+    Ok(slots)
+}
+
+/// Consecutive kept IAT slots of one DLL; each becomes an import descriptor.
+fn runs(descriptors: &[ImportDescriptor]) -> Vec<(&ImportDescriptor, &[ImportThunk])> {
+    let mut out = Vec::new();
+    for desc in descriptors {
+        for run in desc.thunks.split(|t| t.hidden).filter(|r| !r.is_empty()) {
+            out.push((desc, run));
+        }
+    }
+    out
+}
+
+/// Builds the section contents: new import descriptors and lookup tables,
+/// the hashed resolver table and the resolver code. Returns the data and the
+/// offset of the resolver entry.
+fn build_section(
+    image: &Image,
+    descriptors: &[ImportDescriptor],
+    hasher: &NameHasher,
+) -> Result<(Vec<u8>, u32), StepError> {
+    let base_rva = image.next_section_rva();
+    let runs = runs(descriptors);
+
+    // import descriptors, then one lookup table per run
+    let mut data = vec![0u8; (runs.len() + 1) * 20];
+    for (i, (desc, run)) in runs.iter().enumerate() {
+        let lookup_rva = base_rva + data.len() as u32;
+        for thunk in run.iter() {
+            data.extend_from_slice(&thunk.value.to_le_bytes());
+        }
+        data.extend_from_slice(&[0; 8]);
+        let d = &mut data[i * 20..i * 20 + 20];
+        d[0..4].copy_from_slice(&lookup_rva.to_le_bytes());
+        d[12..16].copy_from_slice(&desc.name_rva.to_le_bytes());
+        d[16..20].copy_from_slice(&run[0].slot_rva.to_le_bytes());
+    }
+
+    // resolver table: per DLL {hash, name length, count, key, encrypted name}, then {hash, slot} pairs
+    let table_rva = base_rva + data.len() as u32;
+    let mut dll_count = 0i32;
+    for desc in descriptors {
+        let hidden: Vec<&ImportThunk> = desc.thunks.iter().filter(|t| t.hidden).collect();
+        if hidden.is_empty() {
+            continue;
+        }
+        dll_count += 1;
+        let hash = hasher.hash(&desc.dll);
+        let key = hash.rotate_left(13) ^ 0x5A5A_5A5A;
+        data.extend_from_slice(&hash.to_le_bytes());
+        data.extend_from_slice(&(desc.dll.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(hidden.len() as u32).to_le_bytes());
+        data.extend_from_slice(&key.to_le_bytes());
+        let mut name: Vec<u8> = desc
+            .dll
+            .bytes()
+            .enumerate()
+            .map(|(i, b)| b ^ key.rotate_right(8 * (i as u32 % 4)) as u8)
+            .collect();
+        name.resize(desc.dll.len().div_ceil(4) * 4, 0);
+        data.extend_from_slice(&name);
+        for thunk in hidden {
+            let function = thunk.name.as_ref().map(|(_, n)| n.as_str()).unwrap_or_default();
+            data.extend_from_slice(&hasher.hash(function).to_le_bytes());
+            data.extend_from_slice(&thunk.slot_rva.to_le_bytes());
+        }
+    }
+
+    let hidden_slots = descriptors.iter().flat_map(|d| &d.thunks).filter(|t| t.hidden);
+    let iat_start = hidden_slots.clone().map(|t| t.slot_rva).min().unwrap_or_default();
+    let iat_end = hidden_slots.map(|t| t.slot_rva + 8).max().unwrap_or_default();
+
+    data.resize(data.len().next_multiple_of(16), 0xCC);
+    let code_offset = data.len() as u32;
+    let code_rva = base_rva + code_offset;
+    let code = emit_resolver(
+        hasher,
+        image.image_base + code_rva as u64,
+        code_rva,
+        table_rva,
+        dll_count,
+        (iat_start, iat_end - iat_start),
+    )?;
+    data.extend_from_slice(&code);
+    Ok((data, code_offset))
+}
+
+/// Resolver entry: resolves its own kernel32 imports, makes the IAT writable,
+/// then walks the resolver table filling every hidden slot. A DLL or function
+/// that cannot be found ends the process with `STATUS_ENTRYPOINT_NOT_FOUND`,
+/// like the loader would.
+fn emit_resolver(
+    hasher: &NameHasher,
+    va: u64,
+    code_rva: u32,
+    table_rva: u32,
+    dll_count: i32,
+    iat: (u32, u32),
+) -> Result<Vec<u8>, StepError> {
+    let mut a = CodeAssembler::new(64)?;
+    let mut entry = a.create_label();
+    let mut fail = a.create_label();
+    let mut load_library = a.create_label();
+    let mut virtual_protect = a.create_label();
+    let mut exit_process = a.create_label();
+    let mut name_buf = a.create_label();
+    let mut next_dll = a.create_label();
+    let mut decrypt = a.create_label();
+    let mut decrypted = a.create_label();
+    let mut loaded = a.create_label();
+    let mut next_fn = a.create_label();
+    let mut dll_done = a.create_label();
+
+    a.set_label(&mut entry)?;
+    a.push(rbx)?;
+    a.push(rsi)?;
+    a.push(rdi)?;
+    a.push(r12)?;
+    a.push(r13)?;
+    a.push(r14)?;
+    a.push(r15)?;
+    a.sub(rsp, 0x30)?;
+    // rbx = runtime image base
+    a.lea(rbx, ptr(entry))?;
+    a.sub(rbx, code_rva as i32)?;
+
+    let runtime = Runtime::new(&mut a, *hasher);
+
+    let mut no_kernel32 = a.create_label();
+    a.mov(ecx, hasher.hash("kernel32.dll"))?;
+    a.call(runtime.find_module)?;
+    a.test(rax, rax)?;
+    a.jz(no_kernel32)?;
+    a.mov(r12, rax)?;
+    let slots = [runtime.get_proc_address, load_library, virtual_protect, exit_process];
+    for (name, slot) in BOOTSTRAP_IMPORTS.into_iter().zip(slots) {
+        a.mov(rcx, r12)?;
+        a.mov(edx, hasher.hash(name))?;
+        a.call(runtime.find_export)?;
+        a.test(rax, rax)?;
+        a.jz(no_kernel32)?;
+        a.mov(qword_ptr(slot), rax)?;
+    }
+
+    // VirtualProtect(iat, size, PAGE_READWRITE, &old)
+    a.lea(rcx, ptr(rbx + iat.0 as i32))?;
+    a.mov(edx, iat.1)?;
+    a.mov(r8d, PAGE_READWRITE)?;
+    a.lea(r9, ptr(rsp + 0x20))?;
+    a.call(qword_ptr(virtual_protect))?;
+    a.test(eax, eax)?;
+    a.jz(fail)?;
+
+    a.lea(r13, ptr(rbx + table_rva as i32))?;
+    a.mov(r14d, dll_count)?;
+    a.set_label(&mut next_dll)?;
+    a.mov(ecx, dword_ptr(r13))?;
+    a.call(runtime.find_module)?;
+    a.test(rax, rax)?;
+    a.jnz(loaded)?;
+    // not loaded yet: decrypt the name and LoadLibraryA it
+    a.mov(ecx, dword_ptr(r13 + 4))?;
+    a.mov(edx, dword_ptr(r13 + 12))?;
+    a.lea(rsi, ptr(r13 + 16))?;
+    a.lea(rdi, ptr(name_buf))?;
+    a.set_label(&mut decrypt)?;
+    a.test(ecx, ecx)?;
+    a.jz(decrypted)?;
+    a.mov(al, byte_ptr(rsi))?;
+    a.xor(al, dl)?;
+    a.mov(byte_ptr(rdi), al)?;
+    a.ror(edx, 8)?;
+    a.inc(rsi)?;
+    a.inc(rdi)?;
+    a.dec(ecx)?;
+    a.jmp(decrypt)?;
+    a.set_label(&mut decrypted)?;
+    a.mov(byte_ptr(rdi), 0)?;
+    a.lea(rcx, ptr(name_buf))?;
+    a.call(qword_ptr(load_library))?;
+    a.test(rax, rax)?;
+    a.jz(fail)?;
+    a.set_label(&mut loaded)?;
+    a.mov(r15, rax)?;
+    a.mov(eax, dword_ptr(r13 + 4))?;
+    a.add(eax, 3)?;
+    a.and(eax, -4)?;
+    a.mov(esi, dword_ptr(r13 + 8))?;
+    a.lea(r13, ptr(r13 + rax + 16))?;
+    a.set_label(&mut next_fn)?;
+    a.test(esi, esi)?;
+    a.jz(dll_done)?;
+    a.mov(rcx, r15)?;
+    a.mov(edx, dword_ptr(r13))?;
+    a.call(runtime.find_export)?;
+    a.test(rax, rax)?;
+    a.jz(fail)?;
+    a.mov(ecx, dword_ptr(r13 + 4))?;
+    a.mov(qword_ptr(rbx + rcx), rax)?;
+    a.add(r13, 8)?;
+    a.dec(esi)?;
+    a.jmp(next_fn)?;
+    a.set_label(&mut dll_done)?;
+    a.dec(r14d)?;
+    a.jnz(next_dll)?;
+
+    // restore the IAT protection
+    a.lea(rcx, ptr(rbx + iat.0 as i32))?;
+    a.mov(edx, iat.1)?;
+    a.mov(r8d, dword_ptr(rsp + 0x20))?;
+    a.lea(r9, ptr(rsp + 0x28))?;
+    a.call(qword_ptr(virtual_protect))?;
+
+    a.add(rsp, 0x30)?;
+    a.pop(r15)?;
+    a.pop(r14)?;
+    a.pop(r13)?;
+    a.pop(r12)?;
+    a.pop(rdi)?;
+    a.pop(rsi)?;
+    a.pop(rbx)?;
+    a.ret()?;
+
+    a.set_label(&mut fail)?;
+    a.mov(ecx, EXIT_UNRESOLVED)?;
+    a.call(qword_ptr(exit_process))?;
+    a.set_label(&mut no_kernel32)?;
+    a.ud2()?;
+
+    a.set_label(&mut load_library)?;
+    a.dq(&[0])?;
+    a.set_label(&mut virtual_protect)?;
+    a.dq(&[0])?;
+    a.set_label(&mut exit_process)?;
+    a.dq(&[0])?;
+    a.set_label(&mut name_buf)?;
+    a.db(&[0; 256])?;
+    runtime.emit(&mut a)?;
+
+    Ok(a.assemble(va)?)
+}
+
+/// Wipes what the file still says about hidden imports: their IAT slots and
+/// hint/name entries, the old lookup tables and descriptors, and the names of
+/// DLLs that no longer have a static import.
+fn strip_hidden_names(image: &mut Image, descriptors: &[ImportDescriptor]) -> Result<(), StepError> {
+    let kept_names: HashSet<u32> = descriptors
+        .iter()
+        .flat_map(|d| &d.thunks)
+        .filter(|t| !t.hidden)
+        .filter_map(|t| t.name.as_ref().map(|(rva, _)| *rva))
+        .collect();
+
+    for desc in descriptors {
+        for thunk in &desc.thunks {
+            if !thunk.hidden {
+                continue;
+            }
+            image.write(thunk.slot_rva, &[0; 8])?;
+            if let Some((rva, name)) = &thunk.name {
+                if !kept_names.contains(rva) {
+                    image.write(*rva, &vec![0; name.len() + 3])?;
+                }
+            }
+        }
+        // the lookup tables of the new descriptors live in the added section
+        if desc.lookup_rva != desc.iat_rva {
+            image.write(desc.lookup_rva, &vec![0; (desc.thunks.len() + 1) * 8])?;
+        }
+        if desc.thunks.iter().all(|t| t.hidden) {
+            image.write(desc.name_rva, &vec![0; desc.dll.len()])?;
+        }
+    }
+
+    let (dir_rva, _) = image.data_directory(DIR_IMPORT);
+    image.write(dir_rva, &vec![0; (descriptors.len() + 1) * 20])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::sync::mpsc;
+
+    use iced_x86::{Decoder, DecoderOptions, FlowControl, Mnemonic, Register};
+    use rand::SeedableRng;
+
+    use crate::pipeline::artifacts::Artifacts;
+    use crate::pipeline::cancel::CancellationToken;
+    use crate::pipeline::code::branch_target;
+    use crate::pipeline::fixture::{self, IMAGE_BASE};
+    use crate::pipeline::profile::ArtifactOptions;
+
+    #[test]
+    fn resolver_decodes_and_stays_in_its_code() {
+        let hasher = NameHasher::new(HashAlgorithm::Fnv1a, [0; 2]);
+        let (code_rva, table_rva) = (0x5000, 0x4000);
+        let va = IMAGE_BASE + code_rva as u64;
+        let code = emit_resolver(&hasher, va, code_rva, table_rva, 2, (0x3000, 0x40)).unwrap();
+        let end = va + code.len() as u64;
+
+        // follow the control flow from the entry, like the CPU would
+        let mut pending = vec![va];
+        let mut instructions = BTreeSet::new();
+        let mut data = BTreeSet::new();
+        let mut called = BTreeSet::new();
+        let mut base_adjust = None;
+        while let Some(ip) = pending.pop() {
+            if !instructions.insert(ip) {
+                continue;
+            }
+            let at = (ip - va) as usize;
+            let mut decoder = Decoder::with_ip(64, &code[at..], ip, DecoderOptions::NONE);
+            loop {
+                let i = decoder.decode();
+                assert!(!i.is_invalid(), "undecodable bytes at 0x{:x}", i.ip());
+                instructions.insert(i.ip());
+                if i.is_ip_rel_memory_operand() {
+                    data.insert(i.ip_rel_memory_address());
+                }
+                if i.mnemonic() == Mnemonic::Sub && i.op0_register() == Register::RBX {
+                    base_adjust = Some(i.immediate32());
+                }
+                if let Some(target) = branch_target(&i) {
+                    assert!((va..end).contains(&target), "branch at 0x{:x} leaves the resolver", i.ip());
+                    if i.flow_control() == FlowControl::Call {
+                        called.insert(target);
+                    }
+                    pending.push(target);
+                }
+                match i.flow_control() {
+                    FlowControl::UnconditionalBranch | FlowControl::Return | FlowControl::Exception => break,
+                    _ => {}
+                }
+            }
+        }
+
+        // `lea rbx, [entry]` / `sub rbx, code_rva` compute the image base
+        assert!(data.contains(&va));
+        assert_eq!(base_adjust, Some(code_rva));
+        // hash_str, find_module and find_export
+        assert!(called.len() >= 3, "only {} routines called", called.len());
+        // the API slots and the name buffer are data inside the code, never executed
+        let slots: Vec<u64> = data.iter().copied().filter(|&address| address != va).collect();
+        assert!(slots.len() >= 5);
+        for slot in slots {
+            assert!((va..end).contains(&slot), "0x{:x} is outside the resolver", slot);
+            assert!(!instructions.contains(&slot), "data slot 0x{:x} is executed", slot);
+        }
+    }
+
+    #[test]
+    fn hides_imports_and_rewrites_the_import_table() {
+        let kernel32 = ["ExitProcess", "GetTickCount"];
+        let user32 = ["MessageBoxA"];
+        let bytes = fixture::pe64(&[0x31, 0xc0, 0xc3], &[("KERNEL32.dll", &kernel32), ("USER32.dll", &user32)]);
+        let original = Image::parse(bytes).unwrap();
+        let original_slots: Vec<(String, u32)> = read_imports(&original)
+            .unwrap()
+            .iter()
+            .flat_map(|d| d.thunks.iter().map(|t| (t.name.clone().unwrap().1, t.slot_rva)))
+            .collect();
+
+        let dir = std::env::temp_dir().join(format!("obscura-imports-{}", std::process::id()));
+        let mut options = ImportProtection { enabled: true, ..Default::default() };
+        options.keep.insert("kernel32.dll".into(), vec!["GetTickCount".into()]);
+        let input = dir.join("fixture.exe").to_string_lossy().into_owned();
+        let mut ctx = PipelineContext::new(input, CancellationToken::new());
+        ctx.seed = 7;
+        ctx.artifacts = Artifacts::new(&ArtifactOptions { dir: Some(dir.clone()), ..Default::default() }).unwrap();
+        ctx.image = Some(original);
+        let (tx, _rx) = mpsc::channel();
+        ProtectImportsStep::new(options).run(&mut ctx, &tx).unwrap();
+        let report_path = ctx.artifacts.path(&ctx.input_path, "import-hashes.json").unwrap();
+        assert_eq!(report_path.parent(), Some(dir.as_path()));
+        let report = fs::read_to_string(report_path).unwrap();
+        fs::remove_dir_all(&dir).ok();
+
+        let mut image = ctx.image.take().unwrap();
+        image.finalize().unwrap();
+        let bytes = image.into_bytes();
+        let text = String::from_utf8_lossy(&bytes).to_ascii_lowercase();
+        for name in ["exitprocess", "messageboxa", "user32.dll"] {
+            assert!(!text.contains(name), "{} is still in the file", name);
+        }
+        assert!(text.contains("gettickcount") && text.contains("kernel32.dll"));
+
+        // the loader sees only the kept import, in its original IAT slot
+        let pe = goblin::pe::PE::parse(&bytes).unwrap();
+        let imports: Vec<(&str, &str)> = pe.imports.iter().map(|i| (i.dll, &*i.name)).collect();
+        assert_eq!(imports, [("KERNEL32.dll", "GetTickCount")]);
+        let image = Image::parse(bytes).unwrap();
+        let kept = read_imports(&image).unwrap();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].thunks[0].slot_rva, original_slots[1].1);
+        let section = image.section_at(kept[0].lookup_rva).unwrap();
+        assert!(section.virtual_address > fixture::RDATA_RVA, "descriptors were not moved to {}", SECTION_NAME);
+        assert_ne!(image.entry, fixture::TEXT_RVA, "no entry thunk for the resolver");
+
+        // the hidden slots are empty until the resolver fills them, and the report names them
+        let hasher = NameHasher::new(HashAlgorithm::Fnv1a, [0; 2]);
+        for (name, slot) in original_slots.iter().filter(|(name, _)| name != "GetTickCount") {
+            assert_eq!(image.read_u64(*slot), Some(0));
+            assert!(report.contains(&format!("0x{:08x}", hasher.hash(name))), "{} missing from the report", name);
+            assert!(report.contains(&format!("0x{:x}", slot)));
+        }
+    }
+
+    /// Descriptors hiding every listed import, as the step would build them.
+    fn hidden_imports(imports: &[(&str, &[&str])]) -> Vec<ImportDescriptor> {
+        imports
+            .iter()
+            .map(|(dll, functions)| ImportDescriptor {
+                dll: dll.to_string(),
+                name_rva: 0,
+                lookup_rva: 0,
+                iat_rva: 0,
+                thunks: functions
+                    .iter()
+                    .map(|name| ImportThunk { slot_rva: 0, value: 0, name: Some((0, name.to_string())), hidden: true })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn find_collision_reports_clashing_names() {
+        // djb2 is linear: "a|" and "b[" both hash to 'a' * 33 + '|'
+        let djb2 = NameHasher::new(HashAlgorithm::Djb2, [0; 2]);
+        assert_eq!(djb2.hash("a|"), djb2.hash("b["));
+
+        let dlls = hidden_imports(&[("a|.dll", &["Open"]), ("b[.dll", &["Close"])]);
+        let collision = find_collision(&djb2, &dlls).unwrap();
+        assert!(collision.starts_with("DLL names"), "{}", collision);
+
+        let functions = hidden_imports(&[("x.dll", &["Fa|", "Fb["])]);
+        let collision = find_collision(&djb2, &functions).unwrap();
+        assert!(collision.ends_with("from x.dll have the same hash"), "{}", collision);
+
+        // the same name in another case, or clashing names in different DLLs, can be told apart
+        let distinct = hidden_imports(&[("x.dll", &["Fa|", "FA|"]), ("y.dll", &["Fb["])]);
+        assert_eq!(find_collision(&djb2, &distinct), None);
+        // the resolver's own kernel32 imports count too
+        let fnv = NameHasher::new(HashAlgorithm::Fnv1a, [0; 2]);
+        assert_eq!(find_collision(&fnv, &hidden_imports(&[("KERNEL32.dll", &["GetProcAddress"])])), None);
+    }
+
+    #[test]
+    fn random_siphash_key_is_retried_on_collision() {
+        let rng = StdRng::seed_from_u64(3);
+        let first_key = {
+            let mut rng = rng.clone();
+            [rng.gen::<u64>(), rng.gen::<u64>()]
+        };
+        // two names that collide under the first key drawn (a birthday search over 32-bit hashes)
+        let first = NameHasher::new(HashAlgorithm::SipHash, first_key);
+        let mut seen = BTreeMap::new();
+        let (a, b) = (0u32..)
+            .map(|i| format!("f{}", i))
+            .find_map(|name| seen.insert(first.hash(&name), name.clone()).map(|other| (other, name)))
+            .unwrap();
+        let descriptors = hidden_imports(&[("x.dll", &[a.as_str(), b.as_str()])]);
+        assert!(find_collision(&first, &descriptors).is_some());
+
+        let options = ImportProtection { hash: HashAlgorithm::SipHash, ..Default::default() };
+        let hasher = ProtectImportsStep::new(options.clone()).hasher(&descriptors, &mut rng.clone()).unwrap();
+        assert_ne!(hasher.key, first_key);
+        assert_eq!(find_collision(&hasher, &descriptors), None);
+
+        // a configured key is never replaced
+        let sip_key = Some(format!("{:016x}{:016x}", first_key[0], first_key[1]));
+        let fixed = ImportProtection { sip_key, ..options };
+        let error = ProtectImportsStep::new(fixed).hasher(&descriptors, &mut rng.clone()).err().unwrap();
+        assert!(matches!(error, StepError::InvalidInput(_)), "{}", error);
+    }
+}
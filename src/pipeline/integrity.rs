@@ -0,0 +1,578 @@
+//! Self-checksum of the protected code (`IntegrityStep`).
+//!
+//! The step only records what to hash: `Image::finalize` calls
+//! `emit_checker` once every other change is in place, so the hashes cover
+//! the bytes that are written out. Each range is hashed with FNV-1a (64-bit,
+//! from a random offset basis) over its file-backed bytes, leaving out the
+//! words the loader relocates, the mapped headers, the IAT and whatever the
+//! other stubs rewrite at runtime (`Image::mark_runtime_written`), so a
+//! rebased image still matches. The expected hashes are stored XOR a
+//! keystream. Writable sections, including the stub sections of the other
+//! steps and the checker's own (which also holds the entry thunk), are not
+//! covered.
+
+use std::sync::mpsc::Sender;
+
+use iced_x86::code_asm::*;
+use rand::Rng;
+
+use crate::pipeline::{PipelineContext, PipelineMessage};
+use crate::pipeline::error::StepError;
+use crate::pipeline::hash::{HashAlgorithm, NameHasher};
+use crate::pipeline::image::{Image, ImageFormat, Section, DIR_IAT, DIR_LOAD_CONFIG, IMAGE_SCN_MEM_WRITE};
+use crate::pipeline::plan::PlannedChange;
+use crate::pipeline::reloc;
+use crate::pipeline::profile::{step_rng, IntegrityOptions, TamperResponse};
+use crate::pipeline::step::PipelineStep;
+use crate::pipeline::stub::Runtime;
+
+pub const INTEGRITY_SECTION: &str = ".obsk";
+/// Exit status of the `exit` response.
+const EXIT_TAMPERED: u32 = 1;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+/// Keystream of the stored hashes (Knuth's MMIX generator).
+const KEYSTREAM_MUL: u64 = 6_364_136_223_846_793_005;
+const KEYSTREAM_INC: u64 = 1_442_695_040_888_963_407;
+/// Stack of the ELF watcher thread.
+const WATCHER_STACK: u32 = 0x4000;
+
+const SYS_MMAP: u32 = 9;
+const SYS_NANOSLEEP: u32 = 35;
+const SYS_CLONE: u32 = 56;
+const SYS_EXIT_GROUP: u32 = 231;
+/// CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD | CLONE_SYSVSEM
+const CLONE_THREAD_FLAGS: u32 = 0x0005_0f00;
+
+/// What `finalize` hashes, recorded on the image by `IntegrityStep`.
+#[derive(Debug, Clone)]
+pub struct IntegrityCheck {
+    /// Named ranges as `(name, rva, size)`; every section that is not
+    /// writable, as laid out at finalization, when `None`.
+    pub ranges: Option<Vec<(String, u32, u32)>>,
+    pub interval_secs: Option<u32>,
+    pub response: TamperResponse,
+    /// FNV offset basis.
+    pub basis: u64,
+    /// Seed of the keystream the hashes are stored XOR.
+    pub key: u64,
+}
+
+/// Injects a startup routine that hashes the code and read-only data and
+/// stops the program when they were patched, optionally again from a
+/// background thread every `interval_secs`.
+///
+/// Works on x64 PE images and ELF executables.
+pub struct IntegrityStep {
+    options: IntegrityOptions,
+}
+
+impl IntegrityStep {
+    pub fn new(options: IntegrityOptions) -> Self {
+        Self { options }
+    }
+}
+
+impl PipelineStep for IntegrityStep {
+    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> Result<(), StepError> {
+        ctx.cancel.check()?;
+        tx.send(PipelineMessage::Log("Setting up the integrity check...".into())).ok();
+
+        let Some(image) = ctx.image.as_mut() else {
+            return Err(StepError::Internal("no image loaded before the integrity check".into()));
+        };
+        if !image.supports_startup_routines() {
+            tx.send(PipelineMessage::Log(
+                "The integrity check runs at startup, which needs an x64 PE image or an ELF executable; skipped".into(),
+            ))
+            .ok();
+            return Ok(());
+        }
+
+        let ranges = if self.options.sections.is_empty() {
+            None
+        } else {
+            let named = image.named_sections();
+            let mut ranges = Vec::new();
+            for name in &self.options.sections {
+                let Some((_, section)) = named.iter().find(|(n, _)| n == name) else {
+                    tx.send(PipelineMessage::Log(format!("Integrity: no section named '{}'", name))).ok();
+                    continue;
+                };
+                if section.characteristics & IMAGE_SCN_MEM_WRITE != 0 {
+                    tx.send(PipelineMessage::Log(format!(
+                        "Integrity: {} is writable, so it cannot be hashed; skipped",
+                        name
+                    )))
+                    .ok();
+                    continue;
+                }
+                ranges.push((name.clone(), section.virtual_address, file_backed_size(section)));
+            }
+            if ranges.is_empty() {
+                tx.send(PipelineMessage::Log("Integrity: nothing to hash".into())).ok();
+                return Ok(());
+            }
+            Some(ranges)
+        };
+        let what = match &ranges {
+            Some(ranges) => ranges.iter().map(|r| r.0.as_str()).collect::<Vec<_>>().join(", "),
+            None => "all read-only sections".into(),
+        };
+
+        if ctx.dry_run {
+            ctx.plan.push(PlannedChange::AddSection {
+                name: INTEGRITY_SECTION.into(),
+                description: format!("integrity checker and hashes of {}", what),
+            });
+            return Ok(());
+        }
+
+        let mut rng = step_rng(ctx.seed, "integrity");
+        image.set_integrity_check(IntegrityCheck {
+            ranges,
+            interval_secs: self.options.interval_secs.filter(|&secs| secs > 0),
+            response: self.options.response,
+            basis: rng.gen(),
+            key: rng.gen(),
+        });
+        tx.send(PipelineMessage::Log(format!(
+            "Integrity check of {} registered{}; hashes are computed when the output is written",
+            what,
+            match self.options.interval_secs {
+                Some(secs) if secs > 0 => format!(", repeated every {} s", secs),
+                _ => String::new(),
+            }
+        )))
+        .ok();
+        Ok(())
+    }
+}
+
+/// Bytes of a section that come from the file; the rest is zero-filled.
+fn file_backed_size(section: &Section) -> u32 {
+    match section.virtual_size {
+        0 => section.raw_size,
+        size => size.min(section.raw_size),
+    }
+}
+
+/// A hashed range split around the excluded bytes.
+struct Hashed {
+    name: String,
+    chunks: Vec<(u32, u32)>,
+    hash: u64,
+}
+
+/// Ranges `finalize` must not hash, as `(rva, size)` sorted by RVA.
+fn excluded(image: &Image) -> Vec<(u32, u32)> {
+    let mut excluded: Vec<(u32, u32)> = image
+        .relocations_in(0, u32::MAX)
+        .into_iter()
+        .map(|(rva, kind)| (rva, reloc::word_size(kind)))
+        .collect();
+    excluded.extend(image.headers());
+    excluded.extend_from_slice(image.runtime_writes());
+    if image.format == ImageFormat::Pe {
+        let (iat, size) = image.data_directory(DIR_IAT);
+        if size > 0 {
+            excluded.push((iat, size));
+        }
+        // CFG check and dispatch pointers, set by the loader
+        let (config, size) = image.data_directory(DIR_LOAD_CONFIG);
+        if size >= 0x80 {
+            for field in [0x70, 0x78] {
+                if let Some(va) = image.read_u64(config + field).filter(|&va| va > image.image_base) {
+                    excluded.push(((va - image.image_base) as u32, 8));
+                }
+            }
+        }
+    }
+    excluded.sort_unstable();
+    excluded
+}
+
+fn hash_ranges(image: &Image, check: &IntegrityCheck) -> Result<Vec<Hashed>, StepError> {
+    let ranges = match &check.ranges {
+        Some(ranges) => ranges.clone(),
+        None if image.format == ImageFormat::Pe => image
+            .named_sections()
+            .into_iter()
+            .filter(|(_, s)| s.characteristics & IMAGE_SCN_MEM_WRITE == 0)
+            .map(|(name, s)| (name, s.virtual_address, file_backed_size(&s)))
+            .collect(),
+        None => image
+            .sections
+            .iter()
+            .filter(|s| s.characteristics & IMAGE_SCN_MEM_WRITE == 0)
+            .map(|s| (format!("segment at 0x{:x}", s.virtual_address), s.virtual_address, file_backed_size(s)))
+            .collect(),
+    };
+    let excluded = excluded(image);
+
+    let mut hashed = Vec::new();
+    for (name, start, size) in ranges {
+        let end = start.checked_add(size).ok_or_else(|| {
+            StepError::InvalidInput(format!("{} at 0x{:x} (+0x{:x} bytes) wraps past 4 GiB", name, start, size))
+        })?;
+        let mut chunks = Vec::new();
+        let mut at = start;
+        for &(rva, len) in excluded.iter().filter(|&&(rva, _)| rva < end) {
+            let excluded_end = rva.checked_add(len).ok_or_else(|| {
+                StepError::InvalidInput(format!("excluded range at 0x{:x} (+0x{:x} bytes) wraps past 4 GiB", rva, len))
+            })?;
+            if excluded_end <= start {
+                continue;
+            }
+            if rva > at {
+                chunks.push((at, rva - at));
+            }
+            at = at.max(excluded_end);
+        }
+        if at < end {
+            chunks.push((at, end - at));
+        }
+        let mut hash = check.basis;
+        for &(rva, len) in &chunks {
+            let bytes = image.read(rva, len as usize).ok_or_else(|| {
+                StepError::Internal(format!("{} is not backed by file data at 0x{:x}", name, rva))
+            })?;
+            for &byte in bytes {
+                hash = (hash ^ byte as u64).wrapping_mul(FNV_PRIME);
+            }
+        }
+        if !chunks.is_empty() {
+            hashed.push(Hashed { name, chunks, hash });
+        }
+    }
+    Ok(hashed)
+}
+
+/// Kernel32 functions the PE checker calls; 0 when the lookup failed.
+struct Apis {
+    exit_process: CodeLabel,
+    create_thread: CodeLabel,
+    sleep: CodeLabel,
+    close_handle: CodeLabel,
+}
+
+/// The checker placed at `rva`, with the hashes of the image as it is now,
+/// and a note describing what it covers.
+pub fn emit_checker(image: &Image, check: &IntegrityCheck, rva: u32) -> Result<(Vec<u8>, String), StepError> {
+    let hashed = hash_ranges(image, check)?;
+    if hashed.is_empty() {
+        return Err(StepError::InvalidInput("The integrity check found nothing to hash".into()));
+    }
+    let format = image.format;
+    let mut a = CodeAssembler::new(64)?;
+    let mut entry = a.create_label();
+    let mut verify = a.create_label();
+    let mut tampered = a.create_label();
+    let mut crash = a.create_label();
+    let mut watcher = a.create_label();
+    let mut done = a.create_label();
+    let mut table = a.create_label();
+    let mut prime = a.create_label();
+    let mut apis = Apis {
+        exit_process: a.create_label(),
+        create_thread: a.create_label(),
+        sleep: a.create_label(),
+        close_handle: a.create_label(),
+    };
+    let hasher = NameHasher::new(HashAlgorithm::default(), [0; 2]);
+    let runtime = (format == ImageFormat::Pe).then(|| Runtime::new(&mut a, hasher));
+
+    // startup routine
+    a.set_label(&mut entry)?;
+    a.push(rbp)?;
+    a.mov(rbp, rsp)?;
+    for register in [rbx, rsi, rdi, r12, r13, r14, r15] {
+        a.push(register)?;
+    }
+    a.and(rsp, -16)?;
+    a.sub(rsp, 0x40)?;
+    if let Some(runtime) = &runtime {
+        let mut resolved = a.create_label();
+        a.mov(ecx, hasher.hash("kernel32.dll"))?;
+        a.call(runtime.find_module)?;
+        a.test(rax, rax)?;
+        a.jz(resolved)?;
+        a.mov(r15, rax)?;
+        let mut wanted = vec![("GetProcAddress", runtime.get_proc_address), ("ExitProcess", apis.exit_process)];
+        if check.interval_secs.is_some() {
+            wanted.push(("CreateThread", apis.create_thread));
+            wanted.push(("Sleep", apis.sleep));
+            wanted.push(("CloseHandle", apis.close_handle));
+        }
+        for (name, slot) in wanted {
+            a.mov(rcx, r15)?;
+            a.mov(edx, hasher.hash(name))?;
+            a.call(runtime.find_export)?;
+            a.mov(qword_ptr(slot), rax)?;
+        }
+        a.set_label(&mut resolved)?;
+    }
+    a.call(verify)?;
+    a.test(eax, eax)?;
+    a.jnz(tampered)?;
+    if check.interval_secs.is_some() {
+        if format == ImageFormat::Pe {
+            // CreateThread(NULL, 0, watcher, NULL, 0, NULL), then close the handle
+            for slot in [apis.create_thread, apis.sleep, apis.close_handle] {
+                a.cmp(qword_ptr(slot), 0)?;
+                a.je(done)?;
+            }
+            a.xor(ecx, ecx)?;
+            a.xor(edx, edx)?;
+            a.lea(r8, ptr(watcher))?;
+            a.xor(r9d, r9d)?;
+            a.mov(qword_ptr(rsp + 0x20), 0)?;
+            a.mov(qword_ptr(rsp + 0x28), 0)?;
+            a.call(qword_ptr(apis.create_thread))?;
+            a.test(rax, rax)?;
+            a.jz(done)?;
+            a.mov(rcx, rax)?;
+            a.call(qword_ptr(apis.close_handle))?;
+        } else {
+            // a thread of its own, unknown to libc, on an anonymous stack
+            a.xor(edi, edi)?;
+            a.mov(esi, WATCHER_STACK)?;
+            // PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS
+            a.mov(edx, 3)?;
+            a.mov(r10d, 0x22)?;
+            a.mov(r8, -1i64)?;
+            a.xor(r9d, r9d)?;
+            a.mov(eax, SYS_MMAP)?;
+            a.syscall()?;
+            a.cmp(rax, -4095)?;
+            a.jae(done)?;
+            a.lea(rsi, ptr(rax + WATCHER_STACK as i32))?;
+            a.mov(edi, CLONE_THREAD_FLAGS)?;
+            a.xor(edx, edx)?;
+            a.xor(r10d, r10d)?;
+            a.xor(r8d, r8d)?;
+            a.mov(eax, SYS_CLONE)?;
+            a.syscall()?;
+            a.test(rax, rax)?;
+            a.jz(watcher)?;
+        }
+    }
+    a.set_label(&mut done)?;
+    a.lea(rsp, ptr(rbp - 7 * 8))?;
+    for register in [r15, r14, r13, r12, rdi, rsi, rbx] {
+        a.pop(register)?;
+    }
+    a.pop(rbp)?;
+    a.ret()?;
+
+    // watcher thread: never returns
+    if let Some(secs) = check.interval_secs {
+        let mut again = a.create_label();
+        a.set_label(&mut watcher)?;
+        if format == ImageFormat::Pe {
+            a.sub(rsp, 0x28)?;
+            a.set_label(&mut again)?;
+            a.mov(ecx, secs.saturating_mul(1000))?;
+            a.call(qword_ptr(apis.sleep))?;
+        } else {
+            a.sub(rsp, 0x10)?;
+            a.set_label(&mut again)?;
+            a.mov(qword_ptr(rsp), secs as i32)?;
+            a.mov(qword_ptr(rsp + 8), 0)?;
+            a.mov(rdi, rsp)?;
+            a.xor(esi, esi)?;
+            a.mov(eax, SYS_NANOSLEEP)?;
+            a.syscall()?;
+        }
+        a.call(verify)?;
+        a.test(eax, eax)?;
+        a.jz(again)?;
+    }
+
+    a.set_label(&mut tampered)?;
+    match check.response {
+        TamperResponse::Exit if format == ImageFormat::Pe => {
+            a.mov(rax, qword_ptr(apis.exit_process))?;
+            a.test(rax, rax)?;
+            a.jz(crash)?;
+            a.mov(ecx, EXIT_TAMPERED)?;
+            a.call(rax)?;
+        }
+        TamperResponse::Exit => {
+            a.mov(edi, EXIT_TAMPERED)?;
+            a.mov(eax, SYS_EXIT_GROUP)?;
+            a.syscall()?;
+        }
+        TamperResponse::Crash => {}
+    }
+    if check.response != TamperResponse::Crash {
+        a.set_label(&mut crash)?;
+    }
+    a.ud2()?;
+
+    // verify: eax = 0 when every range matches; clobbers the volatile registers
+    {
+        let mut range = a.create_label();
+        let mut chunk = a.create_label();
+        let mut byte = a.create_label();
+        let mut compare = a.create_label();
+        let mut matched = a.create_label();
+        let mut mismatch = a.create_label();
+        a.set_label(&mut verify)?;
+        a.push(rbx)?;
+        a.lea(rbx, ptr(entry))?;
+        a.sub(rbx, rva as i32)?;
+        a.lea(rsi, ptr(table))?;
+        a.mov(r8, qword_ptr(rsi + 8))?;
+        a.mov(r9d, dword_ptr(rsi + 16))?;
+        a.add(rsi, 24)?;
+        a.set_label(&mut range)?;
+        a.test(r9d, r9d)?;
+        a.jz(matched)?;
+        a.mov(rax, qword_ptr(table))?;
+        a.mov(r11, qword_ptr(rsi))?;
+        a.mov(r10d, dword_ptr(rsi + 8))?;
+        a.add(rsi, 16)?;
+        a.set_label(&mut chunk)?;
+        a.test(r10d, r10d)?;
+        a.jz(compare)?;
+        a.mov(edi, dword_ptr(rsi))?;
+        a.add(rdi, rbx)?;
+        a.mov(ecx, dword_ptr(rsi + 4))?;
+        a.add(rsi, 8)?;
+        a.dec(r10d)?;
+        a.set_label(&mut byte)?;
+        a.test(ecx, ecx)?;
+        a.jz(chunk)?;
+        a.movzx(edx, byte_ptr(rdi))?;
+        a.xor(rax, rdx)?;
+        a.imul_2(rax, qword_ptr(prime))?;
+        a.inc(rdi)?;
+        a.dec(ecx)?;
+        a.jmp(byte)?;
+        a.set_label(&mut compare)?;
+        a.mov(rdx, KEYSTREAM_MUL)?;
+        a.imul_2(r8, rdx)?;
+        a.mov(rdx, KEYSTREAM_INC)?;
+        a.add(r8, rdx)?;
+        a.xor(r11, r8)?;
+        a.cmp(rax, r11)?;
+        a.jne(mismatch)?;
+        a.dec(r9d)?;
+        a.jmp(range)?;
+        a.set_label(&mut matched)?;
+        a.xor(eax, eax)?;
+        a.pop(rbx)?;
+        a.ret()?;
+        a.set_label(&mut mismatch)?;
+        a.mov(eax, 1)?;
+        a.pop(rbx)?;
+        a.ret()?;
+    }
+
+    if let Some(runtime) = runtime {
+        runtime.emit(&mut a)?;
+    }
+    a.set_label(&mut prime)?;
+    a.dq(&[FNV_PRIME])?;
+    for slot in [
+        &mut apis.exit_process,
+        &mut apis.create_thread,
+        &mut apis.sleep,
+        &mut apis.close_handle,
+    ] {
+        a.set_label(slot)?;
+        a.dq(&[0])?;
+    }
+
+    // table: basis, keystream seed, range count, then per range the stored
+    // hash, the chunk count and the chunks as (rva, size)
+    a.set_label(&mut table)?;
+    a.dq(&[check.basis, check.key])?;
+    a.dd(&[hashed.len() as u32, 0])?;
+    let mut state = check.key;
+    for range in &hashed {
+        state = state.wrapping_mul(KEYSTREAM_MUL).wrapping_add(KEYSTREAM_INC);
+        a.dq(&[range.hash ^ state])?;
+        a.dd(&[range.chunks.len() as u32, 0])?;
+        for &(rva, size) in &range.chunks {
+            a.dd(&[rva, size])?;
+        }
+    }
+
+    let code = a.assemble(image.image_base + rva as u64)?;
+    let bytes: u32 = hashed.iter().flat_map(|h| &h.chunks).map(|c| c.1).sum();
+    let note = format!(
+        "Integrity check in {} over {} bytes of {}{}",
+        INTEGRITY_SECTION,
+        bytes,
+        hashed.iter().map(|h| h.name.as_str()).collect::<Vec<_>>().join(", "),
+        match check.interval_secs {
+            Some(secs) => format!(", repeated every {} s", secs),
+            None => String::new(),
+        }
+    );
+    Ok((code, note))
+}
+
+#[cfg(all(test, target_os = "linux", target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::process::Command;
+
+    use crate::pipeline::cancel::CancellationToken;
+
+    /// The protected file and the file offset of a hashed byte in the
+    /// original entry point.
+    fn protect_file(path: &Path) -> Option<(Vec<u8>, usize)> {
+        let bytes = std::fs::read(path).ok()?;
+        let image = Image::parse(bytes).ok()?;
+        if image.format != ImageFormat::Elf || image.is_dll {
+            return None;
+        }
+        let entry = image.entry;
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut ctx = PipelineContext::new(path.display().to_string(), CancellationToken::new());
+        ctx.seed = 7;
+        ctx.image = Some(image);
+        IntegrityStep::new(IntegrityOptions { enabled: true, ..Default::default() }).run(&mut ctx, &tx).unwrap();
+        drop(tx);
+        assert!(rx.iter().any(|m| matches!(m, PipelineMessage::Log(line) if line.contains("registered"))));
+        let mut image = ctx.image.take().unwrap();
+        image.finalize().unwrap();
+        let offset = image.rva_to_offset(entry + 2)?;
+        Some((image.into_bytes(), offset))
+    }
+
+    #[test]
+    fn checker_accepts_the_written_hashes_and_rejects_a_patch() {
+        let dir = std::env::temp_dir().join(format!("obscura-integrity-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut checked_any = false;
+        for (path, args) in [("/bin/true", &[][..]), ("/bin/ls", &["/"][..]), ("/bin/cat", &["/etc/hostname"][..])] {
+            let Some((mut protected, offset)) = protect_file(Path::new(path)) else {
+                continue;
+            };
+            let target = dir.join(Path::new(path).file_name().unwrap());
+            let run = |bytes: &[u8]| {
+                std::fs::write(&target, bytes).unwrap();
+                std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o755)).unwrap();
+                Command::new(&target).args(args).output().unwrap()
+            };
+            let expected = Command::new(path).args(args).output().unwrap();
+            let actual = run(&protected);
+            assert_eq!(actual.status.code(), expected.status.code(), "{}", path);
+            assert_eq!(actual.stdout, expected.stdout, "{}", path);
+
+            protected[offset] ^= 0x40;
+            let patched = run(&protected);
+            assert_eq!(patched.status.code(), Some(EXIT_TAMPERED as i32), "{}: patch not detected", path);
+            assert!(patched.stdout.is_empty(), "{}", path);
+            checked_any = true;
+        }
+        std::fs::remove_dir_all(&dir).ok();
+        assert!(checked_any, "no ELF test binary found");
+    }
+}
@@ -0,0 +1,313 @@
+//! Minidump (`MDMP`) reader for `obscura symbolicate`.
+//!
+//! Reads the streams needed to rebuild a stack: system info (the CPU
+//! architecture), the module list with CodeView records, the threads with
+//! their stack memory and register context, the exception record, and the
+//! memory lists. Windows and Breakpad/Crashpad dumps share the layout, and
+//! Breakpad's x86 and x64 contexts match Windows' `CONTEXT`.
+
+use crate::pipeline::error::StepError;
+use crate::pipeline::pe::{read_u16, read_u32};
+
+const SIGNATURE: u32 = 0x504d_444d; // "MDMP"
+const CODEVIEW_RSDS: u32 = 0x5344_5352; // "RSDS"
+
+const THREAD_LIST_STREAM: u32 = 3;
+const MODULE_LIST_STREAM: u32 = 4;
+const MEMORY_LIST_STREAM: u32 = 5;
+const EXCEPTION_STREAM: u32 = 6;
+const SYSTEM_INFO_STREAM: u32 = 7;
+const MEMORY64_LIST_STREAM: u32 = 9;
+
+const ARCH_X86: u16 = 0;
+const ARCH_AMD64: u16 = 9;
+
+const THREAD_SIZE: usize = 48;
+const MODULE_SIZE: usize = 108;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Architecture {
+    X86,
+    Amd64,
+}
+
+/// The registers a stack walk starts from.
+#[derive(Debug, Clone, Copy)]
+pub struct Context {
+    pub ip: u64,
+    pub sp: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub name: String,
+    pub base: u64,
+    pub size: u32,
+    pub codeview: Option<CodeView>,
+}
+
+impl Module {
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.base && address - self.base < self.size as u64
+    }
+}
+
+/// RSDS CodeView record: which PDB describes the module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeView {
+    pub guid: [u8; 16],
+    pub age: u32,
+    pub pdb: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Thread {
+    pub id: u32,
+    pub context: Option<Context>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Exception {
+    pub thread_id: u32,
+    pub code: u32,
+    pub address: u64,
+    /// Context at the fault, which the thread's own context no longer shows.
+    pub context: Option<Context>,
+}
+
+pub struct Minidump {
+    bytes: Vec<u8>,
+    pub architecture: Architecture,
+    pub modules: Vec<Module>,
+    pub threads: Vec<Thread>,
+    pub exception: Option<Exception>,
+    /// Captured memory as `(address, file offset, size)`.
+    memory: Vec<(u64, usize, usize)>,
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> Option<u64> {
+    bytes.get(at..at + 8).map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn invalid(what: impl std::fmt::Display) -> StepError {
+    StepError::InvalidInput(format!("Malformed minidump: {}", what))
+}
+
+impl Minidump {
+    pub fn parse(bytes: Vec<u8>) -> Result<Self, StepError> {
+        if read_u32(&bytes, 0) != Some(SIGNATURE) {
+            return Err(StepError::UnsupportedFormat("not a minidump (no MDMP signature)".into()));
+        }
+        let count = read_u32(&bytes, 8).ok_or_else(|| invalid("truncated header"))? as usize;
+        let directory = read_u32(&bytes, 12).ok_or_else(|| invalid("truncated header"))? as usize;
+        let mut streams = Vec::new();
+        for i in 0..count {
+            let at = directory + i * 12;
+            let (Some(kind), Some(size), Some(rva)) =
+                (read_u32(&bytes, at), read_u32(&bytes, at + 4), read_u32(&bytes, at + 8))
+            else {
+                return Err(invalid("truncated stream directory"));
+            };
+            if bytes.len() < rva as usize + size as usize {
+                return Err(invalid(format!("stream {} runs past the end of the file", kind)));
+            }
+            streams.push((kind, rva as usize, size as usize));
+        }
+        let stream = |kind: u32| streams.iter().find(|s| s.0 == kind).map(|&(_, rva, size)| (rva, size));
+
+        let architecture = match stream(SYSTEM_INFO_STREAM).and_then(|(at, _)| read_u16(&bytes, at)) {
+            Some(ARCH_AMD64) => Architecture::Amd64,
+            Some(ARCH_X86) => Architecture::X86,
+            Some(other) => {
+                return Err(StepError::UnsupportedFormat(format!(
+                    "minidump of processor architecture {}; only x86 and x64 are supported",
+                    other
+                )))
+            }
+            None => return Err(invalid("no system info stream")),
+        };
+
+        let mut dump = Minidump {
+            architecture,
+            modules: Vec::new(),
+            threads: Vec::new(),
+            exception: None,
+            memory: Vec::new(),
+            bytes,
+        };
+        if let Some((at, _)) = stream(MODULE_LIST_STREAM) {
+            dump.modules = dump.read_modules(at)?;
+        }
+        if let Some((at, _)) = stream(MEMORY_LIST_STREAM) {
+            dump.read_memory_list(at)?;
+        }
+        if let Some((at, _)) = stream(MEMORY64_LIST_STREAM) {
+            dump.read_memory64_list(at)?;
+        }
+        if let Some((at, _)) = stream(THREAD_LIST_STREAM) {
+            dump.threads = dump.read_threads(at)?;
+        }
+        if let Some((at, _)) = stream(EXCEPTION_STREAM) {
+            let field = |offset: usize| read_u32(&dump.bytes, at + offset).ok_or_else(|| invalid("truncated exception stream"));
+            dump.exception = Some(Exception {
+                thread_id: field(0)?,
+                code: field(8)?,
+                address: read_u64(&dump.bytes, at + 24).ok_or_else(|| invalid("truncated exception stream"))?,
+                context: dump.context(field(160)? as usize, field(164)? as usize),
+            });
+        }
+        Ok(dump)
+    }
+
+    fn read_modules(&self, at: usize) -> Result<Vec<Module>, StepError> {
+        let bytes = &self.bytes;
+        let count = read_u32(bytes, at).ok_or_else(|| invalid("truncated module list"))? as usize;
+        let mut modules = Vec::new();
+        for i in 0..count {
+            let entry = at + 4 + i * MODULE_SIZE;
+            let (Some(base), Some(size), Some(name_rva)) =
+                (read_u64(bytes, entry), read_u32(bytes, entry + 8), read_u32(bytes, entry + 20))
+            else {
+                return Err(invalid("truncated module list"));
+            };
+            let codeview = match (read_u32(bytes, entry + 76), read_u32(bytes, entry + 80)) {
+                (Some(size), Some(rva)) if size >= 24 => self.codeview(rva as usize, size as usize),
+                _ => None,
+            };
+            modules.push(Module {
+                name: self.string(name_rva as usize),
+                base,
+                size,
+                codeview,
+            });
+        }
+        Ok(modules)
+    }
+
+    fn read_memory_list(&mut self, at: usize) -> Result<(), StepError> {
+        let count = read_u32(&self.bytes, at).ok_or_else(|| invalid("truncated memory list"))? as usize;
+        for i in 0..count {
+            let entry = at + 4 + i * 16;
+            let (Some(start), Some(size), Some(rva)) =
+                (read_u64(&self.bytes, entry), read_u32(&self.bytes, entry + 8), read_u32(&self.bytes, entry + 12))
+            else {
+                return Err(invalid("truncated memory list"));
+            };
+            self.add_memory(start, rva as usize, size as usize);
+        }
+        Ok(())
+    }
+
+    fn read_memory64_list(&mut self, at: usize) -> Result<(), StepError> {
+        let (Some(count), Some(base)) = (read_u64(&self.bytes, at), read_u64(&self.bytes, at + 8)) else {
+            return Err(invalid("truncated memory64 list"));
+        };
+        let mut offset = base as usize;
+        for i in 0..count as usize {
+            let entry = at + 16 + i * 16;
+            let (Some(start), Some(size)) = (read_u64(&self.bytes, entry), read_u64(&self.bytes, entry + 8)) else {
+                return Err(invalid("truncated memory64 list"));
+            };
+            self.add_memory(start, offset, size as usize);
+            offset = offset
+                .checked_add(size as usize)
+                .ok_or_else(|| invalid("memory64 list runs past 2^64 bytes"))?;
+        }
+        Ok(())
+    }
+
+    fn read_threads(&mut self, at: usize) -> Result<Vec<Thread>, StepError> {
+        let count = read_u32(&self.bytes, at).ok_or_else(|| invalid("truncated thread list"))? as usize;
+        let mut threads = Vec::new();
+        for i in 0..count {
+            let entry = at + 4 + i * THREAD_SIZE;
+            let field = |offset: usize| read_u32(&self.bytes, entry + offset).ok_or_else(|| invalid("truncated thread list"));
+            let stack_start = read_u64(&self.bytes, entry + 24).ok_or_else(|| invalid("truncated thread list"))?;
+            let (stack_size, stack_rva) = (field(32)? as usize, field(36)? as usize);
+            let context = self.context(field(40)? as usize, field(44)? as usize);
+            threads.push(Thread { id: field(0)?, context });
+            // stacks are usually in the memory list too; this covers dumps where they are not
+            self.add_memory(stack_start, stack_rva, stack_size);
+        }
+        Ok(threads)
+    }
+
+    fn add_memory(&mut self, start: u64, offset: usize, size: usize) {
+        let in_file = offset.checked_add(size).is_some_and(|end| end <= self.bytes.len());
+        if size > 0 && in_file && !self.memory.iter().any(|m| m.0 == start && m.2 == size) {
+            self.memory.push((start, offset, size));
+        }
+    }
+
+    /// Instruction and stack pointers of a `CONTEXT` record.
+    fn context(&self, size: usize, at: usize) -> Option<Context> {
+        let (ip, sp) = match self.architecture {
+            Architecture::Amd64 if size >= 0x100 => (read_u64(&self.bytes, at + 0xf8)?, read_u64(&self.bytes, at + 0x98)?),
+            Architecture::X86 if size >= 0xc8 => (
+                read_u32(&self.bytes, at + 0xb8)? as u64,
+                read_u32(&self.bytes, at + 0xc4)? as u64,
+            ),
+            _ => return None,
+        };
+        Some(Context { ip, sp })
+    }
+
+    /// A `MINIDUMP_STRING`: byte length, then UTF-16.
+    fn string(&self, at: usize) -> String {
+        let len = read_u32(&self.bytes, at).unwrap_or(0) as usize / 2;
+        let units: Vec<u16> = (0..len).map_while(|i| read_u16(&self.bytes, at + 4 + i * 2)).collect();
+        String::from_utf16_lossy(&units)
+    }
+
+    fn codeview(&self, at: usize, size: usize) -> Option<CodeView> {
+        if read_u32(&self.bytes, at)? != CODEVIEW_RSDS {
+            return None;
+        }
+        let record = self.bytes.get(at..at + size)?;
+        let path = &record[24..];
+        let end = path.iter().position(|&b| b == 0).unwrap_or(path.len());
+        Some(CodeView {
+            guid: record[4..20].try_into().unwrap(),
+            age: read_u32(record, 20)?,
+            pdb: String::from_utf8_lossy(&path[..end]).into_owned(),
+        })
+    }
+
+    /// `len` bytes of captured memory at `address`.
+    pub fn read(&self, address: u64, len: usize) -> Option<&[u8]> {
+        self.memory.iter().find_map(|&(start, offset, size)| {
+            let skip = usize::try_from(address.checked_sub(start)?).ok()?;
+            (skip.checked_add(len)? <= size).then(|| &self.bytes[offset + skip..offset + skip + len])
+        })
+    }
+
+    pub fn module_at(&self, address: u64) -> Option<&Module> {
+        self.modules.iter().find(|m| m.contains(address))
+    }
+
+    /// Thread context, or the context of the exception for the thread that raised it.
+    pub fn thread_context(&self, thread: &Thread) -> Option<Context> {
+        match &self.exception {
+            Some(e) if e.thread_id == thread.id && e.context.is_some() => e.context,
+            _ => thread.context,
+        }
+    }
+}
+
+/// Name of the common Windows exception codes.
+pub fn exception_name(code: u32) -> Option<&'static str> {
+    Some(match code {
+        0x8000_0003 => "EXCEPTION_BREAKPOINT",
+        0x8000_0004 => "EXCEPTION_SINGLE_STEP",
+        0xc000_0005 => "EXCEPTION_ACCESS_VIOLATION",
+        0xc000_001d => "EXCEPTION_ILLEGAL_INSTRUCTION",
+        0xc000_0094 => "EXCEPTION_INT_DIVIDE_BY_ZERO",
+        0xc000_0096 => "EXCEPTION_PRIV_INSTRUCTION",
+        0xc000_00fd => "EXCEPTION_STACK_OVERFLOW",
+        0xc000_0135 => "STATUS_DLL_NOT_FOUND",
+        0xc000_0139 => "STATUS_ENTRYPOINT_NOT_FOUND",
+        0xc000_0409 => "STATUS_STACK_BUFFER_OVERRUN",
+        0xe06d_7363 => "C++ exception",
+        _ => return None,
+    })
+}
@@ -0,0 +1,61 @@
+use std::sync::mpsc::Sender;
+
+use crate::pipeline::{PipelineContext, PipelineMessage};
+use crate::pipeline::error::StepError;
+use crate::pipeline::plan::PlannedChange;
+use crate::pipeline::reassemble::CODE_SECTION;
+use crate::pipeline::step::PipelineStep;
+
+/// Moves every movable function into the reassembled code section without
+/// changing its instructions. Checks the disassembly and reassembly round
+/// trip on a real binary before any obfuscation pass is involved.
+pub struct MoveFunctionsStep;
+
+impl MoveFunctionsStep {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl PipelineStep for MoveFunctionsStep {
+    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> Result<(), StepError> {
+        ctx.cancel.check()?;
+        let Some(image) = ctx.image.as_mut() else {
+            return Err(StepError::Internal("no image loaded before moving functions".into()));
+        };
+        let code = image.code_map();
+
+        let mut moved = 0;
+        for index in 0..code.functions.len() {
+            let name = code.functions[index].name();
+            if let Err(reason) = code.check_movable(index) {
+                tx.send(PipelineMessage::Log(format!("Not moving {}: {}", name, reason))).ok();
+                continue;
+            }
+            if ctx.dry_run {
+                ctx.plan.push(PlannedChange::TransformFunction {
+                    function: name,
+                    rva: code.functions[index].info.rva,
+                    transform: "move".into(),
+                });
+            } else {
+                code.functions[index].modified = true;
+            }
+            moved += 1;
+        }
+        if ctx.dry_run && moved > 0 {
+            ctx.plan.push_once(PlannedChange::AddSection {
+                name: CODE_SECTION.into(),
+                description: "relocated function bodies".into(),
+            });
+        }
+        tx.send(PipelineMessage::Log(format!(
+            "{} of {} functions will be moved to {}",
+            moved,
+            code.functions.len(),
+            CODE_SECTION
+        )))
+        .ok();
+        Ok(())
+    }
+}
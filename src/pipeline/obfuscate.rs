@@ -1,16 +1,16 @@
-use std::fs;
 use std::sync::mpsc::Sender;
-use std::sync::atomic::Ordering;
 use std::time::Duration;
 
 use crate::pipeline::{PipelineContext, PipelineMessage};
 use crate::pipeline::step::PipelineStep;
-
-use goblin::Object;
+use crate::pipeline::error::StepError;
+use crate::pipeline::plan::PlannedChange;
+use crate::pipeline::symbol_map::{MappedFunction, SymbolMap};
 
 /// Mock initial obfuscation step.
-/// - Detects "functions" by looking at exports when available (fallback: simulated count)
-/// - Produces a fake renaming mapping and writes `<input>.obf-map` as proof-of-concept
+/// - Lists the functions found by the disassembler (`Image::code_map`)
+/// - Produces a fake renaming mapping, which `WriteOutputStep` writes as the
+///   `<input>.obf-map` artifact with the final locations (see `symbol_map`)
 pub struct ObfuscateFunctionsStep;
 
 impl ObfuscateFunctionsStep {
@@ -20,54 +20,45 @@ impl ObfuscateFunctionsStep {
 }
 
 impl PipelineStep for ObfuscateFunctionsStep {
-    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> anyhow::Result<()> {
+    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> Result<(), StepError> {
         tx.send(PipelineMessage::Log("Obfuscation step (mock) started".into())).ok();
         tx.send(PipelineMessage::Progress(0.45)).ok();
         std::thread::sleep(Duration::from_millis(160));
 
-        // read file bytes
-        let bytes = fs::read(&ctx.input_path).map_err(|e| {
-            anyhow::anyhow!("Obfuscation step: failed to read '{}': {}", ctx.input_path, e)
-        })?;
-
-        // attempt to detect functions: prefer exports count as a naive proxy
-        let mut function_names: Vec<String> = Vec::new();
+        // functions discovered in the code (exception data, exports, entry point, calls), as (name, rva, size)
+        let mut functions: Vec<(String, u32, u32)> = Vec::new();
+        let mut image_base = 0;
 
-        match Object::parse(&bytes) {
-            Ok(Object::PE(pe)) => {
-                if !pe.exports.is_empty() {
-                    for (i, exp) in pe.exports.iter().enumerate() {
-                        let name = exp
-                            .name
-                            .as_ref()
-                            .map(|s| s.to_string())
-                            .unwrap_or_else(|| format!("export_{}", i));
-                        function_names.push(name);
-                    }
-                    tx.send(PipelineMessage::Log(format!(
-                        "Obfuscation: detected {} exported functions (using exports as proxy)",
-                        function_names.len()
-                    )))
-                    .ok();
-                } else {
-                    let approx = (pe.sections.len() * 2).saturating_sub(1);
-                    for i in 0..approx {
-                        function_names.push(format!("func_approx_{}", i + 1));
-                    }
+        match ctx.image.as_mut() {
+            Some(image) => {
+                image_base = image.image_base;
+                let code = image.code_map();
+                functions.extend(code.functions.iter().map(|f| (f.name(), f.info.rva, f.info.size)));
+                let named = code.functions.iter().filter(|f| f.info.name.is_some()).count();
+                tx.send(PipelineMessage::Log(format!(
+                    "Obfuscation: discovered {} functions ({} named, {} could not be disassembled)",
+                    code.functions.len(),
+                    named,
+                    code.skipped.len()
+                )))
+                .ok();
+                for (info, reason) in &code.skipped {
                     tx.send(PipelineMessage::Log(format!(
-                        "Obfuscation: no exports found; using heuristic => {} functions (mock)",
-                        function_names.len()
+                        "Obfuscation: skipped {} at 0x{:x}: {}",
+                        info.display_name(),
+                        info.rva,
+                        reason
                     )))
                     .ok();
                 }
             }
-            Ok(_) | Err(_) => {
+            None => {
                 let simulated = 8usize;
                 for i in 0..simulated {
-                    function_names.push(format!("sim_func_{}", i + 1));
+                    functions.push((format!("sim_func_{}", i + 1), 0, 0));
                 }
                 tx.send(PipelineMessage::Log(format!(
-                    "Obfuscation: file not parsed as PE; simulating {} functions (mock)",
+                    "Obfuscation: no parsed image; simulating {} functions (mock)",
                     simulated
                 )))
                 .ok();
@@ -75,40 +66,50 @@ impl PipelineStep for ObfuscateFunctionsStep {
         }
 
         // Simulate renaming: generate mapping old -> new
-        let total = function_names.len();
-        let mut mapping_lines: Vec<String> = Vec::with_capacity(total);
-        for (i, old) in function_names.iter().enumerate() {
+        let total = functions.len();
+        let mut mapping: Vec<MappedFunction> = Vec::with_capacity(total);
+        for (i, (old, rva, size)) in functions.into_iter().enumerate() {
             // verificar cancelamento
-            if ctx.cancel_flag.load(Ordering::Relaxed) {
-                let _ = tx.send(PipelineMessage::Cancelled);
-                return Ok(());
-            }
+            ctx.cancel.check()?;
             let new = format!("f_{:04}", i + 1);
-            mapping_lines.push(format!("{} => {}", old, new));
+            if ctx.dry_run {
+                ctx.plan.push(PlannedChange::RenameFunction {
+                    old: old.clone(),
+                    new: new.clone(),
+                });
+            }
+            mapping.push(MappedFunction {
+                name: old,
+                obfuscated: new,
+                rva,
+                size,
+                moved_to: None,
+            });
 
             let p = 0.45 + (i as f32 + 1.0) / (total.max(1) as f32) * 0.25;
             tx.send(PipelineMessage::Progress(p.min(0.75))).ok();
-            std::thread::sleep(Duration::from_millis(80));
         }
 
-        // Write mapping file next to input (PoC)
-        let map_path = format!("{}.obf-map", ctx.input_path);
-        match fs::write(&map_path, mapping_lines.join("\n")) {
-            Ok(_) => {
-                tx.send(PipelineMessage::Log(format!(
-                    "Obfuscation (mock): wrote mapping file {} ({} entries)",
-                    map_path, total
-                )))
-                .ok();
-            }
-            Err(e) => {
-                tx.send(PipelineMessage::Log(format!(
-                    "Obfuscation (mock): failed to write mapping file: {}",
-                    e
-                )))
-                .ok();
-            }
+        // Mapping file in the artifacts directory, written with the output
+        let map_path = ctx.artifacts.path(&ctx.input_path, "obf-map")?.display().to_string();
+        if ctx.dry_run {
+            ctx.plan.push(PlannedChange::WriteFile {
+                path: map_path,
+                description: format!("function mapping, {} entries", total),
+            });
+            tx.send(PipelineMessage::Log(format!(
+                "Dry run: {} functions planned for renaming, nothing written",
+                total
+            )))
+            .ok();
+            return Ok(());
         }
+        ctx.symbol_map = Some(SymbolMap::new(image_base, mapping));
+        tx.send(PipelineMessage::Log(format!(
+            "Obfuscation (mock): mapping of {} functions is written to {} with the output",
+            total, map_path
+        )))
+        .ok();
 
         tx.send(PipelineMessage::Log(format!("Obfuscated {} functions (mock)", total))).ok();
         tx.send(PipelineMessage::Progress(0.75)).ok();
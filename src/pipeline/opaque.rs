@@ -0,0 +1,410 @@
+use std::collections::HashSet;
+use std::sync::mpsc::Sender;
+
+use iced_x86::{Code, IcedError, Instruction, MemoryOperand, Register};
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::pipeline::{PipelineContext, PipelineMessage};
+use crate::pipeline::code::CodeMap;
+use crate::pipeline::error::StepError;
+use crate::pipeline::image::ImageFormat;
+use crate::pipeline::plan::PlannedChange;
+use crate::pipeline::profile::{step_rng, OpaqueOptions};
+use crate::pipeline::reassemble::CODE_SECTION;
+use crate::pipeline::step::PipelineStep;
+
+/// Registers the junk code works on (saved and restored around it).
+const SCRATCH: [Register; 10] = [
+    Register::RAX,
+    Register::RCX,
+    Register::RDX,
+    Register::RBX,
+    Register::RSI,
+    Register::RDI,
+    Register::R8,
+    Register::R9,
+    Register::R10,
+    Register::R11,
+];
+
+/// Opcode prefixes placed after an always-taken branch: a linear disassembler
+/// decodes them as the start of a longer instruction and loses the real
+/// instruction boundaries that follow.
+const DESYNC_BYTES: [&[u8]; 6] = [&[0xe8], &[0xe9], &[0x48, 0xb8], &[0x0f, 0x84], &[0xff, 0x15], &[0xc7, 0x05]];
+
+/// Inserts opaque predicates and dead junk sequences into the functions
+/// selected in the profile.
+///
+/// A predicate computes a value known at build time from whatever `rax`
+/// holds (`x * (x + 1)` is even, `x * x mod 4` is 0 or 1, `2x + 1` is odd)
+/// and branches on it: either never taken, to bogus code that jumps into
+/// the middle of the function, or always taken, over bytes that break
+/// linear disassembly. Junk sequences compute on a saved register and
+/// restore it. Both save the flags and the registers they use on the stack
+/// (x64 only; Windows has no red zone below `rsp`), and neither goes into
+/// the prologue or the epilogue, whose layout the unwinder relies on.
+pub struct OpaquePredicatesStep {
+    options: OpaqueOptions,
+}
+
+impl OpaquePredicatesStep {
+    pub fn new(options: OpaqueOptions) -> Self {
+        Self { options }
+    }
+}
+
+impl PipelineStep for OpaquePredicatesStep {
+    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> Result<(), StepError> {
+        ctx.cancel.check()?;
+        tx.send(PipelineMessage::Log("Inserting opaque predicates and junk code...".into())).ok();
+
+        let Some(image) = ctx.image.as_mut() else {
+            return Err(StepError::Internal("no image loaded before opaque predicate insertion".into()));
+        };
+        if !image.is_64 || image.format != ImageFormat::Pe {
+            tx.send(PipelineMessage::Log(
+                "Opaque predicates support x64 PE images only; skipped".into(),
+            ))
+            .ok();
+            return Ok(());
+        }
+        let code = image.code_map();
+        let (selected, unmatched) = code.select(&self.options.functions);
+        for selector in unmatched {
+            tx.send(PipelineMessage::Log(format!("Opaque predicates: no function matches '{}'", selector))).ok();
+        }
+
+        let mut rng = step_rng(ctx.seed, "opaque");
+        let density = self.options.density.clamp(0.0, 1.0);
+        let mut changed = 0;
+        for index in selected {
+            ctx.cancel.check()?;
+            let name = code.functions[index].name();
+            if let Err(reason) = code.check_movable(index) {
+                tx.send(PipelineMessage::Log(format!("No opaque predicates in {}: {}", name, reason))).ok();
+                continue;
+            }
+            let mut inserter = Inserter {
+                predicates: 0,
+                junk: 0,
+                bogus: Vec::new(),
+            };
+            let instructions = inserter.run(code, index, &self.options, density, &mut rng)?;
+            if inserter.predicates + inserter.junk == 0 {
+                continue;
+            }
+            if ctx.dry_run {
+                ctx.plan.push(PlannedChange::TransformFunction {
+                    function: name.clone(),
+                    rva: code.functions[index].info.rva,
+                    transform: "opaque predicates".into(),
+                });
+            } else {
+                let function = &mut code.functions[index];
+                function.instructions = instructions;
+                function.rebuild_blocks();
+                function.modified = true;
+            }
+            tx.send(PipelineMessage::Log(format!(
+                "{}: {} opaque predicates, {} junk sequences",
+                name, inserter.predicates, inserter.junk
+            )))
+            .ok();
+            changed += 1;
+        }
+        if ctx.dry_run && changed > 0 {
+            ctx.plan.push_once(PlannedChange::AddSection {
+                name: CODE_SECTION.into(),
+                description: "relocated function bodies".into(),
+            });
+        }
+        tx.send(PipelineMessage::Log(format!("Opaque predicates: {} functions changed", changed))).ok();
+        Ok(())
+    }
+}
+
+struct Inserter {
+    predicates: usize,
+    junk: usize,
+    /// Never executed code the false predicates branch to, appended after the function.
+    bogus: Vec<Instruction>,
+}
+
+impl Inserter {
+    /// The instructions of the function at `index` with the insertions.
+    fn run(
+        &mut self,
+        code: &mut CodeMap,
+        index: usize,
+        options: &OpaqueOptions,
+        density: f64,
+        rng: &mut impl Rng,
+    ) -> Result<Vec<Instruction>, IcedError> {
+        let function = code.functions[index].clone();
+        let prologue = function.prologue_len();
+        let targets: Vec<u64> = function
+            .blocks
+            .iter()
+            .map(|b| function.instructions[b.start].ip())
+            .filter(|&ip| ip != 0)
+            .collect();
+
+        let mut predicates_at = HashSet::new();
+        let mut junk_at = HashSet::new();
+        for block in &function.blocks {
+            let end = function.epilogue_start(block);
+            let first = (block.start + 1).max(prologue);
+            if first >= end {
+                continue;
+            }
+            if options.predicates && rng.gen_bool(density) {
+                predicates_at.insert(rng.gen_range(first..end));
+            }
+            if options.junk && rng.gen_bool(density) {
+                junk_at.insert(rng.gen_range(first..end));
+            }
+        }
+
+        let mut out = Vec::with_capacity(function.instructions.len() * 2);
+        for (i, instruction) in function.instructions.iter().enumerate() {
+            if junk_at.contains(&i) {
+                out.extend(junk(rng)?);
+                self.junk += 1;
+            }
+            if predicates_at.contains(&i) {
+                out.extend(self.predicate(code, &targets, rng)?);
+                self.predicates += 1;
+            }
+            out.push(*instruction);
+        }
+        out.append(&mut self.bogus);
+        Ok(out)
+    }
+
+    fn predicate(&mut self, code: &mut CodeMap, targets: &[u64], rng: &mut impl Rng) -> Result<Vec<Instruction>, IcedError> {
+        let mut out = vec![
+            Instruction::with(Code::Pushfq),
+            Instruction::with1(Code::Push_r64, Register::RAX)?,
+            Instruction::with1(Code::Push_r64, Register::RDX)?,
+        ];
+        // (never-taken branch, always-taken branch) for the computed condition
+        let (never, always) = match rng.gen_range(0..3) {
+            0 => {
+                // x * (x + 1) is even
+                out.push(Instruction::with2(Code::Lea_r64_m, Register::RDX, MemoryOperand::with_base_displ(Register::RAX, 1))?);
+                out.push(Instruction::with2(Code::Imul_r64_rm64, Register::RAX, Register::RDX)?);
+                out.push(Instruction::with2(Code::Test_AL_imm8, Register::AL, 1u32)?);
+                (Code::Jne_rel32_64, Code::Je_rel32_64)
+            }
+            1 => {
+                // x * x mod 4 is 0 or 1
+                out.push(Instruction::with2(Code::Imul_r64_rm64, Register::RAX, Register::RAX)?);
+                out.push(Instruction::with2(Code::And_rm32_imm8, Register::EAX, 3u32)?);
+                out.push(Instruction::with2(Code::Cmp_rm32_imm8, Register::EAX, 2u32)?);
+                (Code::Jae_rel32_64, Code::Jb_rel32_64)
+            }
+            _ => {
+                // 2x + 1 is odd
+                out.push(Instruction::with2(
+                    Code::Lea_r64_m,
+                    Register::RDX,
+                    MemoryOperand::with_base_index_scale_displ_size(Register::RAX, Register::RAX, 1, 1, 1),
+                )?);
+                out.push(Instruction::with2(Code::Test_rm8_imm8, Register::DL, 1u32)?);
+                (Code::Je_rel32_64, Code::Jne_rel32_64)
+            }
+        };
+
+        let mut restore = Instruction::with1(Code::Pop_r64, Register::RDX)?;
+        if rng.gen_bool(0.5) {
+            let label = code.new_label();
+            out.push(Instruction::with_branch(never, label)?);
+            self.bogus.extend(bogus_block(label, targets, rng)?);
+        } else {
+            let label = code.new_label();
+            out.push(Instruction::with_branch(always, label)?);
+            out.push(Instruction::with_declare_byte(DESYNC_BYTES.choose(rng).unwrap())?);
+            restore.set_ip(label);
+        }
+        out.push(restore);
+        out.push(Instruction::with1(Code::Pop_r64, Register::RAX)?);
+        out.push(Instruction::with(Code::Popfq));
+        Ok(out)
+    }
+}
+
+/// A dead computation on a saved register.
+fn junk(rng: &mut impl Rng) -> Result<Vec<Instruction>, IcedError> {
+    let reg = *SCRATCH.choose(rng).unwrap();
+    let mut out = vec![Instruction::with(Code::Pushfq), Instruction::with1(Code::Push_r64, reg)?];
+    for _ in 0..rng.gen_range(2..=5) {
+        out.push(random_alu(reg, rng)?);
+    }
+    out.push(Instruction::with1(Code::Pop_r64, reg)?);
+    out.push(Instruction::with(Code::Popfq));
+    Ok(out)
+}
+
+/// Plausible looking code that is never executed, ending in a jump into the function.
+fn bogus_block(label: u64, targets: &[u64], rng: &mut impl Rng) -> Result<Vec<Instruction>, IcedError> {
+    let mut out = Vec::new();
+    for _ in 0..rng.gen_range(3..=8) {
+        let reg = *SCRATCH.choose(rng).unwrap();
+        out.push(if rng.gen_bool(0.3) {
+            Instruction::with2(Code::Mov_r64_rm64, reg, *SCRATCH.choose(rng).unwrap())?
+        } else {
+            random_alu(reg, rng)?
+        });
+    }
+    out[0].set_ip(label);
+    if let Some(&target) = targets.choose(rng) {
+        out.push(Instruction::with_branch(Code::Jmp_rel32_64, target)?);
+    }
+    Ok(out)
+}
+
+fn random_alu(reg: Register, rng: &mut impl Rng) -> Result<Instruction, IcedError> {
+    let imm: u32 = rng.gen();
+    match rng.gen_range(0..7) {
+        0 => Instruction::with2(Code::Add_rm64_imm32, reg, imm as i32),
+        1 => Instruction::with2(Code::Sub_rm64_imm32, reg, imm as i32),
+        2 => Instruction::with2(Code::Xor_rm64_imm32, reg, imm as i32),
+        3 => Instruction::with2(Code::Rol_rm64_imm8, reg, imm % 63 + 1),
+        4 => Instruction::with3(Code::Imul_r64_rm64_imm32, reg, reg, imm as i32 | 1),
+        5 => Instruction::with1(Code::Not_rm64, reg),
+        _ => Instruction::with2(
+            Code::Lea_r64_m,
+            reg,
+            MemoryOperand::with_base_index_scale_displ_size(reg, reg, 4, (imm & 0xffff) as i64, 8),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+    use std::sync::mpsc;
+
+    use iced_x86::{Decoder, DecoderOptions, FlowControl};
+
+    use crate::pipeline::cancel::CancellationToken;
+    use crate::pipeline::code::branch_target;
+    use crate::pipeline::fixture::{self, IMAGE_BASE, TEXT_RVA};
+    use crate::pipeline::image::Image;
+    use crate::pipeline::profile::{FunctionAttribute, FunctionSelector};
+
+    /// Runs the step on the sample with every block getting a predicate and
+    /// junk; returns the function's instructions before and after, and the
+    /// written file.
+    fn protect(seed: u64) -> (Vec<Instruction>, Vec<Instruction>, Vec<u8>) {
+        let mut image = Image::parse(fixture::pe64(&fixture::loop_function(), &[])).unwrap();
+        let original = image.code_map().functions[0].instructions.clone();
+        let mut ctx = PipelineContext::new("sample.exe".into(), CancellationToken::new());
+        ctx.seed = seed;
+        ctx.image = Some(image);
+        let options = OpaqueOptions {
+            enabled: true,
+            functions: vec![FunctionSelector::Attribute(FunctionAttribute::Entry)],
+            density: 1.0,
+            ..Default::default()
+        };
+        let (tx, _rx) = mpsc::channel();
+        OpaquePredicatesStep::new(options).run(&mut ctx, &tx).unwrap();
+        let mut image = ctx.image.take().unwrap();
+        let function = &image.code_map().functions[0];
+        assert!(function.modified);
+        let transformed = function.instructions.clone();
+        image.finalize().unwrap();
+        (original, transformed, image.into_bytes())
+    }
+
+    #[test]
+    fn keeps_the_original_control_flow() {
+        let (original, transformed, _) = protect(1);
+        assert!(transformed.len() > original.len() + 10, "nothing was inserted");
+
+        // the original instructions are all there, in order and unchanged
+        let kept: Vec<Instruction> =
+            transformed.iter().filter(|i| original.iter().any(|o| o.ip() == i.ip())).copied().collect();
+        assert_eq!(kept, original);
+        // every branch, original or inserted, lands on an instruction of the function
+        for i in &transformed {
+            if let Some(target) = branch_target(i) {
+                assert!(transformed.iter().any(|t| t.ip() == target), "branch to 0x{:x} has no target", target);
+            }
+        }
+    }
+
+    #[test]
+    fn output_decodes_along_the_control_flow() {
+        let (original, _, bytes) = protect(1);
+        let image = Image::parse(bytes).unwrap();
+        let (_, code) = image.named_sections().into_iter().find(|(name, _)| name == CODE_SECTION).unwrap();
+        let end = code.virtual_address + code.virtual_size;
+        let read = |rva: u32| image.read(rva, (end - rva) as usize).unwrap();
+        let section = IMAGE_BASE + code.virtual_address as u64..IMAGE_BASE + end as u64;
+
+        // the old start jumps to the moved copy
+        let entry = IMAGE_BASE + TEXT_RVA as u64;
+        let patch = Decoder::with_ip(64, image.read(TEXT_RVA, 5).unwrap(), entry, DecoderOptions::NONE).decode();
+        let start = branch_target(&patch).unwrap();
+        assert!(section.contains(&start));
+
+        // follow every path; desync bytes after always-taken branches are never reached
+        let mut decoded: BTreeMap<u64, usize> = BTreeMap::new();
+        let mut pending = vec![start];
+        let (mut returns, mut desyncs) = (0, 0);
+        while let Some(ip) = pending.pop() {
+            if decoded.contains_key(&ip) {
+                continue;
+            }
+            let mut decoder = Decoder::with_ip(64, read((ip - IMAGE_BASE) as u32), ip, DecoderOptions::NONE);
+            loop {
+                let i = decoder.decode();
+                assert!(!i.is_invalid(), "undecodable bytes at 0x{:x}", i.ip());
+                if decoded.insert(i.ip(), i.len()).is_some() {
+                    break;
+                }
+                if let Some(target) = branch_target(&i) {
+                    assert!(section.contains(&target), "branch at 0x{:x} leaves the moved copy", i.ip());
+                    pending.push(target);
+                    // an always-taken predicate over desync bytes never falls through
+                    let skipped = read((i.next_ip() - IMAGE_BASE) as u32);
+                    let over = target.wrapping_sub(i.next_ip()) as usize;
+                    if DESYNC_BYTES.iter().any(|bytes| bytes.len() == over && skipped.starts_with(bytes)) {
+                        desyncs += 1;
+                        break;
+                    }
+                }
+                match i.flow_control() {
+                    FlowControl::Return => {
+                        returns += 1;
+                        break;
+                    }
+                    FlowControl::UnconditionalBranch => break,
+                    _ => {}
+                }
+            }
+        }
+        assert_eq!(returns, 1);
+        assert!(desyncs > 0, "no predicate skips desync bytes");
+        assert!(decoded.len() > original.len());
+        // no branch lands inside another instruction
+        for ((at, len), (next, _)) in decoded.iter().zip(decoded.iter().skip(1)) {
+            assert!(at + *len as u64 <= *next, "0x{:x} overlaps the instruction at 0x{:x}", next, at);
+        }
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_insertions() {
+        let (_, first, bytes) = protect(7);
+        let (_, again, bytes_again) = protect(7);
+        assert_eq!(first, again);
+        assert!(bytes == bytes_again, "two runs with seed 7 differ");
+        let (_, _, other) = protect(8);
+        assert!(bytes != other, "seeds 7 and 8 give the same output");
+    }
+}
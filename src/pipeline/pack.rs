@@ -0,0 +1,899 @@
+//! Whole-image packing (`PackStep`); `packer` is the detection of packed inputs.
+//!
+//! `Image::finalize` calls `pack` last, once every other change is written,
+//! so the stub sections of the other steps are packed too. Each packed
+//! section is LZ-compressed into the `.obsp` section, whose stub becomes the
+//! entry point: it decompresses the sections in place, applies their base
+//! relocations, rebuilds the import table (PE) and restores the page
+//! protections before jumping to the previous entry point.
+//!
+//! The loader still reads a few things before the stub runs, so on PE the
+//! sections holding the resources, exports and TLS data stay as they are and
+//! keep a relocation table of their own; the load configuration and debug
+//! directory are dropped. On ELF only read-only segments the dynamic loader
+//! does not read are packed, and their file range is zero-filled rather than
+//! removed (see `Image::strip_section_data`).
+//!
+//! Layout of `.obsp`: a header (`MAGIC`, entry count, relocation count,
+//! offsets of the entries and relocations), the entries as `{rva, size,
+//! memory size, payload offset, payload size, protection}`, the RVAs of the
+//! relocated qwords in packed sections, the payload, the loader's relocation
+//! table (PE) and the stub.
+
+use std::sync::mpsc::Sender;
+
+use iced_x86::code_asm::*;
+
+use crate::pipeline::{PipelineContext, PipelineMessage};
+use crate::pipeline::error::StepError;
+use crate::pipeline::hash::{HashAlgorithm, NameHasher};
+use crate::pipeline::image::{
+    align_up, Image, ImageFormat, Section, DIR_BASERELOC, DIR_BOUND_IMPORT, DIR_COM_DESCRIPTOR, DIR_DEBUG,
+    DIR_EXPORT, DIR_IAT, DIR_IMPORT, DIR_LOAD_CONFIG, DIR_RESOURCE, DIR_TLS, IMAGE_SCN_MEM_EXECUTE,
+    IMAGE_SCN_MEM_READ, IMAGE_SCN_MEM_WRITE, STUB_SECTION,
+};
+use crate::pipeline::plan::PlannedChange;
+use crate::pipeline::profile::StartupHook;
+use crate::pipeline::reloc::{self, IMAGE_REL_BASED_DIR64};
+use crate::pipeline::step::PipelineStep;
+use crate::pipeline::stub::Runtime;
+
+pub const PACK_SECTION: &str = ".obsp";
+pub const MAGIC: &[u8; 8] = b"OBSPACK1";
+const HEADER_SIZE: usize = 24;
+const ENTRY_SIZE: usize = 24;
+
+/// Compression tokens: `0xxxxxxx` copies `x + 1` literal bytes, `1xxxxxxx`
+/// followed by a 16-bit distance copies `x + MIN_MATCH` bytes already output.
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = 0x7f + MIN_MATCH;
+const MAX_LITERALS: usize = 0x80;
+const WINDOW: usize = 0xffff;
+const CHAIN_DEPTH: usize = 32;
+
+/// Exit codes of a failed import, as the loader would report them.
+const STATUS_DLL_NOT_FOUND: u32 = 0xC000_0135;
+const STATUS_ENTRYPOINT_NOT_FOUND: u32 = 0xC000_0139;
+const PAGE_EXECUTE_READWRITE: u32 = 0x40;
+const PAGE_READONLY: u32 = 0x02;
+
+const SYS_MPROTECT: u32 = 10;
+const PROT_READ_WRITE: u32 = 3;
+
+/// Sections the ELF dynamic loader reads before the entry point runs.
+const ELF_LOADER_SECTIONS: [&str; 12] = [
+    ".interp",
+    ".dynsym",
+    ".dynstr",
+    ".hash",
+    ".gnu.hash",
+    ".gnu.version",
+    ".gnu.version_r",
+    ".gnu.version_d",
+    ".rela.dyn",
+    ".rela.plt",
+    ".dynamic",
+    ".tdata",
+];
+
+/// Compresses every section the loader does not need into one payload
+/// unpacked at startup.
+///
+/// Works on x64 PE images and ELF executables.
+pub struct PackStep;
+
+impl PackStep {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl PipelineStep for PackStep {
+    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> Result<(), StepError> {
+        ctx.cancel.check()?;
+        let Some(image) = ctx.image.as_mut() else {
+            return Err(StepError::Internal("no image loaded before packing".into()));
+        };
+        if !image.supports_startup_routines() {
+            return Err(StepError::UnsupportedFormat(
+                "Packing needs an x64 PE image or an ELF executable".into(),
+            ));
+        }
+        check_packable(image)?;
+        if image.startup_hook() == StartupHook::TlsCallback {
+            return Err(StepError::InvalidInput(
+                "The unpacking stub runs from the entry point, after the TLS callbacks; \
+                 packing needs the entry startup hook"
+                    .into(),
+            ));
+        }
+        if ctx.dry_run {
+            ctx.plan.push(PlannedChange::AddSection {
+                name: PACK_SECTION.into(),
+                description: "compressed sections and unpacking stub".into(),
+            });
+            return Ok(());
+        }
+        image.set_packing();
+        tx.send(PipelineMessage::Log(
+            "The image will be packed once every other change is written".into(),
+        ))
+        .ok();
+        Ok(())
+    }
+}
+
+/// Refuses images the stub cannot unpack before the loader needs them.
+fn check_packable(image: &Image) -> Result<(), StepError> {
+    if image.format == ImageFormat::Elf {
+        if image.has_ifunc_relocations() {
+            return Err(StepError::UnsupportedFormat(
+                "IFUNC resolvers run while the image is loaded, before it could be unpacked".into(),
+            ));
+        }
+        return Ok(());
+    }
+    if image.data_directory(DIR_COM_DESCRIPTOR).1 != 0 {
+        return Err(StepError::UnsupportedFormat(".NET assemblies cannot be packed".into()));
+    }
+    let (tls, size) = image.data_directory(DIR_TLS);
+    if size >= 40 {
+        let callbacks = image.read_u64(tls + 24).unwrap_or(0);
+        let first = callbacks
+            .checked_sub(image.image_base)
+            .and_then(|rva| image.read_u64(rva as u32))
+            .unwrap_or(0);
+        if first != 0 {
+            return Err(StepError::UnsupportedFormat(
+                "TLS callbacks run before the unpacking stub; images with TLS callbacks cannot be packed".into(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// One section as the stub sees it.
+struct Entry {
+    index: usize,
+    rva: u32,
+    /// Bytes restored by the stub (0 for sections left as they are).
+    size: u32,
+    memory_size: u32,
+    protection: u32,
+    packed: Vec<u8>,
+}
+
+/// Packs `image`: compresses the packable sections into a new `.obsp`
+/// section, drops their file data and points the entry at the stub.
+/// Returns a note describing the result.
+pub fn pack(image: &mut Image) -> Result<String, StepError> {
+    check_packable(image)?;
+    let format = image.format;
+    let kept = kept_ranges(image);
+    let named = image.named_sections();
+    let relocations = image.base_relocations();
+
+    let mut entries = Vec::new();
+    let mut original = 0;
+    for (index, section) in image.sections.iter().enumerate() {
+        let memory_size = section.virtual_size.max(section.raw_size);
+        let packable = section.raw_size > 0
+            && !kept.iter().any(|&(rva, size)| overlaps(section, rva, size))
+            && (format == ImageFormat::Pe || elf_packable(image, section, &named));
+        if format == ImageFormat::Elf && !packable {
+            continue;
+        }
+        if packable && format == ImageFormat::Elf && relocations.iter().any(|&r| overlaps(section, r, 8)) {
+            return Err(StepError::UnsupportedFormat(format!(
+                "The segment at 0x{:x} has text relocations and cannot be packed",
+                section.virtual_address
+            )));
+        }
+        let mut entry = Entry {
+            index,
+            rva: section.virtual_address,
+            size: 0,
+            memory_size,
+            protection: protection(format, section.characteristics),
+            packed: Vec::new(),
+        };
+        if packable {
+            let size = match section.virtual_size {
+                0 => section.raw_size,
+                size => size.min(section.raw_size),
+            };
+            let data = image.read(section.virtual_address, size as usize).ok_or_else(|| {
+                StepError::Internal(format!("section at 0x{:x} is not backed by file data", section.virtual_address))
+            })?;
+            entry.size = size;
+            entry.packed = compress(data);
+            original += size as usize;
+        }
+        entries.push(entry);
+    }
+    if entries.iter().all(|e| e.size == 0) {
+        return Err(StepError::UnsupportedFormat("Nothing in the image can be packed".into()));
+    }
+    if format == ImageFormat::Pe {
+        // the headers go back to read-only with the sections
+        let first = image.sections.iter().map(|s| s.virtual_address).min().unwrap_or(0);
+        entries.push(Entry {
+            index: usize::MAX,
+            rva: 0,
+            size: 0,
+            memory_size: first,
+            protection: PAGE_READONLY,
+            packed: Vec::new(),
+        });
+    }
+
+    let packed_ranges: Vec<(u32, u32)> = entries.iter().filter(|e| e.size > 0).map(|e| (e.rva, e.memory_size)).collect();
+    let in_packed = |rva: u32| packed_ranges.iter().any(|&(start, size)| rva >= start && rva < start + size);
+    let (stub_relocations, loader_relocations): (Vec<u32>, Vec<u32>) =
+        relocations.into_iter().partition(|&rva| in_packed(rva));
+
+    let section_rva = image.next_section_rva();
+    let PackSection { data, code_offset, relocation_table } = build_section(image, section_rva, &entries, &stub_relocations, &loader_relocations)?;
+
+    let packed_count = entries.iter().filter(|e| e.size > 0).count();
+    for entry in entries.iter().filter(|e| e.size > 0) {
+        image.strip_section_data(entry.index);
+    }
+    if format == ImageFormat::Pe {
+        for dir in [DIR_IMPORT, DIR_IAT, DIR_BOUND_IMPORT, DIR_LOAD_CONFIG, DIR_DEBUG] {
+            image.set_data_directory(dir, 0, 0);
+        }
+    }
+    let rva = image.add_section(PACK_SECTION, &data, STUB_SECTION)?;
+    if rva != section_rva {
+        return Err(StepError::Internal(format!(
+            "Pack section placed at 0x{:x} instead of 0x{:x}",
+            rva, section_rva
+        )));
+    }
+    if let Some((offset, size)) = relocation_table {
+        image.set_data_directory(DIR_BASERELOC, rva + offset, size);
+        image.notes.push("Load configuration and debug directory removed by packing".into());
+    }
+    image.set_entry(rva + code_offset);
+
+    let payload: usize = entries.iter().map(|e| e.packed.len()).sum();
+    Ok(format!(
+        "Packed {} sections into {} ({} bytes compressed to {})",
+        packed_count, PACK_SECTION, original, payload
+    ))
+}
+
+/// RVA ranges the loader reads before the stub runs (PE).
+fn kept_ranges(image: &Image) -> Vec<(u32, u32)> {
+    if image.format == ImageFormat::Elf {
+        return Vec::new();
+    }
+    let mut kept: Vec<(u32, u32)> = [DIR_EXPORT, DIR_RESOURCE, DIR_TLS]
+        .into_iter()
+        .map(|dir| image.data_directory(dir))
+        .filter(|&(_, size)| size > 0)
+        .collect();
+    let (tls, size) = image.data_directory(DIR_TLS);
+    if size >= 40 {
+        // the template the loader copies for the first thread
+        let start = image.read_u64(tls).unwrap_or(0);
+        let end = image.read_u64(tls + 8).unwrap_or(0);
+        if start > image.image_base && end > start {
+            kept.push(((start - image.image_base) as u32, (end - start) as u32));
+        }
+    }
+    kept
+}
+
+fn overlaps(section: &Section, rva: u32, size: u32) -> bool {
+    let end = section.virtual_address + section.virtual_size.max(section.raw_size);
+    rva < end && rva + size.max(1) > section.virtual_address
+}
+
+/// Whether an ELF segment can be packed: read-only, free of the headers and
+/// of the loader's tables, and sharing no page with another segment.
+fn elf_packable(image: &Image, segment: &Section, named: &[(String, Section)]) -> bool {
+    let page = |rva: u32| rva & !0xfff;
+    let start = page(segment.virtual_address);
+    let end = align_up(segment.virtual_address + segment.virtual_size.max(segment.raw_size), 0x1000);
+    segment.characteristics & IMAGE_SCN_MEM_WRITE == 0
+        && !image.headers().is_some_and(|(rva, size)| overlaps(segment, rva, size))
+        && !named
+            .iter()
+            .filter(|(name, _)| ELF_LOADER_SECTIONS.contains(&name.as_str()) || name.starts_with(".note"))
+            .any(|(_, s)| overlaps(segment, s.virtual_address, s.virtual_size))
+        && !image.sections.iter().any(|other| {
+            !std::ptr::eq(other, segment)
+                && page(other.virtual_address) < end
+                && align_up(other.virtual_address + other.virtual_size.max(other.raw_size), 0x1000) > start
+        })
+}
+
+/// Page protection of a section: `PAGE_*` on PE, `PROT_*` on ELF.
+fn protection(format: ImageFormat, characteristics: u32) -> u32 {
+    let read = characteristics & IMAGE_SCN_MEM_READ != 0;
+    let write = characteristics & IMAGE_SCN_MEM_WRITE != 0;
+    let execute = characteristics & IMAGE_SCN_MEM_EXECUTE != 0;
+    if format == ImageFormat::Elf {
+        return read as u32 | (write as u32) << 1 | (execute as u32) << 2;
+    }
+    match (execute, write, read) {
+        (true, true, _) => 0x40,
+        (true, false, true) => 0x20,
+        (true, false, false) => 0x10,
+        (false, true, _) => 0x04,
+        (false, false, true) => 0x02,
+        (false, false, false) => 0x01,
+    }
+}
+
+/// Contents of the pack section.
+struct PackSection {
+    data: Vec<u8>,
+    code_offset: u32,
+    /// Offset and size of the loader's relocation table (PE).
+    relocation_table: Option<(u32, u32)>,
+}
+
+fn build_section(
+    image: &Image,
+    section_rva: u32,
+    entries: &[Entry],
+    stub_relocations: &[u32],
+    loader_relocations: &[u32],
+) -> Result<PackSection, StepError> {
+    let entries_offset = HEADER_SIZE;
+    let relocations_offset = entries_offset + entries.len() * ENTRY_SIZE;
+    let payload_offset = relocations_offset + stub_relocations.len() * 4;
+
+    let mut data = Vec::new();
+    data.extend_from_slice(MAGIC);
+    for value in [entries.len(), stub_relocations.len(), entries_offset, relocations_offset] {
+        data.extend_from_slice(&(value as u32).to_le_bytes());
+    }
+    let mut payload = payload_offset;
+    for entry in entries {
+        let fields = [
+            entry.rva,
+            entry.size,
+            entry.memory_size,
+            payload as u32,
+            entry.packed.len() as u32,
+            entry.protection,
+        ];
+        for field in fields {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        payload += entry.packed.len();
+    }
+    for rva in stub_relocations {
+        data.extend_from_slice(&rva.to_le_bytes());
+    }
+    for entry in entries {
+        data.extend_from_slice(&entry.packed);
+    }
+
+    let mut relocation_table = None;
+    if image.format == ImageFormat::Pe {
+        data.resize(data.len().next_multiple_of(4), 0);
+        let table = reloc::blocks(loader_relocations.iter().map(|&rva| (rva, IMAGE_REL_BASED_DIR64)), section_rva);
+        relocation_table = Some((data.len() as u32, table.len() as u32));
+        data.extend_from_slice(&table);
+    }
+
+    data.resize(data.len().next_multiple_of(16), 0xCC);
+    let code_offset = data.len() as u32;
+    let layout = Layout {
+        section_rva,
+        code_offset,
+        entries: entries.len() as u32,
+        relocations: stub_relocations.len() as u32,
+        entries_offset: entries_offset as u32,
+        relocations_offset: relocations_offset as u32,
+    };
+    let code = match image.format {
+        ImageFormat::Pe => emit_pe_stub(image, &layout)?,
+        ImageFormat::Elf => emit_elf_stub(image, &layout)?,
+    };
+    data.extend_from_slice(&code);
+    Ok(PackSection { data, code_offset, relocation_table })
+}
+
+/// Where the stub finds its tables.
+struct Layout {
+    section_rva: u32,
+    code_offset: u32,
+    entries: u32,
+    relocations: u32,
+    entries_offset: u32,
+    relocations_offset: u32,
+}
+
+/// `rsi` = compressed data, `r8` = its end, `rdi` = destination; clobbers
+/// `rax`, `rcx`, `rdx` and `r9`.
+pub fn emit_decompress(a: &mut CodeAssembler, label: &mut CodeLabel) -> Result<(), IcedError> {
+    let token = *label;
+    let mut copy = a.create_label();
+    let mut done = a.create_label();
+    a.set_label(label)?;
+    a.cmp(rsi, r8)?;
+    a.jae(done)?;
+    a.movzx(eax, byte_ptr(rsi))?;
+    a.inc(rsi)?;
+    a.test(al, al)?;
+    a.js(copy)?;
+    a.lea(ecx, ptr(rax + 1))?;
+    a.rep().movsb()?;
+    a.jmp(token)?;
+    a.set_label(&mut copy)?;
+    a.and(eax, 0x7f)?;
+    a.lea(ecx, ptr(rax + MIN_MATCH as i32))?;
+    a.movzx(edx, word_ptr(rsi))?;
+    a.add(rsi, 2)?;
+    a.mov(r9, rsi)?;
+    a.mov(rsi, rdi)?;
+    a.sub(rsi, rdx)?;
+    // byte by byte, so the copy may overlap what it writes
+    a.rep().movsb()?;
+    a.mov(rsi, r9)?;
+    a.jmp(token)?;
+    a.set_label(&mut done)?;
+    a.ret()
+}
+
+/// Stores the section start in `r12` and the image base in `rbx`.
+fn emit_bases(a: &mut CodeAssembler, entry: CodeLabel, layout: &Layout) -> Result<(), IcedError> {
+    a.lea(r12, ptr(entry))?;
+    a.sub(r12, layout.code_offset as i32)?;
+    a.mov(rbx, r12)?;
+    a.sub(rbx, layout.section_rva as i32)
+}
+
+/// Decompresses every packed entry; `r12` and `rbx` as set by `emit_bases`.
+/// The loops over the entries test the count at the end, as there is always one.
+fn emit_unpack_loop(a: &mut CodeAssembler, layout: &Layout, decompress: CodeLabel) -> Result<(), IcedError> {
+    let mut next = a.create_label();
+    let mut skip = a.create_label();
+    a.lea(r13, ptr(r12 + layout.entries_offset as i32))?;
+    a.mov(r14d, layout.entries)?;
+    a.set_label(&mut next)?;
+    a.mov(eax, dword_ptr(r13 + 16))?;
+    a.test(eax, eax)?;
+    a.jz(skip)?;
+    a.mov(edi, dword_ptr(r13))?;
+    a.add(rdi, rbx)?;
+    a.mov(esi, dword_ptr(r13 + 12))?;
+    a.add(rsi, r12)?;
+    a.lea(r8, ptr(rsi + rax))?;
+    a.call(decompress)?;
+    a.set_label(&mut skip)?;
+    a.add(r13, ENTRY_SIZE as i32)?;
+    a.dec(r14d)?;
+    a.jnz(next)
+}
+
+/// PE stub: runs once (on `DLL_PROCESS_ATTACH` for DLLs), with the entry
+/// arguments saved around it.
+fn emit_pe_stub(image: &Image, layout: &Layout) -> Result<Vec<u8>, StepError> {
+    let hasher = NameHasher::new(HashAlgorithm::default(), [0; 2]);
+    let mut a = CodeAssembler::new(64)?;
+    let mut entry = a.create_label();
+    let mut original = a.create_label();
+    let mut restore = a.create_label();
+    let mut done = a.create_label();
+    let mut decompress = a.create_label();
+    let mut crash = a.create_label();
+    let mut missing_dll = a.create_label();
+    let mut missing_function = a.create_label();
+    let mut fail = a.create_label();
+    let mut load_library = a.create_label();
+    let mut virtual_protect = a.create_label();
+    let mut exit_process = a.create_label();
+    let runtime = Runtime::new(&mut a, hasher);
+    let saved = [rcx, rdx, r8, r9, rbx, rbp, rsi, rdi, r12, r13, r14, r15];
+
+    a.set_label(&mut entry)?;
+    if image.is_dll {
+        a.cmp(edx, 1)?;
+        a.jne(original)?;
+    }
+    for register in saved {
+        a.push(register)?;
+    }
+    a.sub(rsp, 0x38)?;
+    a.cmp(byte_ptr(done), 0)?;
+    a.jne(restore)?;
+    a.mov(byte_ptr(done), 1)?;
+    emit_bases(&mut a, entry, layout)?;
+
+    a.mov(ecx, hasher.hash("kernel32.dll"))?;
+    a.call(runtime.find_module)?;
+    a.test(rax, rax)?;
+    a.jz(crash)?;
+    a.mov(r15, rax)?;
+    let imports = [
+        ("GetProcAddress", runtime.get_proc_address),
+        ("LoadLibraryA", load_library),
+        ("VirtualProtect", virtual_protect),
+        ("ExitProcess", exit_process),
+    ];
+    for (name, slot) in imports {
+        a.mov(rcx, r15)?;
+        a.mov(edx, hasher.hash(name))?;
+        a.call(runtime.find_export)?;
+        a.test(rax, rax)?;
+        a.jz(crash)?;
+        a.mov(qword_ptr(slot), rax)?;
+    }
+
+    // VirtualProtect(base, everything up to this section, PAGE_EXECUTE_READWRITE, &old)
+    a.mov(rcx, rbx)?;
+    a.mov(edx, layout.section_rva)?;
+    a.mov(r8d, PAGE_EXECUTE_READWRITE)?;
+    a.lea(r9, ptr(rsp + 0x30))?;
+    a.call(qword_ptr(virtual_protect))?;
+    a.test(eax, eax)?;
+    a.jz(crash)?;
+    emit_unpack_loop(&mut a, layout, decompress)?;
+
+    // base relocations of the packed sections, by the distance from the preferred base
+    {
+        let mut next = a.create_label();
+        let mut relocated = a.create_label();
+        a.mov(rax, rbx)?;
+        a.mov(rcx, image.image_base)?;
+        a.sub(rax, rcx)?;
+        a.jz(relocated)?;
+        a.lea(rsi, ptr(r12 + layout.relocations_offset as i32))?;
+        a.mov(ecx, layout.relocations)?;
+        a.set_label(&mut next)?;
+        a.test(ecx, ecx)?;
+        a.jz(relocated)?;
+        a.mov(edx, dword_ptr(rsi))?;
+        a.add(qword_ptr(rbx + rdx), rax)?;
+        a.add(rsi, 4)?;
+        a.dec(ecx)?;
+        a.jmp(next)?;
+        a.set_label(&mut relocated)?;
+    }
+
+    // import descriptors: LoadLibraryA each DLL, GetProcAddress each thunk
+    let (import_rva, import_size) = image.data_directory(DIR_IMPORT);
+    if import_size > 0 {
+        let mut next_dll = a.create_label();
+        let mut lookup = a.create_label();
+        let mut next_thunk = a.create_label();
+        let mut by_name = a.create_label();
+        let mut resolve = a.create_label();
+        let mut dll_done = a.create_label();
+        let mut imported = a.create_label();
+        a.lea(r13, ptr(rbx + import_rva as i32))?;
+        a.set_label(&mut next_dll)?;
+        a.mov(ecx, dword_ptr(r13 + 12))?;
+        a.test(ecx, ecx)?;
+        a.jz(imported)?;
+        a.add(rcx, rbx)?;
+        a.call(qword_ptr(load_library))?;
+        a.test(rax, rax)?;
+        a.jz(missing_dll)?;
+        a.mov(r14, rax)?;
+        // OriginalFirstThunk, or the IAT itself when there is no lookup table
+        a.mov(esi, dword_ptr(r13))?;
+        a.test(esi, esi)?;
+        a.jnz(lookup)?;
+        a.mov(esi, dword_ptr(r13 + 16))?;
+        a.set_label(&mut lookup)?;
+        a.add(rsi, rbx)?;
+        a.mov(edi, dword_ptr(r13 + 16))?;
+        a.add(rdi, rbx)?;
+        a.set_label(&mut next_thunk)?;
+        a.mov(rdx, qword_ptr(rsi))?;
+        a.test(rdx, rdx)?;
+        a.jz(dll_done)?;
+        a.bt(rdx, 63)?;
+        a.jnc(by_name)?;
+        a.movzx(edx, dx)?;
+        a.jmp(resolve)?;
+        a.set_label(&mut by_name)?;
+        a.mov(edx, edx)?;
+        // skip the hint
+        a.lea(rdx, ptr(rbx + rdx + 2))?;
+        a.set_label(&mut resolve)?;
+        a.mov(rcx, r14)?;
+        a.call(qword_ptr(runtime.get_proc_address))?;
+        a.test(rax, rax)?;
+        a.jz(missing_function)?;
+        a.mov(qword_ptr(rdi), rax)?;
+        a.add(rsi, 8)?;
+        a.add(rdi, 8)?;
+        a.jmp(next_thunk)?;
+        a.set_label(&mut dll_done)?;
+        a.add(r13, 20)?;
+        a.jmp(next_dll)?;
+        a.set_label(&mut imported)?;
+    }
+
+    // final protections, headers included
+    {
+        let mut next = a.create_label();
+        a.lea(r13, ptr(r12 + layout.entries_offset as i32))?;
+        a.mov(r14d, layout.entries)?;
+        a.set_label(&mut next)?;
+        a.mov(ecx, dword_ptr(r13))?;
+        a.add(rcx, rbx)?;
+        a.mov(edx, dword_ptr(r13 + 8))?;
+        a.mov(r8d, dword_ptr(r13 + 20))?;
+        a.lea(r9, ptr(rsp + 0x30))?;
+        a.call(qword_ptr(virtual_protect))?;
+        a.add(r13, ENTRY_SIZE as i32)?;
+        a.dec(r14d)?;
+        a.jnz(next)?;
+    }
+
+    a.set_label(&mut restore)?;
+    a.add(rsp, 0x38)?;
+    for register in saved.into_iter().rev() {
+        a.pop(register)?;
+    }
+    a.set_label(&mut original)?;
+    if image.entry != 0 {
+        a.jmp(image.image_base + image.entry as u64)?;
+    } else {
+        // resource-only DLL: report success to the loader
+        a.mov(eax, 1)?;
+        a.ret()?;
+    }
+
+    a.set_label(&mut missing_dll)?;
+    a.mov(ecx, STATUS_DLL_NOT_FOUND)?;
+    a.jmp(fail)?;
+    a.set_label(&mut missing_function)?;
+    a.mov(ecx, STATUS_ENTRYPOINT_NOT_FOUND)?;
+    a.set_label(&mut fail)?;
+    a.call(qword_ptr(exit_process))?;
+    a.set_label(&mut crash)?;
+    a.ud2()?;
+
+    emit_decompress(&mut a, &mut decompress)?;
+    runtime.emit(&mut a)?;
+    for slot in [&mut load_library, &mut virtual_protect, &mut exit_process] {
+        a.set_label(slot)?;
+        a.dq(&[0])?;
+    }
+    a.set_label(&mut done)?;
+    a.db(&[0])?;
+
+    Ok(a.assemble(image.image_base + (layout.section_rva + layout.code_offset) as u64)?)
+}
+
+/// ELF stub: makes each packed segment writable, decompresses it and gives
+/// it back its protection; `rdx` (the loader's exit handler) is preserved.
+fn emit_elf_stub(image: &Image, layout: &Layout) -> Result<Vec<u8>, StepError> {
+    let mut a = CodeAssembler::new(64)?;
+    let mut entry = a.create_label();
+    let mut decompress = a.create_label();
+    let saved = [rdx, rbx, r12, r13, r14, r15];
+
+    a.set_label(&mut entry)?;
+    for register in saved {
+        a.push(register)?;
+    }
+    emit_bases(&mut a, entry, layout)?;
+    emit_protect_loop(&mut a, layout, None)?;
+    emit_unpack_loop(&mut a, layout, decompress)?;
+    emit_protect_loop(&mut a, layout, Some(20))?;
+    for register in saved.into_iter().rev() {
+        a.pop(register)?;
+    }
+    a.jmp(image.image_base + image.entry as u64)?;
+    emit_decompress(&mut a, &mut decompress)?;
+
+    Ok(a.assemble(image.image_base + (layout.section_rva + layout.code_offset) as u64)?)
+}
+
+/// `mprotect`s the pages of every entry to read-write, or to the protection
+/// stored at `field` of the entry.
+fn emit_protect_loop(a: &mut CodeAssembler, layout: &Layout, field: Option<i32>) -> Result<(), IcedError> {
+    let mut next = a.create_label();
+    a.lea(r13, ptr(r12 + layout.entries_offset as i32))?;
+    a.mov(r14d, layout.entries)?;
+    a.set_label(&mut next)?;
+    a.mov(edi, dword_ptr(r13))?;
+    a.add(rdi, rbx)?;
+    a.mov(esi, dword_ptr(r13 + 8))?;
+    a.add(rsi, rdi)?;
+    a.and(rdi, -0x1000)?;
+    a.add(rsi, 0xfff)?;
+    a.and(rsi, -0x1000)?;
+    a.sub(rsi, rdi)?;
+    match field {
+        Some(field) => a.mov(edx, dword_ptr(r13 + field))?,
+        None => a.mov(edx, PROT_READ_WRITE)?,
+    }
+    a.mov(eax, SYS_MPROTECT)?;
+    a.syscall()?;
+    a.add(r13, ENTRY_SIZE as i32)?;
+    a.dec(r14d)?;
+    a.jnz(next)
+}
+
+/// LZ77 with a 64 KiB window and hash chains; see `MIN_MATCH` for the tokens.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 2);
+    let mut head = vec![usize::MAX; 1 << 16];
+    let mut previous = vec![usize::MAX; data.len()];
+    let hash = |at: usize| {
+        let word = u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        (word.wrapping_mul(0x9e37_79b1) >> 16) as usize
+    };
+    let insert = |at: usize, head: &mut [usize], previous: &mut [usize]| {
+        if at + MIN_MATCH <= data.len() {
+            let h = hash(at);
+            previous[at] = head[h];
+            head[h] = at;
+        }
+    };
+    let flush = |out: &mut Vec<u8>, literals: &[u8]| {
+        for run in literals.chunks(MAX_LITERALS) {
+            out.push(run.len() as u8 - 1);
+            out.extend_from_slice(run);
+        }
+    };
+
+    let mut literals = 0;
+    let mut at = 0;
+    while at < data.len() {
+        let (mut best_len, mut best_distance) = (0, 0);
+        if at + MIN_MATCH <= data.len() {
+            let max = (data.len() - at).min(MAX_MATCH);
+            let mut candidate = head[hash(at)];
+            let mut depth = 0;
+            while candidate != usize::MAX && at - candidate <= WINDOW && depth < CHAIN_DEPTH {
+                let len = (0..max).take_while(|&k| data[candidate + k] == data[at + k]).count();
+                if len > best_len {
+                    (best_len, best_distance) = (len, at - candidate);
+                    if len == max {
+                        break;
+                    }
+                }
+                candidate = previous[candidate];
+                depth += 1;
+            }
+        }
+        if best_len >= MIN_MATCH {
+            flush(&mut out, &data[literals..at]);
+            out.push(0x80 | (best_len - MIN_MATCH) as u8);
+            out.extend_from_slice(&(best_distance as u16).to_le_bytes());
+            for k in at..at + best_len {
+                insert(k, &mut head, &mut previous);
+            }
+            at += best_len;
+            literals = at;
+        } else {
+            insert(at, &mut head, &mut previous);
+            at += 1;
+        }
+    }
+    flush(&mut out, &data[literals..]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    /// Host-side counterpart of the stub's decompressor.
+    fn decompress(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut at = 0;
+        while at < data.len() {
+            let token = data[at] as usize;
+            at += 1;
+            if token & 0x80 == 0 {
+                out.extend_from_slice(&data[at..at + token + 1]);
+                at += token + 1;
+            } else {
+                let distance = u16::from_le_bytes([data[at], data[at + 1]]) as usize;
+                at += 2;
+                for _ in 0..(token & 0x7f) + MIN_MATCH {
+                    out.push(out[out.len() - distance]);
+                }
+            }
+        }
+        out
+    }
+
+    /// What the stub restores, by RVA, read from a packed file.
+    fn unpack(bytes: Vec<u8>) -> HashMap<u32, Vec<u8>> {
+        let image = Image::parse(bytes).unwrap();
+        let section = image
+            .sections
+            .iter()
+            .find(|s| image.read(s.virtual_address, MAGIC.len()) == Some(MAGIC))
+            .expect("no pack section");
+        let data = image.read(section.virtual_address, section.raw_size as usize).unwrap();
+        let field = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap()) as usize;
+        let (count, entries) = (field(8), field(16));
+        (0..count)
+            .map(|i| entries + i * ENTRY_SIZE)
+            .filter(|&entry| field(entry + 4) > 0)
+            .map(|entry| {
+                let (offset, len) = (field(entry + 12), field(entry + 16));
+                let restored = decompress(&data[offset..offset + len]);
+                assert_eq!(restored.len(), field(entry + 4));
+                (field(entry) as u32, restored)
+            })
+            .collect()
+    }
+
+    fn pack_file(path: &Path) -> Option<(Image, Vec<u8>)> {
+        let bytes = std::fs::read(path).ok()?;
+        let original = Image::parse(bytes.clone()).ok()?;
+        if original.format != ImageFormat::Elf || original.is_dll || original.has_ifunc_relocations() {
+            return None;
+        }
+        let mut image = Image::parse(bytes).unwrap();
+        image.set_packing();
+        image.finalize().unwrap();
+        Some((original, image.into_bytes()))
+    }
+
+    const TEST_BINARIES: [&str; 4] = ["/bin/true", "/bin/ls", "/usr/bin/env", "/bin/cat"];
+
+    #[test]
+    fn compression_round_trips() {
+        let mut data = b"abcabcabcabd".repeat(300);
+        data.extend((0..5000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8));
+        data.extend([0; 70_000]);
+        for input in [&data[..], b"", b"a", b"aaaa", &data[..5]] {
+            assert_eq!(decompress(&compress(input)), input);
+        }
+        assert!(compress(&[0; 70_000]).len() < 2000);
+    }
+
+    #[test]
+    fn unpack_restores_elf_segments() {
+        let mut packed_any = false;
+        for path in TEST_BINARIES.iter().map(Path::new) {
+            let Some((original, packed)) = pack_file(path) else {
+                continue;
+            };
+            let restored = unpack(packed.clone());
+            assert!(!restored.is_empty(), "{}: nothing packed", path.display());
+            for (rva, bytes) in &restored {
+                assert_eq!(original.read(*rva, bytes.len()).unwrap(), &bytes[..], "{} at 0x{:x}", path.display(), rva);
+                let packed_image = Image::parse(packed.clone()).unwrap();
+                assert!(packed_image.read(*rva, bytes.len()).unwrap().iter().all(|&b| b == 0));
+            }
+            packed_any = true;
+        }
+        assert!(packed_any || !cfg!(target_os = "linux"), "no ELF test binary found");
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn packed_elf_binaries_run() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::process::Command;
+
+        let dir = std::env::temp_dir().join(format!("obscura-pack-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (path, args) in [("/bin/true", &[][..]), ("/bin/ls", &["/"][..]), ("/bin/cat", &["/etc/hostname"][..])] {
+            let Some((_, packed)) = pack_file(Path::new(path)) else {
+                continue;
+            };
+            let target = dir.join(Path::new(path).file_name().unwrap());
+            std::fs::write(&target, packed).unwrap();
+            std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o755)).unwrap();
+            let expected = Command::new(path).args(args).output().unwrap();
+            let actual = Command::new(&target).args(args).output().unwrap();
+            assert_eq!(actual.status.code(), expected.status.code(), "{}", path);
+            assert_eq!(actual.stdout, expected.stdout, "{}", path);
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
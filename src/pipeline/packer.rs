@@ -0,0 +1,187 @@
+use goblin::pe::PE;
+use goblin::pe::section_table::{IMAGE_SCN_MEM_EXECUTE, IMAGE_SCN_MEM_WRITE};
+use serde::Serialize;
+
+use crate::pipeline::pe;
+use crate::pipeline::report::{OverlayReport, SectionReport};
+
+/// Entropy above which a section is considered compressed or encrypted.
+pub const HIGH_ENTROPY: f64 = 7.2;
+
+/// What to do when the input looks already packed or encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum PackedInputPolicy {
+    /// Log a warning and continue.
+    #[default]
+    Warn,
+    /// Abort the pipeline before anything is written.
+    Refuse,
+}
+
+/// Result of the packer heuristics run by `ParseStep`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PackerAssessment {
+    pub looks_packed: bool,
+    /// Name of a recognized packer, when a signature matched.
+    pub packer: Option<String>,
+    /// Every signature and heuristic that fired.
+    pub reasons: Vec<String>,
+}
+
+/// Section names left behind by common packers and protectors.
+const SECTION_SIGNATURES: &[(&str, &str)] = &[
+    ("UPX0", "UPX"),
+    ("UPX1", "UPX"),
+    ("UPX2", "UPX"),
+    (".UPX0", "UPX"),
+    (".aspack", "ASPack"),
+    (".adata", "ASPack"),
+    (".MPRESS1", "MPRESS"),
+    (".MPRESS2", "MPRESS"),
+    (".petite", "Petite"),
+    (".nsp0", "NsPack"),
+    (".nsp1", "NsPack"),
+    ("PEC2", "PECompact"),
+    ("PEC2TO", "PECompact"),
+    ("pec1", "PECompact"),
+    (".themida", "Themida"),
+    (".winlice", "WinLicense"),
+    (".vmp0", "VMProtect"),
+    (".vmp1", "VMProtect"),
+    (".enigma1", "Enigma Protector"),
+    (".enigma2", "Enigma Protector"),
+    (".kkrunch", "kkrunchy"),
+    (".yP", "Y0da Protector"),
+    (".RLPack", "RLPack"),
+    ("MEW", "MEW"),
+    ("FSG!", "FSG"),
+];
+
+/// Byte strings found in the headers or body of packed files.
+const BYTE_SIGNATURES: &[(&[u8], &str)] = &[
+    (b"UPX!", "UPX"),
+    (b"$Info: This file is packed with the UPX", "UPX"),
+    (b"MPRESS", "MPRESS"),
+    (b"PECompact2", "PECompact"),
+];
+
+/// Entry point stubs (`None` = wildcard byte).
+const ENTRY_SIGNATURES: &[(&[Option<u8>], &str)] = &[
+    // pushad; mov esi, imm32; lea edi, [esi+imm32]
+    (&[Some(0x60), Some(0xBE), None, None, None, None, Some(0x8D), Some(0xBE)], "UPX"),
+    // push rbx; push rsi; push rdi; push rbp; lea rsi, [rip+imm32]
+    (&[Some(0x53), Some(0x56), Some(0x57), Some(0x55), Some(0x48), Some(0x8D), Some(0x35)], "UPX"),
+    // pushad; call $+8; db 0xE9; jmp short
+    (&[Some(0x60), Some(0xE8), Some(0x03), Some(0x00), Some(0x00), Some(0x00), Some(0xE9), Some(0xEB)], "ASPack"),
+    // pushad; call $+5; pop eax; add eax, imm32
+    (&[Some(0x60), Some(0xE8), Some(0x00), Some(0x00), Some(0x00), Some(0x00), Some(0x58), Some(0x05)], "MPRESS"),
+];
+
+/// How far into the file byte signatures are searched (headers plus the first sections).
+const BYTE_SIGNATURE_WINDOW: usize = 0x2000;
+
+/// Runs the signature and entropy heuristics.
+///
+/// A packer signature alone is conclusive; otherwise at least two independent
+/// heuristics have to agree, so a single high entropy resource section does
+/// not flag a normal binary.
+pub fn assess(
+    bytes: &[u8],
+    pe: &PE,
+    sections: &[SectionReport],
+    overlay: Option<&OverlayReport>,
+) -> PackerAssessment {
+    let mut packer: Option<String> = None;
+    let mut reasons = Vec::new();
+    let mut heuristics = 0;
+
+    for sec in sections {
+        if let Some((_, name)) = SECTION_SIGNATURES.iter().find(|(n, _)| sec.name == *n) {
+            reasons.push(format!("section name {} is used by {}", sec.name, name));
+            packer.get_or_insert_with(|| name.to_string());
+        }
+    }
+
+    let window = &bytes[..bytes.len().min(BYTE_SIGNATURE_WINDOW)];
+    for (sig, name) in BYTE_SIGNATURES {
+        if window.windows(sig.len()).any(|w| w == *sig) {
+            reasons.push(format!("{} signature {:?} found", name, String::from_utf8_lossy(sig)));
+            packer.get_or_insert_with(|| name.to_string());
+        }
+    }
+
+    if let Some(ep) = pe::rva_to_offset(&pe.sections, pe.entry) {
+        for (sig, name) in ENTRY_SIGNATURES {
+            let matches = bytes
+                .get(ep..ep + sig.len())
+                .map(|code| sig.iter().zip(code).all(|(s, b)| s.is_none_or(|s| s == *b)))
+                .unwrap_or(false);
+            if matches {
+                reasons.push(format!("entry point matches the {} stub", name));
+                packer.get_or_insert_with(|| name.to_string());
+            }
+        }
+    }
+
+    let executable = |s: &&SectionReport| s.characteristics & IMAGE_SCN_MEM_EXECUTE != 0;
+    if let Some(sec) = sections.iter().filter(executable).find(|s| s.entropy >= HIGH_ENTROPY) {
+        heuristics += 1;
+        reasons.push(format!(
+            "executable section {} has entropy {:.2} (compressed or encrypted code)",
+            sec.name, sec.entropy
+        ));
+    }
+
+    if let Some(sec) = sections
+        .iter()
+        .filter(executable)
+        .find(|s| s.raw_size == 0 && s.virtual_size > 0)
+    {
+        heuristics += 1;
+        reasons.push(format!(
+            "executable section {} has no raw data but {} bytes in memory (unpacked at runtime)",
+            sec.name, sec.virtual_size
+        ));
+    }
+
+    if let Some(sec) = sections
+        .iter()
+        .find(|s| pe::range_contains(s.virtual_address, s.virtual_size.max(s.raw_size), pe.entry))
+    {
+        if sec.characteristics & IMAGE_SCN_MEM_WRITE != 0 && sec.characteristics & IMAGE_SCN_MEM_EXECUTE != 0 {
+            heuristics += 1;
+            reasons.push(format!("entry point is in writable and executable section {}", sec.name));
+        }
+    }
+
+    if let Some(o) = overlay {
+        if o.entropy >= HIGH_ENTROPY && o.size * 2 > bytes.len() {
+            heuristics += 1;
+            reasons.push(format!(
+                "overlay holds {} of {} bytes with entropy {:.2} (embedded compressed payload)",
+                o.size,
+                bytes.len(),
+                o.entropy
+            ));
+        }
+    }
+
+    let imported: Vec<&str> = pe.imports.iter().map(|i| i.name.as_ref()).collect();
+    if !imported.is_empty()
+        && imported.len() <= 8
+        && imported.contains(&"GetProcAddress")
+        && (imported.contains(&"LoadLibraryA") || imported.contains(&"LoadLibraryW"))
+    {
+        heuristics += 1;
+        reasons.push(format!(
+            "only {} imports, including LoadLibrary/GetProcAddress (imports resolved at runtime)",
+            imported.len()
+        ));
+    }
+
+    PackerAssessment {
+        looks_packed: packer.is_some() || heuristics >= 2,
+        packer,
+        reasons,
+    }
+}
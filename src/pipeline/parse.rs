@@ -1,24 +1,44 @@
 use std::fs;
 use std::path::Path;
 use std::sync::mpsc::Sender;
-use std::sync::atomic::Ordering;
 use std::time::Duration;
 
 use crate::pipeline::{PipelineContext, PipelineMessage};
 use crate::pipeline::step::PipelineStep;
+use crate::pipeline::error::StepError;
+use crate::pipeline::image::{Image, ImageFormat};
+use crate::pipeline::packer::PackedInputPolicy;
+use crate::pipeline::profile::StartupHook;
+use crate::pipeline::report::AnalysisReport;
 
 use goblin::Object;
 
-pub struct ParseStep;
+pub struct ParseStep {
+    packed_policy: PackedInputPolicy,
+    startup_hook: StartupHook,
+}
 
 impl ParseStep {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            packed_policy: PackedInputPolicy::default(),
+            startup_hook: StartupHook::default(),
+        }
+    }
+
+    pub fn with_packed_policy(mut self, policy: PackedInputPolicy) -> Self {
+        self.packed_policy = policy;
+        self
+    }
+
+    pub fn with_startup_hook(mut self, hook: StartupHook) -> Self {
+        self.startup_hook = hook;
+        self
     }
 }
 
 impl PipelineStep for ParseStep {
-    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> anyhow::Result<()> {
+    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> Result<(), StepError> {
         // log start
         tx.send(PipelineMessage::Log(format!("Parsing file: {}", ctx.input_path))).ok();
 
@@ -26,10 +46,7 @@ impl PipelineStep for ParseStep {
         let slices = 3;
         for i in 0..slices {
             // verificar cancelamento
-            if ctx.cancel_flag.load(Ordering::Relaxed) {
-                let _ = tx.send(PipelineMessage::Cancelled);
-                return Ok(());
-            }
+            ctx.cancel.check()?;
             std::thread::sleep(Duration::from_millis(120));
             let progress = (i as f32 + 1.0) / (slices as f32) * 0.10;
             tx.send(PipelineMessage::Progress(progress)).ok();
@@ -37,88 +54,164 @@ impl PipelineStep for ParseStep {
 
         // Try to read the file
         let path = Path::new(&ctx.input_path);
-        let bytes = fs::read(path).map_err(|e| {
-            anyhow::anyhow!("Failed to read file '{}': {}", ctx.input_path, e)
-        })?;
+        let bytes = fs::read(path)
+            .map_err(|e| StepError::io("Failed to read file", path, e))?;
 
         // Try to parse using goblin::Object
-        match Object::parse(&bytes) {
-            Ok(Object::PE(pe)) => {
-                let machine = pe.header.coff_header.machine;
-                let arch = match machine {
-                    0x14c => "x86 (32-bit)".to_string(),
-                    0x8664 => "x86_64 (64-bit)".to_string(),
-                    m => format!("unknown (0x{:x})", m),
-                };
-                tx.send(PipelineMessage::Log(format!("Detected PE: {}", arch))).ok();
-
-                let sections = pe.sections.len();
-                tx.send(PipelineMessage::Log(format!("Sections: {}", sections))).ok();
-
-                let mut names = Vec::new();
-                for sec in &pe.sections {
-                    if let Ok(name) = std::str::from_utf8(&sec.name) {
-                        names.push(name.trim_end_matches(char::from(0)).to_string());
-                    } else {
-                        names.push("<non-utf8>".into());
-                    }
-                }
-                if !names.is_empty() {
-                    tx.send(PipelineMessage::Log(format!(
-                        "Section names: {}",
-                        names.join(", ")
-                    )))
-                    .ok();
-                }
+        let report = analyze(&ctx.input_path, &bytes)?;
+        tx.send(PipelineMessage::Log(format!(
+            "Detected {}: {}",
+            report.format, report.architecture
+        )))
+        .ok();
 
-                let import_count = pe.imports.len();
-                let import_names: Vec<String> =
-                    pe.imports.iter().map(|imp| imp.dll.to_string()).collect();
-                tx.send(PipelineMessage::Log(format!(
-                    "Import DLLs: {} ({} entries)",
-                    if import_names.is_empty() {
-                        "<none>".to_string()
-                    } else {
-                        import_names.join(", ")
-                    },
-                    import_count
-                )))
-                .ok();
+        let sections = report.sections.len();
+        tx.send(PipelineMessage::Log(format!("Sections: {}", sections))).ok();
 
-                let export_count = pe.exports.len();
-                if export_count > 0 {
-                    tx.send(PipelineMessage::Log(format!(
-                        "Exports: {} entries",
-                        export_count
-                    )))
-                    .ok();
-                } else {
-                    tx.send(PipelineMessage::Log("Exports: none detected".into())).ok();
-                }
+        if sections > 0 {
+            let names: Vec<String> = report
+                .sections
+                .iter()
+                .map(|s| format!("{} (entropy {:.2})", s.name, s.entropy))
+                .collect();
+            tx.send(PipelineMessage::Log(format!(
+                "Section names: {}",
+                names.join(", ")
+            )))
+            .ok();
+        }
 
-                tx.send(PipelineMessage::Progress(0.20)).ok();
-                tx.send(PipelineMessage::Log(format!(
-                    "Parsing complete: {} sections found",
-                    sections
-                )))
-                .ok();
+        let import_count: usize = report.imports.iter().map(|d| d.functions.len()).sum();
+        let import_names: Vec<&str> = report.imports.iter().map(|d| d.dll.as_str()).collect();
+        tx.send(PipelineMessage::Log(format!(
+            "Import DLLs: {} ({} entries)",
+            if import_names.is_empty() {
+                "<none>".to_string()
+            } else {
+                import_names.join(", ")
+            },
+            import_count
+        )))
+        .ok();
+
+        let export_count = report.exports.len();
+        if export_count > 0 {
+            tx.send(PipelineMessage::Log(format!(
+                "Exports: {} entries",
+                export_count
+            )))
+            .ok();
+        } else {
+            tx.send(PipelineMessage::Log("Exports: none detected".into())).ok();
+        }
+
+        if let Some(overlay) = &report.overlay {
+            tx.send(PipelineMessage::Log(format!(
+                "Overlay: {} bytes at 0x{:x} (entropy {:.2}{})",
+                overlay.size,
+                overlay.offset,
+                overlay.entropy,
+                if overlay.contains_certificate { ", signed" } else { "" }
+            )))
+            .ok();
+        }
+
+        if report.packer.looks_packed {
+            let what = report.packer.packer.as_deref().unwrap_or("unknown packer");
+            for reason in &report.packer.reasons {
+                tx.send(PipelineMessage::Log(format!("Packer check: {}", reason))).ok();
             }
-            Ok(other) => {
-                tx.send(PipelineMessage::Error(format!(
-                    "File is not a PE executable (detected: {:?})",
-                    other
-                )))
-                .ok();
+            if self.packed_policy == PackedInputPolicy::Refuse {
+                return Err(StepError::UnsupportedFormat(format!(
+                    "Input looks already packed or encrypted ({}); protecting it again would corrupt it. \
+                     Unpack it first or set the packed input policy to 'warn'",
+                    what
+                )));
             }
-            Err(e) => {
-                tx.send(PipelineMessage::Error(format!(
-                    "Failed to parse file '{}': {}",
-                    ctx.input_path, e
-                )))
+            tx.send(PipelineMessage::Log(format!(
+                "WARNING: input looks already packed or encrypted ({}); the protected output may not run",
+                what
+            )))
+            .ok();
+        }
+
+        if !report.tls_callbacks.is_empty() {
+            let callbacks: Vec<String> = report.tls_callbacks.iter().map(|rva| format!("0x{:x}", rva)).collect();
+            tx.send(PipelineMessage::Log(format!(
+                "TLS callbacks: {} ({}){}",
+                callbacks.len(),
+                callbacks.join(", "),
+                match self.startup_hook {
+                    StartupHook::Entry => "; they run before the injected startup routines",
+                    StartupHook::TlsCallback => "; the injected startup routines run first",
+                }
+            )))
+            .ok();
+        }
+
+        if let Some(pdb) = &report.pdb {
+            tx.send(PipelineMessage::Log(format!("Debug info: PDB path {}", pdb.path))).ok();
+        }
+        if !report.resources.is_empty() {
+            tx.send(PipelineMessage::Log(format!(
+                "Resources: {} entries",
+                report.resources.len()
+            )))
+            .ok();
+        }
+
+        tx.send(PipelineMessage::Progress(0.20)).ok();
+        tx.send(PipelineMessage::Log(format!(
+            "Parsing complete: {} sections found",
+            sections
+        )))
+        .ok();
+
+        tx.send(PipelineMessage::Analysis(Box::new(report))).ok();
+        let mut image = Image::parse(bytes)?;
+        if self.startup_hook == StartupHook::TlsCallback {
+            if image.format == ImageFormat::Pe {
+                image.set_startup_hook(StartupHook::TlsCallback);
+            } else {
+                tx.send(PipelineMessage::Log(
+                    "TLS callbacks are a PE feature; the startup routines run from the entry point".into(),
+                ))
                 .ok();
             }
         }
+        ctx.image = Some(image);
 
         Ok(())
     }
 }
+
+/// Parses `bytes` (read from `file`) and builds the analysis report.
+pub fn analyze(file: &str, bytes: &[u8]) -> Result<AnalysisReport, StepError> {
+    match Object::parse(bytes) {
+        Ok(Object::PE(pe)) => Ok(AnalysisReport::from_pe(file, bytes, &pe)),
+        Ok(Object::Elf(elf)) => Ok(AnalysisReport::from_elf(file, bytes, &elf)),
+        Ok(other) => Err(StepError::UnsupportedFormat(format!(
+            "File is not a PE or ELF executable (detected: {})",
+            object_kind(&other)
+        ))),
+        Err(e) => Err(StepError::InvalidInput(format!(
+            "Failed to parse file '{}': {}",
+            file, e
+        ))),
+    }
+}
+
+/// Short name of a non-PE object for error messages (the `Debug` output of a
+/// parsed object dumps the whole structure).
+fn object_kind(obj: &Object) -> String {
+    match obj {
+        Object::Elf(_) => "ELF".into(),
+        Object::PE(_) => "PE".into(),
+        Object::TE(_) => "TE image".into(),
+        Object::COFF(_) => "COFF object".into(),
+        Object::Mach(_) => "Mach-O".into(),
+        Object::Archive(_) => "archive".into(),
+        Object::Unknown(magic) => format!("unknown, magic 0x{:x}", magic),
+        _ => "unknown".into(),
+    }
+}
@@ -0,0 +1,146 @@
+//! Minimal PDB (MSF 7.00) reader: the GUID and age that match a module's
+//! CodeView record, and the public function symbols with their RVAs.
+//!
+//! Publics (`S_PUB32` in the symbol record stream) are what every PDB has,
+//! stripped ones included; they carry no size, so an address belongs to the
+//! closest public below it. Their `segment:offset` is turned into an RVA
+//! with the section headers the DBI stream keeps.
+
+use crate::pipeline::error::StepError;
+use crate::pipeline::pe::{read_u16, read_u32};
+
+const MAGIC: &[u8; 32] = b"Microsoft C/C++ MSF 7.00\r\n\x1aDS\0\0\0";
+const PDB_INFO_STREAM: usize = 1;
+const DBI_STREAM: usize = 3;
+const DBI_HEADER_SIZE: usize = 64;
+/// Index of the section header stream in the DBI optional debug header.
+const SECTION_HEADERS: usize = 5;
+const SECTION_HEADER_SIZE: usize = 40;
+const S_PUB32: u16 = 0x110e;
+/// `CV_PUBSYMFLAGS`: fCode | fFunction.
+const PUBLIC_CODE: u32 = 0x3;
+const NO_STREAM: u16 = 0xffff;
+
+pub struct Pdb {
+    pub guid: [u8; 16],
+    pub age: u32,
+    /// Public functions as `(rva, name)`, sorted by RVA.
+    pub functions: Vec<(u32, String)>,
+}
+
+fn invalid(what: impl std::fmt::Display) -> StepError {
+    StepError::InvalidInput(format!("Malformed PDB: {}", what))
+}
+
+/// The streams of an MSF file.
+struct Msf<'a> {
+    bytes: &'a [u8],
+    block_size: usize,
+    /// Size and blocks of every stream.
+    streams: Vec<(usize, Vec<usize>)>,
+}
+
+impl<'a> Msf<'a> {
+    fn parse(bytes: &'a [u8]) -> Result<Self, StepError> {
+        if bytes.get(..MAGIC.len()) != Some(MAGIC.as_slice()) {
+            return Err(StepError::UnsupportedFormat("not a PDB (MSF 7.00) file".into()));
+        }
+        let field = |at: usize| read_u32(bytes, at).map(|v| v as usize).ok_or_else(|| invalid("truncated superblock"));
+        let (block_size, directory_size, block_map) = (field(32)?, field(44)?, field(52)?);
+        if !block_size.is_power_of_two() || block_size < 512 {
+            return Err(invalid(format!("block size {}", block_size)));
+        }
+        let directory_blocks = (0..directory_size.div_ceil(block_size))
+            .map(|i| read_u32(bytes, block_map * block_size + i * 4).map(|b| b as usize))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| invalid("truncated block map"))?;
+        let mut msf = Msf { bytes, block_size, streams: Vec::new() };
+        let directory = msf.read(directory_size, &directory_blocks)?;
+
+        let word = |at: usize| read_u32(&directory, at).ok_or_else(|| invalid("truncated stream directory"));
+        let count = word(0)? as usize;
+        let sizes = (0..count)
+            .map(|i| word(4 + i * 4).map(|size| if size == u32::MAX { 0 } else { size as usize }))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut at = 4 + count * 4;
+        for size in sizes {
+            let blocks = (0..size.div_ceil(block_size))
+                .map(|i| word(at + i * 4).map(|b| b as usize))
+                .collect::<Result<Vec<_>, _>>()?;
+            at += blocks.len() * 4;
+            msf.streams.push((size, blocks));
+        }
+        Ok(msf)
+    }
+
+    fn read(&self, size: usize, blocks: &[usize]) -> Result<Vec<u8>, StepError> {
+        let mut data = Vec::with_capacity(size);
+        for &block in blocks {
+            let start = block * self.block_size;
+            let len = self.block_size.min(size - data.len());
+            let chunk = self.bytes.get(start..start + len).ok_or_else(|| invalid(format!("block {} past the end", block)))?;
+            data.extend_from_slice(chunk);
+        }
+        Ok(data)
+    }
+
+    fn stream(&self, index: usize) -> Result<Vec<u8>, StepError> {
+        let (size, blocks) = self.streams.get(index).ok_or_else(|| invalid(format!("no stream {}", index)))?;
+        self.read(*size, blocks)
+    }
+}
+
+impl Pdb {
+    pub fn parse(bytes: &[u8]) -> Result<Self, StepError> {
+        let msf = Msf::parse(bytes)?;
+        let info = msf.stream(PDB_INFO_STREAM)?;
+        let guid: [u8; 16] = info.get(12..28).ok_or_else(|| invalid("truncated PDB info stream"))?.try_into().unwrap();
+        let age = read_u32(&info, 8).ok_or_else(|| invalid("truncated PDB info stream"))?;
+
+        let dbi = msf.stream(DBI_STREAM)?;
+        let half = |at: usize| read_u16(&dbi, at).ok_or_else(|| invalid("truncated DBI header"));
+        let word = |at: usize| read_u32(&dbi, at).map(|v| v as usize).ok_or_else(|| invalid("truncated DBI header"));
+        let records = half(20)?;
+        // module info, section contributions, section map, file info, type server map, EC names
+        let substreams = [word(24)?, word(28)?, word(32)?, word(36)?, word(40)?, word(52)?];
+        let debug_header = DBI_HEADER_SIZE + substreams.iter().sum::<usize>();
+        let sections_stream = match read_u16(&dbi, debug_header + SECTION_HEADERS * 2) {
+            Some(index) if index != NO_STREAM && word(48)? > SECTION_HEADERS * 2 => index as usize,
+            _ => return Err(invalid("no section headers in the DBI stream")),
+        };
+        let headers = msf.stream(sections_stream)?;
+        let section_rvas: Vec<u32> = headers
+            .chunks_exact(SECTION_HEADER_SIZE)
+            .map(|header| read_u32(header, 12).unwrap())
+            .collect();
+
+        let mut functions = Vec::new();
+        if records != NO_STREAM {
+            let symbols = msf.stream(records as usize)?;
+            let mut at = 0;
+            while let (Some(len), Some(kind)) = (read_u16(&symbols, at), read_u16(&symbols, at + 2)) {
+                let next = at + 2 + len as usize;
+                if kind == S_PUB32 {
+                    let record = symbols.get(at..next.min(symbols.len())).unwrap_or_default();
+                    if let (Some(flags), Some(offset), Some(segment)) =
+                        (read_u32(record, 4), read_u32(record, 8), read_u16(record, 12))
+                    {
+                        let section = (segment as usize).checked_sub(1).and_then(|i| section_rvas.get(i));
+                        if let (true, Some(&section)) = (flags & PUBLIC_CODE != 0, section) {
+                            let name = &record[14.min(record.len())..];
+                            let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+                            functions.push((section + offset, String::from_utf8_lossy(&name[..end]).into_owned()));
+                        }
+                    }
+                }
+                if len < 2 {
+                    break;
+                }
+                at = next;
+            }
+        }
+        functions.sort();
+        functions.dedup_by_key(|f| f.0);
+        Ok(Pdb { guid, age, functions })
+    }
+}
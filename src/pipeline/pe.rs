@@ -0,0 +1,212 @@
+//! Small PE helpers shared by the steps (things goblin does not expose directly).
+
+use goblin::pe::section_table::{self, SectionTable};
+
+/// Human readable architecture for a COFF machine value.
+pub fn machine_name(machine: u16) -> String {
+    match machine {
+        0x14c => "x86 (32-bit)".to_string(),
+        0x8664 => "x86_64 (64-bit)".to_string(),
+        0xaa64 => "ARM64".to_string(),
+        m => format!("unknown (0x{:x})", m),
+    }
+}
+
+/// Section name without the trailing NUL padding.
+pub fn section_name(sec: &SectionTable) -> String {
+    match std::str::from_utf8(&sec.name) {
+        Ok(name) => name.trim_end_matches(char::from(0)).to_string(),
+        Err(_) => "<non-utf8>".into(),
+    }
+}
+
+/// Names of the characteristic flags set on a section.
+pub fn section_flags(characteristics: u32) -> Vec<&'static str> {
+    const FLAGS: [(u32, &str); 8] = [
+        (section_table::IMAGE_SCN_CNT_CODE, "CODE"),
+        (section_table::IMAGE_SCN_CNT_INITIALIZED_DATA, "INITIALIZED_DATA"),
+        (section_table::IMAGE_SCN_CNT_UNINITIALIZED_DATA, "UNINITIALIZED_DATA"),
+        (section_table::IMAGE_SCN_MEM_DISCARDABLE, "DISCARDABLE"),
+        (section_table::IMAGE_SCN_MEM_SHARED, "SHARED"),
+        (section_table::IMAGE_SCN_MEM_EXECUTE, "EXECUTE"),
+        (section_table::IMAGE_SCN_MEM_READ, "READ"),
+        (section_table::IMAGE_SCN_MEM_WRITE, "WRITE"),
+    ];
+    FLAGS
+        .iter()
+        .filter(|(bit, _)| characteristics & bit != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Whether `rva` lies in `[start, start + size)`; a range that wraps past
+/// `u32::MAX` (a malformed header) contains nothing.
+pub fn range_contains(start: u32, size: u32, rva: u32) -> bool {
+    start.checked_add(size).is_some_and(|end| rva >= start && rva < end)
+}
+
+/// Maps an RVA to a file offset using the section table.
+pub fn rva_to_offset(sections: &[SectionTable], rva: u32) -> Option<usize> {
+    sections.iter().find_map(|sec| {
+        let size = sec.virtual_size.max(sec.size_of_raw_data);
+        if range_contains(sec.virtual_address, size, rva) {
+            let delta = rva - sec.virtual_address;
+            if delta < sec.size_of_raw_data {
+                return sec.pointer_to_raw_data.checked_add(delta).map(|offset| offset as usize);
+            }
+        }
+        None
+    })
+}
+
+/// Resource type or name: either a numeric id or a string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResourceName {
+    Id(u16),
+    Name(String),
+}
+
+impl ResourceName {
+    /// Display form; well known type ids are shown as `RT_*`.
+    pub fn as_type(&self) -> String {
+        match self {
+            ResourceName::Id(id) => match resource_type_name(*id) {
+                Some(name) => name.to_string(),
+                None => format!("#{}", id),
+            },
+            ResourceName::Name(s) => s.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for ResourceName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResourceName::Id(id) => write!(f, "#{}", id),
+            ResourceName::Name(s) => f.write_str(s),
+        }
+    }
+}
+
+fn resource_type_name(id: u16) -> Option<&'static str> {
+    Some(match id {
+        1 => "RT_CURSOR",
+        2 => "RT_BITMAP",
+        3 => "RT_ICON",
+        4 => "RT_MENU",
+        5 => "RT_DIALOG",
+        6 => "RT_STRING",
+        7 => "RT_FONTDIR",
+        8 => "RT_FONT",
+        9 => "RT_ACCELERATOR",
+        10 => "RT_RCDATA",
+        11 => "RT_MESSAGETABLE",
+        12 => "RT_GROUP_CURSOR",
+        14 => "RT_GROUP_ICON",
+        16 => "RT_VERSION",
+        17 => "RT_DLGINCLUDE",
+        19 => "RT_PLUGPLAY",
+        20 => "RT_VXD",
+        21 => "RT_ANICURSOR",
+        22 => "RT_ANIICON",
+        23 => "RT_HTML",
+        24 => "RT_MANIFEST",
+        _ => return None,
+    })
+}
+
+/// A leaf of the resource tree (type / name / language).
+#[derive(Debug, Clone)]
+pub struct ResourceLeaf {
+    pub type_: ResourceName,
+    pub name: ResourceName,
+    pub language: u16,
+    /// RVA and size of the resource data.
+    pub data_rva: u32,
+    pub size: u32,
+}
+
+/// Walks the standard three level resource directory.
+///
+/// Malformed trees are read as far as they are valid; loops are cut by the
+/// fixed depth.
+pub fn resource_leaves(bytes: &[u8], sections: &[SectionTable], rsrc_rva: u32) -> Vec<ResourceLeaf> {
+    let mut leaves = Vec::new();
+    let Some(base) = rva_to_offset(sections, rsrc_rva) else {
+        return leaves;
+    };
+
+    for (type_, type_dir) in directory_entries(bytes, base, 0) {
+        let Some(type_dir) = type_dir else { continue };
+        for (name, name_dir) in directory_entries(bytes, base, type_dir) {
+            let Some(name_dir) = name_dir else { continue };
+            for (lang, data) in directory_entries(bytes, base, name_dir) {
+                if data.is_some() {
+                    continue;
+                }
+                let lang_id = match lang.0 {
+                    ResourceName::Id(id) => id,
+                    ResourceName::Name(_) => 0,
+                };
+                let entry_offset = base + lang.1 as usize;
+                let (Some(data_rva), Some(size)) =
+                    (read_u32(bytes, entry_offset), read_u32(bytes, entry_offset + 4))
+                else {
+                    continue;
+                };
+                leaves.push(ResourceLeaf {
+                    type_: type_.0.clone(),
+                    name: name.0.clone(),
+                    language: lang_id,
+                    data_rva,
+                    size,
+                });
+            }
+        }
+    }
+    leaves
+}
+
+/// Entries of the directory at `dir` (relative to the resource section start).
+/// Returns `((name, raw offset field), Some(subdir))` for subdirectories and
+/// `(.., None)` for data entries.
+fn directory_entries(bytes: &[u8], base: usize, dir: u32) -> Vec<((ResourceName, u32), Option<u32>)> {
+    let start = base + dir as usize;
+    let (Some(named), Some(ids)) = (read_u16(bytes, start + 12), read_u16(bytes, start + 14)) else {
+        return Vec::new();
+    };
+    let mut out = Vec::new();
+    for i in 0..(named as usize + ids as usize) {
+        let at = start + 16 + i * 8;
+        let (Some(name_field), Some(offset_field)) = (read_u32(bytes, at), read_u32(bytes, at + 4)) else {
+            break;
+        };
+        let name = if name_field & 0x8000_0000 != 0 {
+            ResourceName::Name(read_resource_string(bytes, base + (name_field & 0x7FFF_FFFF) as usize))
+        } else {
+            ResourceName::Id(name_field as u16)
+        };
+        let offset = offset_field & 0x7FFF_FFFF;
+        let subdir = (offset_field & 0x8000_0000 != 0).then_some(offset);
+        out.push(((name, offset), subdir));
+    }
+    out
+}
+
+fn read_resource_string(bytes: &[u8], at: usize) -> String {
+    let len = read_u16(bytes, at).unwrap_or(0) as usize;
+    let units: Vec<u16> = (0..len)
+        .map_while(|i| read_u16(bytes, at + 2 + i * 2))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+pub fn read_u16(bytes: &[u8], at: usize) -> Option<u16> {
+    bytes.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+pub fn read_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    bytes
+        .get(at..at + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
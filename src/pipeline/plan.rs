@@ -0,0 +1,143 @@
+use serde::Serialize;
+
+/// Maximum number of characters of a string kept in a plan entry.
+const PREVIEW_LEN: usize = 40;
+
+/// Changes a dry run would make, collected by the steps instead of writing anything.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ProtectionPlan {
+    pub input: String,
+    pub changes: Vec<PlannedChange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PlannedChange {
+    /// A string found in the input that would be encrypted in place.
+    EncryptString {
+        offset: usize,
+        length: usize,
+        preview: String,
+    },
+    /// A function that would be renamed in the mapping file.
+    RenameFunction { old: String, new: String },
+    /// A file the run would create.
+    WriteFile { path: String, description: String },
+    /// An import that would be removed from the import table and resolved at startup.
+    HideImport { dll: String, function: String, hash: String },
+    /// A section that would be added to the output.
+    AddSection { name: String, description: String },
+    /// A function whose code a pass would rewrite (`transform` names the pass).
+    TransformFunction { function: String, rva: u32, transform: String },
+    /// A resource whose data would be encrypted, after compression when `compressed`.
+    ProtectResource { resource: String, size: u32, compressed: bool },
+    /// Debug information or symbols that would be removed (`item` names the kind).
+    Strip { item: String, description: String },
+    /// A carrier that would hold the watermark identifier.
+    Watermark { carrier: String, description: String },
+}
+
+impl PlannedChange {
+    pub fn encrypt_string(offset: usize, bytes: &[u8]) -> Self {
+        let text = String::from_utf8_lossy(bytes);
+        let preview = if text.chars().count() > PREVIEW_LEN {
+            format!("{}…", text.chars().take(PREVIEW_LEN).collect::<String>())
+        } else {
+            text.into_owned()
+        };
+        PlannedChange::EncryptString {
+            offset,
+            length: bytes.len(),
+            preview,
+        }
+    }
+
+    /// One line description used by the Dashboard and the CLI.
+    pub fn describe(&self) -> String {
+        match self {
+            PlannedChange::EncryptString {
+                offset,
+                length,
+                preview,
+            } => format!("encrypt string @0x{:08x} ({} bytes): {:?}", offset, length, preview),
+            PlannedChange::RenameFunction { old, new } => format!("rename {} => {}", old, new),
+            PlannedChange::WriteFile { path, description } => {
+                format!("write {} ({})", path, description)
+            }
+            PlannedChange::HideImport { dll, function, hash } => {
+                format!("hide import {}!{} (hash {})", dll, function, hash)
+            }
+            PlannedChange::AddSection { name, description } => {
+                format!("add section {} ({})", name, description)
+            }
+            PlannedChange::TransformFunction { function, rva, transform } => {
+                format!("{} function {} @0x{:08x}", transform, function, rva)
+            }
+            PlannedChange::ProtectResource { resource, size, compressed } => format!(
+                "{} resource {} ({} bytes)",
+                if *compressed { "compress and encrypt" } else { "encrypt" },
+                resource,
+                size
+            ),
+            PlannedChange::Strip { item, description } => format!("strip {} ({})", item, description),
+            PlannedChange::Watermark { carrier, description } => format!("watermark {} ({})", carrier, description),
+        }
+    }
+}
+
+impl ProtectionPlan {
+    pub fn new(input: String) -> Self {
+        Self {
+            input,
+            changes: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, change: PlannedChange) {
+        self.changes.push(change);
+    }
+
+    /// Adds `change` unless the same change is already planned (a section several steps add to).
+    pub fn push_once(&mut self, change: PlannedChange) {
+        if !self.changes.contains(&change) {
+            self.changes.push(change);
+        }
+    }
+
+    /// Counts per kind, e.g. "12 strings to encrypt, 3 functions to rename, 2 files to write".
+    /// Kinds beyond those three are only listed when present.
+    pub fn summary(&self) -> String {
+        let (mut strings, mut functions, mut files, mut imports, mut sections, mut transforms, mut resources, mut stripped, mut watermarks) =
+            (0, 0, 0, 0, 0, 0, 0, 0, 0);
+        for change in &self.changes {
+            match change {
+                PlannedChange::EncryptString { .. } => strings += 1,
+                PlannedChange::RenameFunction { .. } => functions += 1,
+                PlannedChange::WriteFile { .. } => files += 1,
+                PlannedChange::HideImport { .. } => imports += 1,
+                PlannedChange::AddSection { .. } => sections += 1,
+                PlannedChange::TransformFunction { .. } => transforms += 1,
+                PlannedChange::ProtectResource { .. } => resources += 1,
+                PlannedChange::Strip { .. } => stripped += 1,
+                PlannedChange::Watermark { .. } => watermarks += 1,
+            }
+        }
+        let mut summary = format!(
+            "{} strings to encrypt, {} functions to rename, {} files to write",
+            strings, functions, files
+        );
+        for (count, what) in [
+            (imports, "imports to hide"),
+            (sections, "sections to add"),
+            (transforms, "function transforms"),
+            (resources, "resources to protect"),
+            (stripped, "items to strip"),
+            (watermarks, "watermark carriers"),
+        ] {
+            if count > 0 {
+                summary.push_str(&format!(", {} {}", count, what));
+            }
+        }
+        summary
+    }
+}
@@ -0,0 +1,612 @@
+//! Protection profile: per-feature settings loaded from a JSON file.
+//!
+//! ```json
+//! {
+//!   "imports": {
+//!     "enabled": true,
+//!     "keep": { "user32.dll": ["*"], "kernel32.dll": ["GetTickCount"] },
+//!     "hash": "siphash"
+//!   },
+//!   "flatten": {
+//!     "enabled": true,
+//!     "functions": [{ "name": "check_license" }, { "rva": 4096 }, { "attribute": "exported" }]
+//!   },
+//!   "opaque": { "enabled": true, "functions": [{ "attribute": "all" }], "density": 0.3 },
+//!   "substitute": { "enabled": true, "functions": [{ "name": "check_license" }] },
+//!   "virtualize": { "enabled": true, "functions": [{ "name": "verify_key" }] },
+//!   "encrypt_code": { "enabled": true, "functions": [{ "name": "decode_license" }], "decrypt": "first_call" },
+//!   "anti_debug": { "enabled": true, "checks": ["peb", "timing", "tracer_pid"], "response": "corrupt" },
+//!   "integrity": { "enabled": true, "sections": [".text", ".rdata"], "interval_secs": 30 },
+//!   "resources": {
+//!     "enabled": true,
+//!     "protect": [{ "type": "RT_RCDATA" }, { "type": "SCRIPTS", "name": "MAIN" }],
+//!     "compress": true,
+//!     "decode": "load"
+//!   },
+//!   "strip": { "enabled": true, "items": ["debug_directory", "rich_header", "symbols"] },
+//!   "watermark": { "enabled": true, "id": "acme-2026-10", "key": "000102030405060708090a0b0c0d0e0f" },
+//!   "artifacts": { "dir": "symbols/1.4.0", "key": "<64 hex digits>", "signing_key": "<64 hex digits>" },
+//!   "pack": true,
+//!   "startup_hook": "tls_callback",
+//!   "seed": 1234
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+
+use crate::pipeline::error::StepError;
+use crate::pipeline::hash::HashAlgorithm;
+use crate::pipeline::image::ImageFormat;
+use crate::pipeline::pe::ResourceName;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProtectionProfile {
+    pub imports: ImportProtection,
+    /// Move every movable function into a new code section unchanged (`MoveFunctionsStep`).
+    pub move_functions: bool,
+    pub flatten: FlattenOptions,
+    pub opaque: OpaqueOptions,
+    pub substitute: SubstituteOptions,
+    pub virtualize: VirtualizeOptions,
+    pub encrypt_code: EncryptCodeOptions,
+    pub anti_debug: AntiDebugOptions,
+    pub integrity: IntegrityOptions,
+    pub resources: ResourceProtection,
+    /// Compress the sections into one payload unpacked at startup (`PackStep`).
+    pub pack: bool,
+    /// What calls the startup routines of the steps.
+    pub startup_hook: StartupHook,
+    pub strip: StripOptions,
+    pub watermark: WatermarkOptions,
+    pub artifacts: ArtifactOptions,
+    /// Makes the randomized passes reproducible; a fresh seed is drawn per run
+    /// when unset, and logged so that the run can be repeated.
+    pub seed: Option<u64>,
+}
+
+/// Random generator of one step, derived from the seed of the run and the
+/// step name, so adding a step does not change what the others produce.
+pub fn step_rng(seed: u64, step: &str) -> StdRng {
+    let stream = step.bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    StdRng::seed_from_u64(seed ^ stream)
+}
+
+/// Parses a SipHash key given as 32 hex digits.
+pub fn parse_sip_key(hex: &str) -> Result<[u64; 2], StepError> {
+    let invalid = || StepError::InvalidInput(format!("SipHash key must be 32 hex digits, got '{}'", hex));
+    if hex.len() != 32 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let k0 = u64::from_str_radix(&hex[..16], 16).map_err(|_| invalid())?;
+    let k1 = u64::from_str_radix(&hex[16..], 16).map_err(|_| invalid())?;
+    Ok([k0, k1])
+}
+
+/// Which functions a code pass applies to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FunctionSelector {
+    /// Export or symbol name.
+    Name(String),
+    Rva(u32),
+    Attribute(FunctionAttribute),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FunctionAttribute {
+    Exported,
+    Entry,
+    /// Every function the disassembler found.
+    All,
+}
+
+impl FunctionSelector {
+    /// Parses the command line form: `0x1234` (RVA), `@exported`, `@entry`, `@all` or a name.
+    pub fn parse(text: &str) -> Result<Self, StepError> {
+        if let Some(hex) = text.strip_prefix("0x") {
+            return u32::from_str_radix(hex, 16)
+                .map(FunctionSelector::Rva)
+                .map_err(|_| StepError::InvalidInput(format!("Invalid RVA '{}'", text)));
+        }
+        Ok(match text {
+            "@exported" => FunctionSelector::Attribute(FunctionAttribute::Exported),
+            "@entry" => FunctionSelector::Attribute(FunctionAttribute::Entry),
+            "@all" => FunctionSelector::Attribute(FunctionAttribute::All),
+            _ if text.starts_with('@') => {
+                return Err(StepError::InvalidInput(format!(
+                    "Unknown function attribute '{}' (expected @exported, @entry or @all)",
+                    text
+                )))
+            }
+            name => FunctionSelector::Name(name.to_string()),
+        })
+    }
+}
+
+impl std::fmt::Display for FunctionSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FunctionSelector::Name(name) => f.write_str(name),
+            FunctionSelector::Rva(rva) => write!(f, "0x{:x}", rva),
+            FunctionSelector::Attribute(FunctionAttribute::Exported) => f.write_str("@exported"),
+            FunctionSelector::Attribute(FunctionAttribute::Entry) => f.write_str("@entry"),
+            FunctionSelector::Attribute(FunctionAttribute::All) => f.write_str("@all"),
+        }
+    }
+}
+
+/// How the dispatcher of a flattened function encodes the state variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum StateEncoding {
+    /// Block numbers are stored as they are compared.
+    Plain,
+    /// Stored XOR a per-function key.
+    Xor,
+    /// Stored as `(state - b) * a⁻¹`; the dispatcher computes `value * a + b`.
+    #[default]
+    Affine,
+}
+
+/// Settings of `FlattenStep`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FlattenOptions {
+    pub enabled: bool,
+    pub functions: Vec<FunctionSelector>,
+    /// Larger functions are skipped (bytes of original code).
+    pub max_size: u32,
+    pub state_encoding: StateEncoding,
+}
+
+/// Settings of `OpaquePredicatesStep`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OpaqueOptions {
+    pub enabled: bool,
+    pub functions: Vec<FunctionSelector>,
+    /// Chance (0 to 1) for each basic block to get an opaque predicate, and
+    /// separately a junk sequence.
+    pub density: f64,
+    pub predicates: bool,
+    pub junk: bool,
+}
+
+impl Default for OpaqueOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            functions: Vec::new(),
+            density: 0.5,
+            predicates: true,
+            junk: true,
+        }
+    }
+}
+
+/// Settings of `SubstituteStep`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SubstituteOptions {
+    pub enabled: bool,
+    pub functions: Vec<FunctionSelector>,
+    /// Chance (0 to 1) for each eligible instruction to be rewritten.
+    pub density: f64,
+}
+
+impl Default for SubstituteOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            functions: Vec::new(),
+            density: 1.0,
+        }
+    }
+}
+
+/// Settings of `VirtualizeStep`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VirtualizeOptions {
+    pub enabled: bool,
+    pub functions: Vec<FunctionSelector>,
+}
+
+/// When the bodies encrypted by `EncryptCodeStep` are decrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum DecryptTiming {
+    /// By a stub the function's start jumps to, the first time it is called.
+    #[default]
+    FirstCall,
+    /// All at once by a startup routine, before the original entry point.
+    Startup,
+}
+
+/// Settings of `EncryptCodeStep`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncryptCodeOptions {
+    pub enabled: bool,
+    pub functions: Vec<FunctionSelector>,
+    pub decrypt: DecryptTiming,
+    /// Encrypt the body again when the outermost call returns (first-call decryption only).
+    pub reencrypt: bool,
+}
+
+/// A debugger check of `AntiDebugStep`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum AntiDebugCheck {
+    /// `BeingDebugged` and the heap flags of `NtGlobalFlag` in the PEB (PE).
+    Peb,
+    /// Hardware breakpoints set in `Dr0`-`Dr3` of the starting thread (PE).
+    DebugRegisters,
+    /// A short loop taking far too long, as when it is single-stepped.
+    Timing,
+    /// The parent process is a known debugger.
+    ParentProcess,
+    /// `TracerPid` in `/proc/self/status` (ELF).
+    TracerPid,
+    /// `PTRACE_TRACEME` fails because a tracer is attached (ELF). When it
+    /// succeeds the parent becomes the tracer: an `execve` then raises
+    /// `SIGTRAP` and signals stop the process, so it is not a default check.
+    Ptrace,
+}
+
+impl AntiDebugCheck {
+    /// Whether the check can be injected into images of `format`.
+    pub fn supports(self, format: ImageFormat) -> bool {
+        match self {
+            AntiDebugCheck::Peb | AntiDebugCheck::DebugRegisters => format == ImageFormat::Pe,
+            AntiDebugCheck::TracerPid | AntiDebugCheck::Ptrace => format == ImageFormat::Elf,
+            AntiDebugCheck::Timing | AntiDebugCheck::ParentProcess => true,
+        }
+    }
+}
+
+impl std::fmt::Display for AntiDebugCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AntiDebugCheck::Peb => "peb",
+            AntiDebugCheck::DebugRegisters => "debug_registers",
+            AntiDebugCheck::Timing => "timing",
+            AntiDebugCheck::ParentProcess => "parent_process",
+            AntiDebugCheck::TracerPid => "tracer_pid",
+            AntiDebugCheck::Ptrace => "ptrace",
+        })
+    }
+}
+
+/// What the protected binary does when a check finds a debugger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum DebuggerResponse {
+    /// Exit with status 1.
+    #[default]
+    Exit,
+    /// Execute an invalid instruction.
+    Crash,
+    /// Scramble some initialized data and carry on, so results come out wrong.
+    Corrupt,
+}
+
+/// Settings of `AntiDebugStep`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AntiDebugOptions {
+    pub enabled: bool,
+    /// Checks that do not apply to the input format are skipped.
+    pub checks: Vec<AntiDebugCheck>,
+    pub response: DebuggerResponse,
+}
+
+impl Default for AntiDebugOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            checks: vec![
+                AntiDebugCheck::Peb,
+                AntiDebugCheck::DebugRegisters,
+                AntiDebugCheck::Timing,
+                AntiDebugCheck::ParentProcess,
+                AntiDebugCheck::TracerPid,
+            ],
+            response: DebuggerResponse::default(),
+        }
+    }
+}
+
+/// What the protected binary does when its code no longer matches the hashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum TamperResponse {
+    /// Exit with status 1.
+    #[default]
+    Exit,
+    /// Execute an invalid instruction.
+    Crash,
+}
+
+/// Debug information or symbols `StripStep` can remove.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum StripItem {
+    /// Debug directory entries and their data, PDB path included (PE).
+    DebugDirectory,
+    /// Directories of the CodeView PDB path, when the debug directory stays (PE).
+    PdbPath,
+    /// Rich header listing the build tools (PE).
+    RichHeader,
+    /// COFF symbol and string tables (PE).
+    CoffSymbols,
+    /// `.debug_*` sections and debug links (ELF).
+    DebugSections,
+    /// `.symtab` and its string table (ELF).
+    Symbols,
+}
+
+impl StripItem {
+    /// Whether the item exists in images of `format`.
+    pub fn supports(self, format: ImageFormat) -> bool {
+        match self {
+            StripItem::DebugSections | StripItem::Symbols => format == ImageFormat::Elf,
+            _ => format == ImageFormat::Pe,
+        }
+    }
+}
+
+impl std::fmt::Display for StripItem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            StripItem::DebugDirectory => "debug_directory",
+            StripItem::PdbPath => "pdb_path",
+            StripItem::RichHeader => "rich_header",
+            StripItem::CoffSymbols => "coff_symbols",
+            StripItem::DebugSections => "debug_sections",
+            StripItem::Symbols => "symbols",
+        })
+    }
+}
+
+/// Settings of `StripStep`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StripOptions {
+    pub enabled: bool,
+    /// Items that do not apply to the input format are skipped.
+    pub items: Vec<StripItem>,
+}
+
+impl Default for StripOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            items: vec![
+                StripItem::DebugDirectory,
+                StripItem::RichHeader,
+                StripItem::CoffSymbols,
+                StripItem::DebugSections,
+                StripItem::Symbols,
+            ],
+        }
+    }
+}
+
+/// Where `WatermarkStep` hides the identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum WatermarkCarrier {
+    /// Encrypted copies in the zero padding after the data of the sections.
+    Padding,
+    /// Encrypted copies in the filler between functions.
+    CodeCaves,
+    /// The choice between the two encodings of register-to-register instructions.
+    Instructions,
+}
+
+impl std::fmt::Display for WatermarkCarrier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            WatermarkCarrier::Padding => "padding",
+            WatermarkCarrier::CodeCaves => "code_caves",
+            WatermarkCarrier::Instructions => "instructions",
+        })
+    }
+}
+
+/// Settings of `WatermarkStep`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WatermarkOptions {
+    pub enabled: bool,
+    /// Customer or build identifier, 1 to 32 bytes.
+    pub id: String,
+    /// SipHash key as 32 hex digits. Without one a built-in key is used, and
+    /// anyone with this tool can find the watermark.
+    pub key: Option<String>,
+    pub carriers: Vec<WatermarkCarrier>,
+}
+
+impl Default for WatermarkOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            id: String::new(),
+            key: None,
+            carriers: vec![WatermarkCarrier::Padding, WatermarkCarrier::CodeCaves, WatermarkCarrier::Instructions],
+        }
+    }
+}
+
+impl WatermarkOptions {
+    /// The configured key, if any.
+    pub fn key(&self) -> Result<Option<[u64; 2]>, StepError> {
+        self.key.as_deref().map(parse_sip_key).transpose()
+    }
+}
+
+/// Where and how the side artifacts (symbol map, import hashes, encrypted
+/// strings copy) are written; see `artifacts`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ArtifactOptions {
+    /// Directory of the artifacts; a per-user data directory when unset, so
+    /// they never end up next to the protected output.
+    pub dir: Option<PathBuf>,
+    /// Team key as 64 hex digits: the artifacts are encrypted with ChaCha20-Poly1305.
+    pub key: Option<String>,
+    /// Ed25519 secret key as 64 hex digits: every artifact gets a `.sig` file.
+    pub signing_key: Option<String>,
+}
+
+/// What calls the startup routines injected by the steps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum StartupHook {
+    /// A thunk that becomes the entry point.
+    #[default]
+    Entry,
+    /// A TLS callback called before the existing ones, and so before the
+    /// entry point (PE only).
+    TlsCallback,
+}
+
+/// Settings of `IntegrityStep`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IntegrityOptions {
+    pub enabled: bool,
+    /// Sections to hash by name; every section that is not writable when empty.
+    pub sections: Vec<String>,
+    /// Check again every this many seconds from a background thread.
+    pub interval_secs: Option<u32>,
+    pub response: TamperResponse,
+}
+
+/// Resources `ProtectResourcesStep` encodes: one type, or one name of that type.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceSelector {
+    /// `RT_*` name, `#id` or the name of a custom type.
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// `#id` or name; every resource of the type when unset.
+    pub name: Option<String>,
+}
+
+impl ResourceSelector {
+    /// Parses the command line form: `TYPE` or `TYPE/NAME` (`TYPE/*` is `TYPE`).
+    pub fn parse(text: &str) -> Result<Self, StepError> {
+        let (type_, name) = match text.split_once('/') {
+            Some((type_, name)) => (type_, (name != "*").then(|| name.to_string())),
+            None => (text, None),
+        };
+        if type_.is_empty() || name.as_deref() == Some("") {
+            return Err(StepError::InvalidInput(format!(
+                "Invalid resource '{}' (expected TYPE or TYPE/NAME)",
+                text
+            )));
+        }
+        Ok(Self { type_: type_.to_string(), name })
+    }
+
+    /// Whether the resource of type `type_` named `name` is selected (case-insensitive,
+    /// as `FindResource` compares names).
+    pub fn matches(&self, type_: &ResourceName, name: &ResourceName) -> bool {
+        let type_matches = self.type_.eq_ignore_ascii_case(&type_.as_type())
+            || self.type_.eq_ignore_ascii_case(&type_.to_string());
+        type_matches && self.name.as_ref().is_none_or(|n| n.eq_ignore_ascii_case(&name.to_string()))
+    }
+}
+
+impl std::fmt::Display for ResourceSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{}/{}", self.type_, name),
+            None => f.write_str(&self.type_),
+        }
+    }
+}
+
+/// When the resources encoded by `ProtectResourcesStep` are decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceDecoding {
+    /// By a `LoadResource` hook, the first time the program loads each one.
+    #[default]
+    Load,
+    /// All at once by a startup routine, before the original entry point.
+    Startup,
+}
+
+/// Settings of `ProtectResourcesStep`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResourceProtection {
+    pub enabled: bool,
+    pub protect: Vec<ResourceSelector>,
+    /// Compress the selected resources before encrypting them; their data
+    /// moves to the section of the step.
+    pub compress: bool,
+    pub decode: ResourceDecoding,
+}
+
+impl Default for FlattenOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            functions: Vec::new(),
+            max_size: 4096,
+            state_encoding: StateEncoding::default(),
+        }
+    }
+}
+
+/// Settings of `ProtectImportsStep`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImportProtection {
+    pub enabled: bool,
+    /// Imports that must stay in the import table, per DLL (case-insensitive).
+    /// `"*"` keeps every import of that DLL.
+    pub keep: BTreeMap<String, Vec<String>>,
+    /// Hash stored in place of the DLL and function names.
+    pub hash: HashAlgorithm,
+    /// SipHash key as 32 hex digits; a random key is drawn for every build when unset.
+    pub sip_key: Option<String>,
+}
+
+impl ImportProtection {
+    /// The configured SipHash key, if any.
+    pub fn sip_key(&self) -> Result<Option<[u64; 2]>, StepError> {
+        self.sip_key.as_deref().map(parse_sip_key).transpose()
+    }
+
+    /// Whether `function` of `dll` stays a static import.
+    pub fn keeps(&self, dll: &str, function: &str) -> bool {
+        self.keep
+            .iter()
+            .filter(|(d, _)| d.eq_ignore_ascii_case(dll))
+            .flat_map(|(_, functions)| functions)
+            .any(|f| f == "*" || f == function)
+    }
+}
+
+impl ProtectionProfile {
+    pub fn load(path: &Path) -> Result<Self, StepError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| StepError::io("Failed to read profile", path, e))?;
+        serde_json::from_str(&text).map_err(|e| {
+            StepError::InvalidInput(format!("Invalid profile '{}': {}", path.display(), e))
+        })
+    }
+}
@@ -0,0 +1,243 @@
+//! Writes the functions changed by the code passes back into the image.
+//!
+//! Modified functions are re-encoded with `BlockEncoder` into a new code
+//! section (`.obsc`), which fixes up their relative branches for the new
+//! address; unchanged instructions keep their original bytes. The original start is patched with a
+//! `jmp rel32` to the new copy and the rest of the old body is filled with
+//! `int3`. On x64 the exception directory is rebuilt with an entry for every
+//! moved function that reuses its original `UNWIND_INFO`, which is only valid
+//! because passes leave the prologue bytes unchanged. Base relocations in
+//! a moved function follow the instructions they patch, which must then keep
+//! their original bytes; `Image::finalize` writes the new relocation table.
+
+use iced_x86::{BlockEncoder, BlockEncoderOptions, Decoder, DecoderOptions, Instruction, InstructionBlock};
+
+use crate::pipeline::code::{branch_target, CodeMap, Function, MIN_MOVABLE_SIZE};
+use crate::pipeline::error::StepError;
+use crate::pipeline::image::{Image, DIR_EXCEPTION, IMAGE_SCN_CNT_CODE, IMAGE_SCN_MEM_EXECUTE, IMAGE_SCN_MEM_READ};
+
+pub const CODE_SECTION: &str = ".obsc";
+const FUNCTION_ALIGNMENT: usize = 16;
+const RUNTIME_FUNCTION_SIZE: u32 = 12;
+
+struct Moved<'a> {
+    function: &'a Function,
+    rva: u32,
+    size: u32,
+    /// Relocations of the new copy as `(rva, type)`.
+    relocations: Vec<(u32, u16)>,
+}
+
+/// Moves every modified function of `code` into a new section of `image`.
+pub fn reassemble(image: &mut Image, code: &CodeMap) -> Result<(), StepError> {
+    let section_rva = image.next_section_rva();
+    let mut data: Vec<u8> = Vec::new();
+    let mut moved: Vec<Moved> = Vec::new();
+
+    for function in code.functions.iter().filter(|f| f.modified) {
+        if function.info.size < MIN_MOVABLE_SIZE {
+            return Err(StepError::Internal(format!(
+                "{} is too small to be moved ({} bytes)",
+                function.name(),
+                function.info.size
+            )));
+        }
+        data.resize(data.len().next_multiple_of(FUNCTION_ALIGNMENT), 0xcc);
+        let rva = section_rva + data.len() as u32;
+        let va = image.image_base + rva as u64;
+        let Encodings { instructions, fixups, kept } = original_encodings(image, code.bitness, function)?;
+        let block = InstructionBlock::new(&instructions, va);
+        let mut encoded =
+            BlockEncoder::encode(code.bitness, block, BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS)
+                .map_err(|e| StepError::Internal(format!("Failed to reassemble {}: {}", function.name(), e)))?;
+        for fixup in &fixups {
+            let at = encoded.new_instruction_offsets[fixup.index] as usize;
+            let next = va + (at + fixup.len) as u64;
+            let displacement = fixup.target.wrapping_sub(next) as u32;
+            let field = at + fixup.displacement_offset;
+            encoded.code_buffer[field..field + 4].copy_from_slice(&displacement.to_le_bytes());
+        }
+
+        // the unwind info describes the prologue by offset, so it must encode identically
+        if let Some(unwind) = function.info.unwind {
+            let len = unwind.prolog_size as usize;
+            if image.read(function.info.rva, len) != encoded.code_buffer.get(..len) {
+                image.notes.push(format!(
+                    "{} left in place: its prologue no longer matches the unwind info",
+                    function.name()
+                ));
+                continue;
+            }
+        }
+
+        let info = &function.info;
+        let mut relocations = Vec::new();
+        let mut rewritten = None;
+        for (at, kind) in image.relocations_in(info.rva, info.rva + info.size) {
+            match kept.iter().find(|k| at >= k.rva && at < k.rva + k.len) {
+                Some(k) => relocations.push((rva + encoded.new_instruction_offsets[k.index] + (at - k.rva), kind)),
+                None => {
+                    rewritten = Some(at);
+                    break;
+                }
+            }
+        }
+        if let Some(at) = rewritten {
+            image.notes.push(format!(
+                "{} left in place: the relocated word at 0x{:x} is not in an unchanged instruction",
+                function.name(),
+                at
+            ));
+            continue;
+        }
+        data.extend_from_slice(&encoded.code_buffer);
+        moved.push(Moved {
+            function,
+            rva,
+            size: encoded.code_buffer.len() as u32,
+            relocations,
+        });
+    }
+    if moved.is_empty() {
+        return Ok(());
+    }
+
+    let unwound: Vec<&Moved> = moved.iter().filter(|m| m.function.info.unwind.is_some()).collect();
+    let mut exception_table = None;
+    if !unwound.is_empty() {
+        data.resize(data.len().next_multiple_of(4), 0);
+        let table_rva = section_rva + data.len() as u32;
+        let mut entries = runtime_functions(image);
+        entries.extend(unwound.iter().map(|m| {
+            let unwind = m.function.info.unwind.unwrap();
+            [m.rva, m.rva + m.size, unwind.unwind_rva]
+        }));
+        entries.sort_by_key(|e| e[0]);
+        for entry in &entries {
+            for field in entry {
+                data.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+        exception_table = Some((table_rva, entries.len() as u32 * RUNTIME_FUNCTION_SIZE));
+    }
+
+    let rva = image.add_section(
+        CODE_SECTION,
+        &data,
+        IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE | IMAGE_SCN_MEM_READ,
+    )?;
+    if rva != section_rva {
+        return Err(StepError::Internal(format!(
+            "Code section placed at 0x{:x} instead of 0x{:x}",
+            rva, section_rva
+        )));
+    }
+    if let Some((table_rva, size)) = exception_table {
+        image.set_data_directory(DIR_EXCEPTION, table_rva, size);
+    }
+
+    for m in &moved {
+        let info = &m.function.info;
+        let mut patch = vec![0xcc; info.size as usize];
+        patch[0] = 0xe9;
+        let rel = m.rva.wrapping_sub(info.rva + 5);
+        patch[1..5].copy_from_slice(&rel.to_le_bytes());
+        image.write(info.rva, &patch)?;
+        image.remove_relocations(info.rva, info.rva + info.size);
+        for &(rva, kind) in &m.relocations {
+            image.add_relocation(rva, kind);
+        }
+        image.record_move(info.rva, m.rva, m.size);
+    }
+    image.notes.push(format!(
+        "Moved {} functions to section {} ({} bytes)",
+        moved.len(),
+        CODE_SECTION,
+        data.len()
+    ));
+    Ok(())
+}
+
+struct Encodings {
+    instructions: Vec<Instruction>,
+    fixups: Vec<Fixup>,
+    kept: Vec<Kept>,
+}
+
+/// An instruction copied with its original bytes.
+struct Kept {
+    index: usize,
+    rva: u32,
+    len: u32,
+}
+
+/// A RIP-relative displacement to point at `target` once the instruction at `index` is placed.
+struct Fixup {
+    index: usize,
+    len: usize,
+    displacement_offset: usize,
+    target: u64,
+}
+
+/// The instructions of `function`, with every one a pass left unchanged
+/// replaced by its original bytes. The encoder would otherwise pick its own
+/// forms: `push rbx` without the `40` prefix MSVC emits in prologues changes
+/// the offsets in the unwind codes, and `jmp [rip+x]` without `REX.W` is no
+/// longer recognized as an epilogue by the unwinder. Relative branches are
+/// still left to the encoder; RIP-relative operands are fixed up afterwards.
+fn original_encodings(
+    image: &Image,
+    bitness: u32,
+    function: &Function,
+) -> Result<Encodings, StepError> {
+    let start = image.image_base + function.info.rva as u64;
+    let end = start + function.info.size as u64;
+    let mut instructions = function.instructions.clone();
+    let mut fixups = Vec::new();
+    let mut kept = Vec::new();
+    for (index, instruction) in instructions.iter_mut().enumerate() {
+        if instruction.ip() < start || instruction.ip() >= end || branch_target(instruction).is_some() {
+            continue;
+        }
+        let rva = (instruction.ip() - image.image_base) as u32;
+        let Some(bytes) = image.read(rva, instruction.len()) else {
+            continue;
+        };
+        let mut decoder = Decoder::with_ip(bitness, bytes, instruction.ip(), DecoderOptions::NONE);
+        let original = decoder.decode();
+        if original != *instruction || original.len() != bytes.len() {
+            continue;
+        }
+        if original.is_ip_rel_memory_operand() {
+            fixups.push(Fixup {
+                index,
+                len: bytes.len(),
+                displacement_offset: decoder.get_constant_offsets(&original).displacement_offset(),
+                target: original.ip_rel_memory_address(),
+            });
+        }
+        let mut raw = Instruction::with_declare_byte(bytes)?;
+        raw.set_ip(instruction.ip());
+        *instruction = raw;
+        kept.push(Kept {
+            index,
+            rva,
+            len: bytes.len() as u32,
+        });
+    }
+    Ok(Encodings { instructions, fixups, kept })
+}
+
+/// Entries of the current exception directory as `[begin, end, unwind]`.
+fn runtime_functions(image: &Image) -> Vec<[u32; 3]> {
+    let (table, size) = image.data_directory(DIR_EXCEPTION);
+    if table == 0 {
+        return Vec::new();
+    }
+    (0..size / RUNTIME_FUNCTION_SIZE)
+        .filter_map(|i| {
+            let at = table + i * RUNTIME_FUNCTION_SIZE;
+            Some([image.read_u32(at)?, image.read_u32(at + 4)?, image.read_u32(at + 8)?])
+        })
+        .collect()
+}
@@ -0,0 +1,208 @@
+//! Base relocations of an `Image`: which words the loader patches when the
+//! image is not loaded at its preferred base (ASLR).
+//!
+//! `Image` reads them once, from `.reloc` on PE or the relative relocations
+//! on ELF. Steps ask `Image::is_relocated` before rewriting bytes, and code
+//! moved by `reassemble` takes its relocations along. When the set changed,
+//! `Image::finalize` writes a new relocation table, so `DYNAMIC_BASE` images
+//! stay loadable at any address. ELF relocation tables are not rewritten.
+
+use std::collections::BTreeMap;
+
+/// Padding entry that keeps blocks 32-bit aligned.
+pub const IMAGE_REL_BASED_ABSOLUTE: u16 = 0;
+pub const IMAGE_REL_BASED_HIGHLOW: u16 = 3;
+/// High half of a 32-bit address; the next slot holds the low half, which
+/// the loader needs to round the adjustment.
+pub const IMAGE_REL_BASED_HIGHADJ: u16 = 4;
+pub const IMAGE_REL_BASED_DIR64: u16 = 10;
+const BLOCK_HEADER_SIZE: u32 = 8;
+
+/// Relocated words by RVA, with their `IMAGE_REL_BASED_*` type.
+#[derive(Debug, Clone, Default)]
+pub struct Relocations {
+    entries: BTreeMap<u32, u16>,
+    /// Low halves stored after the `IMAGE_REL_BASED_HIGHADJ` entries, by RVA.
+    high_adjust: BTreeMap<u32, u16>,
+    changed: bool,
+}
+
+impl Relocations {
+    /// Entries of the blocks of a PE relocation directory. A block whose
+    /// page is so high that its entries would wrap past 4 GiB is skipped.
+    pub fn parse_blocks(table: &[u8]) -> Self {
+        let mut relocations = Self::default();
+        let mut at = 0;
+        while at + BLOCK_HEADER_SIZE as usize <= table.len() {
+            let page = u32::from_le_bytes(table[at..at + 4].try_into().unwrap());
+            let size = u32::from_le_bytes(table[at + 4..at + 8].try_into().unwrap()) as usize;
+            if size < BLOCK_HEADER_SIZE as usize || at + size > table.len() {
+                break;
+            }
+            let mut slots = table[at + 8..at + size]
+                .chunks_exact(2)
+                .map(|slot| u16::from_le_bytes([slot[0], slot[1]]));
+            let mut block = Vec::new();
+            while let Some(entry) = slots.next() {
+                let kind = entry >> 12;
+                if kind == IMAGE_REL_BASED_ABSOLUTE {
+                    continue;
+                }
+                let Some(rva) = page.checked_add((entry & 0xfff) as u32) else {
+                    block.clear();
+                    break;
+                };
+                let low_half = if kind == IMAGE_REL_BASED_HIGHADJ { Some(slots.next().unwrap_or(0)) } else { None };
+                block.push((rva, kind, low_half));
+            }
+            for (rva, kind, low_half) in block {
+                relocations.entries.insert(rva, kind);
+                if let Some(low_half) = low_half {
+                    relocations.high_adjust.insert(rva, low_half);
+                }
+            }
+            at += size;
+        }
+        relocations
+    }
+
+    /// Relocations of the given type at `rvas`.
+    pub fn from_rvas(rvas: &[u32], kind: u16) -> Self {
+        Self {
+            entries: rvas.iter().map(|&rva| (rva, kind)).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// RVAs of the relocated words, sorted.
+    pub fn rvas(&self) -> Vec<u32> {
+        self.entries.keys().copied().collect()
+    }
+
+    /// Relocations whose word starts in `start..end`, as `(rva, type)`.
+    pub fn in_range(&self, start: u32, end: u32) -> Vec<(u32, u16)> {
+        self.entries.range(start..end).map(|(&rva, &kind)| (rva, kind)).collect()
+    }
+
+    /// Whether the loader patches any byte of `rva..rva + len`.
+    pub fn overlaps(&self, rva: u32, len: u32) -> bool {
+        // a word starting up to 7 bytes before `rva` may reach into it
+        self.entries
+            .range(rva.saturating_sub(7)..rva.saturating_add(len))
+            .any(|(&at, &kind)| at + word_size(kind) > rva)
+    }
+
+    /// Forgets the relocations starting in `start..end` and returns them.
+    pub fn remove(&mut self, start: u32, end: u32) -> Vec<(u32, u16)> {
+        let removed = self.in_range(start, end);
+        for (rva, _) in &removed {
+            self.entries.remove(rva);
+        }
+        self.changed |= !removed.is_empty();
+        removed
+    }
+
+    pub fn insert(&mut self, rva: u32, kind: u16) {
+        self.entries.insert(rva, kind);
+        self.changed = true;
+    }
+
+    /// Whether there are `IMAGE_REL_BASED_HIGHADJ` entries, whose low half
+    /// would not follow them if they moved.
+    pub fn has_high_adjust(&self) -> bool {
+        !self.high_adjust.is_empty()
+    }
+
+    /// Whether the set differs from what the image was loaded with.
+    pub fn is_changed(&self) -> bool {
+        self.changed
+    }
+
+    /// The relocation directory for these entries (see `blocks`).
+    pub fn to_blocks(&self, fallback_page: u32) -> Vec<u8> {
+        write_blocks(self.entries.iter().map(|(&rva, &kind)| (rva, kind)), &self.high_adjust, fallback_page)
+    }
+}
+
+/// Size of the word a relocation type patches.
+pub fn word_size(kind: u16) -> u32 {
+    match kind {
+        IMAGE_REL_BASED_DIR64 => 8,
+        IMAGE_REL_BASED_HIGHLOW => 4,
+        // HIGH, LOW and HIGHADJ patch a half of a 32-bit address
+        1 | 2 | IMAGE_REL_BASED_HIGHADJ => 2,
+        _ => 4,
+    }
+}
+
+/// PE relocation blocks, one per 4 KiB page, for `entries` sorted by RVA.
+/// With no entries this is a single empty block at `fallback_page`: an empty
+/// directory would mean the relocations were stripped, and the loader would
+/// not move the image.
+pub fn blocks(entries: impl IntoIterator<Item = (u32, u16)>, fallback_page: u32) -> Vec<u8> {
+    write_blocks(entries, &BTreeMap::new(), fallback_page)
+}
+
+/// `blocks`, with the low half of each `IMAGE_REL_BASED_HIGHADJ` entry
+/// written in the slot after it.
+fn write_blocks(
+    entries: impl IntoIterator<Item = (u32, u16)>,
+    high_adjust: &BTreeMap<u32, u16>,
+    fallback_page: u32,
+) -> Vec<u8> {
+    let mut pages: Vec<(u32, Vec<u16>)> = Vec::new();
+    for (rva, kind) in entries {
+        let entry = kind << 12 | (rva & 0xfff) as u16;
+        if pages.last().is_none_or(|(page, _)| *page != rva & !0xfff) {
+            pages.push((rva & !0xfff, Vec::new()));
+        }
+        let page_entries = &mut pages.last_mut().unwrap().1;
+        page_entries.push(entry);
+        if kind == IMAGE_REL_BASED_HIGHADJ {
+            page_entries.push(high_adjust.get(&rva).copied().unwrap_or(0));
+        }
+    }
+    if pages.is_empty() {
+        pages.push((fallback_page & !0xfff, Vec::new()));
+    }
+    let mut table = Vec::new();
+    for (page, mut page_entries) in pages {
+        while page_entries.is_empty() || !page_entries.len().is_multiple_of(2) {
+            page_entries.push(IMAGE_REL_BASED_ABSOLUTE);
+        }
+        table.extend_from_slice(&page.to_le_bytes());
+        table.extend_from_slice(&(BLOCK_HEADER_SIZE + page_entries.len() as u32 * 2).to_le_bytes());
+        for entry in page_entries {
+            table.extend_from_slice(&entry.to_le_bytes());
+        }
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(page: u32, slots: &[u16]) -> Vec<u8> {
+        let mut table = page.to_le_bytes().to_vec();
+        table.extend_from_slice(&(BLOCK_HEADER_SIZE + slots.len() as u32 * 2).to_le_bytes());
+        table.extend(slots.iter().flat_map(|slot| slot.to_le_bytes()));
+        table
+    }
+
+    #[test]
+    fn parse_blocks_handles_high_adjust_and_wrapping_pages() {
+        let mut table = block(0x1000, &[0xa010, 0x4020, 0x8000, 0x3030, 0x0000]);
+        // 0xffffff00 + 0xff0 wraps: the whole block is dropped
+        table.extend(block(0xffff_ff00, &[0x3010, 0x3ff0]));
+        table.extend(block(0x2000, &[0x3008, 0x0000]));
+        let relocations = Relocations::parse_blocks(&table);
+        // the slot after HIGHADJ is its low half (0x8000), not a HIGH entry at 0x1000
+        assert_eq!(relocations.rvas(), [0x1010, 0x1020, 0x1030, 0x2008]);
+        assert_eq!(relocations.in_range(0x1020, 0x1021), [(0x1020, IMAGE_REL_BASED_HIGHADJ)]);
+        assert!(relocations.has_high_adjust());
+        // written back with the low half in place
+        assert_eq!(Relocations::parse_blocks(&relocations.to_blocks(0)).high_adjust, relocations.high_adjust);
+        assert!(relocations.to_blocks(0).starts_with(&block(0x1000, &[0xa010, 0x4020, 0x8000, 0x3030])));
+    }
+}
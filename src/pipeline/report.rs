@@ -0,0 +1,572 @@
+use std::fmt::Write as _;
+
+use goblin::elf::Elf;
+use goblin::pe::PE;
+use goblin::pe::export::Reexport;
+use serde::Serialize;
+
+use crate::pipeline::{elf, pe};
+use crate::pipeline::packer::{self, PackerAssessment};
+
+/// Structured findings of `ParseStep`, exportable as JSON or a standalone HTML page.
+#[derive(Debug, Clone, Serialize)]
+pub struct AnalysisReport {
+    pub file: String,
+    pub file_size: usize,
+    pub format: String,
+    pub architecture: String,
+    pub image_base: u64,
+    pub entry_point_rva: u32,
+    pub sections: Vec<SectionReport>,
+    /// Data appended after the last section, if any.
+    pub overlay: Option<OverlayReport>,
+    pub packer: PackerAssessment,
+    pub imports: Vec<ImportedDll>,
+    pub exports: Vec<ExportReport>,
+    /// RVAs of the TLS callbacks, in call order.
+    pub tls_callbacks: Vec<u64>,
+    pub debug_directory: Vec<DebugEntry>,
+    pub pdb: Option<PdbInfo>,
+    pub rich_header: Option<RichHeaderReport>,
+    pub resources: Vec<ResourceReport>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SectionReport {
+    pub name: String,
+    pub virtual_address: u32,
+    pub virtual_size: u32,
+    pub raw_offset: u32,
+    pub raw_size: u32,
+    pub characteristics: u32,
+    pub flags: Vec<&'static str>,
+    /// Shannon entropy of the raw data, in bits per byte (0.0 - 8.0).
+    pub entropy: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OverlayReport {
+    pub offset: usize,
+    pub size: usize,
+    /// Entropy of the overlay, excluding an Authenticode signature stored there.
+    pub entropy: f64,
+    pub contains_certificate: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportedDll {
+    pub dll: String,
+    pub functions: Vec<ImportedFunction>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportedFunction {
+    pub name: String,
+    /// Hint for named imports, ordinal for imports by ordinal.
+    pub ordinal: u16,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportReport {
+    pub name: Option<String>,
+    pub rva: usize,
+    pub forwarded_to: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DebugEntry {
+    pub kind: String,
+    pub size: u32,
+    pub rva: u32,
+    pub file_offset: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PdbInfo {
+    pub path: String,
+    pub guid: String,
+    pub age: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RichHeaderReport {
+    pub key: u32,
+    pub entries: Vec<RichEntry>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RichEntry {
+    pub product: u16,
+    pub build: u16,
+    pub count: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ResourceReport {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub name: String,
+    pub language: u16,
+    pub rva: u32,
+    pub size: u32,
+}
+
+/// Shannon entropy of `data` in bits per byte.
+pub fn shannon_entropy(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0usize; 256];
+    for &b in data {
+        counts[b as usize] += 1;
+    }
+    let len = data.len() as f64;
+    counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+impl AnalysisReport {
+    pub fn from_pe(file: &str, bytes: &[u8], pe: &PE) -> Self {
+        let sections = pe
+            .sections
+            .iter()
+            .map(|sec| {
+                let start = (sec.pointer_to_raw_data as usize).min(bytes.len());
+                let end = start.saturating_add(sec.size_of_raw_data as usize).min(bytes.len());
+                SectionReport {
+                    name: pe::section_name(sec),
+                    virtual_address: sec.virtual_address,
+                    virtual_size: sec.virtual_size,
+                    raw_offset: sec.pointer_to_raw_data,
+                    raw_size: sec.size_of_raw_data,
+                    characteristics: sec.characteristics,
+                    flags: pe::section_flags(sec.characteristics),
+                    entropy: shannon_entropy(&bytes[start..end]),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let overlay = overlay(bytes, pe);
+        let packer = packer::assess(bytes, pe, &sections, overlay.as_ref());
+
+        let mut imports: Vec<ImportedDll> = Vec::new();
+        for imp in &pe.imports {
+            let function = ImportedFunction {
+                name: imp.name.to_string(),
+                ordinal: imp.ordinal,
+            };
+            match imports.iter_mut().find(|d| d.dll == imp.dll) {
+                Some(dll) => dll.functions.push(function),
+                None => imports.push(ImportedDll {
+                    dll: imp.dll.to_string(),
+                    functions: vec![function],
+                }),
+            }
+        }
+
+        let exports = pe
+            .exports
+            .iter()
+            .map(|exp| ExportReport {
+                name: exp.name.map(|s| s.to_string()),
+                rva: exp.rva,
+                forwarded_to: exp.reexport.as_ref().map(|r| match r {
+                    Reexport::DLLName { export, lib } => format!("{}.{}", lib, export),
+                    Reexport::DLLOrdinal { ordinal, lib } => format!("{}.#{}", lib, ordinal),
+                }),
+            })
+            .collect();
+
+        let tls_callbacks = pe
+            .tls_data
+            .as_ref()
+            .map(|tls| {
+                tls.callbacks
+                    .iter()
+                    .map(|va| va.saturating_sub(pe.image_base))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut debug_directory = Vec::new();
+        let mut pdb = None;
+        if let Some(debug) = &pe.debug_data {
+            for entry in debug.entries().flatten() {
+                debug_directory.push(DebugEntry {
+                    kind: debug_type_name(entry.data_type),
+                    size: entry.size_of_data,
+                    rva: entry.address_of_raw_data,
+                    file_offset: entry.pointer_to_raw_data,
+                });
+            }
+            if let Some(cv) = &debug.codeview_pdb70_debug_info {
+                pdb = Some(PdbInfo {
+                    path: String::from_utf8_lossy(cv.filename)
+                        .trim_end_matches('\0')
+                        .to_string(),
+                    guid: format_guid(&cv.signature),
+                    age: cv.age,
+                });
+            }
+        }
+
+        let rich_header = pe.header.rich_header.as_ref().map(|rich| RichHeaderReport {
+            key: rich.key,
+            entries: rich
+                .metadatas()
+                .flatten()
+                .map(|m| RichEntry {
+                    product: m.product,
+                    build: m.build,
+                    count: m.use_count,
+                })
+                .collect(),
+        });
+
+        let resources = resource_rva(pe)
+            .map(|rva| {
+                pe::resource_leaves(bytes, &pe.sections, rva)
+                    .into_iter()
+                    .map(|leaf| ResourceReport {
+                        type_: leaf.type_.as_type(),
+                        name: leaf.name.to_string(),
+                        language: leaf.language,
+                        rva: leaf.data_rva,
+                        size: leaf.size,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            file: file.to_string(),
+            file_size: bytes.len(),
+            format: if pe.is_64 { "PE32+".into() } else { "PE32".into() },
+            architecture: pe::machine_name(pe.header.coff_header.machine),
+            image_base: pe.image_base,
+            entry_point_rva: pe.entry,
+            sections,
+            overlay,
+            packer,
+            imports,
+            exports,
+            tls_callbacks,
+            debug_directory,
+            pdb,
+            rich_header,
+            resources,
+        }
+    }
+
+    /// Report of an ELF file: sections, needed libraries and exported
+    /// functions. The PE-only parts (overlay, packer heuristics, debug
+    /// directory, resources...) stay empty.
+    pub fn from_elf(file: &str, bytes: &[u8], elf: &Elf) -> Self {
+        let base = elf
+            .program_headers
+            .iter()
+            .filter(|p| p.p_type == goblin::elf::program_header::PT_LOAD)
+            .map(|p| p.p_vaddr & !(elf::PAGE_SIZE as u64 - 1))
+            .min()
+            .unwrap_or(0);
+        let sections = elf
+            .section_headers
+            .iter()
+            .filter(|sh| sh.sh_addr != 0)
+            .map(|sh| {
+                let raw_size = if sh.sh_type == goblin::elf::section_header::SHT_NOBITS { 0 } else { sh.sh_size };
+                let start = (sh.sh_offset as usize).min(bytes.len());
+                let end = start.saturating_add(raw_size as usize).min(bytes.len());
+                let characteristics = elf::section_characteristics(sh.sh_type, sh.sh_flags);
+                SectionReport {
+                    name: elf.shdr_strtab.get_at(sh.sh_name).unwrap_or("").to_string(),
+                    virtual_address: (sh.sh_addr - base) as u32,
+                    virtual_size: sh.sh_size as u32,
+                    raw_offset: sh.sh_offset as u32,
+                    raw_size: raw_size as u32,
+                    characteristics,
+                    flags: pe::section_flags(characteristics),
+                    entropy: shannon_entropy(&bytes[start..end]),
+                }
+            })
+            .collect();
+
+        let imports = elf
+            .libraries
+            .iter()
+            .map(|lib| ImportedDll {
+                dll: lib.to_string(),
+                functions: Vec::new(),
+            })
+            .collect();
+        let exports = elf
+            .dynsyms
+            .iter()
+            .filter(|sym| sym.st_type() == goblin::elf::sym::STT_FUNC && sym.st_shndx != 0 && sym.st_value != 0)
+            .map(|sym| ExportReport {
+                name: elf.dynstrtab.get_at(sym.st_name).map(|s| s.to_string()),
+                rva: (sym.st_value - base) as usize,
+                forwarded_to: None,
+            })
+            .collect();
+
+        Self {
+            file: file.to_string(),
+            file_size: bytes.len(),
+            format: if elf.is_64 { "ELF64".into() } else { "ELF32".into() },
+            architecture: elf::machine_name(elf.header.e_machine),
+            image_base: base,
+            entry_point_rva: (elf.entry - base) as u32,
+            sections,
+            overlay: None,
+            packer: PackerAssessment::default(),
+            imports,
+            exports,
+            tls_callbacks: Vec::new(),
+            debug_directory: Vec::new(),
+            pdb: None,
+            rich_header: None,
+            resources: Vec::new(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+
+    /// Self-contained HTML page (inline CSS, no external resources).
+    pub fn to_html(&self) -> String {
+        let mut h = String::new();
+        let _ = write!(
+            h,
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Analysis report - {}</title>\n\
+             <style>body{{font-family:sans-serif;margin:2em;color:#222}}table{{border-collapse:collapse;margin-bottom:1.5em}}\
+             td,th{{border:1px solid #ccc;padding:4px 8px;text-align:left;font-size:13px}}th{{background:#f0f0f0}}\
+             .mono{{font-family:monospace}}</style></head><body>\n",
+            esc(&self.file)
+        );
+        let _ = writeln!(h, "<h1>Analysis report</h1>");
+        let _ = writeln!(h, "<table>");
+        for (k, v) in [
+            ("File", esc(&self.file)),
+            ("Size", format!("{} bytes", self.file_size)),
+            ("Format", esc(&self.format)),
+            ("Architecture", esc(&self.architecture)),
+            ("Image base", format!("0x{:x}", self.image_base)),
+            ("Entry point RVA", format!("0x{:x}", self.entry_point_rva)),
+        ] {
+            let _ = writeln!(h, "<tr><th>{}</th><td class=\"mono\">{}</td></tr>", k, v);
+        }
+        let _ = writeln!(h, "</table>");
+
+        let _ = writeln!(h, "<h2>Sections ({})</h2>", self.sections.len());
+        let _ = writeln!(
+            h,
+            "<table><tr><th>Name</th><th>VA</th><th>Virtual size</th><th>Raw offset</th><th>Raw size</th><th>Entropy</th><th>Characteristics</th></tr>"
+        );
+        for s in &self.sections {
+            let _ = writeln!(
+                h,
+                "<tr><td class=\"mono\">{}</td><td class=\"mono\">0x{:x}</td><td>{}</td><td class=\"mono\">0x{:x}</td><td>{}</td><td>{:.3}</td><td>0x{:08x} {}</td></tr>",
+                esc(&s.name), s.virtual_address, s.virtual_size, s.raw_offset, s.raw_size, s.entropy,
+                s.characteristics, s.flags.join(" ")
+            );
+        }
+        let _ = writeln!(h, "</table>");
+
+        if let Some(o) = &self.overlay {
+            let _ = writeln!(
+                h,
+                "<p>Overlay: {} bytes at <span class=\"mono\">0x{:x}</span>, entropy {:.3}{}</p>",
+                o.size,
+                o.offset,
+                o.entropy,
+                if o.contains_certificate { " (contains Authenticode signature)" } else { "" }
+            );
+        }
+
+        let _ = writeln!(h, "<h2>Packer detection</h2>");
+        let _ = writeln!(
+            h,
+            "<p><strong>{}</strong>{}</p>",
+            if self.packer.looks_packed { "Looks packed or encrypted" } else { "No packer detected" },
+            self.packer.packer.as_ref().map(|p| format!(" ({})", esc(p))).unwrap_or_default()
+        );
+        if !self.packer.reasons.is_empty() {
+            let _ = writeln!(h, "<ul>");
+            for r in &self.packer.reasons {
+                let _ = writeln!(h, "<li>{}</li>", esc(r));
+            }
+            let _ = writeln!(h, "</ul>");
+        }
+
+        let _ = writeln!(h, "<h2>Imports ({} DLLs)</h2>", self.imports.len());
+        for dll in &self.imports {
+            let _ = writeln!(h, "<h3 class=\"mono\">{}</h3><table><tr><th>Function</th><th>Hint/ordinal</th></tr>", esc(&dll.dll));
+            for f in &dll.functions {
+                let _ = writeln!(h, "<tr><td class=\"mono\">{}</td><td>{}</td></tr>", esc(&f.name), f.ordinal);
+            }
+            let _ = writeln!(h, "</table>");
+        }
+
+        let _ = writeln!(h, "<h2>Exports ({})</h2>", self.exports.len());
+        if !self.exports.is_empty() {
+            let _ = writeln!(h, "<table><tr><th>Name</th><th>RVA</th><th>Forwarded to</th></tr>");
+            for e in &self.exports {
+                let _ = writeln!(
+                    h,
+                    "<tr><td class=\"mono\">{}</td><td class=\"mono\">0x{:x}</td><td>{}</td></tr>",
+                    esc(e.name.as_deref().unwrap_or("<unnamed>")),
+                    e.rva,
+                    esc(e.forwarded_to.as_deref().unwrap_or(""))
+                );
+            }
+            let _ = writeln!(h, "</table>");
+        }
+
+        let _ = writeln!(h, "<h2>TLS callbacks ({})</h2>", self.tls_callbacks.len());
+        if !self.tls_callbacks.is_empty() {
+            let list: Vec<String> = self.tls_callbacks.iter().map(|rva| format!("0x{:x}", rva)).collect();
+            let _ = writeln!(h, "<p class=\"mono\">{}</p>", list.join(", "));
+        }
+
+        let _ = writeln!(h, "<h2>Debug directory ({})</h2>", self.debug_directory.len());
+        if !self.debug_directory.is_empty() {
+            let _ = writeln!(h, "<table><tr><th>Type</th><th>Size</th><th>RVA</th><th>File offset</th></tr>");
+            for d in &self.debug_directory {
+                let _ = writeln!(
+                    h,
+                    "<tr><td>{}</td><td>{}</td><td class=\"mono\">0x{:x}</td><td class=\"mono\">0x{:x}</td></tr>",
+                    esc(&d.kind), d.size, d.rva, d.file_offset
+                );
+            }
+            let _ = writeln!(h, "</table>");
+        }
+        if let Some(pdb) = &self.pdb {
+            let _ = writeln!(
+                h,
+                "<p>PDB: <span class=\"mono\">{}</span> (GUID {}, age {})</p>",
+                esc(&pdb.path), pdb.guid, pdb.age
+            );
+        }
+
+        let _ = writeln!(h, "<h2>Rich header</h2>");
+        match &self.rich_header {
+            Some(rich) => {
+                let _ = writeln!(h, "<p>Key: <span class=\"mono\">0x{:08x}</span></p>", rich.key);
+                let _ = writeln!(h, "<table><tr><th>Product</th><th>Build</th><th>Count</th></tr>");
+                for e in &rich.entries {
+                    let _ = writeln!(h, "<tr><td>{}</td><td>{}</td><td>{}</td></tr>", e.product, e.build, e.count);
+                }
+                let _ = writeln!(h, "</table>");
+            }
+            None => {
+                let _ = writeln!(h, "<p>Not present</p>");
+            }
+        }
+
+        let _ = writeln!(h, "<h2>Resources ({})</h2>", self.resources.len());
+        if !self.resources.is_empty() {
+            let _ = writeln!(h, "<table><tr><th>Type</th><th>Name</th><th>Language</th><th>RVA</th><th>Size</th></tr>");
+            for r in &self.resources {
+                let _ = writeln!(
+                    h,
+                    "<tr><td>{}</td><td class=\"mono\">{}</td><td>{}</td><td class=\"mono\">0x{:x}</td><td>{}</td></tr>",
+                    esc(&r.type_), esc(&r.name), r.language, r.rva, r.size
+                );
+            }
+            let _ = writeln!(h, "</table>");
+        }
+
+        let _ = writeln!(h, "</body></html>");
+        h
+    }
+}
+
+/// Bytes after the end of the last section's raw data.
+fn overlay(bytes: &[u8], pe: &PE) -> Option<OverlayReport> {
+    let start = pe
+        .sections
+        .iter()
+        .map(|s| s.pointer_to_raw_data as usize + s.size_of_raw_data as usize)
+        .max()?;
+    if start >= bytes.len() {
+        return None;
+    }
+
+    // the certificate table is addressed by file offset and usually sits in the overlay
+    let cert = pe
+        .header
+        .optional_header
+        .and_then(|oh| oh.data_directories.get_certificate_table().copied())
+        .map(|dd| (dd.virtual_address as usize, dd.size as usize))
+        .filter(|(off, size)| *size > 0 && *off >= start);
+
+    let data = &bytes[start..];
+    let entropy = match cert {
+        Some((off, size)) => {
+            let rel = (off - start).min(data.len());
+            let end = (rel + size).min(data.len());
+            let mut rest = data[..rel].to_vec();
+            rest.extend_from_slice(&data[end..]);
+            shannon_entropy(&rest)
+        }
+        None => shannon_entropy(data),
+    };
+
+    Some(OverlayReport {
+        offset: start,
+        size: data.len(),
+        entropy,
+        contains_certificate: cert.is_some(),
+    })
+}
+
+/// RVA of the resource directory, if the image has one.
+pub fn resource_rva(pe: &PE) -> Option<u32> {
+    pe.header
+        .optional_header
+        .and_then(|oh| oh.data_directories.get_resource_table().map(|dd| dd.virtual_address))
+        .filter(|rva| *rva != 0)
+}
+
+fn debug_type_name(data_type: u32) -> String {
+    match data_type {
+        1 => "COFF".into(),
+        2 => "CODEVIEW".into(),
+        3 => "FPO".into(),
+        4 => "MISC".into(),
+        5 => "EXCEPTION".into(),
+        6 => "FIXUP".into(),
+        9 => "BORLAND".into(),
+        12 => "VC_FEATURE".into(),
+        13 => "POGO".into(),
+        14 => "ILTCG".into(),
+        16 => "REPRO".into(),
+        20 => "EX_DLLCHARACTERISTICS".into(),
+        other => format!("type {}", other),
+    }
+}
+
+fn format_guid(sig: &[u8; 16]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+        u32::from_le_bytes([sig[0], sig[1], sig[2], sig[3]]),
+        u16::from_le_bytes([sig[4], sig[5]]),
+        u16::from_le_bytes([sig[6], sig[7]]),
+        sig[8], sig[9], sig[10], sig[11], sig[12], sig[13], sig[14], sig[15]
+    )
+}
+
+fn esc(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,623 @@
+//! Resource protection of `ProtectResourcesStep`.
+//!
+//! The data of every selected resource is XORed with the keystream of a
+//! per-resource key (see `encrypt_code::crypt`); with compression it is
+//! compressed first and moved into the `.obsrc` section, leaving zeros in
+//! `.rsrc`. The resource tree is not touched, so `FindResource` and
+//! `SizeofResource` keep working and `LoadResource` returns the usual
+//! address, where the plaintext is written back:
+//!
+//! ```text
+//! decode             decodes the resource of one entry under a spin lock
+//! hook               calls the original LoadResource, then decode (load decoding)
+//! startup routine    installs the hook in the IAT, or decodes every resource
+//! lock, VirtualProtect and original LoadResource slots
+//! entries            24 bytes each: rva, size, key, state, payload rva and size
+//!                    (u32 each; payload rva 0 when encrypted in place)
+//! payloads           compressed and encrypted data
+//! ```
+//!
+//! Icons, cursors, the version information and the manifest are read from
+//! the file by Explorer and the loader and are never encoded. With load
+//! decoding only the program's own `LoadResource` calls see plaintext, so
+//! the types Windows loads for it (dialogs, menus, string tables...) need
+//! startup decoding.
+
+use std::sync::mpsc::Sender;
+
+use iced_x86::code_asm::*;
+use iced_x86::{BlockEncoderOptions, IcedError};
+use rand::Rng;
+
+use crate::pipeline::{PipelineContext, PipelineMessage};
+use crate::pipeline::encrypt_code::{crypt, LCG_INC, LCG_MUL};
+use crate::pipeline::error::StepError;
+use crate::pipeline::hash::{HashAlgorithm, NameHasher};
+use crate::pipeline::image::{Image, ImageFormat, StartupPhase, STUB_SECTION};
+use crate::pipeline::imports::import_slots;
+use crate::pipeline::pack::{self, emit_decompress};
+use crate::pipeline::pe::{ResourceLeaf, ResourceName};
+use crate::pipeline::plan::PlannedChange;
+use crate::pipeline::profile::{step_rng, ResourceDecoding, ResourceProtection};
+use crate::pipeline::step::PipelineStep;
+use crate::pipeline::stub::Runtime;
+
+pub const RESOURCE_SECTION: &str = ".obsrc";
+const ENTRY_SIZE: i32 = 24;
+const PAGE_READWRITE: u32 = 0x04;
+/// Cursors, icons, their groups, animated ones, version information and manifest.
+const SYSTEM_READ_TYPES: [u16; 8] = [1, 3, 12, 14, 16, 21, 22, 24];
+/// Bitmaps, menus, dialogs, string tables, fonts, accelerators, message tables and HTML.
+const SYSTEM_LOADED_TYPES: [u16; 9] = [2, 4, 5, 6, 7, 8, 9, 11, 23];
+
+/// Encrypts (and optionally compresses) the resources selected in the
+/// profile and adds the code decoding them at runtime.
+///
+/// Works on x64 PE images. Load decoding hooks the `LoadResource` imports
+/// of the program; without one, resources are decoded at startup instead.
+/// Resources fetched through a `LoadResource` found with `GetProcAddress`
+/// are not decoded in load mode.
+pub struct ProtectResourcesStep {
+    options: ResourceProtection,
+}
+
+impl ProtectResourcesStep {
+    pub fn new(options: ResourceProtection) -> Self {
+        Self { options }
+    }
+}
+
+/// A resource encoded in the file.
+struct Protected {
+    rva: u32,
+    size: u32,
+    key: u32,
+    /// Compressed and encrypted data stored in the section; `None` when encrypted in place.
+    payload: Option<Vec<u8>>,
+}
+
+impl PipelineStep for ProtectResourcesStep {
+    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> Result<(), StepError> {
+        ctx.cancel.check()?;
+        tx.send(PipelineMessage::Log("Protecting resources...".into())).ok();
+
+        let Some(image) = ctx.image.as_mut() else {
+            return Err(StepError::Internal("no image loaded before resource protection".into()));
+        };
+        if !image.is_64 || image.format != ImageFormat::Pe {
+            tx.send(PipelineMessage::Log("Resource protection supports x64 PE images only; skipped".into())).ok();
+            return Ok(());
+        }
+
+        let leaves = image.resources();
+        for selector in &self.options.protect {
+            if !leaves.iter().any(|leaf| selector.matches(&leaf.type_, &leaf.name)) {
+                tx.send(PipelineMessage::Log(format!("Protect resources: no resource matches '{}'", selector))).ok();
+            }
+        }
+        let mut selected: Vec<&ResourceLeaf> = Vec::new();
+        for leaf in &leaves {
+            if !self.options.protect.iter().any(|s| s.matches(&leaf.type_, &leaf.name)) {
+                continue;
+            }
+            let name = resource_name(leaf);
+            if let Err(reason) = self.check_protectable(image, leaf, &selected) {
+                tx.send(PipelineMessage::Log(format!("Not protecting {}: {}", name, reason))).ok();
+                continue;
+            }
+            selected.push(leaf);
+        }
+        if selected.is_empty() {
+            tx.send(PipelineMessage::Log("No resources to protect".into())).ok();
+            return Ok(());
+        }
+
+        let mut slots = Vec::new();
+        if self.options.decode == ResourceDecoding::Load {
+            slots = import_slots(image, "LoadResource")?;
+            if slots.is_empty() {
+                tx.send(PipelineMessage::Log(
+                    "The input does not import LoadResource; resources are decoded at startup instead".into(),
+                ))
+                .ok();
+            }
+        }
+        let lazy = !slots.is_empty();
+
+        if ctx.dry_run {
+            for leaf in &selected {
+                ctx.plan.push(PlannedChange::ProtectResource {
+                    resource: resource_name(leaf),
+                    size: leaf.size,
+                    compressed: self.options.compress,
+                });
+            }
+            ctx.plan.push(PlannedChange::AddSection {
+                name: RESOURCE_SECTION.into(),
+                description: "resource decoder".into(),
+            });
+            return Ok(());
+        }
+
+        ctx.cancel.check()?;
+        let mut rng = step_rng(ctx.seed, "resources");
+        let mut resources = Vec::with_capacity(selected.len());
+        let (mut original, mut stored) = (0, 0);
+        for leaf in &selected {
+            let mut data = image
+                .read(leaf.data_rva, leaf.size as usize)
+                .ok_or_else(|| StepError::Internal(format!("resource at 0x{:x} is not backed by file data", leaf.data_rva)))?
+                .to_vec();
+            let key = rng.gen();
+            let compressed = self.options.compress.then(|| pack::compress(&data)).filter(|c| c.len() < data.len());
+            let payload = match compressed {
+                Some(mut compressed) => {
+                    crypt(key, &mut compressed);
+                    image.write(leaf.data_rva, &vec![0; data.len()])?;
+                    Some(compressed)
+                }
+                None => {
+                    crypt(key, &mut data);
+                    image.write(leaf.data_rva, &data)?;
+                    None
+                }
+            };
+            image.mark_runtime_written(leaf.data_rva, leaf.size);
+            original += leaf.size as usize;
+            stored += payload.as_ref().map_or(leaf.size as usize, Vec::len);
+            tx.send(PipelineMessage::Log(format!(
+                "Protected {} ({} bytes{})",
+                resource_name(leaf),
+                leaf.size,
+                match &payload {
+                    Some(payload) => format!(", {} compressed", payload.len()),
+                    None => String::new(),
+                }
+            )))
+            .ok();
+            resources.push(Protected {
+                rva: leaf.data_rva,
+                size: leaf.size,
+                key,
+                payload,
+            });
+        }
+
+        let section_rva = image.next_section_rva();
+        let hasher = NameHasher::new(HashAlgorithm::default(), [0; 2]);
+        let slot_rvas: Vec<u32> = slots.iter().map(|&(_, rva)| rva).collect();
+        let (data, startup) = assemble(image.image_base, section_rva, &resources, &slot_rvas, hasher)?;
+        let rva = image.add_section(RESOURCE_SECTION, &data, STUB_SECTION)?;
+        if rva != section_rva {
+            return Err(StepError::Internal(format!(
+                "Resource section placed at 0x{:x} instead of 0x{:x}",
+                rva, section_rva
+            )));
+        }
+        for &(_, slot) in &slots {
+            image.mark_runtime_written(slot, 8);
+        }
+        image.add_startup_routine(
+            StartupPhase::Resources,
+            if lazy { "LoadResource hook" } else { "resource decoding" },
+            startup,
+        );
+        let hooked: Vec<&str> = slots.iter().map(|(dll, _)| dll.as_str()).collect();
+        tx.send(PipelineMessage::Log(format!(
+            "Resource protection: {} resources ({} bytes stored for {}), decoded {}; {} bytes in {}",
+            resources.len(),
+            stored,
+            original,
+            if lazy { format!("when loaded (LoadResource of {})", hooked.join(", ")) } else { "at startup".into() },
+            data.len(),
+            RESOURCE_SECTION
+        )))
+        .ok();
+        Ok(())
+    }
+}
+
+impl ProtectResourcesStep {
+    fn check_protectable(&self, image: &Image, leaf: &ResourceLeaf, selected: &[&ResourceLeaf]) -> Result<(), String> {
+        if let ResourceName::Id(id) = leaf.type_ {
+            if SYSTEM_READ_TYPES.contains(&id) {
+                return Err("Explorer and the loader read it from the file".into());
+            }
+            if SYSTEM_LOADED_TYPES.contains(&id) && self.options.decode == ResourceDecoding::Load {
+                return Err("Windows loads it without the program's LoadResource (use startup decoding)".into());
+            }
+        }
+        if leaf.size == 0 {
+            return Err("it is empty".into());
+        }
+        if image.read(leaf.data_rva, leaf.size as usize).is_none() {
+            return Err("its data is not backed by the file".into());
+        }
+        if image.is_relocated(leaf.data_rva, leaf.size) {
+            return Err("the loader relocates words in its data".into());
+        }
+        if selected.iter().any(|s| s.data_rva < leaf.data_rva + leaf.size && leaf.data_rva < s.data_rva + s.size) {
+            return Err("its data is shared with another protected resource".into());
+        }
+        Ok(())
+    }
+}
+
+/// `RT_RCDATA/CONFIG`, with the language when it is not neutral.
+fn resource_name(leaf: &ResourceLeaf) -> String {
+    match leaf.language {
+        0 => format!("{}/{}", leaf.type_.as_type(), leaf.name),
+        language => format!("{}/{} (language {})", leaf.type_.as_type(), leaf.name, language),
+    }
+}
+
+/// Labels the routines share.
+struct Labels {
+    /// Start of the section, at `section_rva`.
+    start: CodeLabel,
+    section_rva: u32,
+    lock: CodeLabel,
+    virtual_protect: CodeLabel,
+    fail: CodeLabel,
+}
+
+/// Lays out the resource section at `section_rva` and returns its bytes and
+/// the RVA of the startup routine. With IAT `slots` the routine hooks
+/// `LoadResource`; without, it decodes every resource.
+fn assemble(
+    image_base: u64,
+    section_rva: u32,
+    resources: &[Protected],
+    slots: &[u32],
+    hasher: NameHasher,
+) -> Result<(Vec<u8>, u32), StepError> {
+    let section_va = image_base + section_rva as u64;
+    let mut a = CodeAssembler::new(64)?;
+    let mut decode = a.create_label();
+    let mut hook = a.create_label();
+    let mut startup = a.create_label();
+    let mut decompress = a.create_label();
+    let mut lock = a.create_label();
+    let mut virtual_protect = a.create_label();
+    let mut load_resource = a.create_label();
+    let mut fail = a.create_label();
+    let mut entries: Vec<CodeLabel> = resources.iter().map(|_| a.create_label()).collect();
+    let mut payloads: Vec<CodeLabel> = resources.iter().map(|_| a.create_label()).collect();
+    let runtime = Runtime::new(&mut a, hasher);
+    let labels = Labels {
+        start: decode,
+        section_rva,
+        lock,
+        virtual_protect,
+        fail,
+    };
+
+    // first, so that its address is the section start
+    emit_decode(&mut a, &mut decode, decompress, &labels, &runtime, &hasher)?;
+
+    if !slots.is_empty() {
+        // LoadResource(module, info): decodes the data it returns if it is ours
+        let mut next = a.create_label();
+        let mut found = a.create_label();
+        let mut result = a.create_label();
+        let mut out = a.create_label();
+        a.set_label(&mut hook)?;
+        a.push(rbx)?;
+        a.sub(rsp, 0x20)?;
+        a.call(qword_ptr(load_resource))?;
+        a.test(rax, rax)?;
+        a.jz(out)?;
+        a.mov(rbx, rax)?;
+        a.lea(rcx, ptr(labels.start))?;
+        a.sub(rcx, section_rva as i32)?;
+        a.mov(rdx, rax)?;
+        a.sub(rdx, rcx)?;
+        a.lea(rcx, ptr(entries[0]))?;
+        a.mov(r8d, resources.len() as u32)?;
+        a.set_label(&mut next)?;
+        a.mov(eax, dword_ptr(rcx))?;
+        a.cmp(rdx, rax)?;
+        a.je(found)?;
+        a.add(rcx, ENTRY_SIZE)?;
+        a.dec(r8d)?;
+        a.jnz(next)?;
+        a.jmp(result)?;
+        a.set_label(&mut found)?;
+        a.cmp(dword_ptr(rcx + 12), 0)?;
+        a.jne(result)?;
+        a.call(decode)?;
+        a.set_label(&mut result)?;
+        a.mov(rax, rbx)?;
+        a.set_label(&mut out)?;
+        a.add(rsp, 0x20)?;
+        a.pop(rbx)?;
+        a.ret()?;
+
+        // swaps the IAT slots for the hook, keeping the first original
+        a.set_label(&mut startup)?;
+        a.push(rbx)?;
+        a.push(r13)?;
+        a.push(r14)?;
+        a.sub(rsp, 0x30)?;
+        a.lea(rbx, ptr(labels.start))?;
+        a.sub(rbx, section_rva as i32)?;
+        for &slot in slots {
+            let mut skip = a.create_label();
+            let mut saved = a.create_label();
+            a.lea(r13, ptr(rbx + slot as i32))?;
+            a.mov(r14d, 8)?;
+            a.mov(rax, qword_ptr(r13))?;
+            a.test(rax, rax)?;
+            a.jz(skip)?;
+            a.cmp(qword_ptr(load_resource), 0)?;
+            a.jne(saved)?;
+            a.mov(qword_ptr(load_resource), rax)?;
+            a.set_label(&mut saved)?;
+            emit_protect(&mut a, &labels, &runtime, &hasher, true)?;
+            a.lea(rax, ptr(hook))?;
+            a.mov(qword_ptr(r13), rax)?;
+            emit_protect(&mut a, &labels, &runtime, &hasher, false)?;
+            a.set_label(&mut skip)?;
+        }
+        a.add(rsp, 0x30)?;
+        a.pop(r14)?;
+        a.pop(r13)?;
+        a.pop(rbx)?;
+        a.ret()?;
+    } else {
+        let mut next = a.create_label();
+        a.set_label(&mut startup)?;
+        a.push(rbx)?;
+        a.push(r12)?;
+        a.sub(rsp, 0x28)?;
+        a.lea(rbx, ptr(entries[0]))?;
+        a.mov(r12d, resources.len() as u32)?;
+        a.set_label(&mut next)?;
+        a.mov(rcx, rbx)?;
+        a.call(decode)?;
+        a.add(rbx, ENTRY_SIZE)?;
+        a.dec(r12d)?;
+        a.jnz(next)?;
+        a.add(rsp, 0x28)?;
+        a.pop(r12)?;
+        a.pop(rbx)?;
+        a.ret()?;
+    }
+
+    emit_decompress(&mut a, &mut decompress)?;
+    a.set_label(&mut fail)?;
+    a.ud2()?;
+    runtime.emit(&mut a)?;
+    a.set_label(&mut lock)?;
+    a.dd(&[0])?;
+    a.set_label(&mut virtual_protect)?;
+    a.dq(&[0])?;
+    a.set_label(&mut load_resource)?;
+    a.dq(&[0])?;
+    for (i, resource) in resources.iter().enumerate() {
+        let payload_size = resource.payload.as_ref().map_or(0, |p| p.len() as u32);
+        a.set_label(&mut entries[i])?;
+        // payload rva, filled in below
+        a.dd(&[resource.rva, resource.size, resource.key, 0, 0, payload_size])?;
+    }
+    for (i, resource) in resources.iter().enumerate() {
+        if let Some(payload) = &resource.payload {
+            a.set_label(&mut payloads[i])?;
+            a.db(payload)?;
+        }
+    }
+
+    let result = a.assemble_options(section_va, BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS)?;
+    let rva_of = |label: &CodeLabel| -> Result<u32, StepError> { Ok((result.label_ip(label)? - image_base) as u32) };
+    let mut data = result.inner.code_buffer.clone();
+    for (i, resource) in resources.iter().enumerate() {
+        if resource.payload.is_some() {
+            let at = (rva_of(&entries[i])? - section_rva) as usize + 16;
+            data[at..at + 4].copy_from_slice(&rva_of(&payloads[i])?.to_le_bytes());
+        }
+    }
+    Ok((data, rva_of(&startup)?))
+}
+
+/// Emits the routine at `label` that decodes the resource of the entry in
+/// `rcx` unless it already is: decrypts the payload and decompresses it
+/// into the resource, or decrypts the resource in place.
+fn emit_decode(
+    a: &mut CodeAssembler,
+    label: &mut CodeLabel,
+    decompress: CodeLabel,
+    labels: &Labels,
+    runtime: &Runtime,
+    hasher: &NameHasher,
+) -> Result<(), IcedError> {
+    let mut spin = a.create_label();
+    let mut locked = a.create_label();
+    let mut in_place = a.create_label();
+    let mut decoded = a.create_label();
+    let mut done = a.create_label();
+
+    a.set_label(label)?;
+    a.push(rbx)?;
+    a.push(rsi)?;
+    a.push(rdi)?;
+    a.push(r12)?;
+    a.push(r13)?;
+    a.push(r14)?;
+    a.sub(rsp, 0x38)?;
+    a.mov(r12, rcx)?;
+    // rbx = runtime image base
+    a.lea(rbx, ptr(labels.start))?;
+    a.sub(rbx, labels.section_rva as i32)?;
+
+    a.set_label(&mut spin)?;
+    a.mov(eax, 1)?;
+    a.xchg(dword_ptr(labels.lock), eax)?;
+    a.test(eax, eax)?;
+    a.jz(locked)?;
+    a.pause()?;
+    a.jmp(spin)?;
+    a.set_label(&mut locked)?;
+
+    a.cmp(dword_ptr(r12 + 12), 0)?;
+    a.jne(done)?;
+    a.mov(eax, dword_ptr(r12))?;
+    a.lea(r13, ptr(rbx + rax))?;
+    a.mov(r14d, dword_ptr(r12 + 4))?;
+    emit_protect(a, labels, runtime, hasher, true)?;
+
+    a.mov(eax, dword_ptr(r12 + 16))?;
+    a.test(eax, eax)?;
+    a.jz(in_place)?;
+    a.lea(rdi, ptr(rbx + rax))?;
+    a.mov(esi, dword_ptr(r12 + 20))?;
+    emit_crypt(a, r12)?;
+    a.mov(eax, dword_ptr(r12 + 16))?;
+    a.lea(rsi, ptr(rbx + rax))?;
+    a.mov(r8d, dword_ptr(r12 + 20))?;
+    a.add(r8, rsi)?;
+    a.mov(rdi, r13)?;
+    a.call(decompress)?;
+    a.jmp(decoded)?;
+    a.set_label(&mut in_place)?;
+    a.mov(rdi, r13)?;
+    a.mov(esi, r14d)?;
+    emit_crypt(a, r12)?;
+    a.set_label(&mut decoded)?;
+
+    emit_protect(a, labels, runtime, hasher, false)?;
+    a.mov(dword_ptr(r12 + 12), 1)?;
+
+    a.set_label(&mut done)?;
+    a.mov(dword_ptr(labels.lock), 0)?;
+    a.add(rsp, 0x38)?;
+    a.pop(r14)?;
+    a.pop(r13)?;
+    a.pop(r12)?;
+    a.pop(rdi)?;
+    a.pop(rsi)?;
+    a.pop(rbx)?;
+    a.ret()
+}
+
+/// XORs `esi` bytes at `rdi` (at least one; resources and payloads are
+/// never empty) with the keystream of the key of the entry in `entry`;
+/// clobbers `eax` and `edx`.
+fn emit_crypt(a: &mut CodeAssembler, entry: AsmRegister64) -> Result<(), IcedError> {
+    let mut next = a.create_label();
+    a.mov(eax, dword_ptr(entry + 8))?;
+    a.set_label(&mut next)?;
+    a.imul_3(eax, eax, LCG_MUL as i32)?;
+    a.add(eax, LCG_INC as i32)?;
+    a.mov(edx, eax)?;
+    a.shr(edx, 24)?;
+    a.xor(byte_ptr(rdi), dl)?;
+    a.inc(rdi)?;
+    a.dec(esi)?;
+    a.jnz(next)
+}
+
+/// Makes the `r14d` bytes at `r13` writable, keeping the old protection at
+/// `[rsp+0x20]`, or gives them back their protection.
+fn emit_protect(
+    a: &mut CodeAssembler,
+    labels: &Labels,
+    runtime: &Runtime,
+    hasher: &NameHasher,
+    writable: bool,
+) -> Result<(), IcedError> {
+    if writable {
+        let mut resolved = a.create_label();
+        a.mov(rax, qword_ptr(labels.virtual_protect))?;
+        a.test(rax, rax)?;
+        a.jnz(resolved)?;
+        a.mov(ecx, hasher.hash("kernel32.dll"))?;
+        a.call(runtime.find_module)?;
+        a.test(rax, rax)?;
+        a.jz(labels.fail)?;
+        a.mov(rcx, rax)?;
+        a.mov(edx, hasher.hash("VirtualProtect"))?;
+        a.call(runtime.find_export)?;
+        a.test(rax, rax)?;
+        a.jz(labels.fail)?;
+        a.mov(qword_ptr(labels.virtual_protect), rax)?;
+        a.set_label(&mut resolved)?;
+        a.mov(r8d, PAGE_READWRITE)?;
+        a.lea(r9, ptr(rsp + 0x20))?;
+    } else {
+        a.mov(r8d, dword_ptr(rsp + 0x20))?;
+        a.lea(r9, ptr(rsp + 0x28))?;
+    }
+    a.mov(rcx, r13)?;
+    a.mov(edx, r14d)?;
+    a.call(qword_ptr(labels.virtual_protect))?;
+    a.test(eax, eax)?;
+    a.jz(labels.fail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+
+    use crate::pipeline::cancel::CancellationToken;
+    use crate::pipeline::fixture;
+    use crate::pipeline::profile::ResourceSelector;
+
+    /// Stands in for `VirtualProtect`: the mapping is already writable.
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    extern "win64" fn virtual_protect(_address: u64, _size: u64, _protection: u32, old: *mut u32) -> i32 {
+        // SAFETY: the decoder passes a pointer to its stack slot
+        unsafe { *old = 0x02 };
+        1
+    }
+
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    #[test]
+    fn decoded_resources_match_the_original() {
+        let data: Vec<u8> = b"obscura resource "
+            .iter()
+            .cycle()
+            .take(700)
+            .copied()
+            .chain((0..300u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 11) as u8))
+            .collect();
+        for compress in [false, true] {
+            let mut image = Image::parse(fixture::pe64(&[0xc3], &[])).unwrap();
+            let data_rva = fixture::add_rcdata(&mut image, &data);
+            let mut ctx = PipelineContext::new("sample.exe".into(), CancellationToken::new());
+            ctx.seed = 3;
+            ctx.image = Some(image);
+            let options = ResourceProtection {
+                enabled: true,
+                protect: vec![ResourceSelector::parse("RT_RCDATA").unwrap()],
+                compress,
+                decode: ResourceDecoding::Startup,
+            };
+            let (tx, rx) = mpsc::channel();
+            ProtectResourcesStep::new(options).run(&mut ctx, &tx).unwrap();
+            drop(tx);
+            let compressed = rx.iter().any(|m| matches!(m, PipelineMessage::Log(line) if line.contains("compressed")));
+            assert_eq!(compressed, compress);
+            let mut image = ctx.image.take().unwrap();
+            let startup = image.startup_routines()[0].rva;
+            image.finalize().unwrap();
+            let bytes = image.into_bytes();
+
+            let image = Image::parse(bytes.clone()).unwrap();
+            assert_ne!(image.read(data_rva, data.len()).unwrap(), &data[..]);
+            // the slots sit right before the entries: lock, VirtualProtect, LoadResource
+            let (_, section) = image.named_sections().into_iter().find(|(name, _)| name == RESOURCE_SECTION).unwrap();
+            let stored = image.read(section.virtual_address, section.raw_size as usize).unwrap();
+            let mut entry = data_rva.to_le_bytes().to_vec();
+            entry.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            let entries = stored.windows(8).position(|w| w == entry).unwrap() as u32;
+
+            let mapped = fixture::native::Mapped::new(&bytes);
+            let stub = virtual_protect as extern "win64" fn(u64, u64, u32, *mut u32) -> i32;
+            mapped.write(section.virtual_address + entries - 16, &(stub as usize).to_le_bytes());
+            mapped.call(startup, [0; 4]);
+            assert_eq!(mapped.read(data_rva, data.len()), data, "compress: {}", compress);
+        }
+    }
+}
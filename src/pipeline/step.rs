@@ -1,6 +1,7 @@
 use std::sync::mpsc::Sender;
 use crate::pipeline::PipelineMessage;
+use crate::pipeline::error::StepError;
 
 pub trait PipelineStep: Send {
-    fn run(&self, ctx: &mut super::PipelineContext, tx: &Sender<PipelineMessage>) -> anyhow::Result<()>;
-}
\ No newline at end of file
+    fn run(&self, ctx: &mut super::PipelineContext, tx: &Sender<PipelineMessage>) -> Result<(), StepError>;
+}
@@ -0,0 +1,272 @@
+//! Removes debug information and symbols that describe how the input was
+//! built (`StripStep`).
+//!
+//! Each `StripItem` is handled on its own and reported, whether something
+//! was stripped or not. Removed data is zero-filled rather than cut out, so
+//! the file layout and everything that points into it stays valid: on ELF
+//! the section header of a removed section becomes an empty `SHT_NULL` one,
+//! which keeps the indices of the others.
+
+use std::sync::mpsc::Sender;
+
+use goblin::elf::section_header::{SHF_ALLOC, SHT_NOBITS, SHT_SYMTAB};
+use goblin::elf::Elf;
+
+use crate::pipeline::{PipelineContext, PipelineMessage};
+use crate::pipeline::error::StepError;
+use crate::pipeline::image::{Image, DEBUG_ENTRY_SIZE, DIR_DEBUG};
+use crate::pipeline::pe::read_u32;
+use crate::pipeline::plan::PlannedChange;
+use crate::pipeline::profile::{StripItem, StripOptions};
+use crate::pipeline::step::PipelineStep;
+
+const IMAGE_DEBUG_TYPE_CODEVIEW: u32 = 2;
+/// Holds the CET compatibility flag, which the loader reads.
+const IMAGE_DEBUG_TYPE_EX_DLLCHARACTERISTICS: u32 = 20;
+const COFF_SYMBOL_SIZE: usize = 18;
+/// The DOS stub ends here in every linker's output; the Rich header follows it.
+const RICH_HEADER_START: usize = 0x80;
+
+pub struct StripStep {
+    options: StripOptions,
+}
+
+impl StripStep {
+    pub fn new(options: StripOptions) -> Self {
+        Self { options }
+    }
+}
+
+impl PipelineStep for StripStep {
+    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> Result<(), StepError> {
+        ctx.cancel.check()?;
+        let Some(image) = ctx.image.as_mut() else {
+            return Err(StepError::Internal("no image loaded before stripping".into()));
+        };
+        let mut items = self.options.items.clone();
+        items.sort();
+        items.dedup();
+        let format = image.format;
+        for item in items.into_iter().filter(|item| item.supports(format)) {
+            let stripped = match item {
+                StripItem::DebugDirectory => debug_directory(image, ctx.dry_run)?,
+                StripItem::PdbPath => pdb_path(image, ctx.dry_run)?,
+                StripItem::RichHeader => rich_header(image, ctx.dry_run)?,
+                StripItem::CoffSymbols => coff_symbols(image, ctx.dry_run)?,
+                StripItem::DebugSections => elf_sections(image, ctx.dry_run, is_debug_section)?,
+                StripItem::Symbols => elf_symbol_tables(image, ctx.dry_run)?,
+            };
+            let Some(description) = stripped else {
+                tx.send(PipelineMessage::Log(format!("Strip {}: nothing to strip", item))).ok();
+                continue;
+            };
+            if ctx.dry_run {
+                ctx.plan.push(PlannedChange::Strip {
+                    item: item.to_string(),
+                    description,
+                });
+            } else {
+                tx.send(PipelineMessage::Log(format!("Strip {}: {}", item, description))).ok();
+                image.notes.push(format!("Stripped {}: {}", item, description));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Debug directory entries as `(entry rva, type, size of data, file offset of data)`.
+fn debug_entries(image: &Image) -> Vec<(u32, u32, u32, u32)> {
+    let (directory, size) = image.data_directory(DIR_DEBUG);
+    (0..size / DEBUG_ENTRY_SIZE)
+        .filter_map(|i| {
+            let entry = directory + i * DEBUG_ENTRY_SIZE;
+            let field = |at: u32| image.read_u32(entry + at);
+            Some((entry, field(12)?, field(16)?, field(24)?))
+        })
+        .collect()
+}
+
+fn debug_type_name(kind: u32) -> String {
+    match kind {
+        1 => "COFF".into(),
+        IMAGE_DEBUG_TYPE_CODEVIEW => "CodeView".into(),
+        4 => "misc".into(),
+        12 => "VC features".into(),
+        13 => "POGO".into(),
+        14 => "ILTCG".into(),
+        16 => "repro".into(),
+        kind => format!("type {}", kind),
+    }
+}
+
+/// Removes the debug directory entries and zeroes their data. The entry
+/// holding the extended DLL characteristics stays.
+fn debug_directory(image: &mut Image, dry_run: bool) -> Result<Option<String>, StepError> {
+    let entries = debug_entries(image);
+    let (kept, removed): (Vec<&(u32, u32, u32, u32)>, Vec<_>) =
+        entries.iter().partition(|e| e.1 == IMAGE_DEBUG_TYPE_EX_DLLCHARACTERISTICS);
+    if removed.is_empty() {
+        return Ok(None);
+    }
+    let names: Vec<String> = removed.iter().map(|e| debug_type_name(e.1)).collect();
+    let description = format!("removed {} entries ({})", removed.len(), names.join(", "));
+    if dry_run {
+        return Ok(Some(description));
+    }
+    let (directory, _) = image.data_directory(DIR_DEBUG);
+    let mut table = Vec::new();
+    for &&(entry, ..) in &kept {
+        table.extend_from_slice(image.read(entry, DEBUG_ENTRY_SIZE as usize).unwrap_or(&[0; 28]));
+    }
+    for &&(_, _, size, offset) in &removed {
+        image.write_file(offset as usize, &vec![0; size as usize])?;
+    }
+    let mut cleared = table.clone();
+    cleared.resize(entries.len() * DEBUG_ENTRY_SIZE as usize, 0);
+    image.write(directory, &cleared)?;
+    match kept.is_empty() {
+        true => image.set_data_directory(DIR_DEBUG, 0, 0),
+        false => image.set_data_directory(DIR_DEBUG, directory, table.len() as u32),
+    }
+    Ok(Some(description))
+}
+
+/// Reduces the PDB path of the CodeView entries to the file name.
+fn pdb_path(image: &mut Image, dry_run: bool) -> Result<Option<String>, StepError> {
+    let mut stripped = Vec::new();
+    for (_, kind, size, offset) in debug_entries(image) {
+        if kind != IMAGE_DEBUG_TYPE_CODEVIEW {
+            continue;
+        }
+        let (offset, size) = (offset as usize, size as usize);
+        let Some(record) = image.file().get(offset..offset + size) else {
+            continue;
+        };
+        // RSDS: signature, GUID, age, path; NB10: signature, offset, time stamp, age, path
+        let path_at = match &record[..4.min(record.len())] {
+            b"RSDS" => 24,
+            b"NB10" => 16,
+            _ => continue,
+        };
+        let Some(tail) = record.get(path_at..) else {
+            continue;
+        };
+        let len = tail.iter().position(|&b| b == 0).unwrap_or(tail.len());
+        let path = String::from_utf8_lossy(&tail[..len]).into_owned();
+        let name = path.rsplit(['\\', '/']).next().unwrap_or_default().to_string();
+        if name == path {
+            continue;
+        }
+        if !dry_run {
+            let mut field = name.clone().into_bytes();
+            field.resize(len, 0);
+            image.write_file(offset + path_at, &field)?;
+        }
+        stripped.push(format!("{} -> {}", path, name));
+    }
+    Ok((!stripped.is_empty()).then(|| stripped.join(", ")))
+}
+
+/// Zeroes the Rich header, which lists the tools and versions that built the image.
+fn rich_header(image: &mut Image, dry_run: bool) -> Result<Option<String>, StepError> {
+    let bytes = image.file();
+    let pe_offset = read_u32(bytes, 0x3c).unwrap_or(0) as usize;
+    let Some(stub) = bytes.get(RICH_HEADER_START..pe_offset.min(bytes.len())) else {
+        return Ok(None);
+    };
+    let Some(rich) = (0..stub.len().saturating_sub(7)).step_by(4).find(|&at| &stub[at..at + 4] == b"Rich") else {
+        return Ok(None);
+    };
+    let key = u32::from_le_bytes(stub[rich + 4..rich + 8].try_into().unwrap());
+    let Some(start) = (0..rich)
+        .step_by(4)
+        .find(|&at| u32::from_le_bytes(stub[at..at + 4].try_into().unwrap()) ^ key == u32::from_le_bytes(*b"DanS"))
+    else {
+        return Ok(None);
+    };
+    // "DanS" and three zero dwords, then one (id, count) pair per tool, then "Rich" and the key
+    let end = rich + 8;
+    let tools = (rich - start - 16) / 8;
+    if !dry_run {
+        image.write_file(RICH_HEADER_START + start, &vec![0; end - start])?;
+    }
+    Ok(Some(format!("zeroed {} bytes ({} tool entries)", end - start, tools)))
+}
+
+/// Zeroes the COFF symbol and string tables and unlinks them from the file header.
+fn coff_symbols(image: &mut Image, dry_run: bool) -> Result<Option<String>, StepError> {
+    let bytes = image.file();
+    let coff = read_u32(bytes, 0x3c).unwrap_or(0) as usize + 4;
+    let (Some(table), Some(count)) = (read_u32(bytes, coff + 8), read_u32(bytes, coff + 12)) else {
+        return Ok(None);
+    };
+    if table == 0 && count == 0 {
+        return Ok(None);
+    }
+    let table = table as usize;
+    let strings = table + count as usize * COFF_SYMBOL_SIZE;
+    let end = (strings + read_u32(bytes, strings).unwrap_or(0) as usize).min(bytes.len());
+    if !dry_run {
+        if table > 0 && table < end {
+            image.write_file(table, &vec![0; end - table])?;
+        }
+        image.write_file(coff + 8, &[0; 8])?;
+    }
+    Ok(Some(format!("{} symbols, {} bytes zeroed", count, end.saturating_sub(table))))
+}
+
+fn is_debug_section(name: &str, _elf: &Elf) -> bool {
+    name.starts_with(".debug") || name.starts_with(".zdebug") || name == ".gnu_debuglink" || name == ".gnu_debugaltlink"
+}
+
+/// Removes `.symtab` and the string table it links to (`.dynsym` stays: the
+/// dynamic loader needs it).
+fn elf_symbol_tables(image: &mut Image, dry_run: bool) -> Result<Option<String>, StepError> {
+    let symbol_table = |name: &str, elf: &Elf| {
+        elf.section_headers.iter().enumerate().any(|(i, s)| {
+            s.sh_type == SHT_SYMTAB
+                && (elf.shdr_strtab.get_at(s.sh_name) == Some(name)
+                    || (s.sh_link as usize != i
+                        && elf.section_headers.get(s.sh_link as usize).and_then(|t| elf.shdr_strtab.get_at(t.sh_name))
+                            == Some(name)))
+        })
+    };
+    elf_sections(image, dry_run, symbol_table)
+}
+
+/// Zeroes the non-allocated sections matched by `matches` and their section headers.
+fn elf_sections(
+    image: &mut Image,
+    dry_run: bool,
+    matches: impl Fn(&str, &Elf) -> bool,
+) -> Result<Option<String>, StepError> {
+    let bytes = image.file();
+    let elf = Elf::parse(bytes).map_err(|e| StepError::InvalidInput(format!("Malformed ELF file: {}", e)))?;
+    let (header_offset, header_size) = (elf.header.e_shoff as usize, elf.header.e_shentsize as usize);
+    let mut removed = Vec::new();
+    let mut zeroed = Vec::new();
+    let mut total = 0;
+    for (i, section) in elf.section_headers.iter().enumerate() {
+        let Some(name) = elf.shdr_strtab.get_at(section.sh_name) else {
+            continue;
+        };
+        if section.sh_flags & SHF_ALLOC as u64 != 0 || i == elf.header.e_shstrndx as usize || !matches(name, &elf) {
+            continue;
+        }
+        if section.sh_type != SHT_NOBITS {
+            zeroed.push((section.sh_offset as usize, section.sh_size as usize));
+            total += section.sh_size as usize;
+        }
+        zeroed.push((header_offset + i * header_size, header_size));
+        removed.push(name.to_string());
+    }
+    if removed.is_empty() {
+        return Ok(None);
+    }
+    if !dry_run {
+        for (offset, size) in zeroed {
+            image.write_file(offset, &vec![0; size])?;
+        }
+    }
+    Ok(Some(format!("removed {} ({} bytes)", removed.join(", "), total)))
+}
@@ -0,0 +1,172 @@
+//! x64 routines shared by the stubs injected into protected images.
+//!
+//! Everything is position independent (RIP-relative or computed from the
+//! image base at runtime), so the stubs need no base relocations.
+
+use iced_x86::code_asm::*;
+use iced_x86::IcedError;
+
+use crate::pipeline::hash::NameHasher;
+
+/// Module/export lookup helpers (PEB walk plus export directory scan).
+///
+/// `new` only creates the labels so callers can reference them; `emit`
+/// writes the routines wherever the caller wants them in its code.
+pub struct Runtime {
+    hasher: NameHasher,
+    /// `rcx` = string, `rdx` = char stride (1 or 2), `r8` = max chars → `eax` (see `NameHasher::emit`).
+    pub hash_str: CodeLabel,
+    /// `ecx` = module hash → `rax` = module base, or 0 if it is not loaded.
+    pub find_module: CodeLabel,
+    /// `rcx` = module base, `edx` = export hash → `rax` = address, or 0.
+    /// Forwarded exports go through the pointer stored in `get_proc_address`.
+    pub find_export: CodeLabel,
+    /// Qword slot the caller fills with `GetProcAddress` once it is resolved.
+    pub get_proc_address: CodeLabel,
+}
+
+impl Runtime {
+    pub fn new(a: &mut CodeAssembler, hasher: NameHasher) -> Self {
+        Self {
+            hasher,
+            hash_str: a.create_label(),
+            find_module: a.create_label(),
+            find_export: a.create_label(),
+            get_proc_address: a.create_label(),
+        }
+    }
+
+    pub fn emit(self, a: &mut CodeAssembler) -> Result<(), IcedError> {
+        let Runtime {
+            hasher,
+            mut hash_str,
+            mut find_module,
+            mut find_export,
+            mut get_proc_address,
+        } = self;
+
+        // hash_str: rcx = string, rdx = char stride (1 or 2), r8 = max chars → eax
+        hasher.emit(a, &mut hash_str)?;
+
+        // find_module: walks PEB->Ldr->InMemoryOrderModuleList comparing BaseDllName
+        {
+            let mut next = a.create_label();
+            let mut found = a.create_label();
+            let mut not_found = a.create_label();
+            let mut out = a.create_label();
+            a.set_label(&mut find_module)?;
+            a.push(rbx)?;
+            a.push(rsi)?;
+            a.push(rdi)?;
+            a.mov(edi, ecx)?;
+            a.mov(rax, qword_ptr(0x60u64).gs())?;
+            a.mov(rax, qword_ptr(rax + 0x18))?;
+            a.lea(rsi, ptr(rax + 0x20))?;
+            a.mov(rbx, qword_ptr(rsi))?;
+            a.set_label(&mut next)?;
+            a.cmp(rbx, rsi)?;
+            a.je(not_found)?;
+            a.movzx(r8d, word_ptr(rbx + 0x48))?;
+            a.shr(r8d, 1)?;
+            a.mov(rcx, qword_ptr(rbx + 0x50))?;
+            a.mov(edx, 2)?;
+            a.call(hash_str)?;
+            a.cmp(eax, edi)?;
+            a.je(found)?;
+            a.mov(rbx, qword_ptr(rbx))?;
+            a.jmp(next)?;
+            a.set_label(&mut found)?;
+            a.mov(rax, qword_ptr(rbx + 0x20))?;
+            a.jmp(out)?;
+            a.set_label(&mut not_found)?;
+            a.xor(eax, eax)?;
+            a.set_label(&mut out)?;
+            a.pop(rdi)?;
+            a.pop(rsi)?;
+            a.pop(rbx)?;
+            a.ret()?;
+        }
+
+        // find_export: scans the export name table of the module in rcx
+        {
+            let mut next = a.create_label();
+            let mut found = a.create_label();
+            let mut forwarder = a.create_label();
+            let mut not_found = a.create_label();
+            let mut out = a.create_label();
+            a.set_label(&mut find_export)?;
+            a.push(rbx)?;
+            a.push(rsi)?;
+            a.push(rdi)?;
+            a.push(r12)?;
+            a.push(r13)?;
+            a.push(r14)?;
+            a.push(r15)?;
+            a.sub(rsp, 0x30)?;
+            a.mov(rbx, rcx)?;
+            a.mov(r12d, edx)?;
+            a.mov(eax, dword_ptr(rbx + 0x3c))?;
+            a.mov(r13d, dword_ptr(rbx + rax + 0x88))?;
+            a.mov(r14d, dword_ptr(rbx + rax + 0x8c))?;
+            a.test(r13d, r13d)?;
+            a.jz(not_found)?;
+            a.lea(rsi, ptr(rbx + r13))?;
+            a.mov(r15d, dword_ptr(rsi + 0x18))?;
+            a.xor(edi, edi)?;
+            a.set_label(&mut next)?;
+            a.cmp(edi, r15d)?;
+            a.jae(not_found)?;
+            a.mov(eax, dword_ptr(rsi + 0x20))?;
+            a.add(rax, rbx)?;
+            a.mov(eax, dword_ptr(rax + rdi * 4))?;
+            a.lea(rcx, ptr(rbx + rax))?;
+            a.mov(qword_ptr(rsp + 0x20), rcx)?;
+            a.mov(edx, 1)?;
+            a.mov(r8d, 0x200)?;
+            a.call(hash_str)?;
+            a.cmp(eax, r12d)?;
+            a.je(found)?;
+            a.inc(edi)?;
+            a.jmp(next)?;
+            a.set_label(&mut found)?;
+            a.mov(eax, dword_ptr(rsi + 0x24))?;
+            a.add(rax, rbx)?;
+            a.movzx(eax, word_ptr(rax + rdi * 2))?;
+            a.mov(ecx, dword_ptr(rsi + 0x1c))?;
+            a.add(rcx, rbx)?;
+            a.mov(eax, dword_ptr(rcx + rax * 4))?;
+            // an address inside the export directory is a "DLL.Function" forwarder string
+            a.mov(ecx, eax)?;
+            a.sub(ecx, r13d)?;
+            a.cmp(ecx, r14d)?;
+            a.jb(forwarder)?;
+            a.add(rax, rbx)?;
+            a.jmp(out)?;
+            a.set_label(&mut forwarder)?;
+            a.mov(rax, qword_ptr(get_proc_address))?;
+            a.test(rax, rax)?;
+            a.jz(not_found)?;
+            a.mov(rcx, rbx)?;
+            a.mov(rdx, qword_ptr(rsp + 0x20))?;
+            a.call(rax)?;
+            a.jmp(out)?;
+            a.set_label(&mut not_found)?;
+            a.xor(eax, eax)?;
+            a.set_label(&mut out)?;
+            a.add(rsp, 0x30)?;
+            a.pop(r15)?;
+            a.pop(r14)?;
+            a.pop(r13)?;
+            a.pop(r12)?;
+            a.pop(rdi)?;
+            a.pop(rsi)?;
+            a.pop(rbx)?;
+            a.ret()?;
+        }
+
+        a.set_label(&mut get_proc_address)?;
+        a.dq(&[0])?;
+
+        Ok(())
+    }
+}
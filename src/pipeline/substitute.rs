@@ -0,0 +1,506 @@
+use std::collections::HashSet;
+use std::sync::mpsc::Sender;
+
+use iced_x86::{Code, FlowControl, IcedError, Instruction, MemoryOperand, Mnemonic, OpKind, Register, RflagsBits};
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::pipeline::{PipelineContext, PipelineMessage};
+use crate::pipeline::code::Function;
+use crate::pipeline::error::StepError;
+use crate::pipeline::image::ImageFormat;
+use crate::pipeline::plan::PlannedChange;
+use crate::pipeline::profile::{step_rng, SubstituteOptions};
+use crate::pipeline::reassemble::CODE_SECTION;
+use crate::pipeline::step::PipelineStep;
+
+const ARITHMETIC_FLAGS: u32 =
+    RflagsBits::OF | RflagsBits::SF | RflagsBits::ZF | RflagsBits::AF | RflagsBits::CF | RflagsBits::PF;
+
+/// Registers a rewritten instruction may borrow as a temporary (saved on the stack).
+const TEMPORARIES: [Register; 14] = [
+    Register::RAX,
+    Register::RCX,
+    Register::RDX,
+    Register::RBX,
+    Register::RSI,
+    Register::RDI,
+    Register::R8,
+    Register::R9,
+    Register::R10,
+    Register::R11,
+    Register::R12,
+    Register::R13,
+    Register::R14,
+    Register::R15,
+];
+
+/// Rewrites `add`, `sub`, `xor`, `and` and `or` between registers or with an
+/// immediate, and `mov reg, imm`, in the functions selected in the profile
+/// into longer equivalent sequences.
+///
+/// The arithmetic and logic operations become mixed boolean-arithmetic
+/// expressions (`x + y = (x ^ y) + 2 * (x & y)`, `x ^ y = (x | y) - (x & y)`...)
+/// computed with a temporary register saved on the stack. They leave the
+/// flags different from the original instruction, so an instruction is only
+/// rewritten when no later instruction reads the flags it wrote before they
+/// are overwritten. Constants are loaded as a different value followed by
+/// `not` and `lea` steps, which do not touch the flags. Prologues and
+/// epilogues are left alone. x64 PE only: the saved temporary would
+/// overwrite the System V red zone below `rsp`.
+pub struct SubstituteStep {
+    options: SubstituteOptions,
+}
+
+impl SubstituteStep {
+    pub fn new(options: SubstituteOptions) -> Self {
+        Self { options }
+    }
+}
+
+impl PipelineStep for SubstituteStep {
+    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> Result<(), StepError> {
+        ctx.cancel.check()?;
+        tx.send(PipelineMessage::Log("Substituting instructions...".into())).ok();
+
+        let Some(image) = ctx.image.as_mut() else {
+            return Err(StepError::Internal("no image loaded before instruction substitution".into()));
+        };
+        if !image.is_64 || image.format != ImageFormat::Pe {
+            tx.send(PipelineMessage::Log(
+                "Instruction substitution supports x64 PE images only; skipped".into(),
+            ))
+            .ok();
+            return Ok(());
+        }
+        let code = image.code_map();
+        let (selected, unmatched) = code.select(&self.options.functions);
+        for selector in unmatched {
+            tx.send(PipelineMessage::Log(format!("Substitute: no function matches '{}'", selector))).ok();
+        }
+
+        let mut rng = step_rng(ctx.seed, "substitute");
+        let density = self.options.density.clamp(0.0, 1.0);
+        let mut changed = 0;
+        for index in selected {
+            ctx.cancel.check()?;
+            let name = code.functions[index].name();
+            if let Err(reason) = code.check_movable(index) {
+                tx.send(PipelineMessage::Log(format!("Not substituting in {}: {}", name, reason))).ok();
+                continue;
+            }
+            let (instructions, substituted, flags_live) = substitute(&code.functions[index], density, &mut rng)?;
+            if substituted == 0 {
+                continue;
+            }
+            if ctx.dry_run {
+                ctx.plan.push(PlannedChange::TransformFunction {
+                    function: name.clone(),
+                    rva: code.functions[index].info.rva,
+                    transform: "substitute".into(),
+                });
+            } else {
+                let function = &mut code.functions[index];
+                function.instructions = instructions;
+                function.rebuild_blocks();
+                function.modified = true;
+            }
+            tx.send(PipelineMessage::Log(format!(
+                "{}: {} instructions substituted, {} kept because their flags are used",
+                name, substituted, flags_live
+            )))
+            .ok();
+            changed += 1;
+        }
+        if ctx.dry_run && changed > 0 {
+            ctx.plan.push_once(PlannedChange::AddSection {
+                name: CODE_SECTION.into(),
+                description: "relocated function bodies".into(),
+            });
+        }
+        tx.send(PipelineMessage::Log(format!("Instruction substitution: {} functions changed", changed))).ok();
+        Ok(())
+    }
+}
+
+/// The instructions of `function` with the eligible ones rewritten, and the
+/// number rewritten and kept because of flag liveness.
+fn substitute(
+    function: &Function,
+    density: f64,
+    rng: &mut impl Rng,
+) -> Result<(Vec<Instruction>, usize, usize), IcedError> {
+    let count = function.instructions.len();
+    let mut frozen = vec![false; count];
+    frozen[..function.prologue_len()].fill(true);
+    let mut block_of = vec![0; count];
+    for (b, block) in function.blocks.iter().enumerate() {
+        block_of[block.start..block.end].fill(b);
+        frozen[function.epilogue_start(block)..block.end].fill(true);
+    }
+
+    let mut out = Vec::with_capacity(count * 2);
+    let (mut substituted, mut flags_live) = (0, 0);
+    for (i, instruction) in function.instructions.iter().enumerate() {
+        let rewrite = if frozen[i] { None } else { Rewrite::of(instruction) };
+        let Some(rewrite) = rewrite.filter(|_| rng.gen_bool(density)) else {
+            out.push(*instruction);
+            continue;
+        };
+        if rewrite.writes_flags() && flags_live_after(function, &block_of, i) {
+            out.push(*instruction);
+            flags_live += 1;
+            continue;
+        }
+        let mut sequence = rewrite.expand(rng)?;
+        sequence[0].set_ip(instruction.ip());
+        out.extend(sequence);
+        substituted += 1;
+    }
+    Ok((out, substituted, flags_live))
+}
+
+/// Whether a flag written by instruction `index` can be read before being overwritten.
+fn flags_live_after(function: &Function, block_of: &[usize], index: usize) -> bool {
+    let instructions = &function.instructions;
+    let mut pending = vec![(index + 1, ARITHMETIC_FLAGS)];
+    let mut seen = HashSet::new();
+    while let Some((start, mut flags)) = pending.pop() {
+        let Some(&b) = block_of.get(start) else {
+            return true;
+        };
+        let block = &function.blocks[b];
+        for instruction in &instructions[start..block.end] {
+            if instruction.rflags_read() & flags != 0 {
+                return true;
+            }
+            // the flags are not preserved across calls, so callees do not read them
+            if matches!(instruction.flow_control(), FlowControl::Call | FlowControl::IndirectCall) {
+                flags = 0;
+            }
+            flags &= !instruction.rflags_modified();
+            if flags == 0 {
+                break;
+            }
+        }
+        if flags == 0 {
+            continue;
+        }
+        // returns and tail jumps have no successors: the flags are not passed out of the function
+        for &successor in &block.successors {
+            if seen.insert((successor, flags)) {
+                pending.push((function.blocks[successor].start, flags));
+            }
+        }
+    }
+    false
+}
+
+#[derive(Clone, Copy)]
+enum Operand {
+    Reg(Register),
+    Imm(i32),
+}
+
+/// One instruction that can be rewritten: `op x, y` on 32 or 64 bits.
+struct Rewrite {
+    op: Mnemonic,
+    wide: bool,
+    x: Register,
+    y: Operand,
+    /// Constant of `mov x, imm`, sign-extended for a 64-bit `mov r/m64, imm32`.
+    constant: u64,
+}
+
+impl Rewrite {
+    fn of(i: &Instruction) -> Option<Self> {
+        if i.op_count() != 2 || i.op0_kind() != OpKind::Register {
+            return None;
+        }
+        let x = i.op0_register();
+        let wide = x.is_gpr64();
+        if !(wide || x.is_gpr32()) || x.full_register() == Register::RSP {
+            return None;
+        }
+        let op = i.mnemonic();
+        match (op, i.op1_kind()) {
+            (Mnemonic::Add | Mnemonic::Sub | Mnemonic::Xor | Mnemonic::And | Mnemonic::Or, OpKind::Register) => {
+                let y = i.op1_register();
+                if y.size() != x.size() || y.full_register() == Register::RSP {
+                    return None;
+                }
+                Some(Self { op, wide, x, y: Operand::Reg(y), constant: 0 })
+            }
+            (
+                Mnemonic::Add | Mnemonic::Sub | Mnemonic::Xor | Mnemonic::And | Mnemonic::Or,
+                OpKind::Immediate8to32 | OpKind::Immediate32 | OpKind::Immediate8to64 | OpKind::Immediate32to64,
+            ) => {
+                let y = if wide { i.immediate(1) as i64 as i32 } else { i.immediate(1) as u32 as i32 };
+                Some(Self { op, wide, x, y: Operand::Imm(y), constant: 0 })
+            }
+            (Mnemonic::Mov, OpKind::Immediate32 | OpKind::Immediate32to64 | OpKind::Immediate64) => Some(Self {
+                op,
+                wide,
+                x,
+                y: Operand::Imm(0),
+                constant: i.immediate(1),
+            }),
+            _ => None,
+        }
+    }
+
+    fn writes_flags(&self) -> bool {
+        self.op != Mnemonic::Mov
+    }
+
+    /// An equivalent sequence, picked at random among the forms for `op`.
+    fn expand(&self, rng: &mut impl Rng) -> Result<Vec<Instruction>, IcedError> {
+        if self.op == Mnemonic::Mov {
+            return self.load_constant(rng);
+        }
+        let x = self.reg(self.x);
+        let free: Vec<Register> = TEMPORARIES
+            .into_iter()
+            .filter(|&r| r != self.x.full_register() && !matches!(self.y, Operand::Reg(y) if y.full_register() == r))
+            .collect();
+        let t64 = *free.choose(rng).unwrap();
+        let t = self.reg(t64);
+        let (y, tt, xx) = (self.y, Operand::Reg(t), Operand::Reg(x));
+        use Mnemonic::{Add, And, Mov, Or, Sub, Xor};
+        let steps: Vec<Step> = match (self.op, rng.gen_range(0..3)) {
+            // (x ^ y) + 2 * (x & y)
+            (Add, 0) => vec![Step::Alu(Mov, t, xx), Step::Alu(And, t, y), Step::Alu(Xor, x, y), Step::AddTwice(x, t)],
+            // (x | y) + (x & y)
+            (Add, 1) => vec![Step::Alu(Mov, t, xx), Step::Alu(And, t, y), Step::Alu(Or, x, y), Step::Alu(Add, x, tt)],
+            // x - ~y - 1
+            (Add, _) => vec![Step::Alu(Mov, t, y), Step::Not(t), Step::Alu(Sub, x, tt), Step::Alu(Sub, x, Operand::Imm(1))],
+            // (x ^ y) - 2 * (~x & y)
+            (Sub, 0 | 1) => vec![
+                Step::Alu(Mov, t, xx),
+                Step::Not(t),
+                Step::Alu(And, t, y),
+                Step::Alu(Xor, x, y),
+                Step::Alu(Sub, x, tt),
+                Step::Alu(Sub, x, tt),
+            ],
+            // x + ~y + 1
+            (Sub, _) => vec![Step::Alu(Mov, t, y), Step::Not(t), Step::Alu(Add, x, tt), Step::Alu(Add, x, Operand::Imm(1))],
+            // (x | y) - (x & y)
+            (Xor, 0) => vec![Step::Alu(Mov, t, xx), Step::Alu(And, t, y), Step::Alu(Or, x, y), Step::Alu(Sub, x, tt)],
+            // (x | y) & ~(x & y)
+            (Xor, 1) => vec![
+                Step::Alu(Mov, t, xx),
+                Step::Alu(And, t, y),
+                Step::Not(t),
+                Step::Alu(Or, x, y),
+                Step::Alu(And, x, tt),
+            ],
+            // x + y - 2 * (x & y)
+            (Xor, _) => vec![
+                Step::Alu(Mov, t, xx),
+                Step::Alu(And, t, y),
+                Step::Alu(Add, x, y),
+                Step::Alu(Sub, x, tt),
+                Step::Alu(Sub, x, tt),
+            ],
+            // (x + y) - (x | y)
+            (And, 0) => vec![Step::Alu(Mov, t, xx), Step::Alu(Or, t, y), Step::Alu(Add, x, y), Step::Alu(Sub, x, tt)],
+            // ~(~x | ~y)
+            (And, 1) => vec![Step::Alu(Mov, t, y), Step::Not(t), Step::Not(x), Step::Alu(Or, x, tt), Step::Not(x)],
+            // (x | y) ^ (x ^ y)
+            (And, _) => vec![Step::Alu(Mov, t, xx), Step::Alu(Xor, t, y), Step::Alu(Or, x, y), Step::Alu(Xor, x, tt)],
+            // (x ^ y) + (x & y)
+            (Or, 0) => vec![Step::Alu(Mov, t, xx), Step::Alu(And, t, y), Step::Alu(Xor, x, y), Step::Alu(Add, x, tt)],
+            // (x + y) - (x & y)
+            (Or, 1) => vec![Step::Alu(Mov, t, xx), Step::Alu(And, t, y), Step::Alu(Add, x, y), Step::Alu(Sub, x, tt)],
+            // ~(~x & ~y)
+            _ => vec![Step::Alu(Mov, t, y), Step::Not(t), Step::Not(x), Step::Alu(And, x, tt), Step::Not(x)],
+        };
+        let mut out = vec![Instruction::with1(Code::Push_r64, t64)?];
+        for step in steps {
+            out.push(self.encode(step)?);
+        }
+        out.push(Instruction::with1(Code::Pop_r64, t64)?);
+        Ok(out)
+    }
+
+    /// `mov x, v` followed by `not x` and `lea x, [x+k]` steps that turn `v` into the constant.
+    fn load_constant(&self, rng: &mut impl Rng) -> Result<Vec<Instruction>, IcedError> {
+        let x = self.reg(self.x);
+        let steps: Vec<Step> = (0..rng.gen_range(2..=3))
+            .map(|_| if rng.gen_bool(0.3) { Step::Not(x) } else { Step::AddConstant(x, rng.gen()) })
+            .collect();
+        let mut value = self.constant;
+        for step in steps.iter().rev() {
+            value = match *step {
+                Step::Not(_) => !value,
+                Step::AddConstant(_, k) => value.wrapping_sub(k as i64 as u64),
+                _ => value,
+            };
+        }
+        let mut out = vec![if self.wide {
+            Instruction::with2(Code::Mov_r64_imm64, x, value)?
+        } else {
+            Instruction::with2(Code::Mov_r32_imm32, x, value as u32)?
+        }];
+        for step in steps {
+            out.push(self.encode(step)?);
+        }
+        Ok(out)
+    }
+
+    /// `register` in the operand size of the rewritten instruction.
+    fn reg(&self, register: Register) -> Register {
+        if self.wide {
+            register.full_register()
+        } else {
+            register.full_register32()
+        }
+    }
+
+    fn encode(&self, step: Step) -> Result<Instruction, IcedError> {
+        let wide = self.wide;
+        match step {
+            Step::Alu(op, dst, Operand::Reg(src)) => Instruction::with2(alu_code(op, wide, false), dst, src),
+            Step::Alu(op, dst, Operand::Imm(imm)) => Instruction::with2(alu_code(op, wide, true), dst, imm),
+            Step::Not(r) => Instruction::with1(if wide { Code::Not_rm64 } else { Code::Not_rm32 }, r),
+            Step::AddTwice(dst, t) => Instruction::with2(
+                if wide { Code::Lea_r64_m } else { Code::Lea_r32_m },
+                dst,
+                MemoryOperand::with_base_index_scale_displ_size(dst.full_register(), t.full_register(), 2, 0, 0),
+            ),
+            Step::AddConstant(dst, k) => Instruction::with2(
+                if wide { Code::Lea_r64_m } else { Code::Lea_r32_m },
+                dst,
+                MemoryOperand::with_base_displ(dst.full_register(), k as i64),
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Step {
+    Alu(Mnemonic, Register, Operand),
+    Not(Register),
+    /// `lea x, [x+t*2]`
+    AddTwice(Register, Register),
+    /// `lea x, [x+k]`
+    AddConstant(Register, i32),
+}
+
+fn alu_code(op: Mnemonic, wide: bool, imm: bool) -> Code {
+    match (op, wide, imm) {
+        (Mnemonic::Mov, true, false) => Code::Mov_r64_rm64,
+        (Mnemonic::Mov, true, true) => Code::Mov_rm64_imm32,
+        (Mnemonic::Mov, false, false) => Code::Mov_r32_rm32,
+        (Mnemonic::Mov, false, true) => Code::Mov_rm32_imm32,
+        (Mnemonic::Add, true, false) => Code::Add_r64_rm64,
+        (Mnemonic::Add, true, true) => Code::Add_rm64_imm32,
+        (Mnemonic::Add, false, false) => Code::Add_r32_rm32,
+        (Mnemonic::Add, false, true) => Code::Add_rm32_imm32,
+        (Mnemonic::Sub, true, false) => Code::Sub_r64_rm64,
+        (Mnemonic::Sub, true, true) => Code::Sub_rm64_imm32,
+        (Mnemonic::Sub, false, false) => Code::Sub_r32_rm32,
+        (Mnemonic::Sub, false, true) => Code::Sub_rm32_imm32,
+        (Mnemonic::Xor, true, false) => Code::Xor_r64_rm64,
+        (Mnemonic::Xor, true, true) => Code::Xor_rm64_imm32,
+        (Mnemonic::Xor, false, false) => Code::Xor_r32_rm32,
+        (Mnemonic::Xor, false, true) => Code::Xor_rm32_imm32,
+        (Mnemonic::And, true, false) => Code::And_r64_rm64,
+        (Mnemonic::And, true, true) => Code::And_rm64_imm32,
+        (Mnemonic::And, false, false) => Code::And_r32_rm32,
+        (Mnemonic::And, false, true) => Code::And_rm32_imm32,
+        (Mnemonic::Or, true, false) => Code::Or_r64_rm64,
+        (Mnemonic::Or, true, true) => Code::Or_rm64_imm32,
+        (Mnemonic::Or, false, false) => Code::Or_r32_rm32,
+        _ => Code::Or_rm32_imm32,
+    }
+}
+
+#[cfg(all(test, unix, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+
+    use iced_x86::code_asm::*;
+    use rand::SeedableRng;
+
+    use crate::pipeline::cancel::CancellationToken;
+    use crate::pipeline::fixture::{self, native::Mapped, IMAGE_BASE, TEXT_RVA};
+    use crate::pipeline::image::Image;
+    use crate::pipeline::profile::{FunctionAttribute, FunctionSelector};
+
+    /// Mixes its four arguments with every operation the pass rewrites, in 32
+    /// and 64 bits, with a carry that decides a branch.
+    fn arithmetic_function() -> Vec<u8> {
+        let mut a = CodeAssembler::new(64).unwrap();
+        let mut no_carry = a.create_label();
+        let mut small = a.create_label();
+        a.push(rbx).unwrap();
+        a.mov(rax, rcx).unwrap();
+        a.add(rax, rdx).unwrap();
+        a.mov(rbx, 0x1234_5678_9abc_def0u64).unwrap();
+        a.xor(rax, rbx).unwrap();
+        a.and(rdx, r8).unwrap();
+        a.or(rax, rdx).unwrap();
+        a.sub(r9, 0x77).unwrap();
+        a.add(rax, r9).unwrap();
+        a.mov(r10d, 0xdead_beefu32).unwrap();
+        a.xor(r10d, ecx).unwrap();
+        a.sub(eax, r10d).unwrap();
+        a.or(ebx, 0x0f0f).unwrap();
+        a.and(rbx, 0x7fff_ffff).unwrap();
+        a.add(rax, rbx).unwrap();
+        // the flags of this one are read
+        a.add(rcx, r8).unwrap();
+        a.jnc(no_carry).unwrap();
+        a.xor(rax, 0x5555).unwrap();
+        a.set_label(&mut no_carry).unwrap();
+        a.cmp(rax, r8).unwrap();
+        a.jb(small).unwrap();
+        a.sub(rax, rcx).unwrap();
+        a.set_label(&mut small).unwrap();
+        a.pop(rbx).unwrap();
+        a.ret().unwrap();
+        a.assemble(IMAGE_BASE + TEXT_RVA as u64).unwrap()
+    }
+
+    fn substitute_sample(original: &[u8], seed: u64) -> Vec<u8> {
+        let mut ctx = PipelineContext::new("sample.exe".into(), CancellationToken::new());
+        ctx.seed = seed;
+        ctx.image = Some(Image::parse(original.to_vec()).unwrap());
+        let options = SubstituteOptions {
+            enabled: true,
+            functions: vec![FunctionSelector::Attribute(FunctionAttribute::Entry)],
+            density: 1.0,
+        };
+        let (tx, rx) = mpsc::channel();
+        SubstituteStep::new(options).run(&mut ctx, &tx).unwrap();
+        drop(tx);
+        let summary = "Instruction substitution: 1 functions changed";
+        assert!(rx.iter().any(|message| matches!(message, PipelineMessage::Log(line) if line == summary)));
+        let mut image = ctx.image.take().unwrap();
+        image.finalize().unwrap();
+        image.into_bytes()
+    }
+
+    #[test]
+    fn substituted_function_computes_the_same() {
+        let original = fixture::pe64(&arithmetic_function(), &[]);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut inputs: Vec<[u64; 4]> = (0..200).map(|_| rng.gen()).collect();
+        inputs.extend([[0; 4], [u64::MAX; 4], [1, u64::MAX, 2, 0], [u64::MAX, 0, 1, 0x77]]);
+        let expected: Vec<u64> = {
+            let mapped = Mapped::new(&original);
+            inputs.iter().map(|&args| mapped.call(TEXT_RVA, args)).collect()
+        };
+        for seed in 1..=4 {
+            let bytes = substitute_sample(&original, seed);
+            let mapped = Mapped::new(&bytes);
+            for (args, expected) in inputs.iter().zip(&expected) {
+                assert_eq!(mapped.call(TEXT_RVA, *args), *expected, "seed {}, arguments {:x?}", seed, args);
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Symbol map of a protected build, written as the `<input>.<hash>.obf-map` artifact (see `artifacts`).
+//!
+//! `ObfuscateFunctionsStep` records one entry per function and
+//! `WriteOutputStep` completes the map once the output is final:
+//!
+//! ```json
+//! {
+//!   "format": "obscura-symbol-map",
+//!   "version": 1,
+//!   "build_id": "5e0c3b7f1a9d2486",
+//!   "image_base": 5368709120,
+//!   "functions": [
+//!     { "name": "main", "obfuscated": "f_0001", "rva": 4096, "size": 114 },
+//!     { "name": "verify_key", "obfuscated": "f_0002", "rva": 4212, "size": 96,
+//!       "moved_to": { "rva": 270336, "size": 141 } }
+//!   ]
+//! }
+//! ```
+//!
+//! `rva` and `size` give the function in the input; its original start is
+//! still an entry point of the output. `moved_to` is where a function a
+//! code pass rewrote runs from. `build_id` is SipHash-2-4 (zero key) of the
+//! protected file, so a map can be checked against the binary a crash
+//! report comes from. Readers accept any map of `version` up to theirs.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::pipeline::artifacts::ArtifactReader;
+use crate::pipeline::error::StepError;
+use crate::pipeline::hash::siphash24;
+
+pub const FORMAT: &str = "obscura-symbol-map";
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SymbolMap {
+    pub format: String,
+    pub version: u32,
+    /// Empty until the output is written.
+    pub build_id: String,
+    pub image_base: u64,
+    pub functions: Vec<MappedFunction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MappedFunction {
+    /// Name in the input (symbol, export or `sub_<rva>`).
+    pub name: String,
+    pub obfuscated: String,
+    pub rva: u32,
+    pub size: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub moved_to: Option<Extent>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Extent {
+    pub rva: u32,
+    pub size: u32,
+}
+
+/// Where an address of the protected build falls.
+pub struct Symbolized<'a> {
+    pub function: &'a MappedFunction,
+    pub offset: u32,
+    /// In the rewritten copy, whose offsets no longer match the input.
+    pub moved: bool,
+}
+
+impl std::fmt::Display for Symbolized<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}+0x{:x}", self.function.name, self.offset)?;
+        if self.moved {
+            f.write_str(" [moved copy]")?;
+        }
+        Ok(())
+    }
+}
+
+/// Build id of a protected file, as stored in its map.
+pub fn build_id(bytes: &[u8]) -> String {
+    format!("{:016x}", siphash24([0, 0], bytes))
+}
+
+impl SymbolMap {
+    pub fn new(image_base: u64, functions: Vec<MappedFunction>) -> Self {
+        Self {
+            format: FORMAT.into(),
+            version: VERSION,
+            build_id: String::new(),
+            image_base,
+            functions,
+        }
+    }
+
+    /// Reads a map, sealed or signed ones through `reader`.
+    pub fn load(path: &Path, reader: &ArtifactReader) -> Result<Self, StepError> {
+        let bytes = reader.read(path)?;
+        let invalid = |what: String| StepError::InvalidInput(format!("Invalid symbol map '{}': {}", path.display(), what));
+        let map: SymbolMap = serde_json::from_slice(&bytes).map_err(|e| invalid(e.to_string()))?;
+        if map.format != FORMAT {
+            return Err(invalid(format!("format is '{}', expected '{}'", map.format, FORMAT)));
+        }
+        if map.version > VERSION {
+            return Err(invalid(format!("version {} is newer than this tool reads ({})", map.version, VERSION)));
+        }
+        Ok(map)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+
+    /// Fills in where `reassemble` moved functions, given as `(original rva, new rva, new size)`.
+    pub fn record_moves(&mut self, moved: &[(u32, u32, u32)]) {
+        for &(rva, new_rva, size) in moved {
+            if let Some(function) = self.functions.iter_mut().find(|f| f.rva == rva) {
+                function.moved_to = Some(Extent { rva: new_rva, size });
+            }
+        }
+    }
+
+    /// The function containing `rva` in the protected build.
+    pub fn locate(&self, rva: u32) -> Option<Symbolized<'_>> {
+        let moved = self.functions.iter().find_map(|function| {
+            let to = function.moved_to?;
+            (rva >= to.rva && rva < to.rva + to.size).then(|| Symbolized {
+                function,
+                offset: rva - to.rva,
+                moved: true,
+            })
+        });
+        moved.or_else(|| {
+            self.functions
+                .iter()
+                .filter(|f| rva >= f.rva && rva < f.rva + f.size.max(1))
+                .min_by_key(|f| f.size)
+                .map(|function| Symbolized {
+                    function,
+                    offset: rva - function.rva,
+                    moved: false,
+                })
+        })
+    }
+
+    /// Rewrites one line of a stack trace or log: obfuscated names become the
+    /// original ones and addresses get the function they fall in appended.
+    /// `0x...` after a `+` (`module+0x1a2b`) is an RVA, unless it follows a
+    /// function name (`f_0003+0x12`); any other address is taken relative to
+    /// `base`, the load address, unless it is below it.
+    pub fn deobfuscate_line(&self, line: &str, base: u64) -> String {
+        let bytes = line.as_bytes();
+        let word = |b: u8| b.is_ascii_alphanumeric() || b == b'_';
+        let mut out = String::with_capacity(line.len());
+        let mut at = 0;
+        // the previous word and where it ends
+        let mut previous = ("", 0);
+        while at < bytes.len() {
+            if !word(bytes[at]) {
+                let c = line[at..].chars().next().unwrap();
+                out.push(c);
+                at += c.len_utf8();
+                continue;
+            }
+            let end = at + bytes[at..].iter().take_while(|&&b| word(b)).count();
+            let token = &line[at..end];
+            match self.functions.iter().find(|f| f.obfuscated == token) {
+                Some(function) => out.push_str(&function.name),
+                None => out.push_str(token),
+            }
+            let offset = at > 0 && bytes[at - 1] == b'+';
+            let in_function = offset
+                && previous.1 == at - 1
+                && self.functions.iter().any(|f| f.obfuscated == previous.0 || f.name == previous.0);
+            if let Some(value) = token.strip_prefix("0x").and_then(|hex| u64::from_str_radix(hex, 16).ok()) {
+                let rva = if offset || value < base { value } else { value - base };
+                if let Some(symbol) = u32::try_from(rva).ok().filter(|_| !in_function).and_then(|rva| self.locate(rva)) {
+                    out.push_str(&format!(" ({})", symbol));
+                }
+            }
+            previous = (token, end);
+            at = end;
+        }
+        out
+    }
+}
@@ -0,0 +1,337 @@
+//! Symbolicated stacks of protected builds from minidumps (`obscura symbolicate`).
+//!
+//! Frame 0 comes from the register context; the callers are found by
+//! scanning the captured stack for words that point just past a `call` in
+//! a module, as Breakpad does without unwind data. The call is checked in the
+//! code captured by the dump or, for the protected module, in the protected
+//! binary when given; a word whose code is in neither is listed as `scan`.
+//!
+//! Addresses in the protected module are named with the symbol map: code a
+//! pass moved is traced back to the function it was moved from, and the
+//! original symbols (PDB publics, ELF symbols or exports of the unprotected
+//! input) name that function when the map only has `sub_<rva>`.
+
+use iced_x86::{Decoder, DecoderOptions, FlowControl};
+
+use crate::pipeline::error::StepError;
+use crate::pipeline::image::Image;
+use crate::pipeline::minidump::{Architecture, Minidump, Module, Thread};
+use crate::pipeline::pdb::Pdb;
+use crate::pipeline::pe;
+use crate::pipeline::symbol_map::SymbolMap;
+
+const MAX_FRAMES: usize = 64;
+/// Stack words looked at past the stack pointer.
+const MAX_SCAN_WORDS: u64 = 16 * 1024;
+/// Lengths of the `call` forms: `ff /2` with a register, disp8, disp32 (+SIB), and `e8 rel32`.
+const CALL_LENGTHS: [usize; 6] = [2, 3, 4, 5, 6, 7];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trust {
+    /// Instruction pointer of the register context.
+    Context,
+    /// Return address that follows a `call`.
+    CallSite,
+    /// Points into a module, but no code was available to check it.
+    Scan,
+}
+
+impl std::fmt::Display for Trust {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Trust::Context => "context",
+            Trust::CallSite => "call site",
+            Trust::Scan => "scan",
+        })
+    }
+}
+
+pub struct Frame {
+    pub address: u64,
+    pub trust: Trust,
+}
+
+/// Function symbols of the unprotected input.
+pub struct OriginalSymbols {
+    /// `(rva, size, name)` sorted by RVA; size 0 extends to the next symbol.
+    pub functions: Vec<(u32, u32, String)>,
+    /// GUID and age of a PDB, to check against the module's CodeView record.
+    pub pdb: Option<([u8; 16], u32)>,
+    pub description: String,
+}
+
+impl OriginalSymbols {
+    /// Reads a PDB, or the symbol table (ELF) or exports (PE) of a binary.
+    pub fn load(bytes: Vec<u8>) -> Result<Self, StepError> {
+        if bytes.starts_with(b"Microsoft C/C++ MSF 7.00") {
+            let pdb = Pdb::parse(&bytes)?;
+            let count = pdb.functions.len();
+            return Ok(Self {
+                functions: pdb.functions.into_iter().map(|(rva, name)| (rva, 0, name)).collect(),
+                pdb: Some((pdb.guid, pdb.age)),
+                description: format!("PDB, {} public functions", count),
+            });
+        }
+        let image = Image::parse(bytes)?;
+        let (mut functions, what) = match image.symbols() {
+            symbols if !symbols.is_empty() => (symbols, "ELF symbol table"),
+            _ => (image.exports().into_iter().map(|(rva, name)| (rva, 0, name)).collect(), "exports"),
+        };
+        functions.sort();
+        Ok(Self {
+            description: format!("{}, {} functions", what, functions.len()),
+            functions,
+            pdb: None,
+        })
+    }
+
+    /// The function containing `rva`, as `(start, name)`.
+    fn function_at(&self, rva: u32) -> Option<(u32, &str)> {
+        let index = self.functions.partition_point(|f| f.0 <= rva).checked_sub(1)?;
+        let (start, size, name) = &self.functions[index];
+        (*size == 0 || rva - start < *size).then_some((*start, name.as_str()))
+    }
+}
+
+pub struct Symbolicator<'a> {
+    dump: &'a Minidump,
+    /// The protected module.
+    module: &'a Module,
+    map: &'a SymbolMap,
+    symbols: Option<&'a OriginalSymbols>,
+    /// The protected binary, whose code checks return addresses and whose
+    /// section names label the code protection added.
+    binary: Option<&'a Image>,
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit(['\\', '/']).next().unwrap_or(path)
+}
+
+impl<'a> Symbolicator<'a> {
+    pub fn new(
+        dump: &'a Minidump,
+        module: &'a Module,
+        map: &'a SymbolMap,
+        symbols: Option<&'a OriginalSymbols>,
+        binary: Option<&'a Image>,
+    ) -> Self {
+        Self { dump, module, map, symbols, binary }
+    }
+
+    /// `module!function+0xoffset`, or `module+0xoffset` when nothing names the address.
+    pub fn describe(&self, address: u64) -> String {
+        let Some(module) = self.dump.module_at(address) else {
+            return "???".into();
+        };
+        let name = file_name(&module.name);
+        let offset = address - module.base;
+        if module.base != self.module.base {
+            return format!("{}+0x{:x}", name, offset);
+        }
+        let rva = offset as u32;
+        if let Some(symbol) = self.map.locate(rva) {
+            let function = symbol.function;
+            let original = self.symbols.and_then(|s| s.function_at(function.rva));
+            let function_name = original.map_or(function.name.as_str(), |(_, name)| name);
+            let start = original.map_or(function.rva, |(start, _)| start);
+            return match symbol.moved {
+                true => format!(
+                    "{}!{}+0x{:x} [moved copy+0x{:x}]",
+                    name,
+                    function_name,
+                    function.rva - start,
+                    symbol.offset
+                ),
+                false => format!("{}!{}+0x{:x}", name, function_name, rva - start),
+            };
+        }
+        if let Some((start, function)) = self.symbols.and_then(|s| s.function_at(rva)) {
+            return format!("{}!{}+0x{:x}", name, function, rva - start);
+        }
+        let section = self.binary.and_then(|binary| {
+            binary
+                .named_sections()
+                .into_iter()
+                .find(|(_, s)| pe::range_contains(s.virtual_address, s.virtual_size.max(s.raw_size), rva))
+        });
+        match section {
+            Some((section, s)) => format!("{}!<{}>+0x{:x}", name, section, rva - s.virtual_address),
+            None => format!("{}+0x{:x}", name, offset),
+        }
+    }
+
+    /// Frame 0 from the context, then the return addresses found on the stack.
+    pub fn walk(&self, thread: &Thread) -> Vec<Frame> {
+        let Some(context) = self.dump.thread_context(thread) else {
+            return Vec::new();
+        };
+        let mut frames = vec![Frame { address: context.ip, trust: Trust::Context }];
+        let word = match self.dump.architecture {
+            Architecture::Amd64 => 8,
+            Architecture::X86 => 4,
+        };
+        let mut at = context.sp;
+        for _ in 0..MAX_SCAN_WORDS {
+            if frames.len() >= MAX_FRAMES {
+                break;
+            }
+            let Some(bytes) = self.dump.read(at, word) else {
+                break;
+            };
+            let value = match word {
+                8 => u64::from_le_bytes(bytes.try_into().unwrap()),
+                _ => u32::from_le_bytes(bytes.try_into().unwrap()) as u64,
+            };
+            let Some(next) = at.checked_add(word as u64) else {
+                break;
+            };
+            at = next;
+            if self.dump.module_at(value).is_none() {
+                continue;
+            }
+            match self.follows_call(value) {
+                Some(true) => frames.push(Frame { address: value, trust: Trust::CallSite }),
+                Some(false) => {}
+                None => frames.push(Frame { address: value, trust: Trust::Scan }),
+            }
+        }
+        frames
+    }
+
+    /// Whether the code before `address` ends with a `call`; `None` when
+    /// the code is not available.
+    fn follows_call(&self, address: u64) -> Option<bool> {
+        let longest = CALL_LENGTHS[CALL_LENGTHS.len() - 1];
+        let start = address.checked_sub(longest as u64)?;
+        let code = match self.dump.read(start, longest) {
+            Some(code) => code,
+            None if self.module.contains(address) => {
+                let rva = start.checked_sub(self.module.base)? as u32;
+                self.binary?.read(rva, longest)?
+            }
+            None => return None,
+        };
+        let bitness = match self.dump.architecture {
+            Architecture::Amd64 => 64,
+            Architecture::X86 => 32,
+        };
+        Some(CALL_LENGTHS.iter().any(|&len| {
+            let bytes = &code[longest - len..];
+            let instruction = Decoder::new(bitness, bytes, DecoderOptions::NONE).decode();
+            instruction.len() == len
+                && matches!(instruction.flow_control(), FlowControl::Call | FlowControl::IndirectCall)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: u64 = 0x1_4000_0000;
+    const STACK: u64 = 0x1000_0000;
+    const CONTEXT_SIZE: usize = 0x4d0;
+
+    /// An x64 dump of one thread stopped at `BASE + 0x1000` in `app.exe`,
+    /// whose stack holds a return address following a captured `call`.
+    fn sample_dump() -> Vec<u8> {
+        let mut bytes = vec![0u8; 0x800];
+        let mut put = |at: usize, value: &[u8]| bytes[at..at + value.len()].copy_from_slice(value);
+        put(0, b"MDMP");
+        put(8, &5u32.to_le_bytes());
+        put(12, &32u32.to_le_bytes());
+        // system info, modules, threads, memory list, memory64 list
+        let streams = [(7u32, 56u32, 0x60u32), (4, 112, 0xa0), (3, 52, 0x120), (5, 20, 0x160), (9, 32, 0x180)];
+        for (i, (kind, size, rva)) in streams.into_iter().enumerate() {
+            let at = 32 + i * 12;
+            put(at, &kind.to_le_bytes());
+            put(at + 4, &size.to_le_bytes());
+            put(at + 8, &rva.to_le_bytes());
+        }
+        put(0x60, &9u16.to_le_bytes());
+
+        put(0xa0, &1u32.to_le_bytes());
+        put(0xa4, &BASE.to_le_bytes());
+        put(0xac, &0x3000u32.to_le_bytes());
+        put(0xb8, &0x110u32.to_le_bytes());
+        put(0x110, &6u32.to_le_bytes());
+        put(0x114, &[b'a', 0, b'p', 0, b'p', 0]);
+
+        put(0x120, &1u32.to_le_bytes());
+        put(0x124, &7u32.to_le_bytes());
+        put(0x124 + 24, &STACK.to_le_bytes());
+        put(0x124 + 32, &16u32.to_le_bytes());
+        put(0x124 + 36, &0x1a0u32.to_le_bytes());
+        put(0x124 + 40, &(CONTEXT_SIZE as u32).to_le_bytes());
+        put(0x124 + 44, &0x200u32.to_le_bytes());
+
+        // code at BASE + 0x1000: a call ending at +0x10
+        put(0x160, &1u32.to_le_bytes());
+        put(0x164, &(BASE + 0x1000).to_le_bytes());
+        put(0x16c, &16u32.to_le_bytes());
+        put(0x170, &0x1b0u32.to_le_bytes());
+        put(0x1b0 + 0xb, &[0xe8, 0, 0, 0, 0]);
+
+        // the stack again, as a memory64 run
+        put(0x180, &1u64.to_le_bytes());
+        put(0x188, &0x1a0u64.to_le_bytes());
+        put(0x190, &STACK.to_le_bytes());
+        put(0x198, &16u64.to_le_bytes());
+        put(0x1a0, &(BASE + 0x1010).to_le_bytes());
+
+        put(0x200 + 0x98, &STACK.to_le_bytes());
+        put(0x200 + 0xf8, &(BASE + 0x1000).to_le_bytes());
+        bytes.resize(0x200 + CONTEXT_SIZE, 0);
+        bytes
+    }
+
+    /// Parses a dump and walks and names every thread, as `symbolicate` does.
+    fn symbolicate(bytes: Vec<u8>) -> Result<Vec<String>, StepError> {
+        let dump = Minidump::parse(bytes)?;
+        let map = SymbolMap::new(BASE, Vec::new());
+        let mut lines = Vec::new();
+        for module in &dump.modules {
+            let symbolicator = Symbolicator::new(&dump, module, &map, None, None);
+            for thread in &dump.threads {
+                for frame in symbolicator.walk(thread) {
+                    lines.push(format!("{} {}", symbolicator.describe(frame.address), frame.trust));
+                }
+            }
+        }
+        Ok(lines)
+    }
+
+    #[test]
+    fn walks_the_sample_dump() {
+        assert_eq!(symbolicate(sample_dump()).unwrap(), ["app+0x1000 context", "app+0x1010 call site"]);
+    }
+
+    #[test]
+    fn truncated_or_corrupt_dumps_do_not_panic() {
+        let bytes = sample_dump();
+        for len in 0..bytes.len() {
+            let result = symbolicate(bytes[..len].to_vec());
+            if len < 0x60 {
+                assert!(result.is_err(), "{} bytes parsed", len);
+            }
+        }
+        for at in (0..0x200).step_by(4) {
+            for value in [u32::MAX, 0x8000_0000] {
+                let mut corrupt = bytes.clone();
+                corrupt[at..at + 4].copy_from_slice(&value.to_le_bytes());
+                symbolicate(corrupt).ok();
+            }
+            for value in [u64::MAX, u64::MAX - 7] {
+                let mut corrupt = bytes.clone();
+                corrupt[at..at + 8].copy_from_slice(&value.to_le_bytes());
+                symbolicate(corrupt).ok();
+            }
+        }
+        // the stack pointer at the top of the address space
+        let mut corrupt = bytes.clone();
+        corrupt[0x124 + 24..0x124 + 32].copy_from_slice(&(u64::MAX - 15).to_le_bytes());
+        corrupt[0x200 + 0x98..0x200 + 0xa0].copy_from_slice(&(u64::MAX - 15).to_le_bytes());
+        symbolicate(corrupt).unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+use std::sync::mpsc::Sender;
+
+use crate::pipeline::{PipelineContext, PipelineMessage};
+use crate::pipeline::error::StepError;
+use crate::pipeline::image::{IMAGE_SCN_CNT_CODE, IMAGE_SCN_MEM_EXECUTE, IMAGE_SCN_MEM_READ};
+use crate::pipeline::plan::PlannedChange;
+use crate::pipeline::profile::{step_rng, VirtualizeOptions};
+use crate::pipeline::step::PipelineStep;
+use crate::pipeline::vm;
+
+pub const VM_SECTION: &str = ".obsv";
+
+/// Translates the functions selected in the profile into bytecode for an
+/// interpreter added in the `.obsv` section (see `vm`), and replaces their
+/// original body with a jump to the VM entry stub.
+///
+/// Works on x64 PE and ELF images. Functions using instructions the VM does
+/// not implement (SSE, string operations, locked or dynamically sized stack
+/// adjustments...) are left native. The interpreter frames have no unwind
+/// information, so a C++ exception or SEH unwind that has to cross a
+/// virtualized function fails; functions with handlers are not virtualized.
+pub struct VirtualizeStep {
+    options: VirtualizeOptions,
+}
+
+impl VirtualizeStep {
+    pub fn new(options: VirtualizeOptions) -> Self {
+        Self { options }
+    }
+}
+
+impl PipelineStep for VirtualizeStep {
+    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> Result<(), StepError> {
+        ctx.cancel.check()?;
+        tx.send(PipelineMessage::Log("Virtualizing functions...".into())).ok();
+
+        let Some(image) = ctx.image.as_mut() else {
+            return Err(StepError::Internal("no image loaded before virtualization".into()));
+        };
+        if !image.is_64 {
+            tx.send(PipelineMessage::Log("Virtualization supports x64 images only; skipped".into())).ok();
+            return Ok(());
+        }
+        let image_base = image.image_base;
+        let code = image.code_map();
+        let (selected, unmatched) = code.select(&self.options.functions);
+        for selector in unmatched {
+            tx.send(PipelineMessage::Log(format!("Virtualize: no function matches '{}'", selector))).ok();
+        }
+
+        let mut virtualized = 0;
+        let mut programs = Vec::new();
+        // (rva, size) of the original bodies to patch
+        let mut bodies = Vec::new();
+        for index in selected {
+            ctx.cancel.check()?;
+            let name = code.functions[index].name();
+            let program = code
+                .check_movable(index)
+                .and_then(|_| code.check_relocations(index))
+                .and_then(|_| vm::translate(&code.functions[index], image_base));
+            let program = match program {
+                Ok(program) => program,
+                Err(reason) => {
+                    tx.send(PipelineMessage::Log(format!("Not virtualizing {}: {}", name, reason))).ok();
+                    continue;
+                }
+            };
+            let function = &mut code.functions[index];
+            if ctx.dry_run {
+                ctx.plan.push(PlannedChange::TransformFunction {
+                    function: name.clone(),
+                    rva: function.info.rva,
+                    transform: "virtualize".into(),
+                });
+            } else {
+                // the bytecode replaces the body; it is no longer moved
+                function.modified = false;
+                function.replaced = true;
+                bodies.push((function.info.rva, function.info.size));
+                programs.push(program);
+            }
+            tx.send(PipelineMessage::Log(format!("Virtualized {}", name))).ok();
+            virtualized += 1;
+        }
+        if ctx.dry_run && virtualized > 0 {
+            ctx.plan.push_once(PlannedChange::AddSection {
+                name: VM_SECTION.into(),
+                description: "VM interpreter and bytecode".into(),
+            });
+        }
+        if programs.is_empty() {
+            tx.send(PipelineMessage::Log(format!("Virtualization: {} functions", virtualized))).ok();
+            return Ok(());
+        }
+
+        let mut rng = step_rng(ctx.seed, "virtualize");
+        let section_rva = image.next_section_rva();
+        let (data, stubs) = vm::assemble(image_base, section_rva, &programs, &mut rng)?;
+        let rva = image.add_section(
+            VM_SECTION,
+            &data,
+            IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE | IMAGE_SCN_MEM_READ,
+        )?;
+        if rva != section_rva {
+            return Err(StepError::Internal(format!(
+                "VM section placed at 0x{:x} instead of 0x{:x}",
+                rva, section_rva
+            )));
+        }
+        for (&(start, size), stub) in bodies.iter().zip(stubs) {
+            let mut patch = vec![0xcc; size as usize];
+            patch[0] = 0xe9;
+            patch[1..5].copy_from_slice(&stub.wrapping_sub(start + 5).to_le_bytes());
+            image.write(start, &patch)?;
+        }
+        tx.send(PipelineMessage::Log(format!(
+            "Virtualization: {} functions, {} bytes in {}",
+            programs.len(),
+            data.len(),
+            VM_SECTION
+        )))
+        .ok();
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux", target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::process::Command;
+    use std::sync::mpsc;
+
+    use crate::pipeline::cancel::CancellationToken;
+    use crate::pipeline::image::{Image, ImageFormat};
+    use crate::pipeline::profile::{FunctionAttribute, FunctionSelector};
+
+    /// Virtualizes every function of the ELF at `path` that the VM accepts;
+    /// returns the number virtualized and the written file.
+    fn virtualize_file(path: &Path) -> Option<(usize, Vec<u8>)> {
+        let image = Image::parse(std::fs::read(path).ok()?).ok()?;
+        if image.format != ImageFormat::Elf || image.is_dll {
+            return None;
+        }
+        let mut ctx = PipelineContext::new(path.to_string_lossy().into_owned(), CancellationToken::new());
+        ctx.seed = 1;
+        ctx.image = Some(image);
+        let options = VirtualizeOptions {
+            enabled: true,
+            functions: vec![FunctionSelector::Attribute(FunctionAttribute::All)],
+        };
+        let (tx, rx) = mpsc::channel();
+        VirtualizeStep::new(options).run(&mut ctx, &tx).unwrap();
+        drop(tx);
+        let virtualized = rx
+            .iter()
+            .filter(|message| matches!(message, PipelineMessage::Log(line) if line.starts_with("Virtualized ")))
+            .count();
+        let mut image = ctx.image.take().unwrap();
+        image.finalize().unwrap();
+        Some((virtualized, image.into_bytes()))
+    }
+
+    #[test]
+    fn virtualized_elf_binaries_run() {
+        let dir = std::env::temp_dir().join(format!("obscura-virtualize-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut virtualized_any = false;
+        let runs: [(&str, &[&str]); 5] = [
+            ("/bin/true", &[]),
+            ("/bin/ls", &["/"]),
+            ("/bin/ls", &["-la", "--color=never", "/etc"]),
+            ("/bin/cat", &["/etc/hostname"]),
+            ("/bin/cat", &["-n", "/etc/passwd"]),
+        ];
+        for (path, args) in runs {
+            let Some((virtualized, bytes)) = virtualize_file(Path::new(path)) else {
+                continue;
+            };
+            virtualized_any |= virtualized > 0;
+            let target = dir.join(Path::new(path).file_name().unwrap());
+            std::fs::write(&target, bytes).unwrap();
+            std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o755)).unwrap();
+            let expected = Command::new(path).args(args).output().unwrap();
+            let actual = Command::new(&target).args(args).output().unwrap();
+            assert_eq!(actual.status.code(), expected.status.code(), "{} {:?}", path, args);
+            assert_eq!(actual.stdout, expected.stdout, "{} {:?}", path, args);
+        }
+        std::fs::remove_dir_all(&dir).ok();
+        assert!(virtualized_any, "no function of the test binaries was virtualized");
+    }
+}
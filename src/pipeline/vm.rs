@@ -0,0 +1,1244 @@
+//! Bytecode virtual machine of `VirtualizeStep`.
+//!
+//! `translate` turns the instructions of an x64 function into a stack-based
+//! bytecode; `assemble` lays out the section that holds the bytecode of every
+//! virtualized function and the interpreter running it:
+//!
+//! ```text
+//! handler table   256 x i32, offsets from the section start, indexed by opcode
+//! entry records   16 bytes each: bytecode offset (u32), padding, key (u64)
+//! bytecode
+//! native code     vm_enter, the handlers (in random order) and one stub per record
+//! ```
+//!
+//! A stub pushes the offset of its record and jumps to `vm_enter`, which
+//! saves every register and the flags into a context below the virtual stack
+//! pointer and starts dispatching. Each function gets a random key: opcodes
+//! and one-byte operands are XORed with its low byte, other operands with its
+//! low 32 or all 64 bits. Opcode numbers are shuffled for every build.
+//!
+//! While the VM runs, `rbp` points to the context (the 16 general purpose
+//! registers, then the flags), `rsi` to the next bytecode byte, `rbx` to the
+//! section and `rdi` holds the key; `rsp` is the evaluation stack, below the
+//! context. Calls and branches out of the function leave the VM with every
+//! register restored, so the code they reach runs natively; a call pushes the
+//! address of a stub that enters the VM again at the instruction after it.
+
+use std::collections::HashMap;
+
+use iced_x86::code_asm::*;
+use iced_x86::{BlockEncoderOptions, FlowControl, Instruction, Mnemonic, OpKind, Register};
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::pipeline::code::{branch_target, Function};
+use crate::pipeline::error::StepError;
+
+const TABLE_SIZE: usize = 256 * 4;
+const RECORD_SIZE: usize = 16;
+/// Context slot of the flags; slots 0 to 15 hold the registers in encoding order.
+const FLAGS: u8 = 16;
+const RSP_SLOT: i32 = 4 * 8;
+const RBP_SLOT: i32 = 5 * 8;
+const FLAGS_SLOT: i32 = FLAGS as i32 * 8;
+/// Room for the evaluation stack below the context.
+const EVAL_STACK: i32 = 512;
+/// What `vm_enter` and the exit sequence place below the virtual stack
+/// pointer besides the context: the System V red zone (128 bytes), the
+/// 32 bytes staged by the exit sequence below it, the context and a margin.
+const FRAME_RESERVE: u32 = 128 + 32 + 136 + 88;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Alu {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Adc,
+    Sbb,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Shift {
+    Shl,
+    Shr,
+    Sar,
+    Rol,
+    Ror,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Unary {
+    Neg,
+    Not,
+    Inc,
+    Dec,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum MulDiv {
+    Mul,
+    Imul,
+    Div,
+    Idiv,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Convert {
+    Cwde,
+    Cdqe,
+    Cdq,
+    Cqo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Segment {
+    Fs,
+    Gs,
+}
+
+/// A bytecode instruction. Sizes are operand sizes in bytes; `u8`
+/// condition codes are `iced_x86::ConditionCode` minus one (so `cc ^ 1` is
+/// the opposite condition).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Op {
+    /// Pushes the context slot given by the operand.
+    PushReg,
+    /// Pops into the low bytes of a context slot (4 bytes clear the upper half).
+    PopReg(u8),
+    PushImm,
+    /// Pushes the address of an RVA given relative to the section.
+    PushRva,
+    /// Pops an address and pushes the zero-extended value it holds.
+    Load(u8),
+    /// Pops an address, then the value to store there.
+    Store(u8),
+    SegLoad(Segment, u8),
+    /// Pops `b`, then `a`, pushes `a op b` and updates the flags.
+    Binary(Alu, u8),
+    Imul(u8),
+    /// Pops the bit number, then the value, and sets the carry flag to that bit.
+    Bt(u8),
+    /// Pops the count, then the value.
+    Shift(Shift, u8),
+    Unary(Unary, u8),
+    /// One-operand multiply or divide of the context's `rdx:rax`.
+    MulDiv(MulDiv, u8),
+    Convert(Convert),
+    ZeroExtend(u8),
+    SignExtend(u8),
+    Jmp,
+    Jcc(u8),
+    Setcc(u8),
+    /// Pops the source, then the current destination value, pushes the one the condition selects.
+    Cmov(u8),
+    /// Moves the top of the evaluation stack to the virtual stack.
+    VPush,
+    VPop,
+    /// Pops the target, restores the registers and jumps.
+    Exit,
+    Drop,
+    AddDisp,
+    AddrAdd,
+    AddrScale,
+    Trap,
+}
+
+const SIZES: [u8; 4] = [1, 2, 4, 8];
+
+fn all_ops() -> Vec<Op> {
+    let mut ops = vec![
+        Op::PushReg,
+        Op::PushImm,
+        Op::PushRva,
+        Op::Jmp,
+        Op::VPush,
+        Op::VPop,
+        Op::Exit,
+        Op::Drop,
+        Op::AddDisp,
+        Op::AddrAdd,
+        Op::AddrScale,
+        Op::Trap,
+    ];
+    for size in SIZES {
+        ops.extend([Op::PopReg(size), Op::Load(size), Op::Store(size)]);
+        ops.extend([Segment::Fs, Segment::Gs].map(|s| Op::SegLoad(s, size)));
+        ops.extend([Alu::Add, Alu::Sub, Alu::And, Alu::Or, Alu::Xor, Alu::Adc, Alu::Sbb].map(|k| Op::Binary(k, size)));
+        ops.extend([Shift::Shl, Shift::Shr, Shift::Sar, Shift::Rol, Shift::Ror].map(|k| Op::Shift(k, size)));
+        ops.extend([Unary::Neg, Unary::Not, Unary::Inc, Unary::Dec].map(|k| Op::Unary(k, size)));
+    }
+    for size in [2, 4, 8] {
+        ops.extend([Op::Imul(size), Op::Bt(size)]);
+    }
+    for size in [1, 2, 4] {
+        ops.extend([Op::ZeroExtend(size), Op::SignExtend(size)]);
+    }
+    for size in [4, 8] {
+        ops.extend([MulDiv::Mul, MulDiv::Imul, MulDiv::Div, MulDiv::Idiv].map(|k| Op::MulDiv(k, size)));
+    }
+    ops.extend([Convert::Cwde, Convert::Cdqe, Convert::Cdq, Convert::Cqo].map(Op::Convert));
+    for cc in 0..16 {
+        ops.extend([Op::Jcc(cc), Op::Setcc(cc), Op::Cmov(cc)]);
+    }
+    ops
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    None,
+    Byte(u8),
+    Imm32(u32),
+    Imm64(u64),
+    /// An address of the image, as an RVA.
+    Rva(u32),
+    /// Position in `Program::code`.
+    Position(usize),
+    /// Index of an instruction of the function (resolved to a position once translated).
+    Instruction(usize),
+    /// The stub of an entry of the same program.
+    Reentry(usize),
+}
+
+impl Operand {
+    fn size(self) -> usize {
+        match self {
+            Operand::None => 0,
+            Operand::Byte(_) => 1,
+            Operand::Imm64(_) => 8,
+            _ => 4,
+        }
+    }
+}
+
+/// The bytecode of one function.
+pub struct Program {
+    code: Vec<(Op, Operand)>,
+    /// Positions where the VM is entered: the function start, then the instruction after each call.
+    entries: Vec<usize>,
+    /// How far below the `rsp` it was entered with the function can move the stack while in the VM.
+    frame: u32,
+}
+
+/// Translates the instructions of `function`; the error names what the VM cannot run.
+pub fn translate(function: &Function, image_base: u64) -> Result<Program, String> {
+    let mut translator = Translator {
+        image_base,
+        function,
+        code: Vec::new(),
+        entries: vec![0],
+        frame: 0,
+    };
+    let index: HashMap<u64, usize> = function
+        .instructions
+        .iter()
+        .enumerate()
+        .filter(|(_, i)| i.ip() != 0)
+        .map(|(n, i)| (i.ip(), n))
+        .collect();
+    let mut starts = Vec::with_capacity(function.instructions.len());
+    for instruction in &function.instructions {
+        starts.push(translator.code.len());
+        translator
+            .instruction(instruction, &index)
+            .map_err(|reason| format!("{} at 0x{:x}", reason, instruction.ip().wrapping_sub(image_base)))?;
+    }
+    // falling off the end, or branching past the last instruction, traps
+    translator.code.push((Op::Trap, Operand::None));
+    for (_, operand) in &mut translator.code {
+        if let Operand::Instruction(n) = *operand {
+            *operand = Operand::Position(starts[n]);
+        }
+    }
+    Ok(Program {
+        code: translator.code,
+        entries: translator.entries,
+        frame: translator.frame,
+    })
+}
+
+struct Translator<'a> {
+    image_base: u64,
+    function: &'a Function,
+    code: Vec<(Op, Operand)>,
+    entries: Vec<usize>,
+    frame: u32,
+}
+
+/// Context slot of a general purpose register.
+fn slot(register: Register) -> Result<u8, String> {
+    if matches!(register, Register::AH | Register::CH | Register::DH | Register::BH) {
+        return Err(format!("high byte register {:?}", register));
+    }
+    let full = register.full_register();
+    if !full.is_gpr64() {
+        return Err(format!("register {:?}", register));
+    }
+    Ok(full.number() as u8)
+}
+
+fn operand_size(i: &Instruction, n: u32) -> usize {
+    match i.op_kind(n) {
+        OpKind::Register => i.op_register(n).size(),
+        OpKind::Memory => i.memory_size().size(),
+        _ => 0,
+    }
+}
+
+fn is_immediate(kind: OpKind) -> bool {
+    matches!(
+        kind,
+        OpKind::Immediate8
+            | OpKind::Immediate8to16
+            | OpKind::Immediate8to32
+            | OpKind::Immediate8to64
+            | OpKind::Immediate16
+            | OpKind::Immediate32
+            | OpKind::Immediate32to64
+            | OpKind::Immediate64
+    )
+}
+
+impl Translator<'_> {
+    fn emit(&mut self, op: Op, operand: Operand) {
+        self.code.push((op, operand));
+    }
+
+    fn push_register(&mut self, register: Register) -> Result<(), String> {
+        let slot = slot(register)?;
+        self.emit(Op::PushReg, Operand::Byte(slot * 8));
+        Ok(())
+    }
+
+    fn pop_register(&mut self, register: Register, size: usize) -> Result<(), String> {
+        let slot = slot(register)?;
+        self.emit(Op::PopReg(size as u8), Operand::Byte(slot * 8));
+        Ok(())
+    }
+
+    fn segment(i: &Instruction) -> Result<Option<Segment>, String> {
+        match i.segment_prefix() {
+            Register::None | Register::DS | Register::SS | Register::ES | Register::CS => Ok(None),
+            Register::FS => Ok(Some(Segment::Fs)),
+            Register::GS => Ok(Some(Segment::Gs)),
+            other => Err(format!("segment {:?}", other)),
+        }
+    }
+
+    /// Pushes the address of the memory operand of `i` (the offset for `fs`/`gs`).
+    fn address(&mut self, i: &Instruction) -> Result<(), String> {
+        if i.is_ip_rel_memory_operand() {
+            let rva = i.ip_rel_memory_address().wrapping_sub(self.image_base);
+            if rva > u32::MAX as u64 {
+                return Err("RIP-relative operand outside of the image".into());
+            }
+            self.emit(Op::PushRva, Operand::Rva(rva as u32));
+            return Ok(());
+        }
+        let (base, index) = (i.memory_base(), i.memory_index());
+        for register in [base, index] {
+            if register != Register::None && !register.is_gpr64() {
+                return Err("32-bit addressing".into());
+            }
+        }
+        let displacement = i.memory_displacement64() as i64;
+        let mut pushed = false;
+        if base != Register::None {
+            self.push_register(base)?;
+            pushed = true;
+        }
+        if index != Register::None {
+            self.push_register(index)?;
+            if i.memory_index_scale() > 1 {
+                self.emit(Op::AddrScale, Operand::Byte(i.memory_index_scale().trailing_zeros() as u8));
+            }
+            if pushed {
+                self.emit(Op::AddrAdd, Operand::None);
+            }
+            pushed = true;
+        }
+        if !pushed {
+            self.emit(Op::PushImm, Operand::Imm64(displacement as u64));
+        } else if displacement != 0 {
+            match i32::try_from(displacement) {
+                Ok(d) => self.emit(Op::AddDisp, Operand::Imm32(d as u32)),
+                Err(_) => {
+                    self.emit(Op::PushImm, Operand::Imm64(displacement as u64));
+                    self.emit(Op::AddrAdd, Operand::None);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Pushes the value of operand `n` of `i`.
+    fn read(&mut self, i: &Instruction, n: u32) -> Result<(), String> {
+        match i.op_kind(n) {
+            OpKind::Register => self.push_register(i.op_register(n)),
+            OpKind::Memory => {
+                let size = i.memory_size().size() as u8;
+                if !SIZES.contains(&size) {
+                    return Err(format!("{}-byte memory operand", size));
+                }
+                let segment = Self::segment(i)?;
+                self.address(i)?;
+                match segment {
+                    Some(segment) => self.emit(Op::SegLoad(segment, size), Operand::None),
+                    None => self.emit(Op::Load(size), Operand::None),
+                }
+                Ok(())
+            }
+            kind if is_immediate(kind) => {
+                self.emit(Op::PushImm, Operand::Imm64(i.immediate(n)));
+                Ok(())
+            }
+            kind => Err(format!("operand kind {:?}", kind)),
+        }
+    }
+
+    /// Pops the top of the evaluation stack into operand `n` of `i`.
+    fn write(&mut self, i: &Instruction, n: u32, size: usize) -> Result<(), String> {
+        match i.op_kind(n) {
+            OpKind::Register => self.pop_register(i.op_register(n), size),
+            OpKind::Memory => {
+                if Self::segment(i)?.is_some() {
+                    return Err("store through fs or gs".into());
+                }
+                self.address(i)?;
+                self.emit(Op::Store(size as u8), Operand::None);
+                Ok(())
+            }
+            kind => Err(format!("destination operand kind {:?}", kind)),
+        }
+    }
+
+    /// Pushes the target of a call or jump out of the VM.
+    fn target(&mut self, i: &Instruction) -> Result<(), String> {
+        match branch_target(i) {
+            Some(target) => {
+                let rva = target.wrapping_sub(self.image_base);
+                if rva > u32::MAX as u64 {
+                    return Err("branch target outside of the image".into());
+                }
+                self.emit(Op::PushRva, Operand::Rva(rva as u32));
+                Ok(())
+            }
+            None if matches!(i.op0_kind(), OpKind::Register | OpKind::Memory) => self.read(i, 0),
+            None => Err("far branch".into()),
+        }
+    }
+
+    fn internal(&self, target: u64, index: &HashMap<u64, usize>) -> Option<usize> {
+        index.get(&target).copied()
+    }
+
+    /// Tracks how far the instruction moves `rsp` down; see `Program::frame`.
+    fn stack(&mut self, i: &Instruction) -> Result<(), String> {
+        let writes_rsp = i.op_count() > 0
+            && i.op0_kind() == OpKind::Register
+            && i.op0_register().full_register() == Register::RSP;
+        let immediate = (i.op_count() == 2 && is_immediate(i.op1_kind())).then(|| i.immediate(1) as i64);
+        let growth = match (i.mnemonic(), immediate) {
+            (Mnemonic::Push | Mnemonic::Pushfq | Mnemonic::Call, _) => 8,
+            _ if !writes_rsp => 0,
+            (Mnemonic::Sub, Some(imm)) => imm,
+            (Mnemonic::Add, Some(imm)) => -imm,
+            // aligning down moves rsp by less than the inverted mask
+            (Mnemonic::And, Some(imm)) => !imm & 0xffff_ffff,
+            (Mnemonic::Lea, _) if i.memory_base() == Register::RSP && i.memory_index() == Register::None => {
+                -(i.memory_displacement64() as i64)
+            }
+            // restoring the stack pointer from the frame pointer
+            (Mnemonic::Mov, _) if i.op1_kind() == OpKind::Register && i.op1_register() == Register::RBP => 0,
+            (Mnemonic::Lea, _) if i.memory_base() == Register::RBP && i.memory_index() == Register::None => 0,
+            _ => return Err("dynamic stack adjustment".into()),
+        };
+        if growth > 0 {
+            self.frame = u32::try_from(growth)
+                .ok()
+                .and_then(|g| self.frame.checked_add(g))
+                .ok_or("stack frame too large")?;
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, i: &Instruction, index: &HashMap<u64, usize>) -> Result<(), String> {
+        self.stack(i)?;
+        let size = if i.op_count() > 0 { operand_size(i, 0) } else { 0 };
+        let sized = |size: usize| -> Result<u8, String> {
+            if SIZES.contains(&(size as u8)) {
+                Ok(size as u8)
+            } else {
+                Err(format!("{}-byte operand", size))
+            }
+        };
+        match i.mnemonic() {
+            Mnemonic::Nop | Mnemonic::Endbr64 | Mnemonic::Pause => {}
+            // bytes inserted by another pass after an always-taken branch
+            _ if i.code() == iced_x86::Code::DeclareByte => {}
+            Mnemonic::Mov => {
+                self.read(i, 1)?;
+                self.write(i, 0, sized(size)? as usize)?;
+            }
+            Mnemonic::Movzx => {
+                self.read(i, 1)?;
+                if i.op1_kind() == OpKind::Register {
+                    self.emit(Op::ZeroExtend(operand_size(i, 1) as u8), Operand::None);
+                }
+                self.write(i, 0, sized(size)? as usize)?;
+            }
+            Mnemonic::Movsx | Mnemonic::Movsxd => {
+                let from = sized(operand_size(i, 1))?;
+                self.read(i, 1)?;
+                if from < 8 {
+                    self.emit(Op::SignExtend(from), Operand::None);
+                }
+                self.write(i, 0, sized(size)? as usize)?;
+            }
+            Mnemonic::Lea => {
+                self.address(i)?;
+                self.write(i, 0, sized(size)? as usize)?;
+            }
+            Mnemonic::Add | Mnemonic::Sub | Mnemonic::And | Mnemonic::Or | Mnemonic::Xor | Mnemonic::Adc | Mnemonic::Sbb => {
+                let alu = match i.mnemonic() {
+                    Mnemonic::Add => Alu::Add,
+                    Mnemonic::Sub => Alu::Sub,
+                    Mnemonic::And => Alu::And,
+                    Mnemonic::Or => Alu::Or,
+                    Mnemonic::Xor => Alu::Xor,
+                    Mnemonic::Adc => Alu::Adc,
+                    _ => Alu::Sbb,
+                };
+                if i.has_lock_prefix() {
+                    return Err("locked instruction".into());
+                }
+                self.read(i, 0)?;
+                self.read(i, 1)?;
+                self.emit(Op::Binary(alu, sized(size)?), Operand::None);
+                self.write(i, 0, size)?;
+            }
+            Mnemonic::Cmp | Mnemonic::Test => {
+                let alu = if i.mnemonic() == Mnemonic::Cmp { Alu::Sub } else { Alu::And };
+                self.read(i, 0)?;
+                self.read(i, 1)?;
+                self.emit(Op::Binary(alu, sized(size)?), Operand::None);
+                self.emit(Op::Drop, Operand::None);
+            }
+            // a memory operand with a register bit number addresses bits beyond it
+            Mnemonic::Bt if i.op0_kind() == OpKind::Register || i.op1_kind() != OpKind::Register => {
+                self.read(i, 0)?;
+                self.read(i, 1)?;
+                self.emit(Op::Bt(sized(size)?), Operand::None);
+            }
+            Mnemonic::Inc | Mnemonic::Dec | Mnemonic::Neg | Mnemonic::Not => {
+                let unary = match i.mnemonic() {
+                    Mnemonic::Inc => Unary::Inc,
+                    Mnemonic::Dec => Unary::Dec,
+                    Mnemonic::Neg => Unary::Neg,
+                    _ => Unary::Not,
+                };
+                if i.has_lock_prefix() {
+                    return Err("locked instruction".into());
+                }
+                self.read(i, 0)?;
+                self.emit(Op::Unary(unary, sized(size)?), Operand::None);
+                self.write(i, 0, size)?;
+            }
+            Mnemonic::Shl | Mnemonic::Sal | Mnemonic::Shr | Mnemonic::Sar | Mnemonic::Rol | Mnemonic::Ror => {
+                let shift = match i.mnemonic() {
+                    Mnemonic::Shl | Mnemonic::Sal => Shift::Shl,
+                    Mnemonic::Shr => Shift::Shr,
+                    Mnemonic::Sar => Shift::Sar,
+                    Mnemonic::Rol => Shift::Rol,
+                    _ => Shift::Ror,
+                };
+                self.read(i, 0)?;
+                self.read(i, 1)?;
+                self.emit(Op::Shift(shift, sized(size)?), Operand::None);
+                self.write(i, 0, size)?;
+            }
+            Mnemonic::Imul if i.op_count() > 1 => {
+                let (a, b) = if i.op_count() == 3 { (1, 2) } else { (0, 1) };
+                self.read(i, a)?;
+                self.read(i, b)?;
+                self.emit(Op::Imul(sized(size)?), Operand::None);
+                self.write(i, 0, size)?;
+            }
+            Mnemonic::Mul | Mnemonic::Imul | Mnemonic::Div | Mnemonic::Idiv => {
+                if size != 4 && size != 8 {
+                    return Err(format!("{}-byte {:?}", size, i.mnemonic()));
+                }
+                let kind = match i.mnemonic() {
+                    Mnemonic::Mul => MulDiv::Mul,
+                    Mnemonic::Imul => MulDiv::Imul,
+                    Mnemonic::Div => MulDiv::Div,
+                    _ => MulDiv::Idiv,
+                };
+                self.read(i, 0)?;
+                self.emit(Op::MulDiv(kind, size as u8), Operand::None);
+            }
+            Mnemonic::Cwde => self.emit(Op::Convert(Convert::Cwde), Operand::None),
+            Mnemonic::Cdqe => self.emit(Op::Convert(Convert::Cdqe), Operand::None),
+            Mnemonic::Cdq => self.emit(Op::Convert(Convert::Cdq), Operand::None),
+            Mnemonic::Cqo => self.emit(Op::Convert(Convert::Cqo), Operand::None),
+            Mnemonic::Xchg if i.op0_kind() == OpKind::Register && i.op1_kind() == OpKind::Register => {
+                self.read(i, 0)?;
+                self.read(i, 1)?;
+                self.write(i, 0, sized(size)? as usize)?;
+                self.write(i, 1, size)?;
+            }
+            _ if i.code().condition_code() != iced_x86::ConditionCode::None
+                && matches!(i.flow_control(), FlowControl::Next) =>
+            {
+                let cc = i.condition_code() as u8 - 1;
+                // setcc has one operand, cmovcc two
+                if i.op_count() == 1 {
+                    self.emit(Op::Setcc(cc), Operand::None);
+                    self.write(i, 0, 1)?;
+                } else {
+                    self.read(i, 0)?;
+                    self.read(i, 1)?;
+                    self.emit(Op::Cmov(cc), Operand::None);
+                    self.write(i, 0, sized(size)? as usize)?;
+                }
+            }
+            Mnemonic::Push => {
+                if size != 8 && i.op0_kind() != OpKind::Immediate8to64 && i.op0_kind() != OpKind::Immediate32to64 {
+                    return Err(format!("{}-byte push", size));
+                }
+                self.read(i, 0)?;
+                self.emit(Op::VPush, Operand::None);
+            }
+            Mnemonic::Pop => {
+                if size != 8 {
+                    return Err(format!("{}-byte pop", size));
+                }
+                self.emit(Op::VPop, Operand::None);
+                self.write(i, 0, 8)?;
+            }
+            Mnemonic::Pushfq => {
+                self.emit(Op::PushReg, Operand::Byte(FLAGS * 8));
+                self.emit(Op::VPush, Operand::None);
+            }
+            Mnemonic::Popfq => {
+                self.emit(Op::VPop, Operand::None);
+                self.emit(Op::PopReg(8), Operand::Byte(FLAGS * 8));
+            }
+            Mnemonic::Leave => {
+                self.push_register(Register::RBP)?;
+                self.pop_register(Register::RSP, 8)?;
+                self.emit(Op::VPop, Operand::None);
+                self.pop_register(Register::RBP, 8)?;
+            }
+            Mnemonic::Ud2 | Mnemonic::Int3 => self.emit(Op::Trap, Operand::None),
+            Mnemonic::Ret => {
+                if i.op_count() > 0 {
+                    return Err("ret with a stack adjustment".into());
+                }
+                self.emit(Op::VPop, Operand::None);
+                self.emit(Op::Exit, Operand::None);
+            }
+            Mnemonic::Call => {
+                let start = self.function.info.rva as u64 + self.image_base;
+                let end = start + self.function.info.size as u64;
+                if branch_target(i).is_some_and(|t| t > start && t < end) {
+                    return Err("call into its own body".into());
+                }
+                // the target may depend on rsp, so it is computed before the return address is pushed
+                self.target(i)?;
+                let entry = self.entries.len();
+                self.emit(Op::PushRva, Operand::Reentry(entry));
+                self.emit(Op::VPush, Operand::None);
+                self.emit(Op::Exit, Operand::None);
+                self.entries.push(self.code.len());
+            }
+            Mnemonic::Jmp => match branch_target(i).and_then(|t| self.internal(t, index)) {
+                Some(target) => self.emit(Op::Jmp, Operand::Instruction(target)),
+                None => {
+                    self.target(i)?;
+                    self.emit(Op::Exit, Operand::None);
+                }
+            },
+            _ if i.flow_control() == FlowControl::ConditionalBranch
+                && i.condition_code() != iced_x86::ConditionCode::None =>
+            {
+                let cc = i.condition_code() as u8 - 1;
+                match branch_target(i).and_then(|t| self.internal(t, index)) {
+                    Some(target) => self.emit(Op::Jcc(cc), Operand::Instruction(target)),
+                    None => {
+                        // skip the exit unless the branch is taken
+                        let after = self.code.len() + 3;
+                        self.emit(Op::Jcc(cc ^ 1), Operand::Position(after));
+                        self.target(i)?;
+                        self.emit(Op::Exit, Operand::None);
+                    }
+                }
+            }
+            mnemonic => return Err(format!("unsupported instruction {:?}", mnemonic).to_lowercase()),
+        }
+        Ok(())
+    }
+}
+
+/// Lays out the VM section at `section_rva` for `programs` and returns its
+/// bytes and the RVA of the entry stub of each program.
+pub fn assemble(
+    image_base: u64,
+    section_rva: u32,
+    programs: &[Program],
+    rng: &mut impl Rng,
+) -> Result<(Vec<u8>, Vec<u32>), StepError> {
+    let ops = all_ops();
+    let mut numbers: Vec<u8> = (0..=255).collect();
+    numbers.shuffle(rng);
+    let opcodes: HashMap<Op, u8> = ops.iter().copied().zip(numbers).collect();
+    let keys: Vec<u64> = programs.iter().map(|_| rng.gen()).collect();
+
+    // byte offset of every position, per program
+    let records: usize = programs.iter().map(|p| p.entries.len()).sum();
+    let mut offset = TABLE_SIZE + records * RECORD_SIZE;
+    let mut positions: Vec<Vec<usize>> = Vec::with_capacity(programs.len());
+    for program in programs {
+        let mut at = Vec::with_capacity(program.code.len() + 1);
+        for (_, operand) in &program.code {
+            at.push(offset);
+            offset += 1 + operand.size();
+        }
+        at.push(offset);
+        positions.push(at);
+    }
+    let code_offset = offset.next_multiple_of(16);
+    let section_va = image_base + section_rva as u64;
+    let frame = programs.iter().map(|p| p.frame).max().unwrap_or(0);
+    let gap = (frame + FRAME_RESERVE).next_multiple_of(16) as i32;
+
+    let mut a = CodeAssembler::new(64)?;
+    let mut enter = a.create_label();
+    emit_enter(&mut a, &mut enter, code_offset as i32, gap)?;
+    let mut order = ops.clone();
+    order.shuffle(rng);
+    let mut handlers = HashMap::new();
+    for op in order {
+        let mut label = a.create_label();
+        a.set_label(&mut label)?;
+        emit_handler(&mut a, op)?;
+        handlers.insert(op, label);
+    }
+    let mut stubs = Vec::with_capacity(records);
+    for record in 0..records {
+        let mut label = a.create_label();
+        a.set_label(&mut label)?;
+        a.push((TABLE_SIZE + record * RECORD_SIZE) as i32)?;
+        a.jmp(enter)?;
+        stubs.push(label);
+    }
+    let native = a.assemble_options(section_va + code_offset as u64, BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS)?;
+    let relative = |label: &CodeLabel| -> Result<u32, StepError> {
+        Ok((native.label_ip(label)? - section_va) as u32)
+    };
+
+    let mut data = vec![0u8; code_offset];
+    let trap = relative(&handlers[&Op::Trap])?;
+    for number in 0..256 {
+        data[number * 4..number * 4 + 4].copy_from_slice(&trap.to_le_bytes());
+    }
+    for (op, label) in &handlers {
+        let at = opcodes[op] as usize * 4;
+        data[at..at + 4].copy_from_slice(&relative(label)?.to_le_bytes());
+    }
+    let stub_rvas: Vec<u32> = stubs
+        .iter()
+        .map(|label| Ok(section_rva + relative(label)?))
+        .collect::<Result<_, StepError>>()?;
+
+    let mut record = 0;
+    let mut entry_stubs = Vec::with_capacity(programs.len());
+    for ((program, at), key) in programs.iter().zip(&positions).zip(&keys) {
+        let first_record = record;
+        entry_stubs.push(stub_rvas[first_record]);
+        for &entry in &program.entries {
+            let base = TABLE_SIZE + record * RECORD_SIZE;
+            data[base..base + 4].copy_from_slice(&(at[entry] as u32).to_le_bytes());
+            data[base + 8..base + 16].copy_from_slice(&key.to_le_bytes());
+            record += 1;
+        }
+        let (key8, key32) = (*key as u8, *key as u32);
+        for (n, (op, operand)) in program.code.iter().enumerate() {
+            let mut bytes = vec![opcodes[op] ^ key8];
+            match *operand {
+                Operand::None => {}
+                Operand::Byte(b) => bytes.push(b ^ key8),
+                Operand::Imm32(v) => bytes.extend((v ^ key32).to_le_bytes()),
+                Operand::Imm64(v) => bytes.extend((v ^ key).to_le_bytes()),
+                Operand::Rva(rva) => bytes.extend((rva.wrapping_sub(section_rva) ^ key32).to_le_bytes()),
+                Operand::Position(p) => bytes.extend((at[p] as u32 ^ key32).to_le_bytes()),
+                Operand::Reentry(e) => {
+                    let stub = stub_rvas[first_record + e];
+                    bytes.extend((stub.wrapping_sub(section_rva) ^ key32).to_le_bytes());
+                }
+                Operand::Instruction(_) => {
+                    return Err(StepError::Internal("unresolved branch in virtualized code".into()))
+                }
+            }
+            data[at[n]..at[n] + bytes.len()].copy_from_slice(&bytes);
+        }
+    }
+    data.extend_from_slice(&native.inner.code_buffer);
+    Ok((data, entry_stubs))
+}
+
+/// Decodes the next opcode and jumps to its handler.
+fn dispatch(a: &mut CodeAssembler) -> Result<(), IcedError> {
+    a.movzx(eax, byte_ptr(rsi))?;
+    a.inc(rsi)?;
+    a.xor(al, dil)?;
+    a.movsxd(rax, dword_ptr(rbx + rax * 4))?;
+    a.add(rax, rbx)?;
+    a.jmp(rax)
+}
+
+/// `eax` = next one-byte operand.
+fn operand8(a: &mut CodeAssembler) -> Result<(), IcedError> {
+    a.movzx(eax, byte_ptr(rsi))?;
+    a.xor(al, dil)?;
+    a.inc(rsi)
+}
+
+/// `eax` = next four-byte operand.
+fn operand32(a: &mut CodeAssembler) -> Result<(), IcedError> {
+    a.mov(eax, dword_ptr(rsi))?;
+    a.xor(eax, edi)?;
+    a.add(rsi, 4)
+}
+
+fn load_flags(a: &mut CodeAssembler) -> Result<(), IcedError> {
+    a.push(qword_ptr(rbp + FLAGS_SLOT))?;
+    a.popfq()
+}
+
+fn save_flags(a: &mut CodeAssembler) -> Result<(), IcedError> {
+    a.pushfq()?;
+    a.pop(qword_ptr(rbp + FLAGS_SLOT))
+}
+
+/// Registers restored from the context besides `rax`, `rsp` and `rbp`.
+const RESTORED: [(AsmRegister64, i32); 13] = [
+    (rcx, 8),
+    (rdx, 16),
+    (rbx, 24),
+    (rsi, 48),
+    (rdi, 56),
+    (r8, 64),
+    (r9, 72),
+    (r10, 80),
+    (r11, 88),
+    (r12, 96),
+    (r13, 104),
+    (r14, 112),
+    (r15, 120),
+];
+
+/// Entered from a stub with the record offset on the stack: saves the
+/// registers into a context `gap` bytes below the virtual stack pointer
+/// (touching every page down to the evaluation stack first, as Windows
+/// requires) and starts at the bytecode of the record.
+fn emit_enter(a: &mut CodeAssembler, enter: &mut CodeLabel, code_offset: i32, gap: i32) -> Result<(), IcedError> {
+    let mut probe = a.create_label();
+    let mut probed = a.create_label();
+    a.set_label(enter)?;
+    a.pushfq()?;
+    a.push(rbp)?;
+    a.push(rax)?;
+    a.lea(rbp, qword_ptr(rsp + (32 - gap - EVAL_STACK)))?;
+    a.mov(rax, rsp)?;
+    a.set_label(&mut probe)?;
+    a.sub(rax, 0x1000)?;
+    a.cmp(rax, rbp)?;
+    a.jbe(probed)?;
+    a.test(byte_ptr(rax), al)?;
+    a.jmp(probe)?;
+    a.set_label(&mut probed)?;
+    a.test(byte_ptr(rbp), al)?;
+    a.add(rbp, EVAL_STACK)?;
+    a.mov(rsp, rbp)?;
+    for (register, at) in RESTORED {
+        a.mov(qword_ptr(rbp + at), register)?;
+    }
+    // rax, rbp and the flags pushed above; the virtual rsp is above the record offset
+    for (from, to) in [(32, 0), (24, RBP_SLOT), (16, FLAGS_SLOT)] {
+        a.mov(rax, qword_ptr(rbp + (gap - from)))?;
+        a.mov(qword_ptr(rbp + to), rax)?;
+    }
+    a.lea(rax, qword_ptr(rbp + gap))?;
+    a.mov(qword_ptr(rbp + RSP_SLOT), rax)?;
+    a.mov(eax, dword_ptr(rbp + (gap - 8)))?;
+    // vm_enter is the first native instruction of the section
+    a.lea(rbx, qword_ptr(*enter))?;
+    a.sub(rbx, code_offset)?;
+    a.mov(rdi, qword_ptr(rbx + rax + 8))?;
+    a.mov(esi, dword_ptr(rbx + rax))?;
+    a.add(rsi, rbx)?;
+    dispatch(a)
+}
+
+/// Runs `$body` with `$a`/`$b` bound to `al`/`cl`, `ax`/`cx`, `eax`/`ecx` or `rax`/`rcx`.
+macro_rules! sized {
+    ($size:expr, |$a:ident, $b:ident| $body:expr) => {
+        match $size {
+            1 => {
+                let ($a, $b) = (al, cl);
+                $body
+            }
+            2 => {
+                let ($a, $b) = (ax, cx);
+                $body
+            }
+            4 => {
+                let ($a, $b) = (eax, ecx);
+                $body
+            }
+            _ => {
+                let ($a, $b) = (rax, rcx);
+                $body
+            }
+        }
+    };
+}
+
+fn jcc(a: &mut CodeAssembler, cc: u8, label: CodeLabel) -> Result<(), IcedError> {
+    match cc {
+        0 => a.jo(label),
+        1 => a.jno(label),
+        2 => a.jb(label),
+        3 => a.jae(label),
+        4 => a.je(label),
+        5 => a.jne(label),
+        6 => a.jbe(label),
+        7 => a.ja(label),
+        8 => a.js(label),
+        9 => a.jns(label),
+        10 => a.jp(label),
+        11 => a.jnp(label),
+        12 => a.jl(label),
+        13 => a.jge(label),
+        14 => a.jle(label),
+        _ => a.jg(label),
+    }
+}
+
+fn setcc(a: &mut CodeAssembler, cc: u8) -> Result<(), IcedError> {
+    match cc {
+        0 => a.seto(al),
+        1 => a.setno(al),
+        2 => a.setb(al),
+        3 => a.setae(al),
+        4 => a.sete(al),
+        5 => a.setne(al),
+        6 => a.setbe(al),
+        7 => a.seta(al),
+        8 => a.sets(al),
+        9 => a.setns(al),
+        10 => a.setp(al),
+        11 => a.setnp(al),
+        12 => a.setl(al),
+        13 => a.setge(al),
+        14 => a.setle(al),
+        _ => a.setg(al),
+    }
+}
+
+fn cmovcc(a: &mut CodeAssembler, cc: u8) -> Result<(), IcedError> {
+    match cc {
+        0 => a.cmovo(rax, rcx),
+        1 => a.cmovno(rax, rcx),
+        2 => a.cmovb(rax, rcx),
+        3 => a.cmovae(rax, rcx),
+        4 => a.cmove(rax, rcx),
+        5 => a.cmovne(rax, rcx),
+        6 => a.cmovbe(rax, rcx),
+        7 => a.cmova(rax, rcx),
+        8 => a.cmovs(rax, rcx),
+        9 => a.cmovns(rax, rcx),
+        10 => a.cmovp(rax, rcx),
+        11 => a.cmovnp(rax, rcx),
+        12 => a.cmovl(rax, rcx),
+        13 => a.cmovge(rax, rcx),
+        14 => a.cmovle(rax, rcx),
+        _ => a.cmovg(rax, rcx),
+    }
+}
+
+fn emit_handler(a: &mut CodeAssembler, op: Op) -> Result<(), IcedError> {
+    match op {
+        Op::PushReg => {
+            operand8(a)?;
+            a.push(qword_ptr(rbp + rax))?;
+        }
+        Op::PopReg(size) => {
+            operand8(a)?;
+            a.pop(rcx)?;
+            match size {
+                1 => a.mov(byte_ptr(rbp + rax), cl)?,
+                2 => a.mov(word_ptr(rbp + rax), cx)?,
+                4 => {
+                    a.mov(ecx, ecx)?;
+                    a.mov(qword_ptr(rbp + rax), rcx)?;
+                }
+                _ => a.mov(qword_ptr(rbp + rax), rcx)?,
+            }
+        }
+        Op::PushImm => {
+            a.mov(rax, qword_ptr(rsi))?;
+            a.xor(rax, rdi)?;
+            a.add(rsi, 8)?;
+            a.push(rax)?;
+        }
+        Op::PushRva => {
+            operand32(a)?;
+            a.movsxd(rax, eax)?;
+            a.add(rax, rbx)?;
+            a.push(rax)?;
+        }
+        Op::Load(size) => {
+            a.pop(rax)?;
+            match size {
+                1 => a.movzx(eax, byte_ptr(rax))?,
+                2 => a.movzx(eax, word_ptr(rax))?,
+                4 => a.mov(eax, dword_ptr(rax))?,
+                _ => a.mov(rax, qword_ptr(rax))?,
+            }
+            a.push(rax)?;
+        }
+        Op::SegLoad(segment, size) => {
+            a.pop(rax)?;
+            let with = |m: AsmMemoryOperand| if segment == Segment::Fs { m.fs() } else { m.gs() };
+            match size {
+                1 => a.movzx(eax, with(byte_ptr(rax)))?,
+                2 => a.movzx(eax, with(word_ptr(rax)))?,
+                4 => a.mov(eax, with(dword_ptr(rax)))?,
+                _ => a.mov(rax, with(qword_ptr(rax)))?,
+            }
+            a.push(rax)?;
+        }
+        Op::Store(size) => {
+            a.pop(rcx)?;
+            a.pop(rax)?;
+            match size {
+                1 => a.mov(byte_ptr(rcx), al)?,
+                2 => a.mov(word_ptr(rcx), ax)?,
+                4 => a.mov(dword_ptr(rcx), eax)?,
+                _ => a.mov(qword_ptr(rcx), rax)?,
+            }
+        }
+        Op::Binary(alu, size) => {
+            a.pop(rcx)?;
+            a.pop(rax)?;
+            load_flags(a)?;
+            sized!(size, |x, y| match alu {
+                Alu::Add => a.add(x, y)?,
+                Alu::Sub => a.sub(x, y)?,
+                Alu::And => a.and(x, y)?,
+                Alu::Or => a.or(x, y)?,
+                Alu::Xor => a.xor(x, y)?,
+                Alu::Adc => a.adc(x, y)?,
+                Alu::Sbb => a.sbb(x, y)?,
+            });
+            save_flags(a)?;
+            a.push(rax)?;
+        }
+        Op::Imul(size) => {
+            a.pop(rcx)?;
+            a.pop(rax)?;
+            load_flags(a)?;
+            match size {
+                2 => a.imul_2(ax, cx)?,
+                4 => a.imul_2(eax, ecx)?,
+                _ => a.imul_2(rax, rcx)?,
+            }
+            save_flags(a)?;
+            a.push(rax)?;
+        }
+        Op::Bt(size) => {
+            a.pop(rcx)?;
+            a.pop(rax)?;
+            load_flags(a)?;
+            match size {
+                2 => a.bt(ax, cx)?,
+                4 => a.bt(eax, ecx)?,
+                _ => a.bt(rax, rcx)?,
+            }
+            save_flags(a)?;
+        }
+        Op::Shift(shift, size) => {
+            a.pop(rcx)?;
+            a.pop(rax)?;
+            load_flags(a)?;
+            sized!(size, |x, _y| match shift {
+                Shift::Shl => a.shl(x, cl)?,
+                Shift::Shr => a.shr(x, cl)?,
+                Shift::Sar => a.sar(x, cl)?,
+                Shift::Rol => a.rol(x, cl)?,
+                Shift::Ror => a.ror(x, cl)?,
+            });
+            save_flags(a)?;
+            a.push(rax)?;
+        }
+        Op::Unary(unary, size) => {
+            a.pop(rax)?;
+            load_flags(a)?;
+            sized!(size, |x, _y| match unary {
+                Unary::Neg => a.neg(x)?,
+                Unary::Not => a.not(x)?,
+                Unary::Inc => a.inc(x)?,
+                Unary::Dec => a.dec(x)?,
+            });
+            save_flags(a)?;
+            a.push(rax)?;
+        }
+        Op::MulDiv(kind, size) => {
+            a.pop(rcx)?;
+            a.mov(rax, qword_ptr(rbp))?;
+            a.mov(rdx, qword_ptr(rbp + 16))?;
+            load_flags(a)?;
+            match (kind, size) {
+                (MulDiv::Mul, 4) => a.mul(ecx)?,
+                (MulDiv::Imul, 4) => a.imul(ecx)?,
+                (MulDiv::Div, 4) => a.div(ecx)?,
+                (MulDiv::Idiv, 4) => a.idiv(ecx)?,
+                (MulDiv::Mul, _) => a.mul(rcx)?,
+                (MulDiv::Imul, _) => a.imul(rcx)?,
+                (MulDiv::Div, _) => a.div(rcx)?,
+                (MulDiv::Idiv, _) => a.idiv(rcx)?,
+            }
+            save_flags(a)?;
+            if size == 4 {
+                a.mov(eax, eax)?;
+                a.mov(edx, edx)?;
+            }
+            a.mov(qword_ptr(rbp), rax)?;
+            a.mov(qword_ptr(rbp + 16), rdx)?;
+        }
+        Op::Convert(convert) => match convert {
+            Convert::Cwde => {
+                a.movsx(eax, word_ptr(rbp))?;
+                a.mov(qword_ptr(rbp), rax)?;
+            }
+            Convert::Cdqe => {
+                a.movsxd(rax, dword_ptr(rbp))?;
+                a.mov(qword_ptr(rbp), rax)?;
+            }
+            Convert::Cdq => {
+                a.mov(eax, dword_ptr(rbp))?;
+                a.cdq()?;
+                a.mov(qword_ptr(rbp + 16), rdx)?;
+            }
+            Convert::Cqo => {
+                a.mov(rax, qword_ptr(rbp))?;
+                a.cqo()?;
+                a.mov(qword_ptr(rbp + 16), rdx)?;
+            }
+        },
+        Op::ZeroExtend(size) => {
+            a.pop(rax)?;
+            match size {
+                1 => a.movzx(eax, al)?,
+                2 => a.movzx(eax, ax)?,
+                _ => a.mov(eax, eax)?,
+            }
+            a.push(rax)?;
+        }
+        Op::SignExtend(size) => {
+            a.pop(rax)?;
+            match size {
+                1 => a.movsx(rax, al)?,
+                2 => a.movsx(rax, ax)?,
+                _ => a.movsxd(rax, eax)?,
+            }
+            a.push(rax)?;
+        }
+        Op::Jmp => {
+            operand32(a)?;
+            a.lea(rsi, qword_ptr(rbx + rax))?;
+        }
+        Op::Jcc(cc) => {
+            let mut taken = a.create_label();
+            load_flags(a)?;
+            jcc(a, cc, taken)?;
+            a.add(rsi, 4)?;
+            dispatch(a)?;
+            a.set_label(&mut taken)?;
+            operand32(a)?;
+            a.lea(rsi, qword_ptr(rbx + rax))?;
+        }
+        Op::Setcc(cc) => {
+            load_flags(a)?;
+            setcc(a, cc)?;
+            a.movzx(eax, al)?;
+            a.push(rax)?;
+        }
+        Op::Cmov(cc) => {
+            a.pop(rcx)?;
+            a.pop(rax)?;
+            load_flags(a)?;
+            cmovcc(a, cc)?;
+            a.push(rax)?;
+        }
+        Op::VPush => {
+            a.pop(rax)?;
+            a.mov(rcx, qword_ptr(rbp + RSP_SLOT))?;
+            a.lea(rcx, qword_ptr(rcx - 8))?;
+            a.mov(qword_ptr(rcx), rax)?;
+            a.mov(qword_ptr(rbp + RSP_SLOT), rcx)?;
+        }
+        Op::VPop => {
+            a.mov(rcx, qword_ptr(rbp + RSP_SLOT))?;
+            a.push(qword_ptr(rcx))?;
+            a.lea(rcx, qword_ptr(rcx + 8))?;
+            a.mov(qword_ptr(rbp + RSP_SLOT), rcx)?;
+        }
+        Op::Exit => return emit_exit(a),
+        Op::Drop => a.lea(rsp, qword_ptr(rsp + 8))?,
+        Op::AddDisp => {
+            operand32(a)?;
+            a.movsxd(rax, eax)?;
+            a.add(qword_ptr(rsp), rax)?;
+        }
+        Op::AddrAdd => {
+            a.pop(rax)?;
+            a.add(qword_ptr(rsp), rax)?;
+        }
+        Op::AddrScale => {
+            operand8(a)?;
+            a.mov(ecx, eax)?;
+            a.shl(qword_ptr(rsp), cl)?;
+        }
+        Op::Trap => return a.ud2(),
+    }
+    dispatch(a)
+}
+
+/// Restores the registers from the context and jumps to the popped target.
+///
+/// The target, flags, `rax` and `rbp` are staged 128 to 160 bytes below the
+/// virtual stack pointer, out of the red zone, and `ret 128` releases them.
+/// `rsp` only moves up to them once the context has been read, so a signal
+/// cannot overwrite it while it is still needed.
+fn emit_exit(a: &mut CodeAssembler) -> Result<(), IcedError> {
+    a.pop(rax)?;
+    a.mov(rcx, qword_ptr(rbp + RSP_SLOT))?;
+    a.mov(qword_ptr(rcx - 136), rax)?;
+    for (from, to) in [(FLAGS_SLOT, 144), (0, 152), (RBP_SLOT, 160)] {
+        a.mov(rax, qword_ptr(rbp + from))?;
+        a.mov(qword_ptr(rcx - to), rax)?;
+    }
+    for (register, at) in RESTORED {
+        a.mov(register, qword_ptr(rbp + at))?;
+    }
+    a.mov(rax, qword_ptr(rbp + RSP_SLOT))?;
+    a.lea(rsp, qword_ptr(rax - 160))?;
+    a.pop(rbp)?;
+    a.pop(rax)?;
+    a.popfq()?;
+    a.ret_1(128)
+}
@@ -0,0 +1,610 @@
+//! Per-customer watermarks (`WatermarkStep`) and their recovery (`extract`).
+//!
+//! The identifier is hidden by up to three carriers, each readable on its own:
+//!
+//! ```text
+//! padding        records in the zero padding after the data of the sections
+//!                (PE) or between the segments (ELF)
+//! code_caves     records in the int3 or nop filler between functions
+//! instructions   the frame [len, id, tag] spread over the register-to-register
+//!                instructions that have two encodings (`add r/m, r` and
+//!                `add r, r/m`...)
+//! ```
+//!
+//! A record is a random 4-byte nonce followed by `[len, id, tag]` XORed with
+//! a SipHash keystream of the key and nonce; without the key it looks like
+//! random bytes. The tag is a SipHash of the key, nonce and identifier, so
+//! `extract` can try every file offset. In the instruction carrier the bit
+//! index and a whitening bit come from a SipHash of the instruction RVA:
+//! `extract` votes over every candidate instruction of the executable
+//! sections, and instructions the step did not choose only add noise.
+//!
+//! Packing compresses the code and the section data, so the records in
+//! the packed sections and the instruction frame only show in memory.
+
+use std::collections::HashSet;
+use std::sync::mpsc::Sender;
+
+use iced_x86::{Decoder, DecoderOptions};
+use rand::rngs::StdRng;
+use rand::Rng;
+
+use crate::pipeline::{PipelineContext, PipelineMessage};
+use crate::pipeline::error::StepError;
+use crate::pipeline::hash::siphash24;
+use crate::pipeline::image::{Image, ImageFormat, DIR_BASERELOC, IMAGE_SCN_MEM_EXECUTE};
+use crate::pipeline::pe;
+use crate::pipeline::plan::PlannedChange;
+use crate::pipeline::profile::{step_rng, WatermarkCarrier, WatermarkOptions};
+use crate::pipeline::step::PipelineStep;
+
+/// Key used when the profile sets none.
+pub const DEFAULT_KEY: [u64; 2] = [0x6f62_7363_7572_6177, 0x6d61_726b_2d6b_6579];
+pub const MAX_ID_LEN: usize = 32;
+const NONCE_SIZE: usize = 4;
+const TAG_SIZE: usize = 4;
+/// Stands in for the nonce in the tag of the instruction frame, which has none.
+const FRAME_NONCE: &[u8; NONCE_SIZE] = b"inst";
+/// Opcodes of the `r/m, reg` forms; `opcode | 2` is the `reg, r/m` form of the same operation.
+const SWAPPABLE_OPCODES: [u8; 18] = [
+    0x00, 0x01, 0x08, 0x09, 0x10, 0x11, 0x18, 0x19, 0x20, 0x21, 0x28, 0x29, 0x30, 0x31, 0x38, 0x39, 0x88, 0x89,
+];
+/// Register number of `rsp` (and `ah` without REX), left alone.
+const STACK_REGISTER: u8 = 4;
+
+/// Embeds the identifier of the profile with the configured carriers.
+///
+/// Runs after the steps that rewrite code, so the instruction carrier only
+/// uses functions left in place, outside their prologues and epilogues.
+pub struct WatermarkStep {
+    options: WatermarkOptions,
+}
+
+impl WatermarkStep {
+    pub fn new(options: WatermarkOptions) -> Self {
+        Self { options }
+    }
+}
+
+impl PipelineStep for WatermarkStep {
+    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> Result<(), StepError> {
+        ctx.cancel.check()?;
+        let id = self.options.id.as_bytes();
+        if id.is_empty() || id.len() > MAX_ID_LEN {
+            return Err(StepError::InvalidInput(format!(
+                "Watermark identifier must be 1 to {} bytes, got {}",
+                MAX_ID_LEN,
+                id.len()
+            )));
+        }
+        let key = self.options.key()?.unwrap_or(DEFAULT_KEY);
+        if self.options.key.is_none() {
+            tx.send(PipelineMessage::Log(
+                "Watermark: no key set; the built-in key lets anyone with this tool find the watermark".into(),
+            ))
+            .ok();
+        }
+        let Some(image) = ctx.image.as_mut() else {
+            return Err(StepError::Internal("no image loaded before watermarking".into()));
+        };
+
+        let mut rng = step_rng(ctx.seed, "watermark");
+        let mut carriers = self.options.carriers.clone();
+        carriers.sort();
+        carriers.dedup();
+        let mut summary = Vec::new();
+        for carrier in carriers {
+            ctx.cancel.check()?;
+            let description = match carrier {
+                WatermarkCarrier::Padding => {
+                    let regions = padding(image);
+                    place_records(image, &regions, key, id, &mut rng, ctx.dry_run)?
+                }
+                WatermarkCarrier::CodeCaves => {
+                    let regions = code_caves(image);
+                    place_records(image, &regions, key, id, &mut rng, ctx.dry_run)?
+                }
+                WatermarkCarrier::Instructions => encode_instructions(image, key, id, ctx.dry_run)?,
+            };
+            let Some(description) = description else {
+                tx.send(PipelineMessage::Log(format!("Watermark {}: no room", carrier))).ok();
+                continue;
+            };
+            if ctx.dry_run {
+                ctx.plan.push(PlannedChange::Watermark {
+                    carrier: carrier.to_string(),
+                    description,
+                });
+            } else {
+                tx.send(PipelineMessage::Log(format!("Watermark {}: {}", carrier, description))).ok();
+                summary.push(format!("{} {}", carrier, description));
+            }
+        }
+        if !summary.is_empty() {
+            image.notes.push(format!("Watermarked with '{}': {}", self.options.id, summary.join(", ")));
+        }
+        Ok(())
+    }
+}
+
+/// Writes one record at a random place of each region (file offset, size)
+/// large enough, and describes the copies.
+fn place_records(
+    image: &mut Image,
+    regions: &[(usize, usize)],
+    key: [u64; 2],
+    id: &[u8],
+    rng: &mut StdRng,
+    dry_run: bool,
+) -> Result<Option<String>, StepError> {
+    let size = NONCE_SIZE + 1 + id.len() + TAG_SIZE;
+    let mut offsets = Vec::new();
+    for &(start, len) in regions.iter().filter(|&&(_, len)| len >= size) {
+        let at = start + rng.gen_range(0..=len - size);
+        if !dry_run {
+            image.write_file(at, &record(key, nonce(rng), id))?;
+        }
+        offsets.push(format!("0x{:x}", at));
+    }
+    Ok((!offsets.is_empty()).then(|| format!("{} copies at {}", offsets.len(), offsets.join(", "))))
+}
+
+/// A nonce whose bytes are not all the same, so `extract` can skip filler.
+fn nonce(rng: &mut StdRng) -> [u8; NONCE_SIZE] {
+    loop {
+        let nonce: [u8; NONCE_SIZE] = rng.gen();
+        if nonce.iter().any(|&b| b != nonce[0]) {
+            return nonce;
+        }
+    }
+}
+
+fn keystream(key: [u64; 2], nonce: &[u8], len: usize) -> Vec<u8> {
+    let mut stream = Vec::with_capacity(len + 8);
+    for block in 0u32.. {
+        if stream.len() >= len {
+            break;
+        }
+        let input = [nonce, &block.to_le_bytes()].concat();
+        stream.extend_from_slice(&siphash24(key, &input).to_le_bytes());
+    }
+    stream.truncate(len);
+    stream
+}
+
+fn tag(key: [u64; 2], nonce: &[u8], id: &[u8]) -> [u8; TAG_SIZE] {
+    let input = [b"tag".as_slice(), nonce, id].concat();
+    (siphash24(key, &input) as u32).to_le_bytes()
+}
+
+/// `[len, id, tag]`, which the records encrypt and the instruction frame carries.
+fn frame(key: [u64; 2], nonce: &[u8], id: &[u8]) -> Vec<u8> {
+    [&[id.len() as u8], id, &tag(key, nonce, id)].concat()
+}
+
+/// The identifier in `frame` if it is one of `key` and `nonce`.
+fn open_frame(key: [u64; 2], nonce: &[u8], frame: &[u8]) -> Option<String> {
+    let len = *frame.first()? as usize;
+    let id = frame.get(1..1 + len)?;
+    (len > 0 && frame.get(1 + len..1 + len + TAG_SIZE)? == tag(key, nonce, id))
+        .then(|| String::from_utf8_lossy(id).into_owned())
+}
+
+fn record(key: [u64; 2], nonce: [u8; NONCE_SIZE], id: &[u8]) -> Vec<u8> {
+    let mut body = frame(key, &nonce, id);
+    let stream = keystream(key, &nonce, body.len());
+    for (b, k) in body.iter_mut().zip(stream) {
+        *b ^= k;
+    }
+    [nonce.as_slice(), &body].concat()
+}
+
+/// The identifier of a record starting at `bytes[0]`, if there is one.
+fn open_record(key: [u64; 2], bytes: &[u8]) -> Option<String> {
+    let nonce = bytes.get(..NONCE_SIZE)?;
+    if nonce.iter().all(|&b| b == nonce[0]) {
+        return None;
+    }
+    let first = keystream(key, nonce, 8);
+    let len = (bytes.get(NONCE_SIZE)? ^ first[0]) as usize;
+    if len == 0 || len > MAX_ID_LEN {
+        return None;
+    }
+    let mut body = bytes.get(NONCE_SIZE..NONCE_SIZE + 1 + len + TAG_SIZE)?.to_vec();
+    for (b, k) in body.iter_mut().zip(keystream(key, nonce, 1 + len + TAG_SIZE)) {
+        *b ^= k;
+    }
+    open_frame(key, nonce, &body)
+}
+
+/// Zero runs (file offset, size) after the data of each section (PE) or
+/// between the segments (ELF). The PE relocation section is left out: a
+/// larger relocation table is written into its padding.
+fn padding(image: &Image) -> Vec<(usize, usize)> {
+    let file = image.file();
+    let mut regions = Vec::new();
+    match image.format {
+        ImageFormat::Pe => {
+            let (relocations, _) = image.data_directory(DIR_BASERELOC);
+            for s in &image.sections {
+                if s.virtual_size == 0
+                    || s.virtual_size >= s.raw_size
+                    || pe::range_contains(s.virtual_address, s.raw_size, relocations)
+                {
+                    continue;
+                }
+                // a section whose raw data wraps past 4 GiB has no padding to use
+                let (Some(start), Some(end)) =
+                    (s.raw_offset.checked_add(s.virtual_size), s.raw_offset.checked_add(s.raw_size))
+                else {
+                    continue;
+                };
+                regions.extend(longest_run(file, start as usize, (end as usize).min(file.len()), &[0]));
+            }
+        }
+        ImageFormat::Elf => {
+            let mut segments: Vec<_> = image.sections.iter().filter(|s| s.raw_size > 0).collect();
+            segments.sort_by_key(|s| s.raw_offset);
+            let named = image.named_sections();
+            for pair in segments.windows(2) {
+                let Some(start) = pair[0].raw_offset.checked_add(pair[0].raw_size) else {
+                    continue;
+                };
+                let start = start as usize;
+                // up to the next section with file data, if one sits in the gap
+                let end = named
+                    .iter()
+                    .map(|(_, s)| s.raw_offset as usize)
+                    .filter(|&offset| offset >= start)
+                    .fold(pair[1].raw_offset as usize, usize::min)
+                    .min(file.len());
+                if end > start {
+                    regions.extend(longest_run(file, start, end, &[0]));
+                }
+            }
+        }
+    }
+    regions
+}
+
+/// Filler runs (file offset, size) between consecutive functions of the
+/// same section.
+fn code_caves(image: &mut Image) -> Vec<(usize, usize)> {
+    let mut extents: Vec<(u32, u32)> = image
+        .code_map()
+        .functions
+        .iter()
+        .filter(|f| f.info.size > 0)
+        .map(|f| (f.info.rva, f.info.rva + f.info.size))
+        .collect();
+    extents.sort();
+    let mut regions = Vec::new();
+    for pair in extents.windows(2) {
+        let (start, end) = (pair[0].1, pair[1].0);
+        let same_section = match (image.section_at(start), image.section_at(end)) {
+            (Some(a), Some(b)) => std::ptr::eq(a, b),
+            _ => false,
+        };
+        if end <= start || !same_section || !image.is_executable(start) || image.is_relocated(start, end - start) {
+            continue;
+        }
+        let Some(offset) = image.rva_to_offset(start) else {
+            continue;
+        };
+        // alignment filler, not the zeros of data the disassembler took for a gap
+        let end = (offset + (end - start) as usize).min(image.file().len());
+        regions.extend(longest_run(image.file(), offset, end, &[0xcc, 0x90]));
+    }
+    regions
+}
+
+/// Longest run of one of `fillers` in `file[start..end]`, as (offset, size).
+fn longest_run(file: &[u8], start: usize, end: usize, fillers: &[u8]) -> Option<(usize, usize)> {
+    let mut best: Option<(usize, usize)> = None;
+    let mut at = start;
+    while at < end {
+        let run = file[at..end].iter().take_while(|&&b| fillers.contains(&b) && b == file[at]).count();
+        if run == 0 {
+            at += 1;
+            continue;
+        }
+        if best.is_none_or(|(_, size)| run > size) {
+            best = Some((at, run));
+        }
+        at += run;
+    }
+    best
+}
+
+/// Which of its two encodings a swappable instruction uses.
+struct Encoding {
+    opcode_at: usize,
+    /// The `reg, r/m` form.
+    reversed: bool,
+}
+
+/// Parses `bytes`, one whole instruction, as a register-to-register
+/// operation with two encodings.
+fn encoding(bytes: &[u8], bitness: u32) -> Option<Encoding> {
+    let mut at = 0;
+    while bytes.get(at) == Some(&0x66) {
+        at += 1;
+    }
+    let rex = match bytes.get(at) {
+        Some(&b) if bitness == 64 && b & 0xf0 == 0x40 => {
+            at += 1;
+            b
+        }
+        _ => 0,
+    };
+    let opcode = *bytes.get(at)?;
+    let modrm = *bytes.get(at + 1)?;
+    if bytes.len() != at + 2 || modrm >> 6 != 3 || !SWAPPABLE_OPCODES.contains(&(opcode & !2)) {
+        return None;
+    }
+    let reg = (modrm >> 3 & 7) | (rex & 4) << 1;
+    let rm = (modrm & 7) | (rex & 1) << 3;
+    if reg == STACK_REGISTER || rm == STACK_REGISTER {
+        return None;
+    }
+    Some(Encoding { opcode_at: at, reversed: opcode & 2 != 0 })
+}
+
+/// Switches the instruction parsed by `encoding` to its other encoding.
+fn swap_encoding(bytes: &mut [u8], encoding: &Encoding) {
+    let at = encoding.opcode_at;
+    let modrm = bytes[at + 1];
+    bytes[at] ^= 2;
+    bytes[at + 1] = 0xc0 | (modrm & 7) << 3 | (modrm >> 3 & 7);
+    if at > 0 && bytes[at - 1] & 0xf0 == 0x40 {
+        let rex = bytes[at - 1];
+        bytes[at - 1] = (rex & !5) | (rex & 4) >> 2 | (rex & 1) << 2;
+    }
+}
+
+/// Bit index in a frame of `bits` bits and whitening bit of the candidate at `rva`.
+fn bit_position(key: [u64; 2], rva: u32, bits: usize) -> (usize, bool) {
+    let h = siphash24(key, &rva.to_le_bytes());
+    ((h >> 1) as usize % bits, h & 1 != 0)
+}
+
+/// Gives every candidate instruction of the functions left in place the
+/// encoding its frame bit asks for.
+fn encode_instructions(image: &mut Image, key: [u64; 2], id: &[u8], dry_run: bool) -> Result<Option<String>, StepError> {
+    let bitness = if image.is_64 { 64 } else { 32 };
+    let image_base = image.image_base;
+    let mut sites = Vec::new();
+    for function in image.code_map().functions.iter().filter(|f| !f.modified && !f.replaced) {
+        let epilogues: HashSet<usize> = function.blocks.iter().flat_map(|b| function.epilogue_start(b)..b.end).collect();
+        for (i, instruction) in function.instructions.iter().enumerate().skip(function.prologue_len()) {
+            if !epilogues.contains(&i) {
+                sites.push(((instruction.ip() - image_base) as u32, instruction.len()));
+            }
+        }
+    }
+    sites.sort();
+    sites.dedup();
+
+    let frame = frame(key, FRAME_NONCE, id);
+    let bits = frame.len() * 8;
+    let (mut candidates, mut swapped) = (0, 0);
+    let mut covered = vec![false; bits];
+    for (rva, len) in sites {
+        if image.is_relocated(rva, len as u32) {
+            continue;
+        }
+        let Some(mut bytes) = image.read(rva, len).map(<[u8]>::to_vec) else {
+            continue;
+        };
+        let Some(encoding) = encoding(&bytes, bitness) else {
+            continue;
+        };
+        let (index, whitening) = bit_position(key, rva, bits);
+        let wanted = (frame[index / 8] >> (index % 8) & 1 != 0) ^ whitening;
+        candidates += 1;
+        covered[index] = true;
+        if encoding.reversed != wanted {
+            swapped += 1;
+            if !dry_run {
+                swap_encoding(&mut bytes, &encoding);
+                image.write(rva, &bytes)?;
+            }
+        }
+    }
+    let missing = covered.iter().filter(|&&c| !c).count();
+    if missing > 0 {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "{} instructions carry the {}-bit frame, {} re-encoded",
+        candidates, bits, swapped
+    )))
+}
+
+/// A watermark found by `extract`.
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub carrier: WatermarkCarrier,
+    pub id: String,
+    /// Where the records are, or how many instructions agree.
+    pub evidence: String,
+}
+
+/// Looks for the watermarks of `key` in a file.
+pub fn extract(bytes: Vec<u8>, key: [u64; 2]) -> Result<Vec<Finding>, StepError> {
+    let image = Image::parse(bytes)?;
+    let file = image.file();
+    let mut records: Vec<(WatermarkCarrier, String, Vec<usize>)> = Vec::new();
+    for at in 0..file.len() {
+        let Some(id) = open_record(key, &file[at..]) else {
+            continue;
+        };
+        let carrier = match image.offset_to_rva(at) {
+            Some(rva) if image.is_executable(rva) && in_section_data(&image, rva) => WatermarkCarrier::CodeCaves,
+            _ => WatermarkCarrier::Padding,
+        };
+        match records.iter_mut().find(|(c, i, _)| *c == carrier && *i == id) {
+            Some((_, _, offsets)) => offsets.push(at),
+            None => records.push((carrier, id, vec![at])),
+        }
+    }
+    let mut findings: Vec<Finding> = records
+        .into_iter()
+        .map(|(carrier, id, offsets)| Finding {
+            carrier,
+            id,
+            evidence: format!(
+                "{} copies at {}",
+                offsets.len(),
+                offsets.iter().map(|o| format!("0x{:x}", o)).collect::<Vec<_>>().join(", ")
+            ),
+        })
+        .collect();
+    findings.extend(decode_instructions(&image, key));
+    findings.sort_by_key(|f| f.carrier);
+    Ok(findings)
+}
+
+/// Whether `rva` is within the data of its section rather than the padding after it.
+fn in_section_data(image: &Image, rva: u32) -> bool {
+    image
+        .section_at(rva)
+        .is_some_and(|s| s.virtual_size == 0 || rva < s.virtual_address + s.virtual_size.min(s.raw_size))
+}
+
+/// Votes over the candidate instructions of the executable sections for
+/// every possible identifier length.
+fn decode_instructions(image: &Image, key: [u64; 2]) -> Option<Finding> {
+    let bitness = if image.is_64 { 64 } else { 32 };
+    let mut candidates = Vec::new();
+    for section in image.sections.iter().filter(|s| s.characteristics & IMAGE_SCN_MEM_EXECUTE != 0) {
+        let size = match section.virtual_size {
+            0 => section.raw_size,
+            size => size.min(section.raw_size),
+        };
+        let Some(code) = image.read(section.virtual_address, size as usize) else {
+            continue;
+        };
+        let mut decoder = Decoder::with_ip(bitness, code, section.virtual_address as u64, DecoderOptions::NONE);
+        while decoder.can_decode() {
+            let instruction = decoder.decode();
+            let at = (instruction.ip() - section.virtual_address as u64) as usize;
+            if let Some(encoding) = encoding(&code[at..at + instruction.len()], bitness) {
+                let h = siphash24(key, &(instruction.ip() as u32).to_le_bytes());
+                candidates.push((h, encoding.reversed));
+            }
+        }
+    }
+
+    for len in 1..=MAX_ID_LEN {
+        let bits = (1 + len + TAG_SIZE) * 8;
+        let mut votes = vec![0i32; bits];
+        for &(h, reversed) in &candidates {
+            votes[(h >> 1) as usize % bits] += if reversed ^ (h & 1 != 0) { 1 } else { -1 };
+        }
+        let mut frame = vec![0u8; bits / 8];
+        for (index, &vote) in votes.iter().enumerate() {
+            if vote > 0 {
+                frame[index / 8] |= 1 << (index % 8);
+            }
+        }
+        let Some(id) = open_frame(key, FRAME_NONCE, &frame) else {
+            continue;
+        };
+        let agreeing = candidates
+            .iter()
+            .filter(|&&(h, reversed)| {
+                let index = (h >> 1) as usize % bits;
+                (reversed ^ (h & 1 != 0)) == (frame[index / 8] >> (index % 8) & 1 != 0)
+            })
+            .count();
+        return Some(Finding {
+            carrier: WatermarkCarrier::Instructions,
+            id,
+            evidence: format!("{} of {} candidate instructions agree", agreeing, candidates.len()),
+        });
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+
+    use crate::pipeline::cancel::CancellationToken;
+    use crate::pipeline::fixture;
+    use crate::pipeline::profile::parse_sip_key;
+
+    const KEY: &str = "00112233445566778899aabbccddeeff";
+
+    /// Watermarks `bytes` with `id`; returns the written file and the carriers that found room.
+    fn watermark(bytes: Vec<u8>, id: &str, carriers: &[WatermarkCarrier]) -> (Vec<u8>, Vec<WatermarkCarrier>) {
+        let mut ctx = PipelineContext::new("sample".into(), CancellationToken::new());
+        ctx.seed = 11;
+        ctx.image = Some(Image::parse(bytes).unwrap());
+        let options = WatermarkOptions {
+            enabled: true,
+            id: id.into(),
+            key: Some(KEY.into()),
+            carriers: carriers.to_vec(),
+        };
+        let (tx, rx) = mpsc::channel();
+        WatermarkStep::new(options).run(&mut ctx, &tx).unwrap();
+        drop(tx);
+        let logs: Vec<String> = rx
+            .iter()
+            .filter_map(|m| match m {
+                PipelineMessage::Log(line) => Some(line),
+                _ => None,
+            })
+            .collect();
+        let placed = carriers
+            .iter()
+            .copied()
+            .filter(|carrier| {
+                let prefix = format!("Watermark {}: ", carrier);
+                logs.iter().any(|line| line.starts_with(&prefix) && !line.ends_with("no room"))
+            })
+            .collect();
+        let mut image = ctx.image.take().unwrap();
+        image.finalize().unwrap();
+        (image.into_bytes(), placed)
+    }
+
+    #[test]
+    fn embedded_watermark_is_extracted() {
+        let key = parse_sip_key(KEY).unwrap();
+        let sample = fixture::pe64(&fixture::loop_function(), &[]);
+        let (bytes, placed) = watermark(sample, "customer-42", &[WatermarkCarrier::Padding]);
+        assert_eq!(placed, [WatermarkCarrier::Padding]);
+        let findings = extract(bytes.clone(), key).unwrap();
+        assert!(findings.iter().all(|f| f.id == "customer-42"));
+        assert!(findings.iter().any(|f| f.carrier == WatermarkCarrier::Padding));
+        // another key sees random bytes
+        assert!(extract(bytes, DEFAULT_KEY).unwrap().is_empty());
+
+        let Ok(binary) = std::fs::read("/bin/ls") else {
+            return;
+        };
+        let carriers = [WatermarkCarrier::Padding, WatermarkCarrier::CodeCaves, WatermarkCarrier::Instructions];
+        let (bytes, placed) = watermark(binary, "build 7", &carriers);
+        // ls has no filler long enough for a record between its functions
+        assert!(placed.contains(&WatermarkCarrier::Instructions));
+        let findings = extract(bytes, key).unwrap();
+        for carrier in placed {
+            assert!(findings.iter().any(|f| f.carrier == carrier && f.id == "build 7"), "{} not found", carrier);
+        }
+    }
+
+    #[test]
+    fn extraction_without_a_watermark_finds_nothing() {
+        let bytes = fixture::pe64(&fixture::loop_function(), &[]);
+        assert!(extract(bytes.clone(), DEFAULT_KEY).unwrap().is_empty());
+        assert!(extract(bytes.clone(), parse_sip_key(KEY).unwrap()).unwrap().is_empty());
+        // truncated or not an executable at all: an error, not a panic
+        assert!(extract(bytes[..0x100].to_vec(), DEFAULT_KEY).is_err());
+        assert!(extract(b"not an executable".to_vec(), DEFAULT_KEY).is_err());
+    }
+}
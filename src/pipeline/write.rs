@@ -1,26 +1,74 @@
-use super::{PipelineContext, PipelineMessage, step::PipelineStep};
+use super::{PipelineContext, PipelineMessage, error::StepError, plan::PlannedChange, step::PipelineStep, symbol_map};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::Sender;
-use std::sync::atomic::Ordering;
 
 pub struct WriteOutputStep;
 
+impl WriteOutputStep {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
 impl PipelineStep for WriteOutputStep {
-    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> anyhow::Result<()> {
+    fn run(&self, ctx: &mut PipelineContext, tx: &Sender<PipelineMessage>) -> Result<(), StepError> {
         // verificar cancelamento
-        if ctx.cancel_flag.load(Ordering::Relaxed) {
-            let _ = tx.send(PipelineMessage::Cancelled);
+        ctx.cancel.check()?;
+
+        let output_path = output_path(Path::new(&ctx.input_path));
+
+        if ctx.dry_run {
+            ctx.plan.push(PlannedChange::WriteFile {
+                path: output_path.display().to_string(),
+                description: "protected output".into(),
+            });
             return Ok(());
         }
 
         tx.send(PipelineMessage::Log("Writing output file...".into())).ok();
 
-        let input_path = PathBuf::from(&ctx.input_path);
-        let output_path = input_path.with_extension("obscura-protected.exe");
+        match ctx.image.take() {
+            Some(mut image) => {
+                image.finalize()?;
+                for note in &image.notes {
+                    tx.send(PipelineMessage::Log(note.clone())).ok();
+                }
+                for routine in image.startup_routines() {
+                    tx.send(PipelineMessage::Log(format!(
+                        "Startup routine: {} at 0x{:x}",
+                        routine.name, routine.rva
+                    )))
+                    .ok();
+                }
+                if let Some(map) = ctx.symbol_map.as_mut() {
+                    map.record_moves(image.moved_functions());
+                }
+                fs::write(&output_path, image.into_bytes())
+                    .map_err(|e| StepError::io("Failed to write output", &output_path, e))?;
+            }
+            // no rewriting step ran on a parsed image: keep the original bytes
+            None => {
+                fs::copy(&ctx.input_path, &output_path)
+                    .map_err(|e| StepError::io("Failed to write output", &output_path, e))?;
+            }
+        }
+        ctx.track_file(&output_path);
 
-        // For now: just copy the original file as placeholder
-        fs::copy(&ctx.input_path, &output_path)?;
+        if let Some(mut map) = ctx.symbol_map.take() {
+            let output = fs::read(&output_path).map_err(|e| StepError::io("Failed to read output", &output_path, e))?;
+            map.build_id = symbol_map::build_id(&output);
+            let map_path = ctx.artifacts.path(&ctx.input_path, "obf-map")?;
+            ctx.write_artifact(&map_path, map.to_json().as_bytes())?;
+            tx.send(PipelineMessage::Log(format!(
+                "Symbol map written to {} ({} functions, build {}, {})",
+                map_path.display(),
+                map.functions.len(),
+                map.build_id,
+                ctx.artifacts.describe()
+            )))
+            .ok();
+        }
 
         tx.send(PipelineMessage::Log(format!(
             "Output written to {}",
@@ -28,10 +76,78 @@ impl PipelineStep for WriteOutputStep {
         )))
         .ok();
 
-        // Notify pipeline completion with the final path
-        tx.send(PipelineMessage::Done(output_path.to_string_lossy().to_string()))
-            .ok();
+        // The runner reports completion with this path once every step has finished
+        ctx.output_path = Some(output_path);
 
         Ok(())
     }
 }
+
+/// `<stem>.obscura-protected` with the extension of the input, if it has one
+/// (ELF executables usually do not).
+fn output_path(input: &Path) -> PathBuf {
+    match input.extension() {
+        Some(extension) => input.with_extension(format!("obscura-protected.{}", extension.to_string_lossy())),
+        None => input.with_extension("obscura-protected"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+
+    use crate::pipeline::artifacts::{ArtifactReader, Artifacts};
+    use crate::pipeline::cancel::CancellationToken;
+    use crate::pipeline::fixture::{self, IMAGE_BASE, TEXT_RVA};
+    use crate::pipeline::image::Image;
+    use crate::pipeline::obfuscate::ObfuscateFunctionsStep;
+    use crate::pipeline::profile::ArtifactOptions;
+    use crate::pipeline::symbol_map::{build_id, SymbolMap};
+
+    #[test]
+    fn output_keeps_the_extension_of_the_input() {
+        for (input, output) in [
+            ("dir/app.exe", "dir/app.obscura-protected.exe"),
+            ("dir/plugin.dll", "dir/plugin.obscura-protected.dll"),
+            ("dir/libz.so", "dir/libz.obscura-protected.so"),
+            ("dir/tool", "dir/tool.obscura-protected"),
+        ] {
+            assert_eq!(output_path(Path::new(input)), Path::new(output));
+        }
+    }
+
+    #[test]
+    fn written_symbol_map_deobfuscates_the_output() {
+        let dir = std::env::temp_dir().join(format!("obscura-write-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("sample.exe");
+        fs::write(&input, fixture::pe64(&fixture::loop_function(), &[])).unwrap();
+
+        let mut ctx = PipelineContext::new(input.to_string_lossy().into_owned(), CancellationToken::new());
+        ctx.image = Some(Image::parse(fs::read(&input).unwrap()).unwrap());
+        let options = ArtifactOptions { dir: Some(dir.join("artifacts")), ..Default::default() };
+        ctx.artifacts = Artifacts::new(&options).unwrap();
+        let (tx, _rx) = mpsc::channel();
+        ObfuscateFunctionsStep::new().run(&mut ctx, &tx).unwrap();
+        WriteOutputStep::new().run(&mut ctx, &tx).unwrap();
+
+        let output = ctx.output_path.clone().unwrap();
+        assert_eq!(output, dir.join("sample.obscura-protected.exe"));
+        let map_path = ctx.artifacts.path(&ctx.input_path, "obf-map").unwrap();
+        let map = SymbolMap::load(&map_path, &ArtifactReader::new(None, None).unwrap()).unwrap();
+        assert_eq!(map.build_id, build_id(&fs::read(&output).unwrap()));
+        let entry = map.functions.iter().find(|f| f.rva == TEXT_RVA).unwrap();
+        assert_ne!(entry.obfuscated, entry.name);
+        assert_eq!(
+            map.deobfuscate_line(&format!("at {}+0x4", entry.obfuscated), IMAGE_BASE),
+            format!("at {}+0x4", entry.name)
+        );
+        assert_eq!(
+            map.deobfuscate_line(&format!("crash at 0x{:x}", IMAGE_BASE + TEXT_RVA as u64 + 6), IMAGE_BASE),
+            format!("crash at 0x{:x} ({}+0x6)", IMAGE_BASE + TEXT_RVA as u64 + 6, entry.name)
+        );
+        fs::remove_dir_all(&dir).ok();
+    }
+}
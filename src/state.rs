@@ -1,7 +1,12 @@
 use std::sync::mpsc::Receiver;
-use std::sync::{Arc, atomic::AtomicBool};
 use std::time::{SystemTime, UNIX_EPOCH};
 use crate::pipeline::PipelineMessage;
+use crate::pipeline::cancel::CancellationToken;
+use crate::pipeline::error::StepError;
+use crate::pipeline::plan::ProtectionPlan;
+use crate::pipeline::report::AnalysisReport;
+use crate::pipeline::packer::PackedInputPolicy;
+use crate::pipeline::profile::ProtectionProfile;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum AppState {
@@ -24,12 +29,18 @@ pub struct ObscuraState {
     pub selected_file: Option<String>,
     pub encrypt_strings: bool,
     pub obfuscate_functions: bool,
+    pub dry_run: bool,
+    pub packed_input: PackedInputPolicy,
+    pub profile: ProtectionProfile,
 
     pub processing: bool,
     pub progress: f32,
     pub pipeline_rx: Option<Receiver<PipelineMessage>>,
     pub last_output: Option<String>,
-    pub cancel_flag: Option<Arc<AtomicBool>>,
+    pub last_error: Option<StepError>,
+    pub last_plan: Option<ProtectionPlan>,
+    pub last_analysis: Option<Box<AnalysisReport>>,
+    pub cancel_token: Option<CancellationToken>,
 
     // Authentication
     pub token: Option<String>,
@@ -48,11 +59,17 @@ impl ObscuraState {
             selected_file: None,
             encrypt_strings: true,
             obfuscate_functions: true,
+            dry_run: false,
+            packed_input: PackedInputPolicy::default(),
+            profile: ProtectionProfile::default(),
             processing: false,
             progress: 0.0,
             pipeline_rx: None,
             last_output: None,
-            cancel_flag: None,
+            last_error: None,
+            last_plan: None,
+            last_analysis: None,
+            cancel_token: None,
             token: None,
             auth_processing: false,
             auth_rx: None,
@@ -86,18 +103,29 @@ impl ObscuraState {
                         self.last_output = Some(output_path.clone());
                         self.processing = false;
                         self.progress = 1.0;
-                        self.cancel_flag = None;
+                        self.cancel_token = None;
                     }
                     PipelineMessage::Error(e) => {
-                        self.push_log(format!("Pipeline error: {}", e));
+                        self.push_log(format!("Pipeline error ({}): {}", e.category(), e.message()));
+                        self.last_error = Some(e);
                         self.processing = false;
-                        self.cancel_flag = None;
+                        self.cancel_token = None;
+                    }
+                    PipelineMessage::Analysis(report) => {
+                        self.last_analysis = Some(report);
+                    }
+                    PipelineMessage::Planned(plan) => {
+                        self.push_log(format!("Dry run finished: {}", plan.summary()));
+                        self.last_plan = Some(plan);
+                        self.processing = false;
+                        self.progress = 1.0;
+                        self.cancel_token = None;
                     }
                     PipelineMessage::Cancelled => {
                         self.push_log("Pipeline cancelled by user");
                         self.processing = false;
                         self.progress = 0.0;
-                        self.cancel_flag = None;
+                        self.cancel_token = None;
                     }
                 }
             }
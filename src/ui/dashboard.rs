@@ -1,6 +1,10 @@
 use eframe::egui;
 use crate::state::ObscuraState;
 use crate::pipeline;
+use crate::pipeline::error::StepError;
+use crate::pipeline::hash::HashAlgorithm;
+use crate::pipeline::packer::PackedInputPolicy;
+use crate::pipeline::profile::ProtectionProfile;
 
 pub fn show_dashboard(ui: &mut egui::Ui, state: &mut ObscuraState) {
     ui.with_layout(egui::Layout::top_down(eframe::egui::Align::Center), |ui| {
@@ -95,8 +99,8 @@ pub fn show_dashboard(ui: &mut egui::Ui, state: &mut ObscuraState) {
                         
                         // botão cancel
                         if ui.button("❌ Cancel").clicked() {
-                            if let Some(flag) = &state.cancel_flag {
-                                flag.store(true, std::sync::atomic::Ordering::Relaxed);
+                            if let Some(token) = &state.cancel_token {
+                                token.cancel();
                                 state.push_log("Cancellation requested...");
                             }
                         }
@@ -104,6 +108,47 @@ pub fn show_dashboard(ui: &mut egui::Ui, state: &mut ObscuraState) {
                         ui.add_space(10.0);
                     }
 
+                    // opções de proteção
+                    ui.add_enabled_ui(!state.processing, |ui| {
+                        ui.checkbox(&mut state.encrypt_strings, "Encrypt strings");
+                        ui.checkbox(&mut state.obfuscate_functions, "Obfuscate functions");
+                        ui.checkbox(&mut state.dry_run, "Dry run (analyze only, write nothing)");
+                        let mut refuse = state.packed_input == PackedInputPolicy::Refuse;
+                        if ui.checkbox(&mut refuse, "Refuse inputs that look already packed").changed() {
+                            state.packed_input = if refuse {
+                                PackedInputPolicy::Refuse
+                            } else {
+                                PackedInputPolicy::Warn
+                            };
+                        }
+                        ui.checkbox(
+                            &mut state.profile.imports.enabled,
+                            "Hide imports (resolve them at startup)",
+                        );
+                        if state.profile.imports.enabled {
+                            egui::ComboBox::from_label("Import name hash")
+                                .selected_text(state.profile.imports.hash.to_string())
+                                .show_ui(ui, |ui| {
+                                    for algorithm in [
+                                        HashAlgorithm::Crc32,
+                                        HashAlgorithm::Fnv1a,
+                                        HashAlgorithm::Djb2,
+                                        HashAlgorithm::SipHash,
+                                    ] {
+                                        ui.selectable_value(
+                                            &mut state.profile.imports.hash,
+                                            algorithm,
+                                            algorithm.to_string(),
+                                        );
+                                    }
+                                });
+                        }
+                        if ui.button("Load protection profile...").clicked() {
+                            load_profile(state);
+                        }
+                    });
+                    ui.add_space(10.0);
+
                     // botão protect file (desabilitado durante processamento)
                     let protect_enabled = !state.processing;
                     if ui
@@ -114,6 +159,9 @@ pub fn show_dashboard(ui: &mut egui::Ui, state: &mut ObscuraState) {
                             state.push_log(format!("Starting pipeline for {}", path));
 
                             // iniciar pipeline modular (nova API)
+                            state.last_error = None;
+                            state.last_plan = None;
+                            state.last_analysis = None;
                             pipeline::start_pipeline(state, path);
                             state.processing = true;
                             state.progress = 0.0;
@@ -124,6 +172,50 @@ pub fn show_dashboard(ui: &mut egui::Ui, state: &mut ObscuraState) {
 
                     ui.add_space(15.0);
 
+                    // mostrar último erro com a categoria
+                    if let Some(err) = &state.last_error {
+                        let color = match err {
+                            StepError::InvalidInput(_) | StepError::UnsupportedFormat(_) => {
+                                egui::Color32::from_rgb(230, 160, 40)
+                            }
+                            _ => egui::Color32::from_rgb(220, 60, 60),
+                        };
+                        ui.colored_label(color, egui::RichText::new(err.category()).strong());
+                        ui.label(err.message());
+                        ui.add_space(10.0);
+                    }
+
+                    // plano do dry run
+                    if let Some(plan) = &state.last_plan {
+                        ui.separator();
+                        ui.add_space(10.0);
+                        ui.label(egui::RichText::new("Planned changes").strong());
+                        ui.label(plan.summary());
+                        ui.add_space(5.0);
+                        egui::ScrollArea::vertical()
+                            .id_salt("dry_run_plan")
+                            .max_height(160.0)
+                            .show_rows(ui, 18.0, plan.changes.len(), |ui, rows| {
+                                for change in &plan.changes[rows] {
+                                    ui.label(egui::RichText::new(change.describe()).monospace());
+                                }
+                            });
+                        ui.add_space(10.0);
+                    }
+
+                    // exportar relatório de análise
+                    if state.last_analysis.is_some() {
+                        ui.horizontal(|ui| {
+                            if ui.button("📄 Export analysis (JSON)").clicked() {
+                                export_analysis(state, ReportFormat::Json);
+                            }
+                            if ui.button("📄 Export analysis (HTML)").clicked() {
+                                export_analysis(state, ReportFormat::Html);
+                            }
+                        });
+                        ui.add_space(10.0);
+                    }
+
                     // mostrar last output se disponível
                     if let Some(output_path) = &state.last_output {
                         ui.separator();
@@ -149,3 +241,45 @@ pub fn show_dashboard(ui: &mut egui::Ui, state: &mut ObscuraState) {
             });
     });
 }
+
+fn load_profile(state: &mut ObscuraState) {
+    let Some(path) = rfd::FileDialog::new()
+        .add_filter("Protection profile", &["json"])
+        .pick_file()
+    else {
+        return;
+    };
+    match ProtectionProfile::load(&path) {
+        Ok(profile) => {
+            state.profile = profile;
+            state.push_log(format!("Loaded protection profile {}", path.display()));
+        }
+        Err(e) => state.push_log(format!("{}: {}", e.category(), e.message())),
+    }
+}
+
+enum ReportFormat {
+    Json,
+    Html,
+}
+
+fn export_analysis(state: &mut ObscuraState, format: ReportFormat) {
+    let Some(report) = &state.last_analysis else {
+        return;
+    };
+    let (ext, contents) = match format {
+        ReportFormat::Json => ("json", report.to_json()),
+        ReportFormat::Html => ("html", report.to_html()),
+    };
+    let Some(path) = rfd::FileDialog::new()
+        .add_filter(ext, &[ext])
+        .set_file_name(format!("analysis-report.{}", ext))
+        .save_file()
+    else {
+        return;
+    };
+    match std::fs::write(&path, contents) {
+        Ok(_) => state.push_log(format!("Analysis report saved to {}", path.display())),
+        Err(e) => state.push_log(format!("Failed to save analysis report: {}", e)),
+    }
+}
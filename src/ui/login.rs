@@ -80,7 +80,7 @@ pub fn show_login(ui: &mut egui::Ui, state: &mut ObscuraState) {
                         std::thread::spawn(move || {
                             match auth_client::login_request(&base_url, &email, &password) {
                                 Ok(resp) => {
-                                    let _ = tx.send(AuthMsg::Success(resp.accessToken));
+                                    let _ = tx.send(AuthMsg::Success(resp.access_token));
                                 }
                                 Err(e) => {
                                     let msg = match e {
@@ -93,7 +93,6 @@ pub fn show_login(ui: &mut egui::Ui, state: &mut ObscuraState) {
                                         auth_client::AuthError::Network(s) => {
                                             format!("Network error: {}", s)
                                         }
-                                        auth_client::AuthError::Http(s) => s,
                                     };
                                     let _ = tx.send(AuthMsg::Error(msg));
                                 }